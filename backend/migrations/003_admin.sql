@@ -0,0 +1,10 @@
+-- Server-wide roles and account state
+ALTER TABLE users ADD COLUMN role VARCHAR(20) NOT NULL DEFAULT 'user';
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMPTZ;
+-- Bumped to invalidate every token issued before a forced logout
+ALTER TABLE users ADD COLUMN token_version INTEGER NOT NULL DEFAULT 0;
+
+-- Private rooms are only visible to their members (and admins)
+ALTER TABLE rooms ADD COLUMN is_private BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX idx_users_role ON users(role);
@@ -0,0 +1,2 @@
+-- Display name, filled from the directory for LDAP accounts
+ALTER TABLE users ADD COLUMN display_name VARCHAR(100);
@@ -0,0 +1,6 @@
+-- Self-service profile fields (display_name was added for LDAP accounts)
+ALTER TABLE users ADD COLUMN bio TEXT;
+ALTER TABLE users ADD COLUMN timezone VARCHAR(64);
+ALTER TABLE users ADD COLUMN status_text VARCHAR(140);
+-- Resized avatar stored with the other uploads
+ALTER TABLE users ADD COLUMN avatar_file_id UUID;
@@ -0,0 +1,19 @@
+-- Deleting an account no longer wipes its messages from shared rooms. They
+-- are kept without an author, or removed first if the instance policy says so.
+ALTER TABLE messages ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE messages DROP CONSTRAINT messages_user_id_fkey;
+ALTER TABLE messages ADD CONSTRAINT messages_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX idx_messages_user_id ON messages(user_id);
+
+-- Uploaded files and who uploaded them, for exports and account deletion
+CREATE TABLE files (
+    id UUID PRIMARY KEY,
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    filename TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_files_user_id ON files(user_id);
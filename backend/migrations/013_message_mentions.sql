@@ -0,0 +1,14 @@
+-- Mention spans as returned with the message: [{"type", "user_id", "username", "start", "end"}]
+ALTER TABLE messages ADD COLUMN mentions JSONB NOT NULL DEFAULT '[]';
+
+-- Everyone a message mentions, directly or through @room / @here
+CREATE TABLE message_mentions (
+    message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    kind VARCHAR(16) NOT NULL CHECK (kind IN ('user', 'room', 'here')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (message_id, user_id)
+);
+
+CREATE INDEX idx_message_mentions_user_id ON message_mentions(user_id, created_at DESC);
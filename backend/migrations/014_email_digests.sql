@@ -0,0 +1,17 @@
+-- Updated when the user's WebSocket connections open and close
+ALTER TABLE users ADD COLUMN last_seen_at TIMESTAMPTZ;
+
+-- Emailed summaries of unread mentions and direct messages
+ALTER TABLE users ADD COLUMN digest_frequency VARCHAR(16) NOT NULL DEFAULT 'daily'
+    CHECK (digest_frequency IN ('off', 'hourly', 'daily'));
+ALTER TABLE users ADD COLUMN last_digest_at TIMESTAMPTZ;
+
+-- How far each member has read in a room
+CREATE TABLE room_read_markers (
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    last_read_message_id UUID REFERENCES messages(id) ON DELETE SET NULL,
+    last_read_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (room_id, user_id)
+);
@@ -0,0 +1,21 @@
+-- Per-room URLs that let external tools post messages without signing in
+CREATE TABLE incoming_webhooks (
+    id UUID PRIMARY KEY,
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    -- Bot account the webhook's messages are posted as
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(100) NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    token_prefix VARCHAR(16) NOT NULL,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_incoming_webhooks_room_id ON incoming_webhooks(room_id);
+
+-- Display name chosen by the integration (Slack's `username` override)
+ALTER TABLE messages ADD COLUMN author_name VARCHAR(100);
+-- Slack-style attachments: [{"title", "title_link", "text", "color", "fields", ...}]
+ALTER TABLE messages ADD COLUMN attachments JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,53 @@
+-- Set with /topic and shown in the room header
+ALTER TABLE rooms ADD COLUMN topic VARCHAR(250);
+
+-- Admin-registered commands answered by an external HTTP handler
+CREATE TABLE slash_commands (
+    id UUID PRIMARY KEY,
+    name VARCHAR(32) NOT NULL UNIQUE,
+    description VARCHAR(200),
+    usage_hint VARCHAR(100),
+    url TEXT NOT NULL,
+    -- Kept in clear: it's the HMAC key requests are signed with
+    secret VARCHAR(128) NOT NULL,
+    -- Used when the handler's reply doesn't say
+    response_type VARCHAR(16) NOT NULL DEFAULT 'ephemeral' CHECK (response_type IN ('ephemeral', 'in_channel')),
+    -- Bot account public replies are posted as
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    disabled_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Set with /remind and posted to the room when due
+CREATE TABLE reminders (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    text TEXT NOT NULL,
+    remind_at TIMESTAMPTZ NOT NULL,
+    delivered_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_reminders_due ON reminders(remind_at) WHERE delivered_at IS NULL;
+
+-- Started with /poll; the poll shares its message's id
+CREATE TABLE polls (
+    message_id UUID PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    question TEXT NOT NULL,
+    options TEXT[] NOT NULL,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- One vote per user and poll
+CREATE TABLE poll_votes (
+    message_id UUID NOT NULL REFERENCES polls(message_id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    option_index INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (message_id, user_id)
+);
@@ -0,0 +1,11 @@
+ALTER TABLE polls ADD COLUMN multiple_choice BOOLEAN NOT NULL DEFAULT FALSE;
+-- Votes are still stored per user, to allow one vote each, but voters are never shown
+ALTER TABLE polls ADD COLUMN anonymous BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE polls ADD COLUMN closes_at TIMESTAMPTZ;
+-- Set when the creator or a moderator closes the poll early
+ALTER TABLE polls ADD COLUMN closed_at TIMESTAMPTZ;
+
+-- Multiple-choice polls store one row per chosen option
+ALTER TABLE poll_votes DROP CONSTRAINT poll_votes_pkey;
+ALTER TABLE poll_votes ADD PRIMARY KEY (message_id, user_id, option_index);
+ALTER TABLE poll_votes ADD CONSTRAINT poll_votes_option_index_check CHECK (option_index >= 0);
@@ -0,0 +1,23 @@
+-- Messages posted on the author's behalf at `send_at`
+CREATE TABLE scheduled_messages (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    room_id UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    content TEXT NOT NULL,
+    send_at TIMESTAMPTZ NOT NULL,
+    sent_at TIMESTAMPTZ,
+    message_id UUID REFERENCES messages(id) ON DELETE SET NULL,
+    -- Why it couldn't be posted; kept until the author reschedules or cancels it
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_scheduled_messages_due ON scheduled_messages(send_at) WHERE sent_at IS NULL;
+CREATE INDEX idx_scheduled_messages_user_id ON scheduled_messages(user_id);
+
+-- "Remind me about this message"; the reminder quotes it when posted
+ALTER TABLE reminders ADD COLUMN message_id UUID REFERENCES messages(id) ON DELETE SET NULL;
+ALTER TABLE reminders ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+CREATE INDEX idx_reminders_user_id ON reminders(user_id);
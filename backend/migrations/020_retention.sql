@@ -0,0 +1,8 @@
+-- Messages older than this are purged; NULL keeps history forever
+ALTER TABLE rooms ADD COLUMN retention_days INTEGER CHECK (retention_days > 0);
+
+-- Self-destructing messages are purged once this has passed
+ALTER TABLE messages ADD COLUMN expires_at TIMESTAMPTZ;
+
+CREATE INDEX idx_messages_expires_at ON messages(expires_at) WHERE expires_at IS NOT NULL;
+CREATE INDEX idx_messages_room_id_created_at ON messages(room_id, created_at);
@@ -0,0 +1,3 @@
+-- Sanitized HTML rendered from the Markdown in text messages. Existing rows
+-- are rendered at startup.
+ALTER TABLE messages ADD COLUMN content_html TEXT;
@@ -0,0 +1,10 @@
+-- Previews of the links in a message, attached once they've been fetched
+ALTER TABLE messages ADD COLUMN link_previews JSONB NOT NULL DEFAULT '[]';
+
+-- Unfurled pages shared by every message linking to them. `preview` is NULL
+-- when the page couldn't be fetched or had nothing to show.
+CREATE TABLE link_previews (
+    url TEXT PRIMARY KEY,
+    preview JSONB,
+    fetched_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
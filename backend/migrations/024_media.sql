@@ -0,0 +1,7 @@
+-- Set for uploads recognized as images
+ALTER TABLE files ADD COLUMN content_type TEXT;
+ALTER TABLE files ADD COLUMN width INTEGER;
+ALTER TABLE files ADD COLUMN height INTEGER;
+ALTER TABLE files ADD COLUMN blurhash TEXT;
+-- The sizes rendered, stored next to the original
+ALTER TABLE files ADD COLUMN thumbnails JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,5 @@
+-- The owner's users.token_version when the token was created. Bumping it
+-- (forced logout, password change or reset) invalidates API tokens too.
+ALTER TABLE api_tokens ADD COLUMN token_version INTEGER;
+UPDATE api_tokens SET token_version = users.token_version FROM users WHERE users.id = api_tokens.user_id;
+ALTER TABLE api_tokens ALTER COLUMN token_version SET NOT NULL;
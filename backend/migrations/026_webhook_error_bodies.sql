@@ -0,0 +1,9 @@
+-- Failed deliveries used to record the start of the receiver's response
+-- body; keep only the status line.
+UPDATE webhook_delivery_attempts
+SET error = regexp_replace(error, '^(HTTP [0-9]{3}[^:]*): .*$', '\1')
+WHERE error ~ '^HTTP [0-9]{3}';
+
+UPDATE webhook_deliveries
+SET last_error = regexp_replace(last_error, '^(HTTP [0-9]{3}[^:]*): .*$', '\1')
+WHERE last_error ~ '^HTTP [0-9]{3}';
@@ -0,0 +1,11 @@
+-- The upload a `file` message shares, so purges can tell whether an upload
+-- is still in use without scanning message content
+ALTER TABLE messages ADD COLUMN file_id UUID REFERENCES files(id) ON DELETE SET NULL;
+
+UPDATE messages m
+SET file_id = f.id
+FROM files f
+WHERE m.message_type = 'file'
+  AND f.id::TEXT = LOWER(SUBSTRING(m.content FROM '"id"\s*:\s*"([0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})"'));
+
+CREATE INDEX idx_messages_file_id ON messages(file_id) WHERE file_id IS NOT NULL;
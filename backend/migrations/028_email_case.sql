@@ -0,0 +1,17 @@
+-- Email addresses identify accounts regardless of case. Accounts that only
+-- differ in the case of their address have to be changed first.
+DO $$
+DECLARE
+    duplicates TEXT;
+BEGIN
+    SELECT string_agg(address, ', ') INTO duplicates
+    FROM (SELECT LOWER(email) AS address FROM users GROUP BY LOWER(email) HAVING COUNT(*) > 1) shared;
+
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'Email addresses used by several accounts in different case: %. Change all but one of each before upgrading.', duplicates;
+    END IF;
+END $$;
+
+UPDATE users SET email = LOWER(email) WHERE email <> LOWER(email);
+
+CREATE UNIQUE INDEX idx_users_email_lower ON users(LOWER(email));
@@ -0,0 +1,392 @@
+use crate::{
+    api_tokens,
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{get_user_by_id, AuthClaims},
+    digests,
+    error::AppError,
+    models::*,
+    profiles, push, settings, uploads,
+    websocket::{broadcast_to_room, disconnect_user},
+    AppState, SharedState,
+};
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    io::{Cursor, Write},
+};
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/users/me", delete(delete_me_handler))
+        .route("/users/me/export", get(export_handler))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletionSummary {
+    pub policy: String,
+    pub messages_deleted: usize,
+    pub files_deleted: usize,
+}
+
+/// Deletes the account and the bots it owns. Depending on the instance's
+/// account deletion policy, their messages and uploads are either deleted
+/// or left in place without an author.
+pub async fn delete_account(state: &AppState, user_id: Uuid) -> Result<DeletionSummary, AppError> {
+    let policy = settings::get_string(
+        &state.db,
+        settings::ACCOUNT_DELETION_POLICY,
+        settings::DELETION_ANONYMIZE,
+    ).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    let accounts = sqlx::query_as::<_, (Uuid, Option<Uuid>)>(
+        "SELECT id, avatar_file_id FROM users WHERE id = $1 OR bot_owner_id = $1 FOR UPDATE"
+    )
+    .bind(user_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    if accounts.is_empty() {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+    let account_ids: Vec<Uuid> = accounts.iter().map(|(id, _)| *id).collect();
+    let mut removed_files: Vec<Uuid> = accounts.iter().filter_map(|(_, avatar)| *avatar).collect();
+
+    let mut deleted_messages: Vec<(Uuid, Uuid)> = Vec::new();
+    let mut files_deleted = 0;
+    if policy == settings::DELETION_DELETE {
+        deleted_messages = sqlx::query_as::<_, (Uuid, Uuid)>(
+            "DELETE FROM messages WHERE user_id = ANY($1) RETURNING id, room_id"
+        )
+        .bind(&account_ids)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        // Uploads shared in other users' messages or used as an avatar are kept
+        let file_ids = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            DELETE FROM files f
+            WHERE f.user_id = ANY($1)
+              AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.file_id = f.id)
+              AND NOT EXISTS (SELECT 1 FROM users u WHERE u.avatar_file_id = f.id AND u.id <> ALL($1))
+            RETURNING f.id
+            "#
+        )
+        .bind(&account_ids)
+        .fetch_all(&mut *tx)
+        .await?;
+        files_deleted = file_ids.len();
+        removed_files.extend(file_ids);
+    }
+
+    // Anything left referencing the accounts is detached by the foreign keys
+    sqlx::query("DELETE FROM users WHERE id = ANY($1)")
+        .bind(&account_ids)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    for file_id in removed_files {
+        uploads::remove(file_id).await?;
+    }
+    for account_id in &account_ids {
+        disconnect_user(state, *account_id, None, "account deleted").await;
+    }
+
+    let mut by_room: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for (message_id, room_id) in &deleted_messages {
+        by_room.entry(*room_id).or_default().push(*message_id);
+    }
+    for (room_id, message_ids) in by_room {
+        let event = WebSocketMessage::new(
+            "messages_deleted",
+            &serde_json::json!({ "room_id": room_id, "message_ids": message_ids }),
+        );
+        broadcast_to_room(state, room_id, &event).await;
+    }
+
+    Ok(DeletionSummary {
+        policy,
+        messages_deleted: deleted_messages.len(),
+        files_deleted,
+    })
+}
+
+#[derive(Deserialize)]
+struct DeleteAccountRequest {
+    password: Option<String>,
+}
+
+async fn delete_me_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<DeleteAccountRequest>,
+) -> Result<Json<DeletionSummary>, AppError> {
+    let user = get_user_by_id(&state.db, claims.user_id()?)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+    profiles::reauthenticate(&state, &user, &claims, req.password.as_deref()).await?;
+
+    let summary = delete_account(&state, user.id).await?;
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("account.delete")
+            .actor(user.id)
+            .target("user", user.id)
+            .metadata(serde_json::json!({
+                "username": user.username,
+                "policy": summary.policy,
+                "messages_deleted": summary.messages_deleted,
+                "files_deleted": summary.files_deleted,
+            })),
+    ).await;
+
+    Ok(Json(summary))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ExportedMembership {
+    room_id: Uuid,
+    room_name: String,
+    role: String,
+    joined_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ExportedMessage {
+    id: Uuid,
+    room_id: Uuid,
+    room_name: String,
+    content: String,
+    message_type: String,
+    created_at: DateTime<Utc>,
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>, AppError> {
+    serde_json::to_vec_pretty(value).map_err(|e| AppError::InternalError(e.to_string()))
+}
+
+/// Keeps exported file names inside the archive's `files/` directory.
+fn archive_file_name(file: &FileRecord) -> String {
+    let filename: String = file
+        .filename
+        .chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .collect();
+    format!("files/{}_{}", file.id, filename)
+}
+
+/// Gathers everything stored about the user as `(path, contents)` pairs.
+async fn collect_export(pool: &PgPool, user_id: Uuid) -> Result<Vec<(String, Vec<u8>)>, AppError> {
+    let profile = profiles::get_own_profile(pool, user_id).await?;
+    let user = get_user_by_id(pool, user_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    let identities = sqlx::query_as::<_, UserIdentity>(
+        "SELECT * FROM user_identities WHERE user_id = $1 ORDER BY created_at"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let bots = sqlx::query_as::<_, Bot>(
+        "SELECT id, username, display_name, bot_owner_id, disabled_at, created_at FROM users WHERE bot_owner_id = $1"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let account = serde_json::json!({
+        "profile": profile,
+        "role": user.role,
+        "created_at": user.created_at,
+        "two_factor_enabled": user.totp_enabled_at.is_some(),
+        "identities": identities,
+        "api_tokens": api_tokens::list(pool, user_id).await?,
+        "bots": bots,
+        "push_subscriptions": push::list_subscriptions(pool, user_id).await?,
+        "notification_preferences": push::get_preferences(pool, user_id).await?,
+        "email_digest": digests::get_settings(pool, user_id).await?,
+    });
+
+    let memberships = sqlx::query_as::<_, ExportedMembership>(
+        r#"
+        SELECT rm.room_id, r.name AS room_name, rm.role, rm.joined_at
+        FROM room_members rm
+        JOIN rooms r ON r.id = rm.room_id
+        WHERE rm.user_id = $1
+        ORDER BY rm.joined_at
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let messages = sqlx::query_as::<_, ExportedMessage>(
+        r#"
+        SELECT m.id, m.room_id, r.name AS room_name, m.content, m.message_type, m.created_at
+        FROM messages m
+        JOIN rooms r ON r.id = m.room_id
+        WHERE m.user_id = $1
+        ORDER BY m.created_at
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let activity = sqlx::query_as::<_, AuditEventRecord>(
+        "SELECT * FROM audit_events WHERE actor_id = $1 ORDER BY id"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    let files = sqlx::query_as::<_, FileRecord>("SELECT * FROM files WHERE user_id = $1 ORDER BY created_at")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut entries = vec![
+        ("account.json".to_string(), to_json(&account)?),
+        ("memberships.json".to_string(), to_json(&memberships)?),
+        ("messages.json".to_string(), to_json(&messages)?),
+        ("activity.json".to_string(), to_json(&activity)?),
+        ("files.json".to_string(), to_json(&files)?),
+    ];
+    for file in &files {
+        if let Some(data) = uploads::read(file.id).await? {
+            entries.push((archive_file_name(file), data));
+        }
+    }
+    if let Some(avatar_file_id) = user.avatar_file_id {
+        if let Some(data) = uploads::read(avatar_file_id).await? {
+            entries.push(("avatar.png".to_string(), data));
+        }
+    }
+
+    Ok(entries)
+}
+
+fn build_zip(entries: Vec<(String, Vec<u8>)>) -> Result<Vec<u8>, AppError> {
+    let failed = |e: &dyn std::fmt::Display| AppError::InternalError(format!("Failed to build export: {}", e));
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for (path, data) in entries {
+        zip.start_file(path, options).map_err(|e| failed(&e))?;
+        zip.write_all(&data).map_err(|e| failed(&e))?;
+    }
+
+    Ok(zip.finish().map_err(|e| failed(&e))?.into_inner())
+}
+
+/// A zip archive of the user's profile, linked identities, memberships,
+/// messages, own activity log and uploaded files.
+async fn export_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Response, AppError> {
+    let user_id = claims.user_id()?;
+    let entries = collect_export(&state.db, user_id).await?;
+    let archive = tokio::task::spawn_blocking(move || build_zip(entries))
+        .await
+        .map_err(|e| AppError::InternalError(format!("Failed to build export: {}", e)))??;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("account.export")
+            .actor(user_id)
+            .target("user", user_id)
+            .metadata(serde_json::json!({ "size": archive.len() })),
+    ).await;
+
+    let disposition = format!(
+        "attachment; filename=\"konect-export-{}.zip\"",
+        Utc::now().format("%Y%m%d")
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        archive,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat::{create_room, send_message},
+        test_support,
+    };
+
+    async fn upload(pool: &PgPool, user_id: Uuid) -> Uuid {
+        let file_id = Uuid::new_v4();
+        sqlx::query("INSERT INTO files (id, user_id, filename, size) VALUES ($1, $2, 'notes.txt', 5)")
+            .bind(file_id)
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        file_id
+    }
+
+    async fn file_exists(pool: &PgPool, file_id: Uuid) -> bool {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM files WHERE id = $1)")
+            .bind(file_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn deleting_keeps_uploads_other_users_still_share(pool: PgPool) {
+        let state = test_support::state(pool.clone());
+        let alice = test_support::create_user(&pool, "alice").await;
+        let bob = test_support::create_user(&pool, "bob").await;
+        settings::set(&pool, settings::ACCOUNT_DELETION_POLICY, serde_json::json!(settings::DELETION_DELETE), bob.id)
+            .await
+            .unwrap();
+        let room = create_room(&pool, "general", None, false, alice.id).await.unwrap();
+        let shared = upload(&pool, alice.id).await;
+        let unshared = upload(&pool, alice.id).await;
+
+        let content = |file_id: Uuid| serde_json::json!({ "id": file_id }).to_string();
+        send_message(&state, room.id, alice.id, &content(shared), "file").await.unwrap();
+        let forwarded = send_message(&state, room.id, bob.id, &content(shared), "file").await.unwrap();
+        send_message(&state, room.id, alice.id, &content(unshared), "file").await.unwrap();
+
+        let summary = delete_account(&state, alice.id).await.unwrap();
+
+        assert_eq!(summary.messages_deleted, 2);
+        assert_eq!(summary.files_deleted, 1);
+        assert!(file_exists(&pool, shared).await, "an upload still shared was removed");
+        assert!(!file_exists(&pool, unshared).await);
+        let file_id = sqlx::query_scalar::<_, Option<Uuid>>("SELECT file_id FROM messages WHERE id = $1")
+            .bind(forwarded.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(file_id, Some(shared));
+    }
+}
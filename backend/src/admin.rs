@@ -0,0 +1,575 @@
+use crate::{
+    accounts,
+    audit::{self, AuditEvent, AuditFilter, ClientInfo},
+    auth::{is_admin, AuthClaims, ROLE_ADMIN, ROLE_USER},
+    error::AppError,
+    models::*,
+    websocket::disconnect_user,
+    password, settings, two_factor, SharedState, UPLOAD_DIR,
+};
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header, request::Parts},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Extractor that only succeeds for authenticated users holding the admin role.
+pub struct AdminUser(pub AuthClaims);
+
+#[async_trait]
+impl FromRequestParts<SharedState> for AdminUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &SharedState) -> Result<Self, Self::Rejection> {
+        let claims = parts
+            .extensions
+            .get::<AuthClaims>()
+            .cloned()
+            .ok_or_else(|| AppError::Auth("Not authenticated".to_string()))?;
+
+        if !is_admin(&state.db, claims.user_id()?).await? {
+            return Err(AppError::Authorization("Administrator privileges required".to_string()));
+        }
+
+        Ok(AdminUser(claims))
+    }
+}
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/users", get(list_users_handler))
+        .route("/users/:user_id", get(get_user_handler).delete(delete_user_handler))
+        .route("/users/:user_id/role", put(set_role_handler))
+        .route("/users/:user_id/disable", post(disable_user_handler))
+        .route("/users/:user_id/enable", post(enable_user_handler))
+        .route("/users/:user_id/reset-password", post(reset_password_handler))
+        .route("/users/:user_id/logout", post(force_logout_handler))
+        .route("/users/:user_id/2fa/reset", post(reset_two_factor_handler))
+        .route("/rooms", get(list_rooms_handler))
+        .route("/stats", get(stats_handler))
+        .route("/settings", get(get_settings_handler).put(update_settings_handler))
+        .route("/audit", get(list_audit_handler))
+        .route("/audit/export", get(export_audit_handler))
+}
+
+/// Rows fetched per round trip while streaming an audit export.
+const EXPORT_BATCH_SIZE: i64 = 1000;
+
+const USER_VIEW_COLUMNS: &str =
+    "id, username, email, role, disabled_at, email_verified_at, totp_enabled_at, is_bot, created_at, updated_at";
+
+async fn fetch_user_view(pool: &PgPool, user_id: Uuid) -> Result<AdminUserView, AppError> {
+    sqlx::query_as::<_, AdminUserView>(&format!(
+        "SELECT {} FROM users WHERE id = $1",
+        USER_VIEW_COLUMNS
+    ))
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("User not found".to_string()))
+}
+
+/// Invalidates every token issued to the user so far.
+async fn revoke_sessions(pool: &PgPool, user_id: Uuid) -> Result<(), AppError> {
+    sqlx::query("UPDATE users SET token_version = token_version + 1, updated_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+async fn record_admin_action(
+    pool: &PgPool,
+    client: &ClientInfo,
+    admin: &AuthClaims,
+    action: &'static str,
+    user_id: Uuid,
+    metadata: serde_json::Value,
+) -> Result<(), AppError> {
+    audit::record(
+        pool,
+        client,
+        AuditEvent::new(action)
+            .actor(admin.user_id()?)
+            .target("user", user_id)
+            .metadata(metadata),
+    ).await;
+    Ok(())
+}
+
+fn ensure_not_self(admin: &AuthClaims, user_id: Uuid) -> Result<(), AppError> {
+    if admin.user_id()? == user_id {
+        return Err(AppError::BadRequest("You cannot perform this action on your own account".to_string()));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ListUsersQuery {
+    search: Option<String>,
+    role: Option<String>,
+    disabled: Option<bool>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+async fn list_users_handler(
+    AdminUser(_admin): AdminUser,
+    Query(query): Query<ListUsersQuery>,
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<AdminUserView>>, AppError> {
+    let pattern = query.search.as_deref().map(|search| {
+        let escaped = search
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("%{}%", escaped)
+    });
+
+    let users = sqlx::query_as::<_, AdminUserView>(&format!(
+        r#"
+        SELECT {} FROM users
+        WHERE ($1::text IS NULL OR username ILIKE $1 OR email ILIKE $1)
+          AND ($2::text IS NULL OR role = $2)
+          AND ($3::boolean IS NULL OR (disabled_at IS NOT NULL) = $3)
+        ORDER BY created_at DESC
+        LIMIT $4 OFFSET $5
+        "#,
+        USER_VIEW_COLUMNS
+    ))
+    .bind(pattern)
+    .bind(query.role)
+    .bind(query.disabled)
+    .bind(query.limit.unwrap_or(50).clamp(1, 200))
+    .bind(query.offset.unwrap_or(0).max(0))
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(users))
+}
+
+async fn get_user_handler(
+    AdminUser(_admin): AdminUser,
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+) -> Result<Json<AdminUserView>, AppError> {
+    Ok(Json(fetch_user_view(&state.db, user_id).await?))
+}
+
+#[derive(Deserialize)]
+struct SetRoleRequest {
+    role: String,
+}
+
+async fn set_role_handler(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<SetRoleRequest>,
+) -> Result<Json<AdminUserView>, AppError> {
+    ensure_not_self(&admin, user_id)?;
+    if req.role != ROLE_USER && req.role != ROLE_ADMIN {
+        return Err(AppError::Validation("Role must be 'user' or 'admin'".to_string()));
+    }
+
+    let result = sqlx::query("UPDATE users SET role = $2, updated_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .bind(&req.role)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    record_admin_action(
+        &state.db,
+        &client,
+        &admin,
+        "admin.user_role",
+        user_id,
+        serde_json::json!({ "role": req.role }),
+    ).await?;
+
+    Ok(Json(fetch_user_view(&state.db, user_id).await?))
+}
+
+async fn disable_user_handler(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+) -> Result<Json<AdminUserView>, AppError> {
+    ensure_not_self(&admin, user_id)?;
+
+    let result = sqlx::query(
+        "UPDATE users SET disabled_at = COALESCE(disabled_at, NOW()), updated_at = NOW() WHERE id = $1"
+    )
+    .bind(user_id)
+    .execute(&state.db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    disconnect_user(&state, user_id, None, "account disabled").await;
+    record_admin_action(&state.db, &client, &admin, "admin.user_disable", user_id, serde_json::json!({})).await?;
+
+    Ok(Json(fetch_user_view(&state.db, user_id).await?))
+}
+
+async fn enable_user_handler(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+) -> Result<Json<AdminUserView>, AppError> {
+    let result = sqlx::query("UPDATE users SET disabled_at = NULL, updated_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    record_admin_action(&state.db, &client, &admin, "admin.user_enable", user_id, serde_json::json!({})).await?;
+
+    Ok(Json(fetch_user_view(&state.db, user_id).await?))
+}
+
+async fn delete_user_handler(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    ensure_not_self(&admin, user_id)?;
+
+    let user = fetch_user_view(&state.db, user_id).await?;
+    let summary = accounts::delete_account(&state, user_id).await?;
+
+    record_admin_action(
+        &state.db,
+        &client,
+        &admin,
+        "admin.user_delete",
+        user_id,
+        serde_json::json!({
+            "username": user.username,
+            "email": user.email,
+            "policy": summary.policy,
+            "messages_deleted": summary.messages_deleted,
+        }),
+    ).await?;
+
+    Ok(Json(serde_json::json!({ "success": true, "summary": summary })))
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordRequest {
+    password: Option<String>,
+}
+
+async fn reset_password_handler(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<ResetPasswordRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    // Without an explicit password, hand back a random temporary one
+    let (new_password, generated) = match req.password {
+        Some(new_password) => {
+            password::check_policy(&new_password).await?;
+            (new_password, false)
+        }
+        None => (Uuid::new_v4().simple().to_string(), true),
+    };
+
+    let password_hash = password::hash(&new_password)?;
+    let result = sqlx::query(
+        r#"
+        UPDATE users
+        SET password_hash = $2, token_version = token_version + 1, updated_at = NOW()
+        WHERE id = $1
+        "#
+    )
+    .bind(user_id)
+    .bind(password_hash)
+    .execute(&state.db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    disconnect_user(&state, user_id, None, "password reset").await;
+    record_admin_action(
+        &state.db,
+        &client,
+        &admin,
+        "admin.password_reset",
+        user_id,
+        serde_json::json!({ "generated": generated }),
+    ).await?;
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "temporary_password": if generated { Some(new_password) } else { None },
+    })))
+}
+
+async fn force_logout_handler(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    fetch_user_view(&state.db, user_id).await?;
+    revoke_sessions(&state.db, user_id).await?;
+    disconnect_user(&state, user_id, None, "logged out").await;
+    record_admin_action(&state.db, &client, &admin, "admin.force_logout", user_id, serde_json::json!({})).await?;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+/// For users who lost both their authenticator and recovery codes.
+async fn reset_two_factor_handler(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    fetch_user_view(&state.db, user_id).await?;
+    two_factor::disable(&state.db, user_id).await?;
+    record_admin_action(&state.db, &client, &admin, "admin.two_factor_reset", user_id, serde_json::json!({})).await?;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+async fn list_rooms_handler(
+    AdminUser(_admin): AdminUser,
+    Query(query): Query<PageQuery>,
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<AdminRoomView>>, AppError> {
+    let rooms = sqlx::query_as::<_, AdminRoomView>(
+        r#"
+        SELECT r.*,
+               (SELECT COUNT(*) FROM room_members rm WHERE rm.room_id = r.id) AS member_count,
+               (SELECT COUNT(*) FROM messages m WHERE m.room_id = r.id) AS message_count
+        FROM rooms r
+        ORDER BY r.created_at DESC
+        LIMIT $1 OFFSET $2
+        "#
+    )
+    .bind(query.limit.unwrap_or(50).clamp(1, 200))
+    .bind(query.offset.unwrap_or(0).max(0))
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(rooms))
+}
+
+async fn stats_handler(
+    AdminUser(_admin): AdminUser,
+    State(state): State<SharedState>,
+) -> Result<Json<InstanceStats>, AppError> {
+    let (users, disabled_users, admins) = sqlx::query_as::<_, (i64, i64, i64)>(
+        r#"
+        SELECT COUNT(*),
+               COUNT(*) FILTER (WHERE disabled_at IS NOT NULL),
+               COUNT(*) FILTER (WHERE role = 'admin')
+        FROM users
+        "#
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    let (rooms, private_rooms) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT COUNT(*), COUNT(*) FILTER (WHERE is_private) FROM rooms"
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    let messages = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM messages")
+        .fetch_one(&state.db)
+        .await?;
+
+    let messages_per_day = sqlx::query_as::<_, DailyCount>(
+        r#"
+        SELECT date_trunc('day', created_at) AS day, COUNT(*) AS count
+        FROM messages
+        WHERE created_at > NOW() - INTERVAL '30 days'
+        GROUP BY 1
+        ORDER BY 1
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(InstanceStats {
+        users,
+        disabled_users,
+        admins,
+        rooms,
+        private_rooms,
+        messages,
+        messages_per_day,
+        storage_bytes: storage_used().await,
+    }))
+}
+
+/// Total size of stored uploads on disk.
+async fn storage_used() -> u64 {
+    let mut total = 0;
+    if let Ok(mut entries) = tokio::fs::read_dir(UPLOAD_DIR).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Ok(metadata) = entry.metadata().await {
+                if metadata.is_file() {
+                    total += metadata.len();
+                }
+            }
+        }
+    }
+    total
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+async fn get_settings_handler(
+    AdminUser(_admin): AdminUser,
+    State(state): State<SharedState>,
+) -> Result<Json<InstanceSettings>, AppError> {
+    Ok(Json(settings::load(&state.db).await?))
+}
+
+#[derive(Deserialize)]
+struct UpdateSettingsRequest {
+    require_two_factor: Option<bool>,
+    account_deletion_policy: Option<String>,
+    legal_hold: Option<bool>,
+}
+
+async fn update_settings_handler(
+    AdminUser(admin): AdminUser,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<UpdateSettingsRequest>,
+) -> Result<Json<InstanceSettings>, AppError> {
+    let admin_id = admin.user_id()?;
+
+    if let Some(policy) = &req.account_deletion_policy {
+        if policy != settings::DELETION_ANONYMIZE && policy != settings::DELETION_DELETE {
+            return Err(AppError::Validation(
+                "Account deletion policy must be 'anonymize' or 'delete'".to_string(),
+            ));
+        }
+    }
+
+    // Existing sessions stay valid; accounts without 2FA must enroll at their next login
+    if let Some(require_two_factor) = req.require_two_factor {
+        settings::set(
+            &state.db,
+            settings::REQUIRE_TWO_FACTOR,
+            serde_json::json!(require_two_factor),
+            admin_id,
+        ).await?;
+    }
+
+    if let Some(policy) = req.account_deletion_policy {
+        settings::set(&state.db, settings::ACCOUNT_DELETION_POLICY, serde_json::json!(policy), admin_id).await?;
+    }
+
+    // Takes effect at the next purge run; messages that expire meanwhile are kept
+    if let Some(legal_hold) = req.legal_hold {
+        settings::set(&state.db, settings::LEGAL_HOLD, serde_json::json!(legal_hold), admin_id).await?;
+    }
+
+    let updated = settings::load(&state.db).await?;
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("admin.settings_update")
+            .actor(admin_id)
+            .metadata(serde_json::json!(updated)),
+    ).await;
+
+    Ok(Json(updated))
+}
+
+async fn list_audit_handler(
+    AdminUser(_admin): AdminUser,
+    Query(filter): Query<AuditFilter>,
+    Query(query): Query<PageQuery>,
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<AuditEventRecord>>, AppError> {
+    let events = audit::list_events(
+        &state.db,
+        &filter,
+        query.limit.unwrap_or(50).clamp(1, 500),
+        query.offset.unwrap_or(0).max(0),
+    ).await?;
+
+    Ok(Json(events))
+}
+
+/// Streams every matching event as JSON Lines, oldest first.
+async fn export_audit_handler(
+    AdminUser(admin): AdminUser,
+    Query(filter): Query<AuditFilter>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+) -> Result<Response, AppError> {
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("admin.audit_export").actor(admin.user_id()?),
+    ).await;
+
+    let pool = state.db.clone();
+    let stream = futures_util::stream::try_unfold(
+        (pool, filter, 0_i64, false),
+        |(pool, filter, after_id, done)| async move {
+            if done {
+                return Ok(None);
+            }
+
+            let events = audit::list_events_after(&pool, &filter, after_id, EXPORT_BATCH_SIZE).await?;
+            let Some(last) = events.last() else {
+                return Ok(None);
+            };
+            let last_id = last.id;
+            let done = (events.len() as i64) < EXPORT_BATCH_SIZE;
+
+            let mut chunk = String::new();
+            for event in &events {
+                let line = serde_json::to_string(event)
+                    .map_err(|e| AppError::InternalError(e.to_string()))?;
+                chunk.push_str(&line);
+                chunk.push('\n');
+            }
+
+            Ok::<_, AppError>(Some((chunk, (pool, filter, last_id, done))))
+        },
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"audit-events.jsonl\""),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
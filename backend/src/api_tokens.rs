@@ -0,0 +1,403 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{generate_secret, get_user_by_id, hash_secret, AuthClaims},
+    error::AppError,
+    models::*,
+    SharedState,
+};
+use axum::{
+    extract::{Path, State},
+    http::Method,
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// API tokens are told apart from session JWTs by this prefix.
+pub const TOKEN_PREFIX: &str = "kt_";
+/// Characters of the token kept in clear text for display.
+const DISPLAY_PREFIX_LEN: usize = 11;
+const MAX_NAME_LEN: usize = 100;
+const MAX_LIFETIME_DAYS: i64 = 3650;
+
+pub const SCOPE_ROOMS_READ: &str = "rooms:read";
+pub const SCOPE_ROOMS_WRITE: &str = "rooms:write";
+pub const SCOPE_ROOMS_MODERATE: &str = "rooms:moderate";
+pub const SCOPE_MESSAGES_READ: &str = "messages:read";
+pub const SCOPE_MESSAGES_WRITE: &str = "messages:write";
+pub const SCOPE_FILES_WRITE: &str = "files:write";
+pub const SCOPE_USERS_READ: &str = "users:read";
+pub const SCOPE_ADMIN: &str = "admin";
+
+pub const SCOPES: &[&str] = &[
+    SCOPE_ROOMS_READ,
+    SCOPE_ROOMS_WRITE,
+    SCOPE_ROOMS_MODERATE,
+    SCOPE_MESSAGES_READ,
+    SCOPE_MESSAGES_WRITE,
+    SCOPE_FILES_WRITE,
+    SCOPE_USERS_READ,
+    SCOPE_ADMIN,
+];
+
+/// Scopes granted to the API token a request was authenticated with. Absent
+/// for requests made with a session token.
+#[derive(Debug, Clone)]
+pub struct TokenScopes(pub Vec<String>);
+
+impl TokenScopes {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.0.iter().any(|granted| granted == scope)
+    }
+}
+
+/// Endpoints for managing your own tokens, mounted under `/api`.
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/users/me/tokens", get(list_handler).post(create_handler))
+        .route("/users/me/tokens/:token_id", delete(revoke_handler))
+}
+
+/// The scope an API token needs for a request, or `None` when the endpoint is
+/// only available to signed-in users (account settings, token management, ...).
+pub fn required_scope(method: &Method, path: &str) -> Option<&'static str> {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let read = *method == Method::GET || *method == Method::HEAD;
+
+    match segments.as_slice() {
+        ["rooms"] | ["rooms", _] if read => Some(SCOPE_ROOMS_READ),
+        ["rooms"] | ["rooms", _] => Some(SCOPE_ROOMS_WRITE),
+        ["rooms", _, "messages"] if read => Some(SCOPE_MESSAGES_READ),
+        ["rooms", _, "messages"] | ["rooms", _, "messages", _] => Some(SCOPE_MESSAGES_WRITE),
+        ["rooms", _, "messages", _, "reminders"] => Some(SCOPE_MESSAGES_WRITE),
+        ["rooms", _, "members"] if read => Some(SCOPE_ROOMS_READ),
+        ["rooms", _, "read"] => Some(SCOPE_MESSAGES_READ),
+        ["rooms", _, "pins"] if read => Some(SCOPE_MESSAGES_READ),
+        ["rooms", _, "polls", ..] if read => Some(SCOPE_MESSAGES_READ),
+        ["rooms", _, "polls", ..] => Some(SCOPE_MESSAGES_WRITE),
+        ["rooms", _, "reminders"] | ["rooms", _, "scheduled-messages"] => Some(SCOPE_MESSAGES_WRITE),
+        // Notification settings are personal, like the rest of the account settings
+        ["rooms", _, "notifications"] => None,
+        ["rooms", _, ..] => Some(SCOPE_ROOMS_MODERATE),
+        ["upload"] => Some(SCOPE_FILES_WRITE),
+        ["users", _] if read => Some(SCOPE_USERS_READ),
+        ["users", "me", "mentions"] if read => Some(SCOPE_MESSAGES_READ),
+        ["users", "me", "bookmarks", ..] if read => Some(SCOPE_MESSAGES_READ),
+        ["users", "me", "bookmarks", ..] => Some(SCOPE_MESSAGES_WRITE),
+        ["reminders", ..] | ["scheduled-messages", ..] if read => Some(SCOPE_MESSAGES_READ),
+        ["reminders", ..] | ["scheduled-messages", ..] => Some(SCOPE_MESSAGES_WRITE),
+        ["admin", ..] => Some(SCOPE_ADMIN),
+        _ => None,
+    }
+}
+
+pub fn ensure_scope(scopes: &TokenScopes, method: &Method, path: &str) -> Result<(), AppError> {
+    match required_scope(method, path) {
+        Some(scope) if scopes.allows(scope) => Ok(()),
+        Some(scope) => Err(AppError::Authorization(format!("Token lacks the '{}' scope", scope))),
+        None => Err(AppError::Authorization("This endpoint can't be used with an API token".to_string())),
+    }
+}
+
+/// Resolves an API token to claims for its (still active) owner and the
+/// scopes it was granted.
+pub async fn authenticate(pool: &PgPool, token: &str) -> Result<(AuthClaims, TokenScopes), AppError> {
+    let api_token = sqlx::query_as::<_, ApiToken>(
+        r#"
+        SELECT * FROM api_tokens
+        WHERE token_hash = $1
+          AND revoked_at IS NULL
+          AND (expires_at IS NULL OR expires_at > NOW())
+        "#
+    )
+    .bind(hash_secret(token))
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::Auth("Invalid or revoked API token".to_string()))?;
+
+    let user = get_user_by_id(pool, api_token.user_id)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+    if user.disabled_at.is_some() {
+        return Err(AppError::Auth("Account is disabled".to_string()));
+    }
+    // Forced logouts and password changes end API access as well as sessions
+    if api_token.token_version != user.token_version {
+        return Err(AppError::Auth("Invalid or revoked API token".to_string()));
+    }
+
+    // Coarse enough that busy integrations don't write on every request
+    sqlx::query(
+        r#"
+        UPDATE api_tokens SET last_used_at = NOW()
+        WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < NOW() - INTERVAL '1 minute')
+        "#
+    )
+    .bind(api_token.id)
+    .execute(pool)
+    .await?;
+
+    let claims = AuthClaims {
+        sub: user.id.to_string(),
+        username: user.username,
+        exp: api_token
+            .expires_at
+            .map(|expires_at| expires_at.timestamp() as usize)
+            .unwrap_or(usize::MAX),
+        iat: api_token.created_at.timestamp() as usize,
+        ver: user.token_version,
+    };
+
+    Ok((claims, TokenScopes(api_token.scopes)))
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    name: String,
+    scopes: Vec<String>,
+    expires_in_days: Option<i64>,
+}
+
+pub async fn create(
+    pool: &PgPool,
+    user_id: Uuid,
+    created_by: Uuid,
+    req: &CreateTokenRequest,
+) -> Result<CreatedApiToken, AppError> {
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::Validation(format!(
+            "Token name must be between 1 and {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+
+    let mut scopes: Vec<String> = Vec::new();
+    for scope in &req.scopes {
+        if !SCOPES.contains(&scope.as_str()) {
+            return Err(AppError::Validation(format!("Unknown scope '{}'", scope)));
+        }
+        if !scopes.contains(scope) {
+            scopes.push(scope.clone());
+        }
+    }
+    if scopes.is_empty() {
+        return Err(AppError::Validation("At least one scope is required".to_string()));
+    }
+
+    let expires_at = match req.expires_in_days {
+        Some(days) if !(1..=MAX_LIFETIME_DAYS).contains(&days) => {
+            return Err(AppError::Validation(format!(
+                "Tokens can expire after 1 to {} days",
+                MAX_LIFETIME_DAYS
+            )));
+        }
+        Some(days) => Some(Utc::now() + Duration::days(days)),
+        None => None,
+    };
+
+    let token = format!("{}{}", TOKEN_PREFIX, generate_secret());
+    let info = sqlx::query_as::<_, ApiToken>(
+        r#"
+        INSERT INTO api_tokens (id, user_id, name, token_hash, token_prefix, scopes, created_by, expires_at, token_version)
+        SELECT $1, $2, $3, $4, $5, $6, $7, $8, token_version FROM users WHERE id = $2
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(name)
+    .bind(hash_secret(&token))
+    .bind(&token[..DISPLAY_PREFIX_LEN])
+    .bind(&scopes)
+    .bind(created_by)
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(CreatedApiToken { token, info })
+}
+
+pub async fn list(pool: &PgPool, user_id: Uuid) -> Result<Vec<ApiToken>, AppError> {
+    let tokens = sqlx::query_as::<_, ApiToken>(
+        r#"
+        SELECT api_tokens.* FROM api_tokens
+        JOIN users ON users.id = api_tokens.user_id
+        WHERE api_tokens.user_id = $1
+          AND api_tokens.revoked_at IS NULL
+          AND api_tokens.token_version = users.token_version
+        ORDER BY api_tokens.created_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tokens)
+}
+
+pub async fn revoke(pool: &PgPool, user_id: Uuid, token_id: Uuid) -> Result<ApiToken, AppError> {
+    sqlx::query_as::<_, ApiToken>(
+        r#"
+        UPDATE api_tokens SET revoked_at = NOW()
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+        RETURNING *
+        "#
+    )
+    .bind(token_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Token not found".to_string()))
+}
+
+pub async fn record_created(pool: &PgPool, client: &ClientInfo, actor_id: Uuid, created: &CreatedApiToken) {
+    audit::record(
+        pool,
+        client,
+        AuditEvent::new("api_token.create")
+            .actor(actor_id)
+            .target("api_token", created.info.id)
+            .metadata(serde_json::json!({
+                "user_id": created.info.user_id,
+                "name": created.info.name,
+                "scopes": created.info.scopes,
+                "expires_at": created.info.expires_at,
+            })),
+    ).await;
+}
+
+pub async fn record_revoked(pool: &PgPool, client: &ClientInfo, actor_id: Uuid, token: &ApiToken) {
+    audit::record(
+        pool,
+        client,
+        AuditEvent::new("api_token.revoke")
+            .actor(actor_id)
+            .target("api_token", token.id)
+            .metadata(serde_json::json!({ "user_id": token.user_id, "name": token.name })),
+    ).await;
+}
+
+async fn list_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<ApiToken>>, AppError> {
+    Ok(Json(list(&state.db, claims.user_id()?).await?))
+}
+
+async fn create_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<CreateTokenRequest>,
+) -> Result<Json<CreatedApiToken>, AppError> {
+    let user_id = claims.user_id()?;
+    let created = create(&state.db, user_id, user_id, &req).await?;
+    record_created(&state.db, &client, user_id, &created).await;
+
+    Ok(Json(created))
+}
+
+async fn revoke_handler(
+    Path(token_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user_id = claims.user_id()?;
+    let token = revoke(&state.db, user_id, token_id).await?;
+    record_revoked(&state.db, &client, user_id, &token).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn request(scopes: &[&str]) -> CreateTokenRequest {
+        CreateTokenRequest {
+            name: "ci".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires_in_days: None,
+        }
+    }
+
+    #[test]
+    fn reads_and_writes_need_different_scopes() {
+        let room = "/api/rooms/4f0c9a4e-2d2b-4a57-9a56-1f7f3c1f2b11";
+        assert_eq!(required_scope(&Method::GET, "/api/rooms"), Some(SCOPE_ROOMS_READ));
+        assert_eq!(required_scope(&Method::HEAD, room), Some(SCOPE_ROOMS_READ));
+        assert_eq!(required_scope(&Method::POST, "/api/rooms"), Some(SCOPE_ROOMS_WRITE));
+        assert_eq!(required_scope(&Method::GET, &format!("{}/messages", room)), Some(SCOPE_MESSAGES_READ));
+        assert_eq!(required_scope(&Method::POST, &format!("{}/messages", room)), Some(SCOPE_MESSAGES_WRITE));
+        assert_eq!(required_scope(&Method::PUT, &format!("{}/messages/1", room)), Some(SCOPE_MESSAGES_WRITE));
+        assert_eq!(required_scope(&Method::GET, &format!("{}/polls/1", room)), Some(SCOPE_MESSAGES_READ));
+        assert_eq!(required_scope(&Method::POST, &format!("{}/polls/1/votes", room)), Some(SCOPE_MESSAGES_WRITE));
+        assert_eq!(required_scope(&Method::POST, "/api/upload"), Some(SCOPE_FILES_WRITE));
+        assert_eq!(required_scope(&Method::GET, "/api/users/someone"), Some(SCOPE_USERS_READ));
+        assert_eq!(required_scope(&Method::DELETE, "/api/users/me/bookmarks/1"), Some(SCOPE_MESSAGES_WRITE));
+    }
+
+    #[test]
+    fn other_room_changes_need_the_moderation_scope() {
+        let room = "/api/rooms/4f0c9a4e-2d2b-4a57-9a56-1f7f3c1f2b11";
+        assert_eq!(required_scope(&Method::POST, &format!("{}/bans", room)), Some(SCOPE_ROOMS_MODERATE));
+        assert_eq!(required_scope(&Method::GET, &format!("{}/bans", room)), Some(SCOPE_ROOMS_MODERATE));
+        assert_eq!(required_scope(&Method::PUT, &format!("{}/retention", room)), Some(SCOPE_ROOMS_MODERATE));
+        assert_eq!(required_scope(&Method::GET, "/api/admin/users"), Some(SCOPE_ADMIN));
+    }
+
+    #[test]
+    fn account_settings_are_off_limits() {
+        let room = "/api/rooms/4f0c9a4e-2d2b-4a57-9a56-1f7f3c1f2b11";
+        for (method, path) in [
+            (Method::GET, "/api/users/me/tokens"),
+            (Method::POST, "/api/users/me/tokens"),
+            (Method::PUT, "/api/users/me/password"),
+            (Method::DELETE, "/api/users/me"),
+            (Method::POST, "/api/users/me/identities/oidc"),
+            (Method::PUT, &format!("{}/notifications", room)),
+        ] {
+            assert_eq!(required_scope(&method, path), None, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn ensure_scope_names_the_missing_scope() {
+        let scopes = TokenScopes(vec![SCOPE_ROOMS_READ.to_string()]);
+        assert!(ensure_scope(&scopes, &Method::GET, "/api/rooms").is_ok());
+        let Err(AppError::Authorization(message)) = ensure_scope(&scopes, &Method::POST, "/api/rooms") else {
+            panic!("expected a scope error");
+        };
+        assert!(message.contains(SCOPE_ROOMS_WRITE));
+        assert!(ensure_scope(&scopes, &Method::GET, "/api/users/me/tokens").is_err());
+    }
+
+    #[sqlx::test]
+    async fn bumping_the_token_version_invalidates_api_tokens(pool: PgPool) {
+        let user = test_support::create_user(&pool, "alice").await;
+        let created = create(&pool, user.id, user.id, &request(&[SCOPE_ROOMS_READ])).await.unwrap();
+        let (claims, scopes) = authenticate(&pool, &created.token).await.unwrap();
+        assert_eq!(claims.user_id().unwrap(), user.id);
+        assert!(scopes.allows(SCOPE_ROOMS_READ));
+
+        // What a forced logout or password change does
+        sqlx::query("UPDATE users SET token_version = token_version + 1 WHERE id = $1")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(authenticate(&pool, &created.token).await, Err(AppError::Auth(_))));
+        assert!(list(&pool, user.id).await.unwrap().is_empty());
+
+        // Tokens created afterwards work
+        let replacement = create(&pool, user.id, user.id, &request(&[SCOPE_ROOMS_READ])).await.unwrap();
+        assert!(authenticate(&pool, &replacement.token).await.is_ok());
+    }
+}
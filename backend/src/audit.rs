@@ -0,0 +1,218 @@
+use crate::{error::AppError, models::AuditEventRecord};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts},
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::{convert::Infallible, net::SocketAddr};
+use tracing::error;
+use uuid::Uuid;
+
+/// Where a request came from, recorded alongside audit events.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Only honour X-Forwarded-For when running behind a trusted proxy
+        let forwarded_ip = if trust_proxy_headers() {
+            parts
+                .headers
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(|ip| ip.trim().to_string())
+                .filter(|ip| !ip.is_empty())
+        } else {
+            None
+        };
+
+        let ip = forwarded_ip.or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+        });
+
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        Ok(ClientInfo { ip, user_agent })
+    }
+}
+
+fn trust_proxy_headers() -> bool {
+    std::env::var("TRUST_PROXY_HEADERS")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+}
+
+pub struct AuditEvent {
+    pub action: &'static str,
+    pub actor_id: Option<Uuid>,
+    pub target_type: Option<&'static str>,
+    pub target_id: Option<Uuid>,
+    pub room_id: Option<Uuid>,
+    pub metadata: serde_json::Value,
+}
+
+impl AuditEvent {
+    pub fn new(action: &'static str) -> Self {
+        Self {
+            action,
+            actor_id: None,
+            target_type: None,
+            target_id: None,
+            room_id: None,
+            metadata: serde_json::json!({}),
+        }
+    }
+
+    pub fn actor(mut self, actor_id: Uuid) -> Self {
+        self.actor_id = Some(actor_id);
+        self
+    }
+
+    pub fn target(mut self, target_type: &'static str, target_id: Uuid) -> Self {
+        self.target_type = Some(target_type);
+        self.target_id = Some(target_id);
+        self
+    }
+
+    pub fn room(mut self, room_id: Uuid) -> Self {
+        self.room_id = Some(room_id);
+        self
+    }
+
+    pub fn metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+/// Appends an event to the audit log. Failures are logged rather than
+/// returned so that auditing never breaks the action being audited.
+pub async fn record(pool: &PgPool, client: &ClientInfo, event: AuditEvent) {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO audit_events (occurred_at, action, actor_id, target_type, target_id, room_id, ip, user_agent, metadata)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#
+    )
+    .bind(Utc::now())
+    .bind(event.action)
+    .bind(event.actor_id)
+    .bind(event.target_type)
+    .bind(event.target_id)
+    .bind(event.room_id)
+    .bind(client.ip.as_deref())
+    .bind(client.user_agent.as_deref())
+    .bind(&event.metadata)
+    .execute(pool)
+    .await;
+
+    if let Err(e) = result {
+        error!("Failed to record audit event {}: {}", event.action, e);
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    pub action: Option<String>,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub room_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl AuditFilter {
+    fn push_conditions(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        query.push(" WHERE TRUE");
+        if let Some(action) = &self.action {
+            // "auth." matches every auth event, "auth.login" only that one
+            if action.ends_with('.') {
+                query.push(" AND starts_with(action, ").push_bind(action.clone()).push(")");
+            } else {
+                query.push(" AND action = ").push_bind(action.clone());
+            }
+        }
+        if let Some(actor_id) = self.actor_id {
+            query.push(" AND actor_id = ").push_bind(actor_id);
+        }
+        if let Some(target_id) = self.target_id {
+            query.push(" AND target_id = ").push_bind(target_id);
+        }
+        if let Some(room_id) = self.room_id {
+            query.push(" AND room_id = ").push_bind(room_id);
+        }
+        if let Some(ip) = &self.ip {
+            query.push(" AND ip = ").push_bind(ip.clone());
+        }
+        if let Some(since) = self.since {
+            query.push(" AND occurred_at >= ").push_bind(since);
+        }
+        if let Some(until) = self.until {
+            query.push(" AND occurred_at < ").push_bind(until);
+        }
+    }
+}
+
+/// Newest events first.
+pub async fn list_events(
+    pool: &PgPool,
+    filter: &AuditFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<AuditEventRecord>, AppError> {
+    let mut query = QueryBuilder::new("SELECT * FROM audit_events");
+    filter.push_conditions(&mut query);
+    query
+        .push(" ORDER BY id DESC LIMIT ")
+        .push_bind(limit)
+        .push(" OFFSET ")
+        .push_bind(offset);
+
+    let events = query
+        .build_query_as::<AuditEventRecord>()
+        .fetch_all(pool)
+        .await?;
+
+    Ok(events)
+}
+
+/// Oldest events first, resuming after `after_id`; used to page through exports.
+pub async fn list_events_after(
+    pool: &PgPool,
+    filter: &AuditFilter,
+    after_id: i64,
+    limit: i64,
+) -> Result<Vec<AuditEventRecord>, AppError> {
+    let mut query = QueryBuilder::new("SELECT * FROM audit_events");
+    filter.push_conditions(&mut query);
+    query
+        .push(" AND id > ")
+        .push_bind(after_id)
+        .push(" ORDER BY id ASC LIMIT ")
+        .push_bind(limit);
+
+    let events = query
+        .build_query_as::<AuditEventRecord>()
+        .fetch_all(pool)
+        .await?;
+
+    Ok(events)
+}
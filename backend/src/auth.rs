@@ -1,18 +1,58 @@
-use crate::{error::AppError, models::*};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    error::AppError,
+    ldap::Ldap,
+    models::*,
+    password, two_factor,
+};
 use chrono::{Duration, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
+use tracing::info;
 use uuid::Uuid;
 
 const JWT_SECRET: &str = "your-secret-key"; // In production, use environment variable
 
+/// Audience of "mfa pending" tokens, which only grant access to the second login step.
+const MFA_AUDIENCE: &str = "mfa";
+const MFA_TOKEN_MINUTES: i64 = 5;
+
+pub const ROLE_USER: &str = "user";
+pub const ROLE_ADMIN: &str = "admin";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthClaims {
     pub sub: String, // user id
     pub username: String,
     pub exp: usize,
+    #[serde(default)]
+    pub iat: usize,
+    #[serde(default)]
+    pub ver: i32, // users.token_version at issue time
+}
+
+impl AuthClaims {
+    pub fn user_id(&self) -> Result<Uuid, AppError> {
+        Uuid::parse_str(&self.sub).map_err(|_| AppError::Auth("Invalid user ID".to_string()))
+    }
+}
+
+/// Claims of the short-lived token issued between the password and second-factor steps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaClaims {
+    pub sub: String,
+    pub aud: String,
+    pub exp: usize,
+    pub ver: i32,
+}
+
+/// Addresses are stored lowercased, so each one belongs to a single account
+/// however it's capitalised.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
 }
 
 pub async fn create_user(
@@ -20,10 +60,17 @@ pub async fn create_user(
     username: &str,
     email: &str,
     password: &str,
-) -> Result<AuthUser, AppError> {
+) -> Result<User, AppError> {
+    let email = normalize_email(email);
+    if !crate::mailer::is_valid_address(&email) {
+        return Err(AppError::Validation("Invalid email address".to_string()));
+    }
+    // Usernames have to be mentionable
+    validate_username(username)?;
+
     // Check if user already exists
-    let existing_user = sqlx::query("SELECT id FROM users WHERE email = $1 OR username = $2")
-        .bind(email)
+    let existing_user = sqlx::query("SELECT id FROM users WHERE LOWER(email) = $1 OR username = $2")
+        .bind(&email)
         .bind(username)
         .fetch_optional(pool)
         .await?;
@@ -32,68 +79,222 @@ pub async fn create_user(
         return Err(AppError::Validation("User already exists".to_string()));
     }
 
+    password::check_policy(password).await?;
+
     // Hash password
-    let password_hash = hash(password, DEFAULT_COST)?;
+    let password_hash = password::hash(password)?;
     let user_id = Uuid::new_v4();
     let now = Utc::now();
 
-    // Create user
-    sqlx::query(
+    // Create user; accounts listed in ADMIN_EMAILS are promoted once verified
+    let user = sqlx::query_as::<_, User>(
         r#"
-        INSERT INTO users (id, username, email, password_hash, created_at, updated_at)
-        VALUES ($1, $2, $3, $4, $5, $6)
+        INSERT INTO users (id, username, email, password_hash, role, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING *
         "#
     )
     .bind(user_id)
     .bind(username)
-    .bind(email)
+    .bind(&email)
     .bind(password_hash)
+    .bind(ROLE_USER)
     .bind(now)
     .bind(now)
-    .execute(pool)
+    .fetch_one(pool)
     .await?;
 
-    // Generate JWT token
-    let token = generate_token(&user_id.to_string(), username)?;
-
-    Ok(AuthUser {
-        id: user_id,
-        username: username.to_string(),
-        email: email.to_string(),
-        token,
-    })
+    Ok(user)
 }
 
+/// Checks the password (against the directory first when LDAP is configured,
+/// then the local account) and account state. The caller decides whether a
+/// second factor is needed before issuing a session with `issue_session`.
 pub async fn login_user(
     pool: &PgPool,
+    ldap: Option<&Ldap>,
+    client: &ClientInfo,
     email: &str,
     password: &str,
-) -> Result<AuthUser, AppError> {
-    // Get user by email
-    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
-        .bind(email)
-        .fetch_optional(pool)
-        .await?
-        .ok_or_else(|| AppError::Auth("Invalid credentials".to_string()))?;
+) -> Result<User, AppError> {
+    let directory_user = match ldap {
+        Some(ldap) => crate::ldap::authenticate(pool, ldap, client, email, password).await?,
+        None => None,
+    };
+
+    let user = match directory_user {
+        Some(user) => user,
+        None => {
+            // Get user by email
+            let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE LOWER(email) = LOWER($1)")
+                .bind(email)
+                .fetch_optional(pool)
+                .await?
+                .ok_or_else(|| AppError::Auth("Invalid credentials".to_string()))?;
+
+            // Verify password
+            if !verify_password(&user, password)? {
+                return Err(AppError::Auth("Invalid credentials".to_string()));
+            }
+            rehash_password_if_needed(pool, &user, password).await?;
+            user
+        }
+    };
+
+    check_can_sign_in(&user)?;
+    Ok(user)
+}
+
+/// Account state that blocks signing in, however the user authenticated.
+pub fn check_can_sign_in(user: &User) -> Result<(), AppError> {
+    if user.disabled_at.is_some() {
+        return Err(AppError::Authorization("Account is disabled".to_string()));
+    }
+
+    if user.email_verified_at.is_none() && email_verification_required() {
+        return Err(AppError::Authorization("Email address has not been verified".to_string()));
+    }
+
+    Ok(())
+}
+
+/// The step after a password or identity provider has vouched for the
+/// user: a session, or an MFA token when the account has 2FA or the instance
+/// requires it. `metadata` is recorded with the login event.
+pub async fn complete_login(
+    pool: &PgPool,
+    client: &ClientInfo,
+    user: &User,
+    metadata: Option<serde_json::Value>,
+) -> Result<LoginResponse, AppError> {
+    if let Some(enrollment_required) = two_factor::pending_step(pool, user).await? {
+        audit::record(
+            pool,
+            client,
+            AuditEvent::new("auth.2fa_challenge")
+                .actor(user.id)
+                .metadata(serde_json::json!({ "enrollment_required": enrollment_required })),
+        ).await;
+        return Ok(LoginResponse::MfaRequired {
+            mfa_required: true,
+            mfa_token: generate_mfa_token(user)?,
+            enrollment_required,
+        });
+    }
+
+    let mut event = AuditEvent::new("auth.login").actor(user.id);
+    if let Some(metadata) = metadata {
+        event = event.metadata(metadata);
+    }
+    audit::record(pool, client, event).await;
+    Ok(LoginResponse::Authenticated(issue_session(user)?))
+}
 
-    // Verify password
-    if !verify(password, &user.password_hash)? {
-        return Err(AppError::Auth("Invalid credentials".to_string()));
+/// Accounts without a local password never match.
+pub fn verify_password(user: &User, password: &str) -> Result<bool, AppError> {
+    match &user.password_hash {
+        Some(password_hash) => password::verify(password, password_hash),
+        None => Ok(false),
     }
+}
+
+/// Upgrades a verified password's hash (e.g. from bcrypt, or after the Argon2
+/// parameters were raised) while the plain-text password is at hand.
+async fn rehash_password_if_needed(pool: &PgPool, user: &User, password: &str) -> Result<(), AppError> {
+    let Some(current_hash) = &user.password_hash else {
+        return Ok(());
+    };
+    if !password::needs_rehash(current_hash) {
+        return Ok(());
+    }
+
+    // Only replace the hash that was verified, in case the password just changed
+    sqlx::query("UPDATE users SET password_hash = $3 WHERE id = $1 AND password_hash = $2")
+        .bind(user.id)
+        .bind(current_hash)
+        .bind(password::hash(password)?)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Creates a password-less account for a user signing in through an external
+/// identity provider. A numeric suffix is added if the username is taken.
+pub async fn create_external_user(
+    pool: &PgPool,
+    username: &str,
+    email: &str,
+    email_verified: bool,
+) -> Result<User, AppError> {
+    let email = normalize_email(email);
+    if !crate::mailer::is_valid_address(&email) {
+        return Err(AppError::Validation("Invalid email address".to_string()));
+    }
+
+    // Keep the name mentionable: "jane.doe" becomes "jane_doe"
+    let username: String = username
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .take(28)
+        .collect();
+    let username = format!("{:_<3}", username);
+
+    let taken = sqlx::query_scalar::<_, String>(
+        "SELECT username FROM users WHERE LOWER(username) LIKE LOWER($1) || '%'"
+    )
+    .bind(&username)
+    .fetch_all(pool)
+    .await?;
+    let is_taken = |candidate: &str| {
+        crate::mentions::is_reserved(candidate) || taken.iter().any(|name| name.eq_ignore_ascii_case(candidate))
+    };
+    let username = (1..)
+        .map(|n| if n == 1 { username.to_string() } else { format!("{}{}", username, n) })
+        .find(|candidate| !is_taken(candidate))
+        .expect("an unused suffix exists");
 
-    // Generate JWT token
-    let token = generate_token(&user.id.to_string(), &user.username)?;
+    let now = Utc::now();
 
-    Ok(AuthUser {
-        id: user.id,
-        username: user.username,
-        email: user.email,
+    let mut user = sqlx::query_as::<_, User>(
+        r#"
+        INSERT INTO users (id, username, email, password_hash, role, email_verified_at, created_at, updated_at)
+        VALUES ($1, $2, $3, NULL, $4, $5, $6, $6)
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(&username)
+    .bind(&email)
+    .bind(ROLE_USER)
+    .bind(email_verified.then_some(now))
+    .bind(now)
+    .fetch_one(pool)
+    .await?;
+
+    if promote_bootstrap_admin(pool, user.id).await? {
+        user.role = ROLE_ADMIN.to_string();
+    }
+
+    Ok(user)
+}
+
+pub fn issue_session(user: &User) -> Result<AuthResponse, AppError> {
+    if user.is_bot {
+        return Err(AppError::Authorization("Bot accounts can only use API tokens".to_string()));
+    }
+
+    let token = generate_token(&user.id.to_string(), &user.username, user.token_version)?;
+
+    Ok(AuthResponse {
         token,
+        user: UserInfo::from(user),
     })
 }
 
-pub fn generate_token(user_id: &str, username: &str) -> Result<String, AppError> {
-    let expiration = Utc::now()
+pub fn generate_token(user_id: &str, username: &str, token_version: i32) -> Result<String, AppError> {
+    let now = Utc::now();
+    let expiration = now
         .checked_add_signed(Duration::hours(24))
         .expect("Valid timestamp")
         .timestamp() as usize;
@@ -102,6 +303,8 @@ pub fn generate_token(user_id: &str, username: &str) -> Result<String, AppError>
         sub: user_id.to_string(),
         username: username.to_string(),
         exp: expiration,
+        iat: now.timestamp() as usize,
+        ver: token_version,
     };
 
     let token = encode(
@@ -123,3 +326,219 @@ pub fn verify_token(token: &str) -> Result<AuthClaims, AppError> {
     Ok(token_data.claims)
 }
 
+pub fn generate_mfa_token(user: &User) -> Result<String, AppError> {
+    let expiration = Utc::now()
+        .checked_add_signed(Duration::minutes(MFA_TOKEN_MINUTES))
+        .expect("Valid timestamp")
+        .timestamp() as usize;
+
+    let claims = MfaClaims {
+        sub: user.id.to_string(),
+        aud: MFA_AUDIENCE.to_string(),
+        exp: expiration,
+        ver: user.token_version,
+    };
+
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(JWT_SECRET.as_ref()),
+    )?;
+
+    Ok(token)
+}
+
+/// Resolves an "mfa pending" token to its still-active account. Session tokens
+/// carry no audience, so neither kind of token is accepted in place of the other.
+pub async fn authenticate_mfa_token(pool: &PgPool, token: &str) -> Result<User, AppError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[MFA_AUDIENCE]);
+    let claims = decode::<MfaClaims>(token, &DecodingKey::from_secret(JWT_SECRET.as_ref()), &validation)
+        .map_err(|_| AppError::Auth("Invalid or expired login attempt".to_string()))?
+        .claims;
+
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| AppError::Auth("Invalid user ID".to_string()))?;
+    let user = get_user_by_id(pool, user_id)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+
+    if user.disabled_at.is_some() {
+        return Err(AppError::Auth("Account is disabled".to_string()));
+    }
+    if user.token_version != claims.ver {
+        return Err(AppError::Auth("Session has been revoked".to_string()));
+    }
+
+    Ok(user)
+}
+
+/// Verifies the token and checks that the account is still active and that
+/// the token hasn't been revoked by a forced logout.
+pub async fn authenticate(pool: &PgPool, token: &str) -> Result<AuthClaims, AppError> {
+    let mut claims = verify_token(token)?;
+
+    let user = get_user_by_id(pool, claims.user_id()?)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+
+    if user.disabled_at.is_some() {
+        return Err(AppError::Auth("Account is disabled".to_string()));
+    }
+    if user.is_bot {
+        return Err(AppError::Auth("Bot accounts can only use API tokens".to_string()));
+    }
+    if user.token_version != claims.ver {
+        return Err(AppError::Auth("Session has been revoked".to_string()));
+    }
+
+    // The username may have changed since the token was issued
+    claims.username = user.username;
+    Ok(claims)
+}
+
+/// Whether `REQUIRE_EMAIL_VERIFICATION` blocks logins from unverified accounts.
+pub fn email_verification_required() -> bool {
+    std::env::var("REQUIRE_EMAIL_VERIFICATION")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+}
+
+/// A random URL-safe secret for single-use links and API credentials.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Secrets are stored as SHA-256 hashes so a database leak doesn't expose them.
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Usernames chosen after registration (bots, renames) must be 3 to 32
+/// letters, digits, '-' or '_'.
+pub fn validate_username(username: &str) -> Result<(), AppError> {
+    let valid_chars = username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !(3..=32).contains(&username.len()) || !valid_chars {
+        return Err(AppError::Validation(
+            "Usernames must be 3 to 32 letters, digits, '-' or '_'".to_string(),
+        ));
+    }
+    if crate::mentions::is_reserved(username) {
+        return Err(AppError::Validation(format!("'{}' can't be used as a username", username)));
+    }
+    Ok(())
+}
+
+/// Emails listed in `ADMIN_EMAILS` (comma separated), lowercased.
+fn bootstrap_admin_emails() -> Vec<String> {
+    std::env::var("ADMIN_EMAILS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|email| email.trim().to_lowercase())
+        .filter(|email| !email.is_empty())
+        .collect()
+}
+
+pub fn is_bootstrap_admin(email: &str) -> bool {
+    bootstrap_admin_emails().contains(&email.to_lowercase())
+}
+
+/// Accounts listed in `ADMIN_EMAILS` are granted the admin role once their
+/// address is verified, but only while the instance has no admin. After
+/// that, roles are managed through the admin API and demotions stick.
+/// `user_id` limits the grant to one account; `None` checks them all.
+async fn grant_bootstrap_admin(pool: &PgPool, user_id: Option<Uuid>) -> Result<u64, AppError> {
+    let emails = bootstrap_admin_emails();
+    if emails.is_empty() {
+        return Ok(0);
+    }
+
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET role = $1, updated_at = NOW()
+        WHERE LOWER(email) = ANY($2)
+          AND email_verified_at IS NOT NULL
+          AND disabled_at IS NULL
+          AND NOT is_bot
+          AND ($3::UUID IS NULL OR id = $3)
+          AND NOT EXISTS (SELECT 1 FROM users WHERE role = $1)
+        "#
+    )
+    .bind(ROLE_ADMIN)
+    .bind(&emails)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Promotes verified accounts listed in `ADMIN_EMAILS` on startup, if the
+/// instance has no admin yet.
+pub async fn promote_bootstrap_admins(pool: &PgPool) -> Result<(), AppError> {
+    let promoted = grant_bootstrap_admin(pool, None).await?;
+    if promoted > 0 {
+        info!("Granted the admin role to {} account(s) listed in ADMIN_EMAILS", promoted);
+    }
+    Ok(())
+}
+
+/// Promotes one account that just verified its address. Returns whether it
+/// became an admin.
+pub async fn promote_bootstrap_admin(pool: &PgPool, user_id: Uuid) -> Result<bool, AppError> {
+    Ok(grant_bootstrap_admin(pool, Some(user_id)).await? > 0)
+}
+
+
+pub async fn get_user_by_id(pool: &PgPool, user_id: Uuid) -> Result<Option<User>, AppError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(user)
+}
+
+pub async fn is_admin(pool: &PgPool, user_id: Uuid) -> Result<bool, AppError> {
+    let role = sqlx::query_scalar::<_, String>("SELECT role FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(role.as_deref() == Some(ROLE_ADMIN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn emails_are_matched_regardless_of_case(pool: PgPool) {
+        let password = "correct horse battery staple";
+        let user = create_user(&pool, "alice", " Alice@Example.COM ", password).await.unwrap();
+        assert_eq!(user.email, "alice@example.com");
+
+        let duplicate = create_user(&pool, "alice2", "ALICE@example.com", password).await;
+        assert!(matches!(duplicate, Err(AppError::Validation(_))));
+
+        let client = ClientInfo::default();
+        let signed_in = login_user(&pool, None, &client, "ALICE@EXAMPLE.com", password).await.unwrap();
+        assert_eq!(signed_in.id, user.id);
+    }
+
+    #[sqlx::test]
+    async fn email_addresses_are_unique_in_any_case(pool: PgPool) {
+        create_user(&pool, "alice", "alice@example.com", "correct horse battery staple").await.unwrap();
+
+        let inserted = sqlx::query(
+            "INSERT INTO users (id, username, email, role) VALUES ($1, 'mallory', 'ALICE@example.com', 'user')"
+        )
+        .bind(Uuid::new_v4())
+        .execute(&pool)
+        .await;
+        assert!(inserted.is_err());
+    }
+}
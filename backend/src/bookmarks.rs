@@ -0,0 +1,147 @@
+use crate::{
+    auth::AuthClaims,
+    chat::ensure_room_access,
+    error::AppError,
+    models::*,
+    SharedState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+
+const MAX_NOTE_CHARS: usize = 500;
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 200;
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/users/me/bookmarks", get(list_handler))
+        .route("/users/me/bookmarks/:message_id", put(save_handler).delete(remove_handler))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    room_id: Option<Uuid>,
+    limit: Option<i64>,
+    /// Only bookmarks saved before this, for paging.
+    before: Option<DateTime<Utc>>,
+}
+
+/// The caller's bookmarks, newest first, from rooms they can still read.
+async fn list_handler(
+    Query(query): Query<ListQuery>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<Bookmark>>, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let bookmarks = sqlx::query_as::<_, Bookmark>(
+        r#"
+        SELECT m.*, r.name AS room_name, b.note, b.created_at AS bookmarked_at
+        FROM bookmarks b
+        JOIN messages m ON m.id = b.message_id
+        JOIN rooms r ON r.id = b.room_id
+        WHERE b.user_id = $1
+          AND NOT EXISTS (SELECT 1 FROM room_bans rb WHERE rb.room_id = b.room_id AND rb.user_id = b.user_id)
+          AND (NOT r.is_private OR EXISTS (
+              SELECT 1 FROM room_members rm WHERE rm.room_id = b.room_id AND rm.user_id = b.user_id
+          ))
+          AND (m.expires_at IS NULL OR m.expires_at > NOW())
+          AND ($2::UUID IS NULL OR b.room_id = $2)
+          AND ($3::TIMESTAMPTZ IS NULL OR b.created_at < $3)
+        ORDER BY b.created_at DESC
+        LIMIT $4
+        "#
+    )
+    .bind(claims.user_id()?)
+    .bind(query.room_id)
+    .bind(query.before)
+    .bind(limit)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(bookmarks))
+}
+
+#[derive(Deserialize, Default)]
+struct SaveRequest {
+    note: Option<String>,
+}
+
+/// Bookmarks a message, or replaces the note on an existing bookmark.
+async fn save_handler(
+    Path(message_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    req: Option<Json<SaveRequest>>,
+) -> Result<Json<Bookmark>, AppError> {
+    let user_id = claims.user_id()?;
+    let Json(req) = req.unwrap_or_default();
+    let note = req.note.as_deref().map(str::trim).filter(|note| !note.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_NOTE_CHARS) {
+        return Err(AppError::Validation(format!(
+            "Notes can be at most {} characters",
+            MAX_NOTE_CHARS
+        )));
+    }
+
+    let room_id = sqlx::query_scalar::<_, Uuid>(
+        "SELECT room_id FROM messages WHERE id = $1 AND (expires_at IS NULL OR expires_at > NOW())"
+    )
+    .bind(message_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Message not found".to_string()))?;
+    ensure_room_access(&state.db, room_id, user_id).await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO bookmarks (user_id, message_id, room_id, note)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id, message_id) DO UPDATE SET note = $4, updated_at = NOW()
+        "#
+    )
+    .bind(user_id)
+    .bind(message_id)
+    .bind(room_id)
+    .bind(note)
+    .execute(&state.db)
+    .await?;
+
+    let bookmark = sqlx::query_as::<_, Bookmark>(
+        r#"
+        SELECT m.*, r.name AS room_name, b.note, b.created_at AS bookmarked_at
+        FROM bookmarks b
+        JOIN messages m ON m.id = b.message_id
+        JOIN rooms r ON r.id = b.room_id
+        WHERE b.user_id = $1 AND b.message_id = $2
+        "#
+    )
+    .bind(user_id)
+    .bind(message_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(bookmark))
+}
+
+async fn remove_handler(
+    Path(message_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let result = sqlx::query("DELETE FROM bookmarks WHERE user_id = $1 AND message_id = $2")
+        .bind(claims.user_id()?)
+        .bind(message_id)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Bookmark not found".to_string()));
+    }
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
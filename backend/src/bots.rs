@@ -0,0 +1,195 @@
+use crate::{
+    api_tokens::{self, CreateTokenRequest},
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{is_admin, validate_username, AuthClaims, ROLE_USER},
+    error::AppError,
+    models::*,
+    websocket::disconnect_user,
+    SharedState,
+};
+use axum::{
+    extract::{Path, State},
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const BOT_COLUMNS: &str = "id, username, display_name, bot_owner_id, disabled_at, created_at";
+/// Bots never receive mail; the address only satisfies the users table.
+const BOT_EMAIL_DOMAIN: &str = "bots.invalid";
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/bots", get(list_handler).post(create_handler))
+        .route("/bots/:bot_id", delete(delete_handler))
+        .route("/bots/:bot_id/tokens", get(list_tokens_handler).post(create_token_handler))
+        .route("/bots/:bot_id/tokens/:token_id", delete(revoke_token_handler))
+}
+
+/// Loads a bot the caller may manage: its owner, or any admin.
+async fn managed_bot(pool: &PgPool, claims: &AuthClaims, bot_id: Uuid) -> Result<Bot, AppError> {
+    let user_id = claims.user_id()?;
+    let bot = sqlx::query_as::<_, Bot>(&format!(
+        "SELECT {} FROM users WHERE id = $1 AND is_bot",
+        BOT_COLUMNS
+    ))
+    .bind(bot_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Bot not found".to_string()))?;
+
+    if bot.bot_owner_id != Some(user_id) && !is_admin(pool, user_id).await? {
+        return Err(AppError::NotFound("Bot not found".to_string()));
+    }
+
+    Ok(bot)
+}
+
+async fn list_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<Bot>>, AppError> {
+    let bots = sqlx::query_as::<_, Bot>(&format!(
+        "SELECT {} FROM users WHERE is_bot AND bot_owner_id = $1 ORDER BY created_at",
+        BOT_COLUMNS
+    ))
+    .bind(claims.user_id()?)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(bots))
+}
+
+#[derive(Deserialize)]
+struct CreateBotRequest {
+    username: String,
+    display_name: Option<String>,
+}
+
+/// Creates a token-only bot account. `owner_id` is `None` for bots the
+/// server manages itself, like the authors of incoming webhooks.
+pub async fn create(
+    pool: &PgPool,
+    username: &str,
+    display_name: Option<&str>,
+    owner_id: Option<Uuid>,
+) -> Result<Bot, AppError> {
+    validate_username(username)?;
+
+    let existing = sqlx::query("SELECT id FROM users WHERE LOWER(username) = LOWER($1)")
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+    if existing.is_some() {
+        return Err(AppError::Validation("Username is already taken".to_string()));
+    }
+
+    let bot_id = Uuid::new_v4();
+    let now = Utc::now();
+    let bot = sqlx::query_as::<_, Bot>(&format!(
+        r#"
+        INSERT INTO users (id, username, email, password_hash, role, display_name, is_bot, bot_owner_id,
+                           email_verified_at, created_at, updated_at)
+        VALUES ($1, $2, $3, NULL, $4, $5, TRUE, $6, $7, $7, $7)
+        RETURNING {}
+        "#,
+        BOT_COLUMNS
+    ))
+    .bind(bot_id)
+    .bind(username)
+    .bind(format!("{}@{}", bot_id, BOT_EMAIL_DOMAIN))
+    .bind(ROLE_USER)
+    .bind(display_name.map(str::trim).filter(|name| !name.is_empty()))
+    .bind(owner_id)
+    .bind(now)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(bot)
+}
+
+async fn create_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<CreateBotRequest>,
+) -> Result<Json<Bot>, AppError> {
+    let owner_id = claims.user_id()?;
+    let bot = create(&state.db, &req.username, req.display_name.as_deref(), Some(owner_id)).await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("bot.create")
+            .actor(owner_id)
+            .target("user", bot.id)
+            .metadata(serde_json::json!({ "username": bot.username })),
+    ).await;
+
+    Ok(Json(bot))
+}
+
+async fn delete_handler(
+    Path(bot_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let bot = managed_bot(&state.db, &claims, bot_id).await?;
+    sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(bot.id)
+        .execute(&state.db)
+        .await?;
+
+    disconnect_user(&state, bot.id, None, "bot deleted").await;
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("bot.delete")
+            .actor(claims.user_id()?)
+            .target("user", bot.id)
+            .metadata(serde_json::json!({ "username": bot.username })),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+async fn list_tokens_handler(
+    Path(bot_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<ApiToken>>, AppError> {
+    let bot = managed_bot(&state.db, &claims, bot_id).await?;
+    Ok(Json(api_tokens::list(&state.db, bot.id).await?))
+}
+
+async fn create_token_handler(
+    Path(bot_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<CreateTokenRequest>,
+) -> Result<Json<CreatedApiToken>, AppError> {
+    let bot = managed_bot(&state.db, &claims, bot_id).await?;
+    let actor_id = claims.user_id()?;
+    let created = api_tokens::create(&state.db, bot.id, actor_id, &req).await?;
+    api_tokens::record_created(&state.db, &client, actor_id, &created).await;
+
+    Ok(Json(created))
+}
+
+async fn revoke_token_handler(
+    Path((bot_id, token_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let bot = managed_bot(&state.db, &claims, bot_id).await?;
+    let token = api_tokens::revoke(&state.db, bot.id, token_id).await?;
+    api_tokens::record_revoked(&state.db, &client, claims.user_id()?, &token).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
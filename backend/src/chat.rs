@@ -1,7 +1,5 @@
-#[allow(dead_code)]
-
-use crate::{error::AppError, models::*};
-use chrono::Utc;
+use crate::{error::AppError, link_previews, markdown, mentions, models::*, uploads, AppState};
+use chrono::{DateTime, Utc};
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -9,50 +7,123 @@ pub async fn create_room(
     pool: &PgPool,
     name: &str,
     description: Option<&str>,
+    is_private: bool,
+    created_by: Uuid,
 ) -> Result<Room, AppError> {
     let room_id = Uuid::new_v4();
     let now = Utc::now();
 
+    let mut tx = pool.begin().await?;
+
     let room = sqlx::query_as::<_, Room>(
         r#"
-        INSERT INTO rooms (id, name, description, created_at, updated_at)
-        VALUES ($1, $2, $3, $4, $5)
+        INSERT INTO rooms (id, name, description, is_private, created_by, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
         RETURNING *
         "#
     )
     .bind(room_id)
     .bind(name)
     .bind(description)
+    .bind(is_private)
+    .bind(created_by)
     .bind(now)
     .bind(now)
-    .fetch_one(pool)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // The creator owns the room
+    sqlx::query(
+        "INSERT INTO room_members (room_id, user_id, role, joined_at) VALUES ($1, $2, 'owner', $3)"
+    )
+    .bind(room_id)
+    .bind(created_by)
+    .bind(now)
+    .execute(&mut *tx)
     .await?;
 
+    tx.commit().await?;
+
     Ok(room)
 }
 
-pub async fn get_rooms(pool: &PgPool) -> Result<Vec<Room>, AppError> {
-    let rooms = sqlx::query_as::<_, Room>("SELECT * FROM rooms ORDER BY created_at DESC")
-        .fetch_all(pool)
-        .await?;
+/// Lists public rooms plus the private rooms the user belongs to.
+pub async fn get_rooms(pool: &PgPool, user_id: Uuid) -> Result<Vec<Room>, AppError> {
+    let rooms = sqlx::query_as::<_, Room>(
+        r#"
+        SELECT * FROM rooms r
+        WHERE NOT r.is_private
+           OR EXISTS(SELECT 1 FROM room_members rm WHERE rm.room_id = r.id AND rm.user_id = $1)
+        ORDER BY created_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
 
     Ok(rooms)
 }
 
+/// Message types that only the server may produce.
+const RESERVED_MESSAGE_TYPES: &[&str] = &["system", "poll", "reminder"];
+
+pub fn ensure_client_message_type(message_type: &str) -> Result<(), AppError> {
+    if RESERVED_MESSAGE_TYPES.contains(&message_type) {
+        return Err(AppError::Validation(format!("Message type '{}' is reserved", message_type)));
+    }
+    Ok(())
+}
+
+/// Presentation details integrations can set on the messages they post.
+#[derive(Default)]
+pub struct MessageOptions {
+    pub author_name: Option<String>,
+    pub attachments: Vec<MessageAttachment>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Stores a message along with the mentions it contains, and sends mentioned
+/// users a `mention` event. Broadcasting the message itself is up to the caller.
 pub async fn send_message(
-    pool: &PgPool,
+    state: &AppState,
+    room_id: Uuid,
+    user_id: Uuid,
+    content: &str,
+    message_type: &str,
+) -> Result<Message, AppError> {
+    send_message_with(state, room_id, user_id, content, message_type, MessageOptions::default()).await
+}
+
+/// `send_message` with an author name override and attachments.
+pub async fn send_message_with(
+    state: &AppState,
     room_id: Uuid,
     user_id: Uuid,
     content: &str,
     message_type: &str,
+    options: MessageOptions,
 ) -> Result<Message, AppError> {
+    let (file_id, file_content) = match message_type {
+        "file" => {
+            let (file_id, file_content) = uploads::file_message_content(&state.db, content).await?;
+            (Some(file_id), Some(file_content))
+        }
+        _ => (None, None),
+    };
+    let content = file_content.as_deref().unwrap_or(content);
     let message_id = Uuid::new_v4();
     let now = Utc::now();
+    let mentions = mentions::resolve(state, room_id, user_id, content, message_type).await?;
+    let content_html = markdown::is_rendered(message_type).then(|| markdown::render(content, &mentions.spans));
+    let links = link_previews::lookup(state, content_html.as_deref()).await?;
+
+    let mut tx = state.db.begin().await?;
 
     let message = sqlx::query_as::<_, Message>(
         r#"
-        INSERT INTO messages (id, room_id, user_id, content, message_type, created_at)
-        VALUES ($1, $2, $3, $4, $5, $6)
+        INSERT INTO messages (id, room_id, user_id, content, content_html, message_type, is_bot,
+                              mentions, author_name, attachments, link_previews, expires_at, created_at, file_id)
+        SELECT $1, $2, $3, $4, $11, $5, u.is_bot, $6, $7, $8, $12, $9, $10, $13 FROM users u WHERE u.id = $3
         RETURNING *
         "#
     )
@@ -61,13 +132,89 @@ pub async fn send_message(
     .bind(user_id)
     .bind(content)
     .bind(message_type)
+    .bind(sqlx::types::Json(&mentions.spans))
+    .bind(&options.author_name)
+    .bind(sqlx::types::Json(&options.attachments))
+    .bind(options.expires_at)
     .bind(now)
-    .fetch_one(pool)
+    .bind(content_html)
+    .bind(sqlx::types::Json(links.previews()))
+    .bind(file_id)
+    .fetch_one(&mut *tx)
     .await?;
 
+    mentions::record(&mut tx, &message, &mentions.recipients).await?;
+    tx.commit().await?;
+
+    mentions::notify(state, &message, &mentions.recipients).await;
+    link_previews::unfurl(state, &message, links);
+
     Ok(message)
 }
 
+pub async fn get_message(pool: &PgPool, room_id: Uuid, message_id: Uuid) -> Result<Message, AppError> {
+    sqlx::query_as::<_, Message>(
+        "SELECT * FROM messages WHERE id = $1 AND room_id = $2 AND (expires_at IS NULL OR expires_at > NOW())"
+    )
+    .bind(message_id)
+    .bind(room_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Message not found".to_string()))
+}
+
+/// Replaces a message's content and re-resolves its mentions. Only users
+/// mentioned for the first time get a `mention` event.
+pub async fn edit_message(state: &AppState, message: &Message, content: &str) -> Result<Message, AppError> {
+    let user_id = message.user_id.unwrap_or_default();
+    let mut mentions = mentions::resolve(state, message.room_id, user_id, content, &message.message_type).await?;
+    let content_html = markdown::is_rendered(&message.message_type)
+        .then(|| markdown::render(content, &mentions.spans));
+    let links = link_previews::lookup(state, content_html.as_deref()).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    let previous = sqlx::query_scalar::<_, Uuid>(
+        "DELETE FROM message_mentions WHERE message_id = $1 RETURNING user_id"
+    )
+    .bind(message.id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let edited = sqlx::query_as::<_, Message>(
+        r#"
+        UPDATE messages SET content = $2, content_html = $4, mentions = $3, link_previews = $5, edited_at = NOW()
+        WHERE id = $1
+        RETURNING *
+        "#
+    )
+    .bind(message.id)
+    .bind(content)
+    .bind(sqlx::types::Json(&mentions.spans))
+    .bind(content_html)
+    .bind(sqlx::types::Json(links.previews()))
+    .fetch_one(&mut *tx)
+    .await?;
+
+    mentions::record(&mut tx, &edited, &mentions.recipients).await?;
+    tx.commit().await?;
+
+    mentions.recipients.retain(|recipient, _| !previous.contains(recipient));
+    mentions::notify(state, &edited, &mentions.recipients).await;
+    link_previews::unfurl(state, &edited, links);
+
+    Ok(edited)
+}
+
+pub async fn delete_message(pool: &PgPool, message_id: Uuid) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM messages WHERE id = $1")
+        .bind(message_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
 pub async fn get_messages(
     pool: &PgPool,
     room_id: Uuid,
@@ -77,7 +224,7 @@ pub async fn get_messages(
     let messages = sqlx::query_as::<_, Message>(
         r#"
         SELECT * FROM messages
-        WHERE room_id = $1
+        WHERE room_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
         ORDER BY created_at DESC
         LIMIT $2 OFFSET $3
         "#
@@ -91,6 +238,63 @@ pub async fn get_messages(
     Ok(messages)
 }
 
+/// Up to `limit` messages centered on `message_id`, newest first like
+/// `get_messages`: the message itself and those after it fill the first half.
+pub async fn get_messages_around(
+    pool: &PgPool,
+    room_id: Uuid,
+    message_id: Uuid,
+    limit: i64,
+) -> Result<Vec<Message>, AppError> {
+    let target = get_message(pool, room_id, message_id).await?;
+
+    let mut newer = sqlx::query_as::<_, Message>(
+        r#"
+        SELECT * FROM messages
+        WHERE room_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+          AND (created_at, id) >= ($2, $3)
+        ORDER BY created_at, id
+        LIMIT $4
+        "#
+    )
+    .bind(room_id)
+    .bind(target.created_at)
+    .bind(target.id)
+    .bind(limit - limit / 2)
+    .fetch_all(pool)
+    .await?;
+    newer.reverse();
+
+    let older = sqlx::query_as::<_, Message>(
+        r#"
+        SELECT * FROM messages
+        WHERE room_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+          AND (created_at, id) < ($2, $3)
+        ORDER BY created_at DESC, id DESC
+        LIMIT $4
+        "#
+    )
+    .bind(room_id)
+    .bind(target.created_at)
+    .bind(target.id)
+    .bind(limit / 2)
+    .fetch_all(pool)
+    .await?;
+
+    newer.extend(older);
+    Ok(newer)
+}
+
+/// Deletes the room along with its messages and memberships.
+pub async fn delete_room(pool: &PgPool, room_id: Uuid) -> Result<Option<Room>, AppError> {
+    let room = sqlx::query_as::<_, Room>("DELETE FROM rooms WHERE id = $1 RETURNING *")
+        .bind(room_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(room)
+}
+
 pub async fn get_room_by_id(pool: &PgPool, room_id: Uuid) -> Result<Option<Room>, AppError> {
     let room = sqlx::query_as::<_, Room>("SELECT * FROM rooms WHERE id = $1")
         .bind(room_id)
@@ -98,4 +302,141 @@ pub async fn get_room_by_id(pool: &PgPool, room_id: Uuid) -> Result<Option<Room>
         .await?;
 
     Ok(room)
-}
\ No newline at end of file
+}
+
+/// Fails unless the user may read the room: it must exist, the user must not be
+/// banned, and private rooms additionally require membership.
+pub async fn ensure_room_access(pool: &PgPool, room_id: Uuid, user_id: Uuid) -> Result<Room, AppError> {
+    let room = get_room_by_id(pool, room_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Room not found".to_string()))?;
+
+    if crate::moderation::is_banned(pool, room_id, user_id).await? {
+        return Err(AppError::Authorization("You are banned from this room".to_string()));
+    }
+
+    if room.is_private && get_member_role(pool, room_id, user_id).await?.is_none() {
+        return Err(AppError::NotFound("Room not found".to_string()));
+    }
+
+    Ok(room)
+}
+
+/// Adds the user to the room as a regular member if they aren't one already.
+/// Returns whether a new membership was created.
+pub async fn join_room(pool: &PgPool, room_id: Uuid, user_id: Uuid) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO room_members (room_id, user_id, role, joined_at)
+        VALUES ($1, $2, 'member', $3)
+        ON CONFLICT (room_id, user_id) DO NOTHING
+        "#
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .bind(Utc::now())
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn leave_room(pool: &PgPool, room_id: Uuid, user_id: Uuid) -> Result<bool, AppError> {
+    let result = sqlx::query("DELETE FROM room_members WHERE room_id = $1 AND user_id = $2")
+        .bind(room_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_member_role(
+    pool: &PgPool,
+    room_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<String>, AppError> {
+    let role = sqlx::query_scalar::<_, String>(
+        "SELECT role FROM room_members WHERE room_id = $1 AND user_id = $2"
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(role)
+}
+
+pub async fn get_room_members(pool: &PgPool, room_id: Uuid) -> Result<Vec<RoomMember>, AppError> {
+    let members = sqlx::query_as::<_, RoomMember>(
+        r#"
+        SELECT rm.room_id, rm.user_id, u.username, rm.role, rm.joined_at
+        FROM room_members rm
+        JOIN users u ON u.id = rm.user_id
+        WHERE rm.room_id = $1
+        ORDER BY rm.joined_at
+        "#
+    )
+    .bind(room_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(members)
+}
+
+pub async fn get_read_marker(
+    pool: &PgPool,
+    room_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<RoomReadMarker>, AppError> {
+    let marker = sqlx::query_as::<_, RoomReadMarker>(
+        "SELECT * FROM room_read_markers WHERE room_id = $1 AND user_id = $2"
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(marker)
+}
+
+/// Marks the room as read up to and including the message. The marker only
+/// moves forward, so a stale client can't mark newer messages unread.
+pub async fn mark_read(
+    pool: &PgPool,
+    room_id: Uuid,
+    user_id: Uuid,
+    message_id: Uuid,
+) -> Result<RoomReadMarker, AppError> {
+    let created_at = sqlx::query_scalar::<_, chrono::DateTime<Utc>>(
+        "SELECT created_at FROM messages WHERE id = $1 AND room_id = $2"
+    )
+    .bind(message_id)
+    .bind(room_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Message not found".to_string()))?;
+
+    let marker = sqlx::query_as::<_, RoomReadMarker>(
+        r#"
+        INSERT INTO room_read_markers (room_id, user_id, last_read_message_id, last_read_at, updated_at)
+        VALUES ($1, $2, $3, $4, NOW())
+        ON CONFLICT (room_id, user_id) DO UPDATE
+        SET last_read_message_id = CASE
+                WHEN EXCLUDED.last_read_at > room_read_markers.last_read_at THEN EXCLUDED.last_read_message_id
+                ELSE room_read_markers.last_read_message_id
+            END,
+            last_read_at = GREATEST(room_read_markers.last_read_at, EXCLUDED.last_read_at),
+            updated_at = NOW()
+        RETURNING *
+        "#
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .bind(message_id)
+    .bind(created_at)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(marker)
+}
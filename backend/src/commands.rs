@@ -0,0 +1,850 @@
+use crate::{
+    admin::AdminUser,
+    api_tokens::{TokenScopes, SCOPE_ROOMS_MODERATE},
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{generate_secret, AuthClaims},
+    bots,
+    chat::{ensure_room_access, get_room_by_id, send_message, send_message_with, MessageOptions},
+    egress::Egress,
+    error::AppError,
+    incoming_webhooks::{clean_attachment, plain},
+    models::*,
+    moderation::{self, post_system_message, require_moderator},
+    outgoing_webhooks::{self, signature, validate_url, EVENT_MESSAGE_CREATED},
+    polls, push, reminders,
+    websocket::broadcast_to_room,
+    AppState, SharedState,
+};
+use axum::{
+    extract::{Path, State},
+    routing::{get, put},
+    Json, Router,
+};
+use chrono::{Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub const RESPONSE_EPHEMERAL: &str = "ephemeral";
+pub const RESPONSE_IN_CHANNEL: &str = "in_channel";
+const RESPONSE_TYPES: &[&str] = &[RESPONSE_EPHEMERAL, RESPONSE_IN_CHANNEL];
+
+const MAX_NAME_LEN: usize = 32;
+const MAX_DESCRIPTION_LEN: usize = 200;
+const MAX_USAGE_LEN: usize = 100;
+const MAX_TOPIC_CHARS: usize = 250;
+const MAX_REPLY_CHARS: usize = 4000;
+const MAX_ATTACHMENTS: usize = 20;
+/// Handlers have to answer about as fast as a user expects a message to post.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+struct Builtin {
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin { name: "me", usage: "<action>", description: "Describe what you're doing" },
+    Builtin { name: "topic", usage: "[topic | --clear]", description: "Show or set the room topic" },
+    Builtin { name: "invite", usage: "@user", description: "Add a user to the room" },
+    Builtin { name: "kick", usage: "@user [reason]", description: "Remove a user from the room" },
+    Builtin { name: "mute", usage: "@user <duration> [reason]", description: "Mute a user, e.g. /mute @sam 10m" },
+    Builtin {
+        name: "remind",
+        usage: "[me] in <duration> <text> | [me] at <HH:MM> <text>",
+        description: "Post a reminder to the room later",
+    },
+    Builtin {
+        name: "poll",
+        usage: "[--multi] [--anonymous] [--closes <duration>] \"question\" \"option\" \"option\" ...",
+        description: "Start a poll",
+    },
+];
+
+/// Where custom commands may be dispatched to; see `outgoing_webhooks::egress`.
+pub struct Commands {
+    egress: Egress,
+}
+
+impl Commands {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self { egress: outgoing_webhooks::egress()? })
+    }
+}
+
+/// Who ran a command, and in which room. `scopes` is set when they're using
+/// an API token.
+pub struct Invocation<'a> {
+    pub room_id: Uuid,
+    pub claims: &'a AuthClaims,
+    pub scopes: Option<&'a TokenScopes>,
+    pub client: &'a ClientInfo,
+}
+
+/// The result of a built-in command, before it's turned into a response.
+enum Reply {
+    Posted(Box<Message>),
+    Ephemeral(String),
+    Nothing,
+}
+
+pub fn router() -> Router<SharedState> {
+    Router::new().route("/commands", get(list_handler))
+}
+
+/// Custom command management, mounted under `/api/admin`.
+pub fn admin_router() -> Router<SharedState> {
+    Router::new()
+        .route("/commands", get(admin_list_handler).post(create_handler))
+        .route("/commands/:command_id", put(update_handler).delete(delete_handler))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Splits `/name args` into the lower-cased name and its arguments. Content
+/// whose first word isn't a command name (`/etc/hosts`, `/ 2`) isn't a command.
+pub fn parse(content: &str) -> Option<(String, &str)> {
+    let rest = content.trim_start().strip_prefix('/')?;
+    let (name, args) = match rest.find(char::is_whitespace) {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, ""),
+    };
+    is_valid_name(name).then(|| (name.to_ascii_lowercase(), args))
+}
+
+/// The first whitespace-separated word and the rest.
+fn first_word(args: &str) -> (&str, &str) {
+    let args = args.trim();
+    match args.find(char::is_whitespace) {
+        Some(i) => (&args[..i], args[i..].trim()),
+        None => (args, ""),
+    }
+}
+
+/// Splits arguments on whitespace, keeping "quoted strings" together.
+fn split_quoted(args: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut in_word = false;
+    for c in args.chars() {
+        match quote {
+            Some(close) if c == close => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' => {
+                quote = Some('"');
+                in_word = true;
+            }
+            None if c == '“' => {
+                quote = Some('”');
+                in_word = true;
+            }
+            None if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    words
+}
+
+/// Durations like `30s`, `10m`, `2h`, `1d` or `1w`.
+fn parse_duration(value: &str) -> Option<i64> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let amount: i64 = value[..split].parse().ok()?;
+    let unit = match &value[split..] {
+        "s" | "sec" | "secs" => 1,
+        "m" | "min" | "mins" => 60,
+        "h" | "hr" | "hrs" => 3600,
+        "d" | "day" | "days" => 86400,
+        "w" | "week" | "weeks" => 604800,
+        _ => return None,
+    };
+    amount.checked_mul(unit).filter(|seconds| *seconds > 0)
+}
+
+fn usage(name: &str) -> AppError {
+    let usage = BUILTINS
+        .iter()
+        .find(|builtin| builtin.name == name)
+        .map_or("", |builtin| builtin.usage);
+    AppError::Validation(format!("Usage: /{} {}", name, usage))
+}
+
+/// API tokens need `rooms:moderate` for the commands that moderate the room.
+fn require_moderate_scope(invocation: &Invocation<'_>) -> Result<(), AppError> {
+    match invocation.scopes {
+        Some(scopes) if !scopes.allows(SCOPE_ROOMS_MODERATE) => Err(AppError::Authorization(format!(
+            "Token lacks the '{}' scope",
+            SCOPE_ROOMS_MODERATE
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Resolves `@name` (or a bare name) to a user id and username.
+async fn find_user(pool: &PgPool, name: &str) -> Result<(Uuid, String), AppError> {
+    let name = name.trim_start_matches('@');
+    sqlx::query_as::<_, (Uuid, String)>("SELECT id, username FROM users WHERE LOWER(username) = LOWER($1)")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("No user named '{}'", name)))
+}
+
+/// Broadcasts a message a command posted and hands it to push and webhooks.
+async fn publish(state: &AppState, message: &Message) {
+    broadcast_to_room(state, message.room_id, &WebSocketMessage::new("new_message", message)).await;
+    push::notify_new_message(state, message);
+    outgoing_webhooks::dispatch_message(&state.db, EVENT_MESSAGE_CREATED, message).await;
+}
+
+/// Runs a command typed into a room. Built-ins are tried first, then the
+/// custom commands admins registered.
+pub async fn execute(
+    state: &AppState,
+    invocation: &Invocation<'_>,
+    content: &str,
+) -> Result<CommandResponse, AppError> {
+    let (name, args) = parse(content).ok_or_else(|| AppError::Validation("Not a command".to_string()))?;
+    ensure_room_access(&state.db, invocation.room_id, invocation.claims.user_id()?).await?;
+
+    let reply = match name.as_str() {
+        "me" => me(state, invocation, args).await?,
+        "topic" => topic(state, invocation, args).await?,
+        "invite" => invite(state, invocation, args).await?,
+        "kick" => kick(state, invocation, args).await?,
+        "mute" => mute(state, invocation, args).await?,
+        "remind" => remind(state, invocation, args).await?,
+        "poll" => poll(state, invocation, args).await?,
+        _ => custom(state, invocation, &name, args).await?,
+    };
+
+    let mut response = CommandResponse {
+        command: name,
+        ..Default::default()
+    };
+    match reply {
+        Reply::Posted(message) => response.message = Some(*message),
+        Reply::Ephemeral(text) => response.ephemeral = Some(text),
+        Reply::Nothing => {}
+    }
+    Ok(response)
+}
+
+async fn me(state: &AppState, invocation: &Invocation<'_>, args: &str) -> Result<Reply, AppError> {
+    if args.is_empty() {
+        return Err(usage("me"));
+    }
+    let user_id = invocation.claims.user_id()?;
+    moderation::ensure_can_post(&state.db, invocation.room_id, user_id).await?;
+
+    let message = send_message(state, invocation.room_id, user_id, args, "action").await?;
+    publish(state, &message).await;
+    Ok(Reply::Posted(Box::new(message)))
+}
+
+/// Shows the topic to anyone; setting it is up to moderators.
+async fn topic(state: &AppState, invocation: &Invocation<'_>, args: &str) -> Result<Reply, AppError> {
+    let room_id = invocation.room_id;
+    if args.is_empty() {
+        let room = get_room_by_id(&state.db, room_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound("Room not found".to_string()))?;
+        return Ok(Reply::Ephemeral(match room.topic {
+            Some(topic) => format!("The topic is: {}", topic),
+            None => "This room has no topic".to_string(),
+        }));
+    }
+
+    require_moderate_scope(invocation)?;
+    let actor_id = invocation.claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    let topic = (args != "--clear").then_some(args);
+    if topic.is_some_and(|topic| topic.chars().count() > MAX_TOPIC_CHARS) {
+        return Err(AppError::Validation(format!(
+            "Topic must be at most {} characters",
+            MAX_TOPIC_CHARS
+        )));
+    }
+
+    let room = sqlx::query_as::<_, Room>("UPDATE rooms SET topic = $2, updated_at = NOW() WHERE id = $1 RETURNING *")
+        .bind(room_id)
+        .bind(topic)
+        .fetch_one(&state.db)
+        .await?;
+
+    audit::record(
+        &state.db,
+        invocation.client,
+        AuditEvent::new("room.topic")
+            .actor(actor_id)
+            .room(room_id)
+            .metadata(serde_json::json!({ "topic": topic })),
+    ).await;
+    broadcast_to_room(state, room_id, &WebSocketMessage::new("room_updated", &room)).await;
+    let text = match topic {
+        Some(topic) => format!("{} set the topic to: {}", invocation.claims.username, topic),
+        None => format!("{} cleared the topic", invocation.claims.username),
+    };
+    post_system_message(state, room_id, actor_id, &text).await?;
+
+    Ok(Reply::Nothing)
+}
+
+async fn invite(state: &AppState, invocation: &Invocation<'_>, args: &str) -> Result<Reply, AppError> {
+    let (name, _) = first_word(args);
+    if name.is_empty() {
+        return Err(usage("invite"));
+    }
+    require_moderate_scope(invocation)?;
+
+    let (user_id, username) = find_user(&state.db, name).await?;
+    let added = moderation::add_member(state, invocation.client, invocation.room_id, invocation.claims, user_id).await?;
+    if added {
+        Ok(Reply::Nothing)
+    } else {
+        Ok(Reply::Ephemeral(format!("{} is already a member of this room", username)))
+    }
+}
+
+async fn kick(state: &AppState, invocation: &Invocation<'_>, args: &str) -> Result<Reply, AppError> {
+    let (name, reason) = first_word(args);
+    if name.is_empty() {
+        return Err(usage("kick"));
+    }
+    require_moderate_scope(invocation)?;
+
+    let (user_id, _) = find_user(&state.db, name).await?;
+    let reason = (!reason.is_empty()).then_some(reason);
+    moderation::kick(state, invocation.client, invocation.room_id, invocation.claims, user_id, reason).await?;
+    Ok(Reply::Nothing)
+}
+
+async fn mute(state: &AppState, invocation: &Invocation<'_>, args: &str) -> Result<Reply, AppError> {
+    let (name, rest) = first_word(args);
+    let (duration, reason) = first_word(rest);
+    let Some(seconds) = parse_duration(duration) else {
+        return Err(usage("mute"));
+    };
+    require_moderate_scope(invocation)?;
+
+    let (user_id, _) = find_user(&state.db, name).await?;
+    let reason = (!reason.is_empty()).then_some(reason);
+    moderation::mute(
+        state,
+        invocation.client,
+        invocation.room_id,
+        invocation.claims,
+        user_id,
+        seconds,
+        reason,
+    ).await?;
+    Ok(Reply::Nothing)
+}
+
+/// `in <duration>` counts from now; `at <HH:MM>` is the next time the clock
+/// in the user's profile timezone shows that time.
+async fn remind(state: &AppState, invocation: &Invocation<'_>, args: &str) -> Result<Reply, AppError> {
+    let user_id = invocation.claims.user_id()?;
+    let (first, rest) = first_word(args);
+    let args = if first.eq_ignore_ascii_case("me") { rest } else { args };
+
+    let timezone: Tz = sqlx::query_scalar::<_, Option<String>>("SELECT timezone FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&state.db)
+        .await?
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(Tz::UTC);
+    let now = Utc::now();
+
+    let (when, rest) = first_word(args);
+    let (remind_at, text) = match when.to_ascii_lowercase().as_str() {
+        "in" => {
+            let (duration, text) = first_word(rest);
+            let seconds = parse_duration(duration).ok_or_else(|| usage("remind"))?;
+            (now + Duration::seconds(seconds), text)
+        }
+        "at" => {
+            let (time, text) = first_word(rest);
+            let time = NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| usage("remind"))?;
+            let today = now.with_timezone(&timezone).date_naive();
+            let next = [today, today + Duration::days(1)]
+                .into_iter()
+                .filter_map(|date| timezone.from_local_datetime(&date.and_time(time)).earliest())
+                .map(|local| local.with_timezone(&Utc))
+                .find(|at| *at > now)
+                .ok_or_else(|| usage("remind"))?;
+            (next, text)
+        }
+        _ => {
+            let seconds = parse_duration(when).ok_or_else(|| usage("remind"))?;
+            (now + Duration::seconds(seconds), rest)
+        }
+    };
+    if text.is_empty() {
+        return Err(usage("remind"));
+    }
+
+    let reminder = reminders::create(&state.db, user_id, invocation.room_id, text, remind_at, None).await?;
+    let local = reminder.remind_at.with_timezone(&timezone);
+    Ok(Reply::Ephemeral(format!(
+        "I'll post \"{}\" here on {} ({})",
+        reminder.text,
+        local.format("%b %-d at %H:%M"),
+        timezone.name(),
+    )))
+}
+
+/// Leading `--multi`, `--anonymous` and `--closes <duration>` flags set the
+/// poll's options; the first remaining word is the question.
+async fn poll(state: &AppState, invocation: &Invocation<'_>, args: &str) -> Result<Reply, AppError> {
+    let mut words = split_quoted(args).into_iter().peekable();
+    let mut multiple_choice = false;
+    let mut anonymous = false;
+    let mut closes_at = None;
+    while let Some(flag) = words.next_if(|word| word.starts_with("--")) {
+        match flag.as_str() {
+            "--multi" => multiple_choice = true,
+            "--anonymous" => anonymous = true,
+            "--closes" => {
+                let seconds = words.next().as_deref().and_then(parse_duration).ok_or_else(|| usage("poll"))?;
+                closes_at = Some(Utc::now() + Duration::seconds(seconds));
+            }
+            _ => return Err(usage("poll")),
+        }
+    }
+    let Some(question) = words.next() else {
+        return Err(usage("poll"));
+    };
+    let options: Vec<String> = words.collect();
+    if options.len() < polls::MIN_OPTIONS {
+        return Err(usage("poll"));
+    }
+    let user_id = invocation.claims.user_id()?;
+    moderation::ensure_can_post(&state.db, invocation.room_id, user_id).await?;
+
+    let new_poll = polls::NewPoll {
+        question,
+        options,
+        multiple_choice,
+        anonymous,
+        closes_at,
+    };
+    let message = polls::create(state, invocation.room_id, user_id, &new_poll).await?;
+    publish(state, &message).await;
+    Ok(Reply::Posted(Box::new(message)))
+}
+
+/// A custom command handler's reply, in Slack's format. An empty body means
+/// there's nothing to show.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct HandlerReply {
+    text: Option<String>,
+    response_type: Option<String>,
+    attachments: Vec<MessageAttachment>,
+}
+
+fn truncate(text: String) -> String {
+    if text.chars().count() > MAX_REPLY_CHARS {
+        format!("{}…", text.chars().take(MAX_REPLY_CHARS).collect::<String>())
+    } else {
+        text
+    }
+}
+
+/// Sends the command to its handler as signed JSON (the same signature
+/// scheme as outgoing webhooks) and relays the reply.
+async fn custom(state: &AppState, invocation: &Invocation<'_>, name: &str, args: &str) -> Result<Reply, AppError> {
+    let command = sqlx::query_as::<_, SlashCommand>(
+        "SELECT * FROM slash_commands WHERE name = $1 AND disabled_at IS NULL"
+    )
+    .bind(name)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::Validation(format!("Unknown command /{}", name)))?;
+    let secret = sqlx::query_scalar::<_, String>("SELECT secret FROM slash_commands WHERE id = $1")
+        .bind(command.id)
+        .fetch_one(&state.db)
+        .await?;
+    let room = get_room_by_id(&state.db, invocation.room_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Room not found".to_string()))?;
+
+    let user_id = invocation.claims.user_id()?;
+    let now = Utc::now();
+    let body = serde_json::json!({
+        "command": format!("/{}", name),
+        "text": args,
+        "room_id": room.id,
+        "room_name": room.name,
+        "user_id": user_id,
+        "username": invocation.claims.username,
+        "timestamp": now,
+    })
+    .to_string();
+    let failed = |reason: String| AppError::BadRequest(format!("/{} failed: {}", name, reason));
+
+    let url = reqwest::Url::parse(&command.url).map_err(|e| failed(e.to_string()))?;
+    let http = state
+        .commands
+        .egress
+        .client(reqwest::Client::builder().timeout(REQUEST_TIMEOUT).user_agent("rust-konect-commands"), &url)
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    let response = http
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Konect-Command", format!("/{}", name))
+        .header("X-Konect-Timestamp", now.timestamp().to_string())
+        .header("X-Konect-Signature", signature(&secret, now.timestamp(), &body))
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| failed(if e.is_timeout() { "the handler timed out".to_string() } else { e.to_string() }))?;
+    if !response.status().is_success() {
+        return Err(failed(format!("the handler returned HTTP {}", response.status())));
+    }
+    let body = response.text().await.map_err(|e| failed(e.to_string()))?;
+    if body.trim().is_empty() {
+        return Ok(Reply::Nothing);
+    }
+
+    // Handlers that answer with plain text get it shown to the caller
+    let reply = serde_json::from_str::<HandlerReply>(&body).unwrap_or_else(|_| HandlerReply {
+        text: Some(body.clone()),
+        ..Default::default()
+    });
+    let attachments: Vec<MessageAttachment> = reply
+        .attachments
+        .into_iter()
+        .take(MAX_ATTACHMENTS)
+        .map(clean_attachment)
+        .collect();
+    let Some(text) = plain(reply.text).or_else(|| {
+        attachments
+            .iter()
+            .find_map(|attachment| attachment.fallback.clone().or_else(|| attachment.text.clone()))
+    }) else {
+        return Ok(Reply::Nothing);
+    };
+    let text = truncate(text);
+
+    let response_type = reply
+        .response_type
+        .filter(|response_type| RESPONSE_TYPES.contains(&response_type.as_str()))
+        .unwrap_or(command.response_type);
+    if response_type != RESPONSE_IN_CHANNEL {
+        return Ok(Reply::Ephemeral(text));
+    }
+
+    moderation::ensure_can_post(&state.db, invocation.room_id, user_id).await?;
+    let message = send_message_with(
+        state,
+        invocation.room_id,
+        command.user_id,
+        &text,
+        "text",
+        MessageOptions {
+            author_name: None,
+            attachments,
+            ..Default::default()
+        },
+    ).await?;
+    publish(state, &message).await;
+    Ok(Reply::Posted(Box::new(message)))
+}
+
+/// Built-ins followed by the enabled custom commands, for autocompletion.
+async fn list_handler(State(state): State<SharedState>) -> Result<Json<Vec<CommandInfo>>, AppError> {
+    let custom = sqlx::query_as::<_, SlashCommand>(
+        "SELECT * FROM slash_commands WHERE disabled_at IS NULL ORDER BY name"
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let commands = BUILTINS
+        .iter()
+        .map(|builtin| CommandInfo {
+            name: builtin.name.to_string(),
+            description: Some(builtin.description.to_string()),
+            usage_hint: Some(builtin.usage.to_string()),
+            builtin: true,
+        })
+        .chain(custom.into_iter().map(|command| CommandInfo {
+            name: command.name,
+            description: command.description,
+            usage_hint: command.usage_hint,
+            builtin: false,
+        }))
+        .collect();
+
+    Ok(Json(commands))
+}
+
+async fn admin_list_handler(
+    AdminUser(_admin): AdminUser,
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<SlashCommand>>, AppError> {
+    let commands = sqlx::query_as::<_, SlashCommand>("SELECT * FROM slash_commands ORDER BY name")
+        .fetch_all(&state.db)
+        .await?;
+
+    Ok(Json(commands))
+}
+
+/// Everything about a command except its secret; updates replace all fields.
+#[derive(Deserialize)]
+struct CommandRequest {
+    name: String,
+    description: Option<String>,
+    usage_hint: Option<String>,
+    url: String,
+    #[serde(default = "ephemeral_by_default")]
+    response_type: String,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+fn ephemeral_by_default() -> String {
+    RESPONSE_EPHEMERAL.to_string()
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn optional_text(value: &Option<String>, field: &str, max_len: usize) -> Result<Option<String>, AppError> {
+    let value = value.as_deref().map(str::trim).filter(|value| !value.is_empty());
+    if value.is_some_and(|value| value.chars().count() > max_len) {
+        return Err(AppError::Validation(format!("{} must be at most {} characters", field, max_len)));
+    }
+    Ok(value.map(str::to_string))
+}
+
+async fn validate(req: &CommandRequest) -> Result<(String, Option<String>, Option<String>), AppError> {
+    let name = req.name.trim().trim_start_matches('/').to_string();
+    if !is_valid_name(&name) || name != name.to_ascii_lowercase() {
+        return Err(AppError::Validation(format!(
+            "Command names must be 1 to {} lowercase letters, digits, '-' or '_'",
+            MAX_NAME_LEN
+        )));
+    }
+    if BUILTINS.iter().any(|builtin| builtin.name == name) {
+        return Err(AppError::Validation(format!("/{} is a built-in command", name)));
+    }
+    validate_url(&req.url).await?;
+    if !RESPONSE_TYPES.contains(&req.response_type.as_str()) {
+        return Err(AppError::Validation(format!(
+            "Response type must be one of: {}",
+            RESPONSE_TYPES.join(", ")
+        )));
+    }
+
+    Ok((
+        name,
+        optional_text(&req.description, "Description", MAX_DESCRIPTION_LEN)?,
+        optional_text(&req.usage_hint, "Usage hint", MAX_USAGE_LEN)?,
+    ))
+}
+
+async fn ensure_name_available(pool: &PgPool, name: &str, except: Option<Uuid>) -> Result<(), AppError> {
+    let taken = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM slash_commands WHERE name = $1 AND id IS DISTINCT FROM $2)"
+    )
+    .bind(name)
+    .bind(except)
+    .fetch_one(pool)
+    .await?;
+    if taken {
+        return Err(AppError::Validation(format!("/{} already exists", name)));
+    }
+    Ok(())
+}
+
+async fn create_handler(
+    AdminUser(admin): AdminUser,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<CommandRequest>,
+) -> Result<Json<CreatedSlashCommand>, AppError> {
+    let (name, description, usage_hint) = validate(&req).await?;
+    ensure_name_available(&state.db, &name, None).await?;
+    let admin_id = admin.user_id()?;
+
+    // Public replies are posted by a bot of the command's own
+    let command_id = Uuid::new_v4();
+    let author = bots::create(
+        &state.db,
+        &format!("command-{}", &command_id.simple().to_string()[..8]),
+        Some(&format!("/{}", name)),
+        None,
+    ).await?;
+
+    let secret = generate_secret();
+    let command = sqlx::query_as::<_, SlashCommand>(
+        r#"
+        INSERT INTO slash_commands (id, name, description, usage_hint, url, secret, response_type, user_id,
+                                    created_by, disabled_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, CASE WHEN $10 THEN NULL ELSE NOW() END)
+        RETURNING *
+        "#
+    )
+    .bind(command_id)
+    .bind(&name)
+    .bind(&description)
+    .bind(&usage_hint)
+    .bind(&req.url)
+    .bind(&secret)
+    .bind(&req.response_type)
+    .bind(author.id)
+    .bind(admin_id)
+    .bind(req.enabled)
+    .fetch_one(&state.db)
+    .await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("admin.command_create")
+            .actor(admin_id)
+            .target("slash_command", command.id)
+            .metadata(serde_json::json!({ "name": command.name, "url": command.url })),
+    ).await;
+
+    Ok(Json(CreatedSlashCommand { secret, command }))
+}
+
+async fn update_handler(
+    AdminUser(admin): AdminUser,
+    Path(command_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<CommandRequest>,
+) -> Result<Json<SlashCommand>, AppError> {
+    let (name, description, usage_hint) = validate(&req).await?;
+    ensure_name_available(&state.db, &name, Some(command_id)).await?;
+
+    let command = sqlx::query_as::<_, SlashCommand>(
+        r#"
+        UPDATE slash_commands
+        SET name = $2, description = $3, usage_hint = $4, url = $5, response_type = $6,
+            disabled_at = CASE WHEN $7 THEN NULL ELSE COALESCE(disabled_at, NOW()) END,
+            updated_at = NOW()
+        WHERE id = $1
+        RETURNING *
+        "#
+    )
+    .bind(command_id)
+    .bind(&name)
+    .bind(&description)
+    .bind(&usage_hint)
+    .bind(&req.url)
+    .bind(&req.response_type)
+    .bind(req.enabled)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Command not found".to_string()))?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("admin.command_update")
+            .actor(admin.user_id()?)
+            .target("slash_command", command.id)
+            .metadata(serde_json::json!({
+                "name": command.name,
+                "url": command.url,
+                "enabled": command.disabled_at.is_none(),
+            })),
+    ).await;
+
+    Ok(Json(command))
+}
+
+/// Deletes the command. Its bot stays, as the author of earlier replies.
+async fn delete_handler(
+    AdminUser(admin): AdminUser,
+    Path(command_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let name = sqlx::query_scalar::<_, String>("DELETE FROM slash_commands WHERE id = $1 RETURNING name")
+        .bind(command_id)
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Command not found".to_string()))?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("admin.command_delete")
+            .actor(admin.user_id()?)
+            .target("slash_command", command_id)
+            .metadata(serde_json::json!({ "name": name })),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_splits_name_and_arguments() {
+        assert_eq!(parse("/me waves"), Some(("me".to_string(), "waves")));
+        assert_eq!(parse("  /Topic   New topic  "), Some(("topic".to_string(), "New topic")));
+        assert_eq!(parse("/deploy-prod"), Some(("deploy-prod".to_string(), "")));
+        assert_eq!(parse("/poll\n\"Lunch?\" \"Yes\""), Some(("poll".to_string(), "\"Lunch?\" \"Yes\"")));
+    }
+
+    #[test]
+    fn parse_ignores_messages_that_are_not_commands() {
+        assert_eq!(parse("hello /me"), None);
+        assert_eq!(parse("/"), None);
+        assert_eq!(parse("/ me"), None);
+        assert_eq!(parse("/usr/bin/env"), None);
+        assert_eq!(parse(&format!("/{}", "a".repeat(MAX_NAME_LEN + 1))), None);
+    }
+
+    #[test]
+    fn split_quoted_keeps_quoted_strings_together() {
+        assert_eq!(split_quoted(r#"--multi "Where to?" Pizza "Sushi bar""#), ["--multi", "Where to?", "Pizza", "Sushi bar"]);
+        assert_eq!(split_quoted("“Smart quotes” work"), ["Smart quotes", "work"]);
+        assert_eq!(split_quoted(r#"say"hi there"now"#), ["sayhi therenow"]);
+        assert_eq!(split_quoted(r#""" empty"#), ["", "empty"]);
+        assert_eq!(split_quoted(r#""unterminated quote"#), ["unterminated quote"]);
+        assert!(split_quoted("   ").is_empty());
+    }
+
+    #[test]
+    fn parse_duration_accepts_units() {
+        assert_eq!(parse_duration("30s"), Some(30));
+        assert_eq!(parse_duration("10min"), Some(600));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("1w"), Some(604_800));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(parse_duration("99999999999999999w"), None);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::{
+    auth::AuthClaims,
+    error::AppError,
+    mailer::DIGEST,
+    models::*,
+    push::preview,
+    AppState, SharedState,
+};
+use axum::{
+    extract::State,
+    routing::get,
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::fmt::Write as _;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+pub const FREQUENCY_OFF: &str = "off";
+pub const FREQUENCY_HOURLY: &str = "hourly";
+pub const FREQUENCY_DAILY: &str = "daily";
+const FREQUENCIES: &[&str] = &[FREQUENCY_OFF, FREQUENCY_HOURLY, FREQUENCY_DAILY];
+
+const DEFAULT_CHECK_INTERVAL_SECONDS: u64 = 300;
+/// Users claimed per query, so one slow mail server can't hold a huge batch.
+const BATCH_SIZE: i64 = 50;
+/// Never reach further back than this, however long the user has been away.
+const MAX_LOOKBACK_DAYS: i64 = 7;
+const MAX_ITEMS: i64 = 200;
+const MAX_ITEMS_PER_ROOM: usize = 10;
+
+/// Starts the loop that sends due digests. Every instance can run it: users
+/// are claimed with `FOR UPDATE SKIP LOCKED`, so each digest goes out once.
+pub fn spawn(state: SharedState) {
+    let seconds = std::env::var("DIGEST_CHECK_INTERVAL_SECONDS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_CHECK_INTERVAL_SECONDS);
+    if seconds == 0 {
+        info!("Email digests disabled (DIGEST_CHECK_INTERVAL_SECONDS=0)");
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(seconds));
+        loop {
+            interval.tick().await;
+            if let Err(e) = send_due_digests(&state).await {
+                warn!("Failed to send email digests: {}", e);
+            }
+        }
+    });
+}
+
+#[derive(sqlx::FromRow)]
+struct DueUser {
+    id: Uuid,
+    username: String,
+    email: String,
+    timezone: Option<String>,
+    digest_frequency: String,
+    last_seen_at: Option<DateTime<Utc>>,
+    previous_digest_at: Option<DateTime<Utc>>,
+}
+
+/// A missed message: a mention or a direct message.
+#[derive(sqlx::FromRow)]
+struct DigestItem {
+    #[sqlx(flatten)]
+    message: Message,
+    room_name: String,
+    sender: Option<String>,
+    mention_kind: Option<String>,
+}
+
+pub async fn send_due_digests(state: &AppState) -> Result<(), AppError> {
+    // Users who are online are reading along, so they're left for later
+    let online: Vec<Uuid> = state.connections.read().await.keys().copied().collect();
+
+    loop {
+        let users = claim_due_users(&state.db, &online).await?;
+        if users.is_empty() {
+            return Ok(());
+        }
+        for user in &users {
+            if let Err(e) = send_digest(state, user).await {
+                warn!("Failed to send email digest to {}: {}", user.id, e);
+            }
+        }
+        if (users.len() as i64) < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+/// Claims users whose digest is due by stamping `last_digest_at`, returning
+/// the previous value so the digest knows where to start.
+async fn claim_due_users(pool: &PgPool, online: &[Uuid]) -> Result<Vec<DueUser>, AppError> {
+    let users = sqlx::query_as::<_, DueUser>(
+        r#"
+        WITH due AS (
+            SELECT id, last_digest_at
+            FROM users
+            WHERE digest_frequency <> 'off'
+              AND NOT is_bot
+              AND disabled_at IS NULL
+              AND email_verified_at IS NOT NULL
+              AND NOT (id = ANY($1))
+              AND (
+                  last_digest_at IS NULL
+                  OR last_digest_at <= NOW() - CASE digest_frequency
+                      WHEN 'hourly' THEN INTERVAL '1 hour'
+                      ELSE INTERVAL '1 day'
+                  END
+              )
+            ORDER BY last_digest_at NULLS FIRST
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        )
+        UPDATE users u
+        SET last_digest_at = NOW()
+        FROM due
+        WHERE u.id = due.id
+        RETURNING u.id, u.username, u.email, u.timezone, u.digest_frequency, u.last_seen_at,
+                  due.last_digest_at AS previous_digest_at
+        "#
+    )
+    .bind(online)
+    .bind(BATCH_SIZE)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(users)
+}
+
+/// Unread mentions and direct messages since `since`, oldest first. Messages
+/// before the user's read marker and rooms set to `none` are left out.
+async fn unread_items(pool: &PgPool, user_id: Uuid, since: DateTime<Utc>) -> Result<Vec<DigestItem>, AppError> {
+    let items = sqlx::query_as::<_, DigestItem>(
+        r#"
+        SELECT m.*, r.name AS room_name, u.username AS sender, mm.kind AS mention_kind
+        FROM messages m
+        JOIN rooms r ON r.id = m.room_id
+        JOIN room_members me ON me.room_id = m.room_id AND me.user_id = $1
+        LEFT JOIN users u ON u.id = m.user_id
+        LEFT JOIN message_mentions mm ON mm.message_id = m.id AND mm.user_id = $1
+        LEFT JOIN room_read_markers rrm ON rrm.room_id = m.room_id AND rrm.user_id = $1
+        LEFT JOIN room_notification_settings rns ON rns.room_id = m.room_id AND rns.user_id = $1
+        WHERE m.created_at > $2
+          AND m.user_id IS DISTINCT FROM $1
+          AND m.message_type <> 'system'
+          AND (rrm.last_read_at IS NULL OR m.created_at > rrm.last_read_at)
+          AND COALESCE(rns.level, 'all') <> 'none'
+          AND (
+              mm.user_id IS NOT NULL
+              OR (r.is_private AND (SELECT COUNT(*) FROM room_members WHERE room_id = m.room_id) = 2)
+          )
+        ORDER BY m.created_at
+        LIMIT $3
+        "#
+    )
+    .bind(user_id)
+    .bind(since)
+    .bind(MAX_ITEMS)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(items)
+}
+
+async fn send_digest(state: &AppState, user: &DueUser) -> Result<(), AppError> {
+    let since = [user.last_seen_at, user.previous_digest_at]
+        .into_iter()
+        .flatten()
+        .fold(Utc::now() - Duration::days(MAX_LOOKBACK_DAYS), DateTime::max);
+    let items = unread_items(&state.db, user.id, since).await?;
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let timezone: Tz = user
+        .timezone
+        .as_deref()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(Tz::UTC);
+    let rooms = render_rooms(&items, &state.mailer.base_url, timezone);
+    let unread = if items.len() == 1 {
+        "1 unread message".to_string()
+    } else if items.len() as i64 >= MAX_ITEMS {
+        format!("{}+ unread messages", MAX_ITEMS)
+    } else {
+        format!("{} unread messages", items.len())
+    };
+
+    state
+        .mailer
+        .send(
+            &user.email,
+            &DIGEST,
+            &[
+                ("username", &user.username),
+                ("unread", &unread),
+                ("rooms", &rooms),
+                ("frequency", &user.digest_frequency),
+                ("link", &state.mailer.base_url),
+            ],
+        )
+        .await?;
+
+    info!("Sent {} email digest to {} ({})", user.digest_frequency, user.id, unread);
+    Ok(())
+}
+
+/// Plain-text sections, one per room in order of its first unread message,
+/// each ending with a link that opens the room.
+fn render_rooms(items: &[DigestItem], base_url: &str, timezone: Tz) -> String {
+    let mut rooms: Vec<(Uuid, &str, Vec<&DigestItem>)> = Vec::new();
+    for item in items {
+        match rooms.iter_mut().find(|(room_id, _, _)| *room_id == item.message.room_id) {
+            Some((_, _, room_items)) => room_items.push(item),
+            None => rooms.push((item.message.room_id, &item.room_name, vec![item])),
+        }
+    }
+
+    let mut text = String::new();
+    for (room_id, room_name, room_items) in rooms {
+        let _ = writeln!(text, "# {} ({} unread)", room_name, room_items.len());
+        for item in room_items.iter().take(MAX_ITEMS_PER_ROOM) {
+            let time = item.message.created_at.with_timezone(&timezone).format("%b %-d %H:%M");
+            let sender = item.sender.as_deref().unwrap_or("Deleted user");
+            let marker = match item.mention_kind.as_deref() {
+                Some(crate::mentions::KIND_USER) => " (mentioned you)",
+                Some(_) => " (mentioned everyone)",
+                None => "",
+            };
+            let _ = writeln!(text, "  {} {}{}: {}", time, sender, marker, preview(&item.message));
+        }
+        if room_items.len() > MAX_ITEMS_PER_ROOM {
+            let _ = writeln!(text, "  ...and {} more", room_items.len() - MAX_ITEMS_PER_ROOM);
+        }
+        let _ = writeln!(text, "  Open: {}/?room={}\n", base_url, room_id);
+    }
+    text.trim_end().to_string()
+}
+
+pub fn router() -> Router<SharedState> {
+    Router::new().route("/users/me/email-digest", get(get_settings_handler).put(update_settings_handler))
+}
+
+pub async fn get_settings(pool: &PgPool, user_id: Uuid) -> Result<EmailDigestSettings, AppError> {
+    let settings = sqlx::query_as::<_, EmailDigestSettings>(
+        "SELECT digest_frequency AS frequency, last_digest_at FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    Ok(settings)
+}
+
+async fn get_settings_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<EmailDigestSettings>, AppError> {
+    Ok(Json(get_settings(&state.db, claims.user_id()?).await?))
+}
+
+#[derive(Deserialize)]
+struct UpdateSettingsRequest {
+    frequency: String,
+}
+
+async fn update_settings_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<UpdateSettingsRequest>,
+) -> Result<Json<EmailDigestSettings>, AppError> {
+    if !FREQUENCIES.contains(&req.frequency.as_str()) {
+        return Err(AppError::Validation(format!(
+            "Digest frequency must be one of: {}",
+            FREQUENCIES.join(", ")
+        )));
+    }
+
+    let settings = sqlx::query_as::<_, EmailDigestSettings>(
+        r#"
+        UPDATE users SET digest_frequency = $1, updated_at = NOW()
+        WHERE id = $2
+        RETURNING digest_frequency AS frequency, last_digest_at
+        "#
+    )
+    .bind(&req.frequency)
+    .bind(claims.user_id()?)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    Ok(Json(settings))
+}
@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -26,6 +26,9 @@ pub enum AppError {
     #[error("Bad request: {0}")]
     BadRequest(String),
     
+    #[error("Rate limited: retry after {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+    
     #[error("Internal server error: {0}")]
     InternalError(String),
     
@@ -38,6 +41,19 @@ pub enum AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let AppError::RateLimited { retry_after } = self {
+            let body = Json(json!({
+                "error": "Too many requests",
+                "retry_after": retry_after,
+            }));
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                body,
+            )
+                .into_response();
+        }
+
         let (status, error_message) = match self {
             AppError::Database(ref e) => {
                 tracing::error!("Database error: {}", e);
@@ -48,6 +64,7 @@ impl IntoResponse for AppError {
             AppError::Validation(ref message) => (StatusCode::BAD_REQUEST, message.as_str()),
             AppError::NotFound(ref message) => (StatusCode::NOT_FOUND, message.as_str()),
             AppError::BadRequest(ref message) => (StatusCode::BAD_REQUEST, message.as_str()),
+            AppError::RateLimited { .. } => (StatusCode::TOO_MANY_REQUESTS, "Too many requests"),
             AppError::InternalError(ref message) => {
                 tracing::error!("Internal error: {}", message);
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
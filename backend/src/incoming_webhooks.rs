@@ -0,0 +1,368 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{generate_secret, hash_secret, AuthClaims},
+    bots,
+    chat::{send_message_with, MessageOptions},
+    error::AppError,
+    models::*,
+    moderation::require_moderator,
+    websocket::broadcast_to_room,
+    SharedState,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub const TOKEN_PREFIX: &str = "kw_";
+/// Characters of the token kept in clear so webhooks can be told apart.
+const DISPLAY_PREFIX_LEN: usize = 11;
+const MAX_NAME_LEN: usize = 100;
+const MAX_TEXT_CHARS: usize = 40_000;
+const MAX_ATTACHMENTS: usize = 20;
+const MAX_FIELDS: usize = 20;
+
+/// Webhook URLs carry their own secret, so they bypass session auth.
+pub fn public_router() -> Router<SharedState> {
+    Router::new().route("/:token", post(receive_handler))
+}
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/rooms/:room_id/webhooks", get(list_handler).post(create_handler))
+        .route("/rooms/:room_id/webhooks/:webhook_id", delete(revoke_handler))
+}
+
+/// The JSON body of a Slack incoming webhook. Fields we don't support
+/// (`channel`, `icon_emoji`, `blocks`, ...) are accepted and ignored.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SlackPayload {
+    text: Option<String>,
+    username: Option<String>,
+    attachments: Vec<MessageAttachment>,
+}
+
+/// Rewrites Slack's markup to plain text: `<url|label>` becomes
+/// `label (url)`, `<!channel>` and `<!here>` become mentions, and the
+/// `&amp;`, `&lt;` and `&gt;` escapes are undone.
+fn slack_to_plain(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        out.push_str(&rest[..open]);
+        let Some(close) = rest[open..].find('>') else {
+            rest = &rest[open..];
+            break;
+        };
+        let inner = &rest[open + 1..open + close];
+        let (target, label) = match inner.split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (inner, None),
+        };
+        match (target, label) {
+            ("!channel" | "!everyone", _) => out.push_str("@room"),
+            ("!here", _) => out.push_str("@here"),
+            (_, Some(label)) if target.starts_with('!') => out.push_str(label),
+            (_, Some(label)) if target.starts_with('@') => {
+                out.push('@');
+                out.push_str(label.trim_start_matches('@'));
+            }
+            (_, Some(label)) if target.starts_with('#') => {
+                out.push('#');
+                out.push_str(label.trim_start_matches('#'));
+            }
+            (_, Some(label)) => out.push_str(&format!("{} ({})", label, target)),
+            (_, None) => out.push_str(target),
+        }
+        rest = &rest[open + close + 1..];
+    }
+    out.push_str(rest);
+
+    out.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+pub fn plain(text: Option<String>) -> Option<String> {
+    text.map(|text| slack_to_plain(&text)).filter(|text| !text.trim().is_empty())
+}
+
+/// Only web links, so attachments can't smuggle in `javascript:` URLs.
+fn web_link(url: Option<String>) -> Option<String> {
+    url.filter(|url| url.starts_with("https://") || url.starts_with("http://"))
+}
+
+/// Slack's named colors, or a `#rrggbb` hex color.
+fn color(color: Option<String>) -> Option<String> {
+    let color = color?;
+    match color.as_str() {
+        "good" => Some("#2eb67d".to_string()),
+        "warning" => Some("#daa038".to_string()),
+        "danger" => Some("#a30200".to_string()),
+        _ => {
+            let hex = color.trim_start_matches('#');
+            (hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit())).then(|| format!("#{}", hex))
+        }
+    }
+}
+
+pub fn clean_attachment(attachment: MessageAttachment) -> MessageAttachment {
+    MessageAttachment {
+        fallback: plain(attachment.fallback),
+        color: color(attachment.color),
+        pretext: plain(attachment.pretext),
+        author_name: plain(attachment.author_name),
+        title: plain(attachment.title),
+        title_link: web_link(attachment.title_link),
+        text: plain(attachment.text),
+        fields: attachment
+            .fields
+            .into_iter()
+            .take(MAX_FIELDS)
+            .map(|field| AttachmentField {
+                title: slack_to_plain(&field.title),
+                value: slack_to_plain(&field.value),
+                short: field.short,
+            })
+            .collect(),
+        image_url: web_link(attachment.image_url),
+        footer: plain(attachment.footer),
+    }
+}
+
+/// Slack accepts the payload as a JSON body or as a `payload` form field.
+/// Form-encoded bodies without that field are tried as JSON, since
+/// `curl -d '{...}'` sends JSON with a form content type.
+fn parse_payload(headers: &HeaderMap, body: &Bytes) -> Result<SlackPayload, AppError> {
+    #[derive(Deserialize)]
+    struct Form {
+        payload: String,
+    }
+
+    let is_form = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    let form = is_form
+        .then(|| serde_urlencoded::from_bytes::<Form>(body).ok())
+        .flatten();
+
+    let parsed = match form {
+        Some(form) => serde_json::from_str(&form.payload),
+        None => serde_json::from_slice(body),
+    };
+
+    parsed.map_err(|e| AppError::BadRequest(format!("Invalid payload: {}", e)))
+}
+
+/// Looks up an active webhook by its secret token.
+async fn find_active(pool: &PgPool, token: &str) -> Result<IncomingWebhook, AppError> {
+    let webhook = sqlx::query_as::<_, IncomingWebhook>(
+        "SELECT * FROM incoming_webhooks WHERE token_hash = $1 AND revoked_at IS NULL"
+    )
+    .bind(hash_secret(token))
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Unknown or revoked webhook".to_string()))?;
+
+    Ok(webhook)
+}
+
+async fn receive_handler(
+    Path(token): Path<String>,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<&'static str, AppError> {
+    let webhook = find_active(&state.db, &token).await?;
+    state
+        .rate_limits
+        .check(&state.rate_limits.webhooks, &[format!("webhook:{}", webhook.id)])?;
+
+    let payload = parse_payload(&headers, &body)?;
+    let attachments: Vec<MessageAttachment> = payload
+        .attachments
+        .into_iter()
+        .take(MAX_ATTACHMENTS)
+        .map(clean_attachment)
+        .collect();
+
+    // Attachment-only payloads use the first attachment's summary as the text
+    let content = plain(payload.text)
+        .or_else(|| {
+            attachments.iter().find_map(|attachment| {
+                attachment
+                    .fallback
+                    .clone()
+                    .or_else(|| attachment.pretext.clone())
+                    .or_else(|| attachment.title.clone())
+                    .or_else(|| attachment.text.clone())
+            })
+        })
+        .ok_or_else(|| AppError::Validation("Payload needs text or attachments".to_string()))?;
+    if content.chars().count() > MAX_TEXT_CHARS {
+        return Err(AppError::Validation(format!(
+            "Text must be at most {} characters",
+            MAX_TEXT_CHARS
+        )));
+    }
+
+    let author_name = payload
+        .username
+        .map(|name| name.trim().chars().take(MAX_NAME_LEN).collect::<String>())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| webhook.name.clone());
+
+    let message = send_message_with(
+        &state,
+        webhook.room_id,
+        webhook.user_id,
+        &content,
+        "text",
+        MessageOptions {
+            author_name: Some(author_name),
+            attachments,
+            ..Default::default()
+        },
+    ).await?;
+
+    broadcast_to_room(&state, webhook.room_id, &WebSocketMessage::new("new_message", &message)).await;
+    crate::push::notify_new_message(&state, &message);
+
+    // Coarse enough that busy integrations don't write on every request
+    sqlx::query(
+        r#"
+        UPDATE incoming_webhooks SET last_used_at = NOW()
+        WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < NOW() - INTERVAL '1 minute')
+        "#
+    )
+    .bind(webhook.id)
+    .execute(&state.db)
+    .await?;
+
+    // Slack answers with a plain "ok", which some clients check for
+    Ok("ok")
+}
+
+async fn list_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<IncomingWebhook>>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+
+    let webhooks = sqlx::query_as::<_, IncomingWebhook>(
+        "SELECT * FROM incoming_webhooks WHERE room_id = $1 ORDER BY created_at"
+    )
+    .bind(room_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(webhooks))
+}
+
+#[derive(Deserialize)]
+struct CreateWebhookRequest {
+    name: String,
+}
+
+async fn create_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<CreateWebhookRequest>,
+) -> Result<Json<CreatedIncomingWebhook>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::Validation(format!(
+            "Webhook name must be between 1 and {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+
+    // Each webhook posts as its own bot, so revoking one doesn't touch the others
+    let webhook_id = Uuid::new_v4();
+    let author = bots::create(
+        &state.db,
+        &format!("webhook-{}", &webhook_id.simple().to_string()[..8]),
+        Some(name),
+        None,
+    ).await?;
+
+    let token = format!("{}{}", TOKEN_PREFIX, generate_secret());
+    let webhook = sqlx::query_as::<_, IncomingWebhook>(
+        r#"
+        INSERT INTO incoming_webhooks (id, room_id, user_id, name, token_hash, token_prefix, created_by)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING *
+        "#
+    )
+    .bind(webhook_id)
+    .bind(room_id)
+    .bind(author.id)
+    .bind(name)
+    .bind(hash_secret(&token))
+    .bind(&token[..DISPLAY_PREFIX_LEN])
+    .bind(actor_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("webhook.create")
+            .actor(actor_id)
+            .room(room_id)
+            .target("incoming_webhook", webhook.id)
+            .metadata(serde_json::json!({ "name": webhook.name })),
+    ).await;
+
+    Ok(Json(CreatedIncomingWebhook {
+        url: format!("{}/api/hooks/{}", state.mailer.base_url, token),
+        webhook,
+    }))
+}
+
+async fn revoke_handler(
+    Path((room_id, webhook_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<IncomingWebhook>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    let webhook = sqlx::query_as::<_, IncomingWebhook>(
+        r#"
+        UPDATE incoming_webhooks SET revoked_at = COALESCE(revoked_at, NOW())
+        WHERE id = $1 AND room_id = $2
+        RETURNING *
+        "#
+    )
+    .bind(webhook_id)
+    .bind(room_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Webhook not found".to_string()))?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("webhook.revoke")
+            .actor(actor_id)
+            .room(room_id)
+            .target("incoming_webhook", webhook.id)
+            .metadata(serde_json::json!({ "name": webhook.name })),
+    ).await;
+
+    Ok(Json(webhook))
+}
@@ -0,0 +1,711 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{create_external_user, get_user_by_id, is_bootstrap_admin, AuthClaims, ROLE_ADMIN, ROLE_USER},
+    chat::join_room,
+    error::AppError,
+    models::{User, UserIdentity},
+    moderation::is_banned,
+    oidc::link_identity,
+    outgoing_webhooks::{self, EVENT_MEMBER_JOINED},
+    SharedState,
+};
+use axum::{extract::State, routing::post, Extension, Json, Router};
+use ldap3::{ldap_escape, Ldap as LdapClient, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+pub const PROVIDER: &str = "ldap";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Result code for a failed bind (wrong password, unknown DN, locked account).
+const INVALID_CREDENTIALS: u32 = 49;
+
+pub struct LdapConfig {
+    pub url: String,
+    pub starttls: bool,
+    /// Service account used to look users up; anonymous search if unset.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub base_dn: String,
+    /// Search filter with `{login}` standing for what the user typed.
+    pub user_filter: String,
+    pub username_attr: String,
+    pub email_attr: String,
+    pub display_name_attr: String,
+    pub group_attr: String,
+    /// Alternatively find groups by searching, with `{dn}` standing for the
+    /// user's DN, for servers without a `memberOf` attribute.
+    pub group_filter: Option<String>,
+    pub group_base_dn: String,
+    /// Members of this group are admins; everyone else is demoted on login.
+    pub admin_group: Option<String>,
+    /// Members of a group are added to the paired room on login.
+    pub group_rooms: Vec<(String, Uuid)>,
+}
+
+impl LdapConfig {
+    /// LDAP logins are enabled by setting `LDAP_URL` and `LDAP_BASE_DN`.
+    fn from_env() -> Option<Self> {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        let url = env("LDAP_URL")?;
+        let base_dn = env("LDAP_BASE_DN")?;
+        let group_base_dn = env("LDAP_GROUP_BASE_DN").unwrap_or_else(|| base_dn.clone());
+
+        // "cn=eng,ou=groups,dc=example,dc=org=>ROOM_ID|cn=ops,...=>ROOM_ID"
+        let group_rooms = env("LDAP_GROUP_ROOMS")
+            .unwrap_or_default()
+            .split('|')
+            .filter(|entry| !entry.trim().is_empty())
+            .filter_map(|entry| {
+                let parsed = entry
+                    .rsplit_once("=>")
+                    .and_then(|(group, room)| Some((group.trim().to_lowercase(), room.trim().parse().ok()?)));
+                if parsed.is_none() {
+                    warn!("Ignoring invalid LDAP_GROUP_ROOMS entry {:?}", entry);
+                }
+                parsed
+            })
+            .collect();
+
+        Some(Self {
+            url,
+            starttls: env("LDAP_STARTTLS").is_some_and(|value| value == "true" || value == "1"),
+            bind_dn: env("LDAP_BIND_DN"),
+            bind_password: env("LDAP_BIND_PASSWORD"),
+            base_dn,
+            user_filter: env("LDAP_USER_FILTER").unwrap_or_else(|| "(|(uid={login})(mail={login}))".to_string()),
+            username_attr: env("LDAP_USERNAME_ATTR").unwrap_or_else(|| "uid".to_string()),
+            email_attr: env("LDAP_EMAIL_ATTR").unwrap_or_else(|| "mail".to_string()),
+            display_name_attr: env("LDAP_DISPLAY_NAME_ATTR").unwrap_or_else(|| "cn".to_string()),
+            group_attr: env("LDAP_GROUP_ATTR").unwrap_or_else(|| "memberOf".to_string()),
+            group_filter: env("LDAP_GROUP_FILTER"),
+            group_base_dn,
+            admin_group: env("LDAP_ADMIN_GROUP").map(|group| group.to_lowercase()),
+            group_rooms,
+        })
+    }
+}
+
+/// Endpoints for signed-in users, mounted under `/api`.
+pub fn router() -> Router<SharedState> {
+    Router::new().route("/users/me/identities/ldap", post(link_handler))
+}
+
+/// The directory entry of a user whose password checked out.
+struct DirectoryUser {
+    dn: String,
+    username: String,
+    email: String,
+    display_name: Option<String>,
+    groups: Vec<String>,
+}
+
+/// Authenticates users against an LDAP or Active Directory server. Each
+/// login opens a short-lived connection: search for the entry, then bind as it.
+pub struct Ldap {
+    pub config: LdapConfig,
+}
+
+impl Ldap {
+    pub fn from_env() -> Option<Self> {
+        let config = LdapConfig::from_env()?;
+        info!("LDAP authentication enabled against {}", config.url);
+        Some(Self { config })
+    }
+
+    async fn connect(&self) -> Result<LdapClient, LdapError> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(TIMEOUT)
+            .set_starttls(self.config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+        ldap.with_timeout(TIMEOUT);
+        Ok(ldap)
+    }
+
+    /// Looks the login up and binds as the entry. `Ok(None)` means the
+    /// directory doesn't know the user, so a local account may still match.
+    async fn verify(&self, login: &str, password: &str) -> Result<Option<DirectoryUser>, LdapError> {
+        let config = &self.config;
+        let mut ldap = self.connect().await?;
+
+        if let (Some(bind_dn), Some(bind_password)) = (&config.bind_dn, &config.bind_password) {
+            ldap.simple_bind(bind_dn, bind_password).await?.success()?;
+        }
+
+        let filter = config.user_filter.replace("{login}", &ldap_escape(login));
+        let attrs = [
+            config.username_attr.as_str(),
+            config.email_attr.as_str(),
+            config.display_name_attr.as_str(),
+            config.group_attr.as_str(),
+        ];
+        let (entries, _) = ldap
+            .search(&config.base_dn, Scope::Subtree, &filter, attrs)
+            .await?
+            .success()?;
+
+        // Ambiguous matches are treated like unknown users rather than guessed at
+        if entries.len() != 1 {
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        }
+        let entry = SearchEntry::construct(entries.into_iter().next().expect("one entry"));
+
+        let mut groups: Vec<String> = entry
+            .attrs
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&config.group_attr))
+            .map(|(_, values)| values.iter().map(|group| group.to_lowercase()).collect())
+            .unwrap_or_default();
+        if let Some(group_filter) = &config.group_filter {
+            let filter = group_filter.replace("{dn}", &ldap_escape(entry.dn.as_str()));
+            let (group_entries, _) = ldap
+                .search(&config.group_base_dn, Scope::Subtree, &filter, ["1.1"])
+                .await?
+                .success()?;
+            groups.extend(
+                group_entries
+                    .into_iter()
+                    .map(|group| SearchEntry::construct(group).dn.to_lowercase()),
+            );
+        }
+
+        let bind = ldap.simple_bind(&entry.dn, password).await;
+        let _ = ldap.unbind().await;
+        bind?.success()?;
+
+        let first = |attr: &str| {
+            entry
+                .attrs
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(attr))
+                .and_then(|(_, values)| values.first().cloned())
+        };
+        let username = first(&config.username_attr).unwrap_or_else(|| login.to_string());
+        let Some(email) = first(&config.email_attr) else {
+            warn!("LDAP entry {} has no {} attribute", entry.dn, config.email_attr);
+            return Ok(None);
+        };
+        Ok(Some(DirectoryUser {
+            display_name: first(&config.display_name_attr),
+            dn: entry.dn,
+            username,
+            email,
+            groups,
+        }))
+    }
+}
+
+/// Checks the password against the directory and returns the matching local
+/// account, provisioning it on first login and syncing groups every time.
+pub async fn authenticate(
+    pool: &PgPool,
+    ldap: &Ldap,
+    client: &ClientInfo,
+    login: &str,
+    password: &str,
+) -> Result<Option<User>, AppError> {
+    // An empty password would be an anonymous bind, which always succeeds
+    if password.is_empty() {
+        return Err(AppError::Auth("Invalid credentials".to_string()));
+    }
+
+    // A wrong directory password still gets checked against the local account,
+    // and local accounts keep working while the directory is unreachable
+    let directory_user = match ldap.verify(login, password).await {
+        Ok(Some(directory_user)) => directory_user,
+        Ok(None) => return Ok(None),
+        Err(LdapError::LdapResult { result }) if result.rc == INVALID_CREDENTIALS => return Ok(None),
+        Err(e) => {
+            error!("LDAP authentication failed: {}", e);
+            return Ok(None);
+        }
+    };
+
+    let linked_user_id = sqlx::query_scalar::<_, Uuid>(
+        "SELECT user_id FROM user_identities WHERE provider = $1 AND subject = $2"
+    )
+    .bind(PROVIDER)
+    .bind(directory_user.dn.to_lowercase())
+    .fetch_optional(pool)
+    .await?;
+
+    let user = match linked_user_id {
+        Some(user_id) => {
+            sqlx::query("UPDATE user_identities SET last_login_at = NOW() WHERE provider = $1 AND subject = $2")
+                .bind(PROVIDER)
+                .bind(directory_user.dn.to_lowercase())
+                .execute(pool)
+                .await?;
+            get_user_by_id(pool, user_id)
+                .await?
+                .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?
+        }
+        None => provision_user(pool, client, &directory_user).await?,
+    };
+
+    sync_user(pool, ldap, client, &user, &directory_user).await?;
+
+    // Reload so the caller sees the synced role
+    get_user_by_id(pool, user.id).await
+}
+
+async fn provision_user(pool: &PgPool, client: &ClientInfo, directory_user: &DirectoryUser) -> Result<User, AppError> {
+    // Same rule as single sign-on: existing accounts are only linked by their owner
+    let email_taken = sqlx::query_scalar::<_, Uuid>("SELECT id FROM users WHERE LOWER(email) = LOWER($1)")
+        .bind(&directory_user.email)
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+    if email_taken {
+        return Err(AppError::Validation(
+            "An account with this email already exists. Sign in with your Konect password and link your directory account first".to_string(),
+        ));
+    }
+
+    let user = create_external_user(pool, &directory_user.username, &directory_user.email, true).await?;
+    link_identity(
+        pool,
+        user.id,
+        PROVIDER,
+        &directory_user.dn.to_lowercase(),
+        Some(&directory_user.email),
+    ).await?;
+
+    audit::record(
+        pool,
+        client,
+        AuditEvent::new("auth.register")
+            .actor(user.id)
+            .target("user", user.id)
+            .metadata(serde_json::json!({ "method": "ldap", "dn": directory_user.dn })),
+    ).await;
+
+    Ok(user)
+}
+
+/// Applies the directory's display name, admin group and room groups.
+async fn sync_user(
+    pool: &PgPool,
+    ldap: &Ldap,
+    client: &ClientInfo,
+    user: &User,
+    directory_user: &DirectoryUser,
+) -> Result<(), AppError> {
+    let config = &ldap.config;
+
+    sqlx::query("UPDATE users SET display_name = $2 WHERE id = $1 AND display_name IS DISTINCT FROM $2")
+        .bind(user.id)
+        .bind(&directory_user.display_name)
+        .execute(pool)
+        .await?;
+
+    // Admins listed in ADMIN_EMAILS keep the role regardless of groups
+    let mut role_change = None;
+    if let Some(admin_group) = &config.admin_group {
+        let in_group = directory_user.groups.contains(admin_group);
+        let bootstrap_admin = user.role == ROLE_ADMIN && is_bootstrap_admin(&user.email);
+        let role = if in_group || bootstrap_admin { ROLE_ADMIN } else { ROLE_USER };
+        if user.role != role {
+            sqlx::query("UPDATE users SET role = $2, updated_at = NOW() WHERE id = $1")
+                .bind(user.id)
+                .bind(role)
+                .execute(pool)
+                .await?;
+            role_change = Some(role);
+        }
+    }
+
+    let mut joined_rooms = Vec::new();
+    for (group, room_id) in &config.group_rooms {
+        if !directory_user.groups.contains(group) || is_banned(pool, *room_id, user.id).await? {
+            continue;
+        }
+        match join_room(pool, *room_id, user.id).await {
+            Ok(true) => {
+                outgoing_webhooks::dispatch_member(pool, *room_id, EVENT_MEMBER_JOINED, user.id, None).await;
+                joined_rooms.push(*room_id);
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Could not add {} to room {} from LDAP group: {}", user.username, room_id, e),
+        }
+    }
+
+    if role_change.is_some() || !joined_rooms.is_empty() {
+        audit::record(
+            pool,
+            client,
+            AuditEvent::new("auth.ldap_sync")
+                .actor(user.id)
+                .target("user", user.id)
+                .metadata(serde_json::json!({ "role": role_change, "joined_rooms": joined_rooms })),
+        ).await;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct LinkRequest {
+    login: String,
+    password: String,
+}
+
+/// Links the signed-in account to a directory entry, proven by its password.
+async fn link_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<LinkRequest>,
+) -> Result<Json<UserIdentity>, AppError> {
+    let ldap = state
+        .ldap
+        .as_deref()
+        .ok_or_else(|| AppError::NotFound("LDAP authentication is not configured".to_string()))?;
+    let user_id = claims.user_id()?;
+
+    if req.password.is_empty() {
+        return Err(AppError::Auth("Invalid directory credentials".to_string()));
+    }
+    let directory_user = match ldap.verify(&req.login, &req.password).await {
+        Ok(Some(directory_user)) => directory_user,
+        Ok(None) | Err(LdapError::LdapResult { .. }) => {
+            return Err(AppError::Auth("Invalid directory credentials".to_string()));
+        }
+        Err(e) => {
+            error!("LDAP link failed: {}", e);
+            return Err(AppError::InternalError("Directory server unavailable".to_string()));
+        }
+    };
+
+    let identity = link_identity(
+        &state.db,
+        user_id,
+        PROVIDER,
+        &directory_user.dn.to_lowercase(),
+        Some(&directory_user.email),
+    ).await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.identity_linked")
+            .actor(user_id)
+            .target("user", user_id)
+            .metadata(serde_json::json!({ "provider": identity.provider, "subject": identity.subject })),
+    ).await;
+
+    Ok(Json(identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat::create_room, test_support};
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    const SERVICE_DN: &str = "cn=admin,dc=example,dc=org";
+    const SERVICE_PASSWORD: &str = "adminpassword";
+    const ADMINS: &str = "cn=admins,ou=groups,dc=example,dc=org";
+    const ENGINEERS: &str = "cn=eng,ou=groups,dc=example,dc=org";
+
+    struct Entry {
+        dn: String,
+        password: String,
+        attrs: Vec<(String, Vec<String>)>,
+    }
+
+    type Directory = Arc<Mutex<Vec<Entry>>>;
+
+    fn person(uid: &str, groups: &[&str]) -> Entry {
+        Entry {
+            dn: format!("uid={},ou=people,dc=example,dc=org", uid),
+            password: format!("{}-secret", uid),
+            attrs: vec![
+                ("uid".to_string(), vec![uid.to_string()]),
+                ("mail".to_string(), vec![format!("{}@example.org", uid)]),
+                ("cn".to_string(), vec![format!("{} Example", uid)]),
+                ("memberOf".to_string(), groups.iter().map(|group| group.to_string()).collect()),
+            ],
+        }
+    }
+
+    /// BER tag-length-value.
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        if content.len() < 0x80 {
+            out.push(content.len() as u8);
+        } else {
+            let length = (content.len() as u32).to_be_bytes();
+            let skip = length.iter().take_while(|byte| **byte == 0).count();
+            out.push(0x80 | (4 - skip) as u8);
+            out.extend_from_slice(&length[skip..]);
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    fn integer(tag: u8, value: i64) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let mut start = 0;
+        while start < 7 && ((bytes[start] == 0 && bytes[start + 1] < 0x80) || (bytes[start] == 0xFF && bytes[start + 1] >= 0x80)) {
+            start += 1;
+        }
+        tlv(tag, &bytes[start..])
+    }
+
+    /// Splits BER content into its elements.
+    fn elements(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut out = Vec::new();
+        while data.len() >= 2 {
+            let (length, header) = match data[1] {
+                short if short < 0x80 => (short as usize, 2),
+                long => {
+                    let count = (long & 0x7F) as usize;
+                    (data[2..2 + count].iter().fold(0, |n, byte| n << 8 | *byte as usize), 2 + count)
+                }
+            };
+            out.push((data[0], &data[header..header + length]));
+            data = &data[header + length..];
+        }
+        out
+    }
+
+    fn text(data: &[u8]) -> String {
+        String::from_utf8_lossy(data).to_lowercase()
+    }
+
+    fn matches(entry: &Entry, tag: u8, filter: &[u8]) -> bool {
+        let values = |attr: &str| entry.attrs.iter().find(|(name, _)| name.eq_ignore_ascii_case(attr)).map(|(_, values)| values);
+        match tag {
+            0xA0 => elements(filter).iter().all(|(tag, inner)| matches(entry, *tag, inner)),
+            0xA1 => elements(filter).iter().any(|(tag, inner)| matches(entry, *tag, inner)),
+            0xA2 => elements(filter).iter().all(|(tag, inner)| !matches(entry, *tag, inner)),
+            0xA3 => {
+                let parts = elements(filter);
+                values(&text(parts[0].1)).is_some_and(|values| values.iter().any(|value| value.to_lowercase() == text(parts[1].1)))
+            }
+            0x87 => text(filter) == "objectclass" || values(&text(filter)).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Result of a bind or search: `[APPLICATION tag] { code, matchedDN, message }`.
+    fn result(message_id: i64, tag: u8, code: i64) -> Vec<u8> {
+        let body = [integer(0x0A, code), tlv(0x04, b""), tlv(0x04, b"")].concat();
+        tlv(0x30, &[integer(0x02, message_id), tlv(tag, &body)].concat())
+    }
+
+    fn respond(directory: &Directory, message: &[u8]) -> Option<Vec<u8>> {
+        let parts = elements(message);
+        let message_id = parts[0].1.iter().fold(0i64, |n, byte| n << 8 | *byte as i64);
+        let (op, request) = parts[1];
+        let fields = elements(request);
+        let entries = directory.lock().unwrap();
+        match op {
+            // Bind: anonymous, or an entry's own DN and password
+            0x60 => {
+                let (dn, password) = (text(fields[1].1), String::from_utf8_lossy(fields[2].1).to_string());
+                let known = (dn.is_empty() && password.is_empty())
+                    || (dn == SERVICE_DN && password == SERVICE_PASSWORD)
+                    || entries.iter().any(|entry| entry.dn.to_lowercase() == dn && entry.password == password);
+                Some(result(message_id, 0x61, if known { 0 } else { INVALID_CREDENTIALS as i64 }))
+            }
+            // Search: every attribute of the entries under the base that match
+            0x63 => {
+                let base = text(fields[0].1);
+                let (filter_tag, filter) = fields[6];
+                let mut out = Vec::new();
+                for entry in entries.iter() {
+                    if !entry.dn.to_lowercase().ends_with(&base) || !matches(entry, filter_tag, filter) {
+                        continue;
+                    }
+                    let attrs: Vec<u8> = entry
+                        .attrs
+                        .iter()
+                        .map(|(name, values)| {
+                            let values: Vec<u8> = values.iter().flat_map(|value| tlv(0x04, value.as_bytes())).collect();
+                            tlv(0x30, &[tlv(0x04, name.as_bytes()), tlv(0x31, &values)].concat())
+                        })
+                        .collect::<Vec<_>>()
+                        .concat();
+                    let body = [tlv(0x04, entry.dn.as_bytes()), tlv(0x30, &attrs)].concat();
+                    out.extend(tlv(0x30, &[integer(0x02, message_id), tlv(0x64, &body)].concat()));
+                }
+                out.extend(result(message_id, 0x65, 0));
+                Some(out)
+            }
+            // Unbind, or anything else: hang up
+            _ => None,
+        }
+    }
+
+    async fn read_message(socket: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut header = [0u8; 2];
+        socket.read_exact(&mut header).await.ok()?;
+        let length = if header[1] < 0x80 {
+            header[1] as usize
+        } else {
+            let mut length = vec![0u8; (header[1] & 0x7F) as usize];
+            socket.read_exact(&mut length).await.ok()?;
+            length.iter().fold(0, |n, byte| n << 8 | *byte as usize)
+        };
+        let mut content = vec![0u8; length];
+        socket.read_exact(&mut content).await.ok()?;
+        Some(content)
+    }
+
+    /// A directory server speaking just enough LDAP for binds and searches.
+    async fn start_directory(entries: Vec<Entry>) -> (Directory, String) {
+        let directory = Arc::new(Mutex::new(entries));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let url = format!("ldap://{}", listener.local_addr().expect("address"));
+        let served = Arc::clone(&directory);
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let directory = Arc::clone(&served);
+                tokio::spawn(async move {
+                    while let Some(message) = read_message(&mut socket).await {
+                        let Some(response) = respond(&directory, &message) else { break };
+                        if socket.write_all(&response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (directory, url)
+    }
+
+    fn config(url: String) -> LdapConfig {
+        LdapConfig {
+            url,
+            starttls: false,
+            bind_dn: Some(SERVICE_DN.to_string()),
+            bind_password: Some(SERVICE_PASSWORD.to_string()),
+            base_dn: "ou=people,dc=example,dc=org".to_string(),
+            user_filter: "(|(uid={login})(mail={login}))".to_string(),
+            username_attr: "uid".to_string(),
+            email_attr: "mail".to_string(),
+            display_name_attr: "cn".to_string(),
+            group_attr: "memberOf".to_string(),
+            group_filter: None,
+            group_base_dn: "ou=groups,dc=example,dc=org".to_string(),
+            admin_group: Some(ADMINS.to_string()),
+            group_rooms: Vec::new(),
+        }
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo { ip: None, user_agent: None }
+    }
+
+    async fn user_count(pool: &PgPool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(pool).await.unwrap()
+    }
+
+    #[sqlx::test]
+    async fn provisions_directory_users_on_first_login(pool: PgPool) {
+        let (_, url) = start_directory(vec![person("alice", &[])]).await;
+        let ldap = Ldap { config: config(url) };
+
+        let user = authenticate(&pool, &ldap, &client(), "alice@example.org", "alice-secret")
+            .await
+            .unwrap()
+            .expect("directory user");
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.email, "alice@example.org");
+        assert_eq!(user.display_name.as_deref(), Some("alice Example"));
+        assert!(user.password_hash.is_none());
+        assert!(user.email_verified_at.is_some());
+
+        // The next login, by uid this time, finds the same account through the linked DN
+        let again = authenticate(&pool, &ldap, &client(), "alice", "alice-secret").await.unwrap().unwrap();
+        assert_eq!(again.id, user.id);
+        assert_eq!(user_count(&pool).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn failed_binds_fall_back_to_local_accounts(pool: PgPool) {
+        let (_, url) = start_directory(vec![person("alice", &[])]).await;
+
+        // Wrong user password
+        let ldap = Ldap { config: config(url.clone()) };
+        let result = authenticate(&pool, &ldap, &client(), "alice", "wrong").await.unwrap();
+        assert!(result.is_none());
+
+        // Unknown user
+        let result = authenticate(&pool, &ldap, &client(), "mallory", "alice-secret").await.unwrap();
+        assert!(result.is_none());
+
+        // Wrong service account password
+        let mut broken = config(url);
+        broken.bind_password = Some("wrong".to_string());
+        let ldap = Ldap { config: broken };
+        let result = authenticate(&pool, &ldap, &client(), "alice", "alice-secret").await.unwrap();
+        assert!(result.is_none());
+
+        // Empty passwords would be anonymous binds
+        let result = authenticate(&pool, &ldap, &client(), "alice", "").await;
+        assert!(matches!(result, Err(AppError::Auth(_))));
+
+        assert_eq!(user_count(&pool).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn unreachable_directory_falls_back_to_local_accounts(pool: PgPool) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let ldap = Ldap { config: config(url) };
+        let result = authenticate(&pool, &ldap, &client(), "alice", "alice-secret").await.unwrap();
+        assert!(result.is_none());
+    }
+
+    #[sqlx::test]
+    async fn admin_group_grants_and_revokes_the_role(pool: PgPool) {
+        let (directory, url) = start_directory(vec![person("alice", &[ADMINS, ENGINEERS]), person("bob", &[ENGINEERS])]).await;
+        let ldap = Ldap { config: config(url) };
+
+        let alice = authenticate(&pool, &ldap, &client(), "alice", "alice-secret").await.unwrap().unwrap();
+        let bob = authenticate(&pool, &ldap, &client(), "bob", "bob-secret").await.unwrap().unwrap();
+        assert_eq!(alice.role, ROLE_ADMIN);
+        assert_eq!(bob.role, ROLE_USER);
+
+        // Leaving the group costs the role at the next login
+        directory.lock().unwrap()[0].attrs[3].1.retain(|group| group != ADMINS);
+        let alice = authenticate(&pool, &ldap, &client(), "alice", "alice-secret").await.unwrap().unwrap();
+        assert_eq!(alice.role, ROLE_USER);
+    }
+
+    #[sqlx::test]
+    async fn group_members_join_mapped_rooms(pool: PgPool) {
+        let (_, url) = start_directory(vec![person("alice", &[ENGINEERS]), person("bob", &[])]).await;
+        let owner = test_support::create_user(&pool, "owner").await;
+        let room = create_room(&pool, "engineering", None, true, owner.id).await.unwrap();
+        let mut config = config(url);
+        config.group_rooms = vec![(ENGINEERS.to_string(), room.id)];
+        let ldap = Ldap { config };
+
+        let alice = authenticate(&pool, &ldap, &client(), "alice", "alice-secret").await.unwrap().unwrap();
+        let bob = authenticate(&pool, &ldap, &client(), "bob", "bob-secret").await.unwrap().unwrap();
+
+        let members: Vec<Uuid> = sqlx::query_scalar("SELECT user_id FROM room_members WHERE room_id = $1")
+            .bind(room.id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(members.contains(&alice.id));
+        assert!(!members.contains(&bob.id));
+    }
+}
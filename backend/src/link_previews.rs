@@ -0,0 +1,535 @@
+use crate::{
+    egress::Egress,
+    error::AppError,
+    models::*,
+    websocket::broadcast_to_room,
+    AppState,
+};
+use anyhow::{bail, Context};
+use reqwest::{header, Url};
+use scraper::{Html, Selector};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+/// Per request, including reading the body.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// For everything needed to preview one link: redirects and oEmbed included.
+const PREVIEW_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pages are cut off here; the metadata is in the `<head>`.
+const MAX_BODY_BYTES: usize = 512 * 1024;
+const MAX_REDIRECTS: usize = 3;
+const MAX_PREVIEWS_PER_MESSAGE: usize = 3;
+const MAX_CONCURRENT_MESSAGES: usize = 8;
+const CACHE_HOURS: i32 = 24;
+/// Links that couldn't be previewed are retried sooner.
+const FAILED_CACHE_HOURS: i32 = 1;
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 300;
+const USER_AGENT: &str = "rust-konect-link-previews (+https://github.com/0xTnxl/rust-konect)";
+
+static LINKS: LazyLock<Selector> = LazyLock::new(|| Selector::parse("a[href]").unwrap());
+static META: LazyLock<Selector> = LazyLock::new(|| Selector::parse("meta[content]").unwrap());
+static TITLE: LazyLock<Selector> = LazyLock::new(|| Selector::parse("title").unwrap());
+static OEMBED: LazyLock<Selector> =
+    LazyLock::new(|| Selector::parse(r#"link[rel~="alternate"][type="application/json+oembed"][href]"#).unwrap());
+
+/// Unfurls links in messages. Pages are fetched through `Egress`, so
+/// neither links nor redirects reach internal hosts; no cookies, credentials
+/// or proxies are involved.
+pub struct LinkPreviews {
+    egress: Egress,
+    fetches: Semaphore,
+}
+
+impl LinkPreviews {
+    /// Enabled unless `LINK_PREVIEWS_ENABLED=false`. Internal hosts can be
+    /// allowed with `LINK_PREVIEW_ALLOWED_HOSTS`, a comma-separated list of
+    /// host names, addresses and CIDR ranges.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        if env("LINK_PREVIEWS_ENABLED").is_some_and(|value| value == "false" || value == "0") {
+            info!("Link previews disabled");
+            return Ok(None);
+        }
+
+        let egress = Egress::from_env("LINK_PREVIEW_ALLOWED_HOSTS")?.web_ports_only();
+        if egress.allows_internal_hosts() {
+            info!("Link previews may fetch from internal hosts: {}", env("LINK_PREVIEW_ALLOWED_HOSTS").unwrap_or_default());
+        }
+
+        Ok(Some(Self::new(egress)))
+    }
+
+    fn new(egress: Egress) -> Self {
+        Self {
+            egress,
+            fetches: Semaphore::new(MAX_CONCURRENT_MESSAGES),
+        }
+    }
+
+    /// GETs `url`, following redirects that pass the same checks, and reads
+    /// at most `MAX_BODY_BYTES` of the body.
+    async fn fetch(&self, url: &Url, accept: &str) -> anyhow::Result<Fetched> {
+        let mut url = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let http = self
+                .egress
+                .client(reqwest::Client::builder().timeout(FETCH_TIMEOUT).user_agent(USER_AGENT), &url)
+                .await?;
+            let mut response = http.get(url.clone()).header(header::ACCEPT, accept).send().await?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .context("redirect without a location")?;
+                url = url.join(location)?;
+                continue;
+            }
+            if !response.status().is_success() {
+                bail!("status {}", response.status());
+            }
+
+            let content_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                let room = MAX_BODY_BYTES - body.len();
+                body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+                if body.len() == MAX_BODY_BYTES {
+                    break;
+                }
+            }
+            return Ok(Fetched { url, content_type, body });
+        }
+        bail!("too many redirects")
+    }
+
+    /// OpenGraph metadata from the page, with gaps filled from its oEmbed
+    /// endpoint if it has one.
+    async fn preview(&self, link: &str) -> anyhow::Result<Option<LinkPreview>> {
+        let page = self.fetch(&Url::parse(link)?, "text/html,application/xhtml+xml").await?;
+        if !page.content_type.starts_with("text/html") && !page.content_type.starts_with("application/xhtml+xml") {
+            return Ok(None);
+        }
+        let (mut preview, oembed) = parse_page(link, &page.url, &String::from_utf8_lossy(&page.body));
+
+        if let Some(oembed) = oembed.filter(|_| preview.title.is_none() || preview.image_url.is_none()) {
+            match self.fetch(&oembed, "application/json").await {
+                Ok(response) => match serde_json::from_slice::<OEmbed>(&response.body) {
+                    Ok(oembed) => {
+                        preview.title = preview.title.or(oembed.title.as_deref().and_then(|t| clean(t, MAX_TITLE_CHARS)));
+                        preview.site_name = preview.site_name.or(oembed.provider_name.as_deref().and_then(|s| clean(s, MAX_TITLE_CHARS)));
+                        preview.image_url = preview.image_url.or(oembed.thumbnail_url.as_deref().and_then(|i| image_url(&response.url, i)));
+                    }
+                    Err(e) => debug!("Invalid oEmbed response for {}: {}", link, e),
+                },
+                Err(e) => debug!("Failed to fetch oEmbed for {}: {}", link, e),
+            }
+        }
+
+        Ok((preview.title.is_some() || preview.description.is_some()).then_some(preview))
+    }
+}
+
+struct Fetched {
+    /// Where the response came from, after redirects.
+    url: Url,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct OEmbed {
+    title: Option<String>,
+    provider_name: Option<String>,
+    thumbnail_url: Option<String>,
+}
+
+/// The preview for `link` from a page fetched at `url`, and the page's oEmbed
+/// endpoint.
+fn parse_page(link: &str, url: &Url, html: &str) -> (LinkPreview, Option<Url>) {
+    let document = Html::parse_document(html);
+    let mut meta = HashMap::new();
+    for element in document.select(&META) {
+        let element = element.value();
+        let Some(key) = element.attr("property").or(element.attr("name")) else {
+            continue;
+        };
+        // The first of duplicate tags wins
+        meta.entry(key.to_ascii_lowercase()).or_insert(element.attr("content").unwrap_or_default());
+    }
+    let first = |keys: &[&str]| keys.iter().find_map(|key| meta.get(*key).copied());
+
+    let title = first(&["og:title", "twitter:title"])
+        .map(str::to_string)
+        .or_else(|| document.select(&TITLE).next().map(|title| title.text().collect()));
+    let preview = LinkPreview {
+        url: link.to_string(),
+        site_name: first(&["og:site_name"]).and_then(|site| clean(site, MAX_TITLE_CHARS)),
+        title: title.as_deref().and_then(|title| clean(title, MAX_TITLE_CHARS)),
+        description: first(&["og:description", "twitter:description", "description"])
+            .and_then(|description| clean(description, MAX_DESCRIPTION_CHARS)),
+        image_url: first(&["og:image:secure_url", "og:image", "twitter:image"]).and_then(|image| image_url(url, image)),
+    };
+    let oembed = document
+        .select(&OEMBED)
+        .next()
+        .and_then(|link| url.join(link.value().attr("href")?).ok());
+
+    (preview, oembed)
+}
+
+/// Collapses whitespace and shortens `text`; `None` if nothing is left.
+fn clean(text: &str, max_chars: usize) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return None;
+    }
+    if text.chars().count() <= max_chars {
+        return Some(text);
+    }
+    let mut short: String = text.chars().take(max_chars - 1).collect();
+    short.push('…');
+    Some(short)
+}
+
+/// Images are loaded by clients, so only absolute web URLs are kept.
+fn image_url(base: &Url, image: &str) -> Option<String> {
+    let image = base.join(image.trim()).ok()?;
+    matches!(image.scheme(), "http" | "https").then(|| image.to_string())
+}
+
+/// The distinct web links in rendered message HTML, in order.
+fn links(content_html: &str) -> Vec<String> {
+    let fragment = Html::parse_fragment(content_html);
+    let mut links: Vec<String> = Vec::new();
+    for link in fragment.select(&LINKS) {
+        let Some(href) = link.value().attr("href") else {
+            continue;
+        };
+        if (href.starts_with("https://") || href.starts_with("http://")) && !links.iter().any(|l| l == href) {
+            links.push(href.to_string());
+        }
+        if links.len() == MAX_PREVIEWS_PER_MESSAGE {
+            break;
+        }
+    }
+    links
+}
+
+/// The links in a message and what the cache knows about them. A cached
+/// `None` means the link has nothing to preview.
+#[derive(Default)]
+pub struct Lookup {
+    links: Vec<String>,
+    cached: HashMap<String, Option<LinkPreview>>,
+}
+
+impl Lookup {
+    /// Previews for the links that are cached, in message order.
+    pub fn previews(&self) -> Vec<LinkPreview> {
+        self.links
+            .iter()
+            .filter_map(|link| self.cached.get(link).cloned().flatten())
+            .collect()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.links.iter().all(|link| self.cached.contains_key(link))
+    }
+}
+
+/// Finds the links in a message about to be stored, and the previews that
+/// are already cached so they can be stored with it.
+pub async fn lookup(state: &AppState, content_html: Option<&str>) -> Result<Lookup, AppError> {
+    let Some(content_html) = content_html.filter(|_| state.link_previews.is_some()) else {
+        return Ok(Lookup::default());
+    };
+    let links = links(content_html);
+    if links.is_empty() {
+        return Ok(Lookup::default());
+    }
+
+    let cached = sqlx::query_as::<_, (String, Option<sqlx::types::Json<LinkPreview>>)>(
+        r#"
+        SELECT url, preview FROM link_previews
+        WHERE url = ANY($1)
+          AND fetched_at > NOW() - make_interval(hours => CASE WHEN preview IS NULL THEN $2 ELSE $3 END)
+        "#
+    )
+    .bind(&links)
+    .bind(FAILED_CACHE_HOURS)
+    .bind(CACHE_HOURS)
+    .fetch_all(&state.db)
+    .await?
+    .into_iter()
+    .map(|(url, preview)| (url, preview.map(|preview| preview.0)))
+    .collect();
+
+    Ok(Lookup { links, cached })
+}
+
+/// Fetches the previews `lookup` didn't find in the background, then stores
+/// them with the message and sends the room a `message_updated` event.
+pub fn unfurl(state: &AppState, message: &Message, lookup: Lookup) {
+    let Some(link_previews) = state.link_previews.clone() else {
+        return;
+    };
+    if lookup.is_complete() {
+        return;
+    }
+    let state = state.clone();
+    let message = message.clone();
+    tokio::spawn(async move {
+        if let Err(e) = attach(&state, &link_previews, &message, lookup).await {
+            warn!("Failed to attach link previews to message {}: {}", message.id, e);
+        }
+    });
+}
+
+async fn attach(
+    state: &AppState,
+    link_previews: &Arc<LinkPreviews>,
+    message: &Message,
+    mut lookup: Lookup,
+) -> Result<(), AppError> {
+    let _permit = link_previews.fetches.acquire().await.map_err(|e| AppError::InternalError(e.to_string()))?;
+
+    let missing: Vec<String> = lookup.links.iter().filter(|link| !lookup.cached.contains_key(*link)).cloned().collect();
+    for link in missing {
+        let preview = match tokio::time::timeout(PREVIEW_TIMEOUT, link_previews.preview(&link)).await {
+            Ok(Ok(preview)) => preview,
+            Ok(Err(e)) => {
+                debug!("No preview for {}: {}", link, e);
+                None
+            }
+            Err(_) => {
+                debug!("No preview for {}: timed out", link);
+                None
+            }
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO link_previews (url, preview, fetched_at) VALUES ($1, $2, NOW())
+            ON CONFLICT (url) DO UPDATE SET preview = $2, fetched_at = NOW()
+            "#
+        )
+        .bind(&link)
+        .bind(preview.as_ref().map(sqlx::types::Json))
+        .execute(&state.db)
+        .await?;
+        lookup.cached.insert(link, preview);
+    }
+
+    let previews = lookup.previews();
+    if previews.len() == message.link_previews.len() {
+        return Ok(());
+    }
+
+    // Skipped if the message was edited or deleted in the meantime
+    let updated = sqlx::query_as::<_, Message>(
+        r#"
+        UPDATE messages SET link_previews = $3
+        WHERE id = $1 AND content = $2
+        RETURNING *
+        "#
+    )
+    .bind(message.id)
+    .bind(&message.content)
+    .bind(sqlx::types::Json(&previews))
+    .fetch_optional(&state.db)
+    .await?;
+    if let Some(updated) = updated {
+        broadcast_to_room(state, updated.room_id, &WebSocketMessage::new("message_updated", &updated)).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat::{create_room, send_message},
+        test_support,
+    };
+    use axum::{
+        http::StatusCode,
+        response::{Html as HtmlResponse, IntoResponse, Redirect},
+        routing::get,
+        Json, Router,
+    };
+    use sqlx::PgPool;
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    const PAGE: &str = r#"<html><head>
+        <title>Fallback title</title>
+        <meta property="og:title" content="Release notes">
+        <meta property="og:description" content="  What's new
+            this week  ">
+        <meta property="og:site_name" content="Example">
+        <meta property="og:image" content="/cover.png">
+        </head><body>Hello</body></html>"#;
+
+    /// Fixture pages, and how often `/page` was fetched.
+    async fn site() -> (SocketAddr, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let page_hits = Arc::clone(&hits);
+        let router = Router::new()
+            .route(
+                "/page",
+                get(move || async move {
+                    page_hits.fetch_add(1, Ordering::SeqCst);
+                    HtmlResponse(PAGE)
+                }),
+            )
+            .route("/redirect", get(|| async { Redirect::temporary("/page") }))
+            .route("/metadata", get(|| async { Redirect::temporary("http://169.254.169.254/latest/meta-data") }))
+            .route("/loopback", get(|| async { Redirect::temporary("http://[::ffff:7f00:2]/page") }))
+            .route("/loop", get(|| async { Redirect::temporary("/loop") }))
+            .route(
+                "/large",
+                get(|| async {
+                    let padding = "x".repeat(MAX_BODY_BYTES * 2);
+                    HtmlResponse(format!(r#"<html><head><title>Large</title></head><body>{}</body></html>"#, padding))
+                }),
+            )
+            .route(
+                "/video",
+                get(|| async {
+                    HtmlResponse(r#"<html><head><title>A video</title>
+                        <link rel="alternate" type="application/json+oembed" href="/oembed.json"></head></html>"#)
+                }),
+            )
+            .route(
+                "/oembed.json",
+                get(|| async {
+                    Json(serde_json::json!({ "provider_name": "Tube", "thumbnail_url": "https://cdn.example/thumb.jpg" }))
+                }),
+            )
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND.into_response() }));
+        (test_support::serve(router).await, hits)
+    }
+
+    fn previews(allowed_hosts: &str) -> LinkPreviews {
+        LinkPreviews::new(Egress::allowing(allowed_hosts).unwrap().web_ports_only())
+    }
+
+    #[tokio::test]
+    async fn pages_are_previewed_from_opengraph_tags() {
+        let (address, _) = site().await;
+        let link = format!("http://{}/page", address);
+
+        let preview = previews("127.0.0.1").preview(&link).await.unwrap().expect("a preview");
+
+        assert_eq!(preview.url, link);
+        assert_eq!(preview.title.as_deref(), Some("Release notes"));
+        assert_eq!(preview.description.as_deref(), Some("What's new this week"));
+        assert_eq!(preview.site_name.as_deref(), Some("Example"));
+        assert_eq!(preview.image_url, Some(format!("http://{}/cover.png", address)));
+    }
+
+    #[tokio::test]
+    async fn oembed_fills_in_missing_metadata() {
+        let (address, _) = site().await;
+
+        let preview = previews("127.0.0.1").preview(&format!("http://{}/video", address)).await.unwrap().unwrap();
+
+        assert_eq!(preview.title.as_deref(), Some("A video"));
+        assert_eq!(preview.site_name.as_deref(), Some("Tube"));
+        assert_eq!(preview.image_url.as_deref(), Some("https://cdn.example/thumb.jpg"));
+    }
+
+    #[tokio::test]
+    async fn internal_hosts_are_not_fetched() {
+        let (address, hits) = site().await;
+        let link = format!("http://{}/page", address);
+
+        let error = previews("").preview(&link).await.unwrap_err();
+
+        assert!(error.to_string().contains("blocked address"), "{}", error);
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+        for link in ["http://localhost/", "http://10.0.0.1/", "http://[::1]/", "http://[::ffff:a9fe:a9fe]/"] {
+            assert!(previews("").preview(link).await.is_err(), "{} was fetched", link);
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects_are_checked_like_links() {
+        let (address, hits) = site().await;
+        let previews = previews("127.0.0.1");
+
+        let preview = previews.preview(&format!("http://{}/redirect", address)).await.unwrap().unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Release notes"));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        for path in ["metadata", "loopback"] {
+            let error = previews.preview(&format!("http://{}/{}", address, path)).await.unwrap_err();
+            assert!(error.to_string().contains("blocked address"), "{}: {}", path, error);
+        }
+        let error = previews.preview(&format!("http://{}/loop", address)).await.unwrap_err();
+        assert_eq!(error.to_string(), "too many redirects");
+    }
+
+    #[tokio::test]
+    async fn bodies_are_capped() {
+        let (address, _) = site().await;
+        let url = Url::parse(&format!("http://{}/large", address)).unwrap();
+
+        let page = previews("127.0.0.1").fetch(&url, "text/html").await.unwrap();
+
+        assert_eq!(page.body.len(), MAX_BODY_BYTES);
+        assert!(previews("127.0.0.1").fetch(&url.join("/missing").unwrap(), "text/html").await.is_err());
+    }
+
+    async fn stored_previews(pool: &PgPool, message_id: uuid::Uuid) -> Vec<LinkPreview> {
+        for _ in 0..50 {
+            let previews: sqlx::types::Json<Vec<LinkPreview>> =
+                sqlx::query_scalar("SELECT link_previews FROM messages WHERE id = $1")
+                    .bind(message_id)
+                    .fetch_one(pool)
+                    .await
+                    .unwrap();
+            if !previews.0.is_empty() {
+                return previews.0;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Vec::new()
+    }
+
+    #[sqlx::test]
+    async fn cached_previews_are_reused(pool: PgPool) {
+        let (address, hits) = site().await;
+        let mut state = test_support::state(pool.clone());
+        state.link_previews = Some(Arc::new(previews("127.0.0.1")));
+        let user = test_support::create_user(&pool, "alice").await;
+        let room = create_room(&pool, "general", None, false, user.id).await.unwrap();
+        let content = format!("Have a look: http://{}/page", address);
+
+        let first = send_message(&state, room.id, user.id, &content, "text").await.unwrap();
+        assert!(first.link_previews.is_empty(), "fetched in the background");
+        let stored = stored_previews(&pool, first.id).await;
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].title.as_deref(), Some("Release notes"));
+
+        let second = send_message(&state, room.id, user.id, &content, "text").await.unwrap();
+        assert_eq!(second.link_previews.len(), 1, "stored with the message from the cache");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::error::AppError;
+use lettre::{
+    message::Mailbox,
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::{debug, info, warn};
+
+/// An email template: the first line of the file is the subject, the rest is
+/// the plain-text body. `{{name}}` placeholders are filled in by `render`.
+pub struct Template {
+    source: &'static str,
+    /// The body carries a single-use token, so it's never logged.
+    secret: bool,
+}
+
+pub const VERIFY_EMAIL: Template = Template::secret(include_str!("../templates/email/verify_email.txt"));
+pub const PASSWORD_RESET: Template = Template::secret(include_str!("../templates/email/password_reset.txt"));
+pub const EMAIL_CHANGE: Template = Template::secret(include_str!("../templates/email/email_change.txt"));
+pub const EMAIL_CHANGED: Template = Template::new(include_str!("../templates/email/email_changed.txt"));
+pub const DIGEST: Template = Template::new(include_str!("../templates/email/digest.txt"));
+
+impl Template {
+    const fn new(source: &'static str) -> Self {
+        Self { source, secret: false }
+    }
+
+    const fn secret(source: &'static str) -> Self {
+        Self { source, secret: true }
+    }
+
+    pub fn render(&self, vars: &[(&str, &str)]) -> (String, String) {
+        let (subject, body) = self.source.split_once('\n').unwrap_or((self.source, ""));
+        let mut subject = subject.trim().to_string();
+        let mut body = body.trim_start_matches('\n').to_string();
+        for (name, value) in vars {
+            let placeholder = format!("{{{{{}}}}}", name);
+            subject = subject.replace(&placeholder, value);
+            body = body.replace(&placeholder, value);
+        }
+        (subject, body)
+    }
+}
+
+/// Outbound mail over SMTP. Without `SMTP_HOST`, emails are only logged at
+/// debug level, minus the bodies of emails that carry tokens, which keeps
+/// local development working without a mail server.
+pub struct Mailer {
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Mailbox,
+    /// Public URL of the web app, used to build links in emails.
+    pub base_url: String,
+}
+
+impl Mailer {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let from = std::env::var("MAIL_FROM")
+            .unwrap_or_else(|_| "Rust Konect <noreply@localhost>".to_string())
+            .parse::<Mailbox>()?;
+        let base_url = std::env::var("PUBLIC_BASE_URL")
+            .unwrap_or_else(|_| "http://localhost:3000".to_string())
+            .trim_end_matches('/')
+            .to_string();
+
+        let transport = match std::env::var("SMTP_HOST") {
+            Ok(host) => {
+                // "none" for local catchers like MailHog/mailpit, "starttls" or "tls" otherwise
+                let security = std::env::var("SMTP_SECURITY").unwrap_or_else(|_| "none".to_string());
+                let mut builder = match security.as_str() {
+                    "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+                    "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+                    _ => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+                };
+                if let Some(port) = std::env::var("SMTP_PORT").ok().and_then(|port| port.parse().ok()) {
+                    builder = builder.port(port);
+                }
+                let username = std::env::var("SMTP_USERNAME").ok().filter(|value| !value.is_empty());
+                let password = std::env::var("SMTP_PASSWORD").ok().filter(|value| !value.is_empty());
+                if let (Some(username), Some(password)) = (username, password) {
+                    builder = builder.credentials(Credentials::new(username, password));
+                }
+                info!("Sending email through SMTP server {}", host);
+                Some(builder.build())
+            }
+            Err(_) => {
+                warn!("SMTP_HOST not set, emails will not be sent");
+                None
+            }
+        };
+
+        Ok(Self {
+            transport,
+            from,
+            base_url,
+        })
+    }
+
+    /// A mailer that never sends, for tests.
+    #[cfg(test)]
+    pub fn disabled(base_url: &str) -> Self {
+        Self {
+            transport: None,
+            from: "Rust Konect <noreply@localhost>".parse().expect("valid mailbox"),
+            base_url: base_url.to_string(),
+        }
+    }
+
+    pub async fn send(&self, to: &str, template: &Template, vars: &[(&str, &str)]) -> Result<(), AppError> {
+        let (subject, body) = template.render(vars);
+
+        let Some(transport) = &self.transport else {
+            if template.secret {
+                debug!("Email to {}: {} (body withheld)", to, subject);
+            } else {
+                debug!("Email to {}: {}\n{}", to, subject, body);
+            }
+            return Ok(());
+        };
+
+        let to = to
+            .parse::<Mailbox>()
+            .map_err(|e| AppError::Validation(format!("Invalid email address: {}", e)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body)
+            .map_err(|e| AppError::InternalError(format!("Failed to build email: {}", e)))?;
+
+        transport
+            .send(message)
+            .await
+            .map_err(|e| AppError::InternalError(format!("Failed to send email: {}", e)))?;
+
+        Ok(())
+    }
+}
+
+pub fn is_valid_address(email: &str) -> bool {
+    email.len() <= 255 && email.parse::<lettre::Address>().is_ok()
+}
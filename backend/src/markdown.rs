@@ -0,0 +1,272 @@
+use crate::{error::AppError, models::MentionSpan};
+use ammonia::UrlRelative;
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag, TagEnd, TextMergeStream};
+use sqlx::PgPool;
+use std::{collections::HashMap, sync::LazyLock};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Message types whose content is Markdown. Files carry JSON, polls a plain
+/// question, and system messages are generated text.
+pub const RENDERED_MESSAGE_TYPES: &[&str] = &["text", "action", "reminder"];
+const BACKFILL_BATCH_SIZE: i64 = 500;
+
+/// The supported subset: paragraphs and line breaks, bold, italics,
+/// strikethrough, inline code and code blocks, links, lists and quotes.
+/// Anything else the parser produces is either mapped onto the subset or
+/// stripped here.
+static SANITIZER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+    let mut builder = ammonia::Builder::empty();
+    builder
+        .add_tags(&["p", "br", "strong", "em", "del", "code", "pre", "a", "ul", "ol", "li", "blockquote", "span"])
+        .add_tag_attributes("a", &["href"])
+        .add_tag_attributes("ol", &["start"])
+        .add_tag_attributes("span", &["data-user-id", "data-mention"])
+        .add_allowed_classes("span", &["mention"])
+        .url_schemes(["http", "https", "mailto"].into_iter().collect())
+        .url_relative(UrlRelative::Deny)
+        .link_rel(Some("noopener noreferrer nofollow"));
+    builder
+});
+
+pub fn is_rendered(message_type: &str) -> bool {
+    RENDERED_MESSAGE_TYPES.contains(&message_type)
+}
+
+/// Renders message content to sanitized HTML. Mentions become
+/// `<span class="mention">` elements and bare web URLs become links.
+pub fn render(content: &str, mentions: &[MentionSpan]) -> String {
+    // The text each mention was written as, e.g. `@alice`, keyed lowercase
+    let chars: Vec<char> = content.chars().collect();
+    let mentions: HashMap<String, &MentionSpan> = mentions
+        .iter()
+        .filter(|span| span.start < span.end && span.end <= chars.len())
+        .map(|span| (chars[span.start..span.end].iter().collect::<String>().to_lowercase(), span))
+        .collect();
+
+    let parser = TextMergeStream::new(Parser::new_ext(content, Options::ENABLE_STRIKETHROUGH));
+    let mut events = Vec::new();
+    let mut in_link = false;
+    let mut in_code_block = false;
+    for event in parser {
+        match event {
+            // Raw HTML is shown as typed
+            Event::Html(html) | Event::InlineHtml(html) => events.push(Event::Text(html)),
+            Event::SoftBreak => events.push(Event::HardBreak),
+            Event::Start(Tag::Heading { .. }) => events.push(Event::Start(Tag::Paragraph)),
+            Event::End(TagEnd::Heading(_)) => events.push(Event::End(TagEnd::Paragraph)),
+            // Images aren't loaded from arbitrary hosts; they're linked instead
+            Event::Start(Tag::Image { link_type, dest_url, title, id }) => {
+                in_link = true;
+                events.push(Event::Start(Tag::Link { link_type, dest_url, title, id }));
+            }
+            Event::End(TagEnd::Image) => {
+                in_link = false;
+                events.push(Event::End(TagEnd::Link));
+            }
+            Event::Start(Tag::Link { .. }) => {
+                in_link = true;
+                events.push(event);
+            }
+            Event::End(TagEnd::Link) => {
+                in_link = false;
+                events.push(event);
+            }
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                events.push(event);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                events.push(event);
+            }
+            Event::Text(text) if !in_link && !in_code_block => decorate(&text, &mentions, &mut events),
+            event => events.push(event),
+        }
+    }
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    SANITIZER.clean(&html).to_string()
+}
+
+/// Splits a run of text around mentions and bare URLs.
+fn decorate<'a>(text: &str, mentions: &HashMap<String, &MentionSpan>, events: &mut Vec<Event<'a>>) {
+    let mut plain_start = 0;
+    let mut i = 0;
+    let flush = |events: &mut Vec<Event<'a>>, from: usize, to: usize| {
+        if from < to {
+            events.push(Event::Text(CowStr::from(text[from..to].to_string())));
+        }
+    };
+    while i < text.len() {
+        let rest = &text[i..];
+        let at_word_start = !text[..i].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_');
+
+        if at_word_start && (rest.starts_with("https://") || rest.starts_with("http://")) {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let url = rest[..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"']);
+            if url.len() > "https://".len() {
+                flush(events, plain_start, i);
+                events.push(Event::InlineHtml(CowStr::from(format!(
+                    "<a href=\"{}\">{}</a>",
+                    escape(url),
+                    escape(url)
+                ))));
+                i += url.len();
+                plain_start = i;
+                continue;
+            }
+        }
+
+        if at_word_start && rest.starts_with('@') {
+            let end = rest[1..]
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.'))
+                .map_or(rest.len(), |end| end + 1);
+            let mut word = &rest[..end];
+            // Usernames can't end in a dot, sentences can
+            word = word.trim_end_matches('.');
+            if let Some(span) = mentions.get(&word.to_lowercase()) {
+                flush(events, plain_start, i);
+                let user = span.user_id.map(|id| format!(" data-user-id=\"{}\"", id)).unwrap_or_default();
+                events.push(Event::InlineHtml(CowStr::from(format!(
+                    "<span class=\"mention\" data-mention=\"{}\"{}>{}</span>",
+                    escape(&span.kind),
+                    user,
+                    escape(word)
+                ))));
+                i += word.len();
+                plain_start = i;
+                continue;
+            }
+        }
+
+        i += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    flush(events, plain_start, text.len());
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Renders messages stored before rendering existed, in the background.
+pub fn spawn_backfill(pool: PgPool) {
+    tokio::spawn(async move {
+        match backfill(&pool).await {
+            Ok(0) => {}
+            Ok(count) => info!("Rendered Markdown for {} existing messages", count),
+            Err(e) => warn!("Failed to render existing messages: {}", e),
+        }
+    });
+}
+
+async fn backfill(pool: &PgPool) -> Result<usize, AppError> {
+    let mut total = 0;
+    loop {
+        let rows = sqlx::query_as::<_, (Uuid, String, sqlx::types::Json<Vec<MentionSpan>>)>(
+            r#"
+            SELECT id, content, mentions FROM messages
+            WHERE content_html IS NULL AND message_type = ANY($1)
+            LIMIT $2
+            "#
+        )
+        .bind(RENDERED_MESSAGE_TYPES)
+        .bind(BACKFILL_BATCH_SIZE)
+        .fetch_all(pool)
+        .await?;
+
+        for (id, content, mentions) in &rows {
+            sqlx::query("UPDATE messages SET content_html = $2 WHERE id = $1")
+                .bind(id)
+                .bind(render(content, mentions))
+                .execute(pool)
+                .await?;
+        }
+        total += rows.len();
+        if (rows.len() as i64) < BACKFILL_BATCH_SIZE {
+            return Ok(total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mention(username: &str, start: usize) -> MentionSpan {
+        MentionSpan {
+            kind: "user".to_string(),
+            user_id: Some(Uuid::nil()),
+            username: Some(username.to_string()),
+            start,
+            end: start + username.chars().count() + 1,
+        }
+    }
+
+    #[test]
+    fn raw_html_is_shown_as_text() {
+        assert_eq!(render("<script>alert(1)</script>", &[]), "&lt;script&gt;alert(1)&lt;/script&gt;");
+        assert_eq!(
+            render("hi <img src=x onerror=alert(1)> there", &[]),
+            "<p>hi &lt;img src=x onerror=alert(1)&gt; there</p>\n"
+        );
+        assert!(!render(r#"<a href="javascript:alert(1)">y</a>"#, &[]).contains("<a"));
+    }
+
+    #[test]
+    fn unsafe_link_targets_are_dropped() {
+        for link in ["javascript:alert(1)", "JaVaScRiPt:alert(1)", "data:text/html,hi", "vbscript:x", "/relative", "//evil.example"] {
+            let html = render(&format!("[click]({})", link), &[]);
+            assert_eq!(html, "<p><a rel=\"noopener noreferrer nofollow\">click</a></p>\n", "{}", link);
+        }
+    }
+
+    #[test]
+    fn links_get_rel_and_images_become_links() {
+        assert_eq!(
+            render("[site](https://example.com)", &[]),
+            "<p><a href=\"https://example.com\" rel=\"noopener noreferrer nofollow\">site</a></p>\n"
+        );
+        assert_eq!(
+            render("![alt](https://example.com/a.png)", &[]),
+            "<p><a href=\"https://example.com/a.png\" rel=\"noopener noreferrer nofollow\">alt</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn bare_urls_are_linked_and_escaped() {
+        assert_eq!(
+            render("see https://example.com/a?b=1&c=2).", &[]),
+            "<p>see <a href=\"https://example.com/a?b=1&amp;c=2\" rel=\"noopener noreferrer nofollow\">https://example.com/a?b=1&amp;c=2</a>).</p>\n"
+        );
+        let html = render(r#"https://example.com/"onmouseover="alert(1)"#, &[]);
+        assert!(html.contains(r#"href="https://example.com/&quot;onmouseover=&quot;alert(1""#), "{}", html);
+        assert!(!html.contains(" onmouseover"));
+    }
+
+    #[test]
+    fn unsupported_blocks_are_mapped_onto_the_subset() {
+        assert_eq!(
+            render("# Title\n**bold** _it_ ~~del~~ `code`", &[]),
+            "<p>Title</p>\n<p><strong>bold</strong> <em>it</em> <del>del</del> <code>code</code></p>\n"
+        );
+        assert_eq!(render("line one\nline two", &[]), "<p>line one<br>\nline two</p>\n");
+    }
+
+    #[test]
+    fn mentions_are_marked_outside_code() {
+        assert_eq!(
+            render("hi @alice. and @bob", &[mention("alice", 3)]),
+            "<p>hi <span class=\"mention\" data-mention=\"user\" data-user-id=\"00000000-0000-0000-0000-000000000000\">@alice</span>. and @bob</p>\n"
+        );
+        assert_eq!(
+            render("```\n@alice https://x.io\n```", &[mention("alice", 4)]),
+            "<pre><code>@alice https://x.io\n</code></pre>\n"
+        );
+    }
+}
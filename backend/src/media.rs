@@ -0,0 +1,491 @@
+use crate::{error::AppError, models::Thumbnail};
+use exif::{experimental::Writer, Context, In, Tag};
+use image::{imageops::FilterType, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+use tracing::warn;
+
+/// Thumbnails fit in a square of this many pixels. Sizes at least as large
+/// as the original aren't generated; clients use the original instead.
+pub const THUMBNAIL_SIZES: &[(&str, u32)] = &[("small", 160), ("medium", 480), ("large", 1280)];
+/// Larger images are stored but not decoded, so they get no thumbnails.
+const MAX_SOURCE_DIMENSION: u32 = 12000;
+const MAX_DECODE_BYTES: u64 = 512 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 80;
+/// Blurhash components along the image's longer side; the shorter gets 3.
+const BLURHASH_COMPONENTS: u32 = 4;
+/// Images are shrunk to this before computing the blurhash.
+const BLURHASH_SOURCE_SIZE: u32 = 32;
+
+/// What was learnt from an uploaded image.
+pub struct ProcessedImage {
+    pub content_type: &'static str,
+    /// Set when the upload had to be changed, e.g. to remove its location.
+    pub data: Option<Vec<u8>>,
+    /// As displayed, after EXIF orientation.
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: Option<String>,
+    pub thumbnails: Vec<(Thumbnail, Vec<u8>)>,
+}
+
+/// Looks at an upload, and if it's a PNG, JPEG, GIF or WebP image removes
+/// any GPS position from its EXIF data and renders thumbnails. `None` for
+/// anything else; images that can't be decoded keep their content type but
+/// get no thumbnails. Images whose position can't be removed are refused.
+/// Blocking.
+pub fn process(data: &[u8], strip_gps: bool) -> Result<Option<ProcessedImage>, AppError> {
+    let Ok(format) = image::guess_format(data) else {
+        return Ok(None);
+    };
+    let content_type = match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::WebP => "image/webp",
+        _ => return Ok(None),
+    };
+
+    let stripped = if strip_gps { strip_gps_data(data, format)? } else { None };
+    let data = stripped.as_deref().unwrap_or(data);
+
+    let mut processed = ProcessedImage {
+        content_type,
+        data: None,
+        width: 0,
+        height: 0,
+        blurhash: None,
+        thumbnails: Vec::new(),
+    };
+    match decode(data, format) {
+        Ok(image) => {
+            processed.width = image.width();
+            processed.height = image.height();
+            processed.blurhash = blurhash(&image);
+            processed.thumbnails = thumbnails(&image);
+        }
+        Err(e) => warn!("Failed to decode uploaded {} image: {}", content_type, e),
+    }
+    processed.data = stripped;
+    Ok(Some(processed))
+}
+
+fn decode(data: &[u8], format: ImageFormat) -> image::ImageResult<DynamicImage> {
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn blurhash(image: &DynamicImage) -> Option<String> {
+    let small = image.thumbnail(BLURHASH_SOURCE_SIZE, BLURHASH_SOURCE_SIZE).to_rgba8();
+    let (x, y) = if small.width() >= small.height() {
+        (BLURHASH_COMPONENTS, 3)
+    } else {
+        (3, BLURHASH_COMPONENTS)
+    };
+    blurhash::encode(x, y, small.width(), small.height(), small.as_raw()).ok()
+}
+
+/// JPEG thumbnails, or PNG for images with transparency.
+fn thumbnails(image: &DynamicImage) -> Vec<(Thumbnail, Vec<u8>)> {
+    let longest = image.width().max(image.height());
+    let has_alpha = image.color().has_alpha();
+    let mut thumbnails = Vec::new();
+    for (size, max) in THUMBNAIL_SIZES {
+        if longest <= *max {
+            break;
+        }
+        let resized = image.resize(*max, *max, FilterType::Triangle);
+        let mut encoded = Cursor::new(Vec::new());
+        let (content_type, result) = if has_alpha {
+            ("image/png", resized.write_to(&mut encoded, ImageFormat::Png))
+        } else {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY);
+            ("image/jpeg", DynamicImage::ImageRgb8(resized.to_rgb8()).write_with_encoder(encoder))
+        };
+        if let Err(e) = result {
+            warn!("Failed to encode {} thumbnail: {}", size, e);
+            continue;
+        }
+        thumbnails.push((
+            Thumbnail {
+                size: size.to_string(),
+                width: resized.width() as i32,
+                height: resized.height() as i32,
+                content_type: content_type.to_string(),
+            },
+            encoded.into_inner(),
+        ));
+    }
+    thumbnails
+}
+
+/// The image with its EXIF GPS fields removed, or `None` if it had none.
+/// The other fields are kept (orientation above all), except the maker note
+/// and embedded thumbnail, which can't be rewritten safely. EXIF data that
+/// can't be read or rewritten is dropped entirely, and if even that fails
+/// the image is refused rather than stored with its position.
+fn strip_gps_data(data: &[u8], format: ImageFormat) -> Result<Option<Vec<u8>>, AppError> {
+    // GIFs have no EXIF data
+    if !matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP) {
+        return Ok(None);
+    }
+    let replacement = match exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+        Ok(exif) if !exif.fields().any(|field| field.tag.context() == Context::Gps) => return Ok(None),
+        Ok(exif) => without_gps(&exif),
+        Err(exif::Error::NotFound(_)) => return Ok(None),
+        Err(e) => {
+            warn!("Failed to read EXIF data, dropping it: {}", e);
+            None
+        }
+    };
+
+    let replace = |tiff: Option<&[u8]>| match format {
+        ImageFormat::Jpeg => replace_jpeg_exif(data, tiff),
+        ImageFormat::Png => replace_png_exif(data, tiff),
+        _ => replace_webp_exif(data, tiff),
+    };
+    let stripped = match replacement {
+        Some(tiff) => replace(Some(&tiff)).or_else(|| replace(None)),
+        None => replace(None),
+    };
+    match stripped {
+        Some(stripped) => Ok(Some(stripped)),
+        None => {
+            warn!("Failed to remove GPS data from an uploaded {:?} image, refusing it", format);
+            Err(AppError::Validation(
+                "The image's location data couldn't be removed; remove it and upload the image again".to_string(),
+            ))
+        }
+    }
+}
+
+/// The primary image's EXIF fields without the GPS ones, as TIFF data.
+fn without_gps(exif: &exif::Exif) -> Option<Vec<u8>> {
+    let mut writer = Writer::new();
+    for field in exif.fields() {
+        if field.tag.context() != Context::Gps && field.tag != Tag::MakerNote && field.ifd_num == In::PRIMARY {
+            writer.push_field(field);
+        }
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    match writer.write(&mut tiff, exif.little_endian()) {
+        Ok(()) => Some(tiff.into_inner()),
+        Err(e) => {
+            warn!("Failed to rewrite EXIF data, dropping it: {}", e);
+            None
+        }
+    }
+}
+
+const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Replaces the payload of APP1 Exif segments, or removes them.
+fn replace_jpeg_exif(data: &[u8], tiff: Option<&[u8]>) -> Option<Vec<u8>> {
+    let mut out = data[..2].to_vec();
+    let mut pos = 2;
+    loop {
+        if data.get(pos) != Some(&0xFF) {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        // Fill bytes
+        if marker == 0xFF {
+            out.push(0xFF);
+            pos += 1;
+            continue;
+        }
+        // Standalone markers
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            out.extend_from_slice(&data[pos..pos + 2]);
+            pos += 2;
+            continue;
+        }
+        // Start of scan: the rest is image data
+        if marker == 0xDA || marker == 0xD9 {
+            out.extend_from_slice(&data[pos..]);
+            return Some(out);
+        }
+        let length = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        if length < 2 {
+            return None;
+        }
+        let segment = data.get(pos..pos + 2 + length)?;
+        let payload = &segment[4..];
+        if marker == 0xE1 && payload.starts_with(JPEG_EXIF_HEADER) {
+            if let Some(tiff) = tiff {
+                let length = u16::try_from(2 + JPEG_EXIF_HEADER.len() + tiff.len()).ok()?;
+                out.extend_from_slice(&[0xFF, 0xE1]);
+                out.extend_from_slice(&length.to_be_bytes());
+                out.extend_from_slice(JPEG_EXIF_HEADER);
+                out.extend_from_slice(tiff);
+            }
+        } else {
+            out.extend_from_slice(segment);
+        }
+        pos += 2 + length;
+    }
+}
+
+/// Replaces the data of `eXIf` chunks, or removes them.
+fn replace_png_exif(data: &[u8], tiff: Option<&[u8]>) -> Option<Vec<u8>> {
+    let mut out = data.get(..8)?.to_vec();
+    let mut pos = 8;
+    while pos < data.len() {
+        let length = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk = data.get(pos..pos + 12 + length)?;
+        if &chunk[4..8] == b"eXIf" {
+            if let Some(tiff) = tiff {
+                out.extend_from_slice(&u32::try_from(tiff.len()).ok()?.to_be_bytes());
+                let start = out.len();
+                out.extend_from_slice(b"eXIf");
+                out.extend_from_slice(tiff);
+                let crc = crc32fast::hash(&out[start..]);
+                out.extend_from_slice(&crc.to_be_bytes());
+            }
+        } else {
+            out.extend_from_slice(chunk);
+        }
+        pos += 12 + length;
+    }
+    Some(out)
+}
+
+/// Replaces the payload of `EXIF` chunks, or removes them along with the
+/// EXIF flag in the `VP8X` header.
+fn replace_webp_exif(data: &[u8], tiff: Option<&[u8]>) -> Option<Vec<u8>> {
+    let mut out = data.get(..12)?.to_vec();
+    let mut pos = 12;
+    while pos < data.len() {
+        let length = u32::from_le_bytes(data.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        let padded = length + length % 2;
+        let chunk = data.get(pos..(pos + 8 + padded).min(data.len()))?;
+        match &chunk[..4] {
+            b"EXIF" => {
+                if let Some(tiff) = tiff {
+                    // Some writers prefix the payload like JPEG does
+                    let prefix = if chunk[8..].starts_with(JPEG_EXIF_HEADER) { JPEG_EXIF_HEADER } else { &[] };
+                    let length = prefix.len() + tiff.len();
+                    out.extend_from_slice(b"EXIF");
+                    out.extend_from_slice(&u32::try_from(length).ok()?.to_le_bytes());
+                    out.extend_from_slice(prefix);
+                    out.extend_from_slice(tiff);
+                    if length % 2 == 1 {
+                        out.push(0);
+                    }
+                }
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+        pos += 8 + padded;
+    }
+
+    if tiff.is_none() && out.get(12..16) == Some(b"VP8X") {
+        if let Some(flags) = out.get_mut(20) {
+            *flags &= !0x08;
+        }
+    }
+    let riff_size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{Field, Rational, Value};
+    use image::{codecs::jpeg::JpegEncoder, ImageEncoder, RgbImage};
+
+    type Fixture = fn(Option<&[u8]>) -> Vec<u8>;
+    type Replace = fn(&[u8], Option<&[u8]>) -> Option<Vec<u8>>;
+
+    const WIDTH: u32 = 8;
+    const HEIGHT: u32 = 4;
+
+    /// TIFF data with an orientation and, if `gps`, a position.
+    fn tiff(gps: bool) -> Vec<u8> {
+        let orientation = Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![6]) };
+        let latitude_ref = Field { tag: Tag::GPSLatitudeRef, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"N".to_vec()]) };
+        let latitude = Field {
+            tag: Tag::GPSLatitude,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(vec![Rational { num: 51, denom: 1 }, Rational { num: 30, denom: 1 }, Rational { num: 0, denom: 1 }]),
+        };
+        let mut writer = Writer::new();
+        writer.push_field(&orientation);
+        if gps {
+            writer.push_field(&latitude_ref);
+            writer.push_field(&latitude);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        tiff.into_inner()
+    }
+
+    fn pixels() -> RgbImage {
+        RgbImage::from_fn(WIDTH, HEIGHT, |x, y| image::Rgb([(x * 30) as u8, (y * 60) as u8, 128]))
+    }
+
+    /// A JPEG with an APP1 Exif segment right after the start of image.
+    fn jpeg(tiff: Option<&[u8]>) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        JpegEncoder::new(&mut encoded).write_image(&pixels(), WIDTH, HEIGHT, image::ExtendedColorType::Rgb8).unwrap();
+        let mut data = encoded[..2].to_vec();
+        if let Some(tiff) = tiff {
+            data.extend_from_slice(&[0xFF, 0xE1]);
+            data.extend_from_slice(&((2 + JPEG_EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+            data.extend_from_slice(JPEG_EXIF_HEADER);
+            data.extend_from_slice(tiff);
+        }
+        data.extend_from_slice(&encoded[2..]);
+        data
+    }
+
+    /// A PNG with an `eXIf` chunk after the header.
+    fn png(tiff: Option<&[u8]>) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut encoded)
+            .write_image(&pixels(), WIDTH, HEIGHT, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        // Signature and IHDR
+        let header = 8 + 12 + 13;
+        let mut data = encoded[..header].to_vec();
+        if let Some(tiff) = tiff {
+            data.extend_from_slice(&(tiff.len() as u32).to_be_bytes());
+            let start = data.len();
+            data.extend_from_slice(b"eXIf");
+            data.extend_from_slice(tiff);
+            let crc = crc32fast::hash(&data[start..]);
+            data.extend_from_slice(&crc.to_be_bytes());
+        }
+        data.extend_from_slice(&encoded[header..]);
+        data
+    }
+
+    /// An extended WebP: `VP8X` with the EXIF flag, the lossless image and an
+    /// `EXIF` chunk.
+    fn webp(tiff: Option<&[u8]>) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        image::codecs::webp::WebPEncoder::new_lossless(&mut encoded)
+            .write_image(&pixels(), WIDTH, HEIGHT, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let mut data = b"RIFF\0\0\0\0WEBPVP8X".to_vec();
+        data.extend_from_slice(&10u32.to_le_bytes());
+        data.extend_from_slice(&[if tiff.is_some() { 0x08 } else { 0 }, 0, 0, 0]);
+        data.extend_from_slice(&(WIDTH - 1).to_le_bytes()[..3]);
+        data.extend_from_slice(&(HEIGHT - 1).to_le_bytes()[..3]);
+        data.extend_from_slice(&encoded[12..]);
+        if let Some(tiff) = tiff {
+            data.extend_from_slice(b"EXIF");
+            data.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+            data.extend_from_slice(tiff);
+            if tiff.len() % 2 == 1 {
+                data.push(0);
+            }
+        }
+        let size = (data.len() - 8) as u32;
+        data[4..8].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    fn read_exif(data: &[u8]) -> Option<exif::Exif> {
+        exif::Reader::new().read_from_container(&mut Cursor::new(data)).ok()
+    }
+
+    fn has_gps(data: &[u8]) -> bool {
+        read_exif(data).is_some_and(|exif| exif.fields().any(|field| field.tag.context() == Context::Gps))
+    }
+
+    fn fixtures() -> [(&'static str, Fixture); 3] {
+        [("jpeg", jpeg), ("png", png), ("webp", webp)]
+    }
+
+    #[test]
+    fn fixtures_carry_their_exif_data() {
+        for (name, fixture) in fixtures() {
+            assert!(has_gps(&fixture(Some(&tiff(true)))), "{}", name);
+            assert!(read_exif(&fixture(None)).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn replacing_exif_swaps_the_payload() {
+        let replacement = tiff(false);
+        for (name, replaced) in [
+            ("jpeg", replace_jpeg_exif(&jpeg(Some(&tiff(true))), Some(&replacement))),
+            ("png", replace_png_exif(&png(Some(&tiff(true))), Some(&replacement))),
+            ("webp", replace_webp_exif(&webp(Some(&tiff(true))), Some(&replacement))),
+        ] {
+            let replaced = replaced.unwrap_or_else(|| panic!("{} wasn't rewritten", name));
+            let exif = read_exif(&replaced).unwrap_or_else(|| panic!("{} lost its EXIF data", name));
+            assert!(!has_gps(&replaced), "{}", name);
+            assert!(exif.get_field(Tag::Orientation, In::PRIMARY).is_some(), "{}", name);
+            assert!(image::load_from_memory(&replaced).is_ok(), "{} doesn't decode", name);
+        }
+    }
+
+    #[test]
+    fn replacing_exif_with_nothing_removes_it() {
+        for (name, fixture, replace) in [
+            ("jpeg", jpeg as Fixture, replace_jpeg_exif as Replace),
+            ("png", png, replace_png_exif),
+            ("webp", webp, replace_webp_exif),
+        ] {
+            let removed = replace(&fixture(Some(&tiff(true))), None).unwrap();
+            assert!(read_exif(&removed).is_none(), "{} kept EXIF data", name);
+            assert!(image::load_from_memory(&removed).is_ok(), "{} doesn't decode", name);
+        }
+        // The extended header no longer announces EXIF data
+        assert_eq!(replace_webp_exif(&webp(Some(&tiff(true))), None).unwrap()[20] & 0x08, 0);
+    }
+
+    #[test]
+    fn malformed_containers_are_not_rewritten() {
+        assert!(replace_png_exif(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR", None).is_none());
+        assert!(replace_webp_exif(b"RIFF\0\0\0\0WEBPVP8X\xff\xff", None).is_none());
+        assert!(replace_jpeg_exif(&[0xFF, 0xD8, 0x00], None).is_none());
+    }
+
+    #[test]
+    fn processing_strips_gps_and_keeps_orientation() {
+        for (name, fixture) in fixtures() {
+            let processed = process(&fixture(Some(&tiff(true))), true).unwrap().unwrap();
+            let data = processed.data.unwrap_or_else(|| panic!("{} wasn't rewritten", name));
+            assert!(!has_gps(&data), "{}", name);
+            assert!(read_exif(&data).is_some_and(|exif| exif.get_field(Tag::Orientation, In::PRIMARY).is_some()), "{}", name);
+            // Orientation 6 turns the image on its side
+            assert_eq!((processed.width, processed.height), (HEIGHT, WIDTH), "{}", name);
+        }
+    }
+
+    #[test]
+    fn images_without_gps_are_stored_as_uploaded() {
+        for (name, fixture) in fixtures() {
+            for data in [fixture(None), fixture(Some(&tiff(false)))] {
+                let processed = process(&data, true).unwrap().unwrap();
+                assert!(processed.data.is_none(), "{} was rewritten", name);
+            }
+            let processed = process(&fixture(Some(&tiff(true))), false).unwrap().unwrap();
+            assert!(processed.data.is_none(), "{} was rewritten with stripping off", name);
+        }
+    }
+
+    #[test]
+    fn images_whose_gps_cannot_be_removed_are_refused() {
+        // The Exif segment parses, but the segment after it is garbage
+        let mut data = jpeg(Some(&tiff(true)));
+        let exif_end = 2 + 2 + 2 + JPEG_EXIF_HEADER.len() + tiff(true).len();
+        data.insert(exif_end, 0x00);
+        assert!(has_gps(&data));
+
+        assert!(matches!(process(&data, true), Err(AppError::Validation(_))));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::{
+    auth::AuthClaims,
+    error::AppError,
+    models::*,
+    websocket::send_to_user,
+    AppState, SharedState,
+};
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+pub const KIND_USER: &str = "user";
+pub const KIND_ROOM: &str = "room";
+pub const KIND_HERE: &str = "here";
+
+/// `@room` notifies every member, `@here` the members who are online.
+const RESERVED_NAMES: &[&str] = &[KIND_ROOM, KIND_HERE];
+const MAX_USERNAME_LEN: usize = 32;
+const DEFAULT_INBOX_LIMIT: i64 = 50;
+const MAX_INBOX_LIMIT: i64 = 100;
+
+/// Message types whose content isn't scanned for mentions.
+const UNPARSED_MESSAGE_TYPES: &[&str] = &["system", "file"];
+
+pub fn is_reserved(username: &str) -> bool {
+    RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(username))
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// An `@name` token: the name and its character span, `@` included.
+struct Candidate {
+    name: String,
+    start: usize,
+    end: usize,
+}
+
+/// Finds `@name` tokens that aren't part of a longer word (so `a@b.com` is
+/// left alone).
+fn candidates(content: &str) -> Vec<Candidate> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let preceded_by_word = i > 0 && (is_name_char(chars[i - 1]) || chars[i - 1] == '@');
+        if chars[i] != '@' || preceded_by_word {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut end = i + 1;
+        while end < chars.len() && is_name_char(chars[end]) && end - start <= MAX_USERNAME_LEN {
+            end += 1;
+        }
+        if end > start + 1 && !(end < chars.len() && is_name_char(chars[end])) {
+            found.push(Candidate {
+                name: chars[start + 1..end].iter().collect(),
+                start,
+                end,
+            });
+        }
+        i = end;
+    }
+    found
+}
+
+/// Mentions resolved against the room: the spans to store with the message
+/// and everyone to notify, with the most direct way they were mentioned.
+#[derive(Default)]
+pub struct Resolved {
+    pub spans: Vec<MentionSpan>,
+    pub recipients: HashMap<Uuid, &'static str>,
+}
+
+fn precedence(kind: &str) -> u8 {
+    match kind {
+        KIND_USER => 2,
+        KIND_HERE => 1,
+        _ => 0,
+    }
+}
+
+impl Resolved {
+    fn add_recipient(&mut self, user_id: Uuid, kind: &'static str) {
+        let entry = self.recipients.entry(user_id).or_insert(kind);
+        if precedence(kind) > precedence(entry) {
+            *entry = kind;
+        }
+    }
+}
+
+/// Resolves mentions in a new message. Only room members can be mentioned;
+/// other `@names` stay plain text. The sender is never a recipient.
+pub async fn resolve(
+    state: &AppState,
+    room_id: Uuid,
+    sender_id: Uuid,
+    content: &str,
+    message_type: &str,
+) -> Result<Resolved, AppError> {
+    let mut resolved = Resolved::default();
+    if UNPARSED_MESSAGE_TYPES.contains(&message_type) {
+        return Ok(resolved);
+    }
+    let candidates = candidates(content);
+    if candidates.is_empty() {
+        return Ok(resolved);
+    }
+
+    let names: Vec<String> = candidates
+        .iter()
+        .filter(|candidate| !is_reserved(&candidate.name))
+        .map(|candidate| candidate.name.to_lowercase())
+        .collect();
+    let wants_everyone = candidates.iter().any(|candidate| is_reserved(&candidate.name));
+
+    let members = sqlx::query_as::<_, (Uuid, String)>(
+        r#"
+        SELECT u.id, u.username
+        FROM room_members rm
+        JOIN users u ON u.id = rm.user_id
+        WHERE rm.room_id = $1 AND ($2 OR LOWER(u.username) = ANY($3))
+        "#
+    )
+    .bind(room_id)
+    .bind(wants_everyone)
+    .bind(&names)
+    .fetch_all(&state.db)
+    .await?;
+    let by_name: HashMap<String, &(Uuid, String)> = members
+        .iter()
+        .map(|member| (member.1.to_lowercase(), member))
+        .collect();
+
+    let online: HashSet<Uuid> = if candidates.iter().any(|candidate| candidate.name.eq_ignore_ascii_case(KIND_HERE)) {
+        state.connections.read().await.keys().copied().collect()
+    } else {
+        HashSet::new()
+    };
+
+    for candidate in candidates {
+        let lowered = candidate.name.to_lowercase();
+        let kind = match lowered.as_str() {
+            KIND_ROOM => KIND_ROOM,
+            KIND_HERE => KIND_HERE,
+            _ => KIND_USER,
+        };
+
+        if kind == KIND_USER {
+            let Some((user_id, username)) = by_name.get(&lowered).copied() else {
+                continue;
+            };
+            if *user_id != sender_id {
+                resolved.add_recipient(*user_id, KIND_USER);
+            }
+            resolved.spans.push(MentionSpan {
+                kind: KIND_USER.to_string(),
+                user_id: Some(*user_id),
+                username: Some(username.clone()),
+                start: candidate.start,
+                end: candidate.end,
+            });
+        } else {
+            for (user_id, _) in &members {
+                if *user_id != sender_id && (kind == KIND_ROOM || online.contains(user_id)) {
+                    resolved.add_recipient(*user_id, kind);
+                }
+            }
+            resolved.spans.push(MentionSpan {
+                kind: kind.to_string(),
+                user_id: None,
+                username: None,
+                start: candidate.start,
+                end: candidate.end,
+            });
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Records who a message mentions, in the transaction that stores it.
+pub async fn record(
+    tx: &mut Transaction<'_, Postgres>,
+    message: &Message,
+    recipients: &HashMap<Uuid, &'static str>,
+) -> Result<(), AppError> {
+    if recipients.is_empty() {
+        return Ok(());
+    }
+    let (user_ids, kinds): (Vec<Uuid>, Vec<String>) = recipients
+        .iter()
+        .map(|(user_id, kind)| (*user_id, kind.to_string()))
+        .unzip();
+
+    sqlx::query(
+        r#"
+        INSERT INTO message_mentions (message_id, user_id, room_id, kind, created_at)
+        SELECT $1, mentioned.user_id, $2, mentioned.kind, $3
+        FROM UNNEST($4::uuid[], $5::text[]) AS mentioned(user_id, kind)
+        "#
+    )
+    .bind(message.id)
+    .bind(message.room_id)
+    .bind(message.created_at)
+    .bind(&user_ids)
+    .bind(&kinds)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Sends a `mention` event to each recipient's open connections, whichever
+/// room they are looking at.
+pub async fn notify(state: &AppState, message: &Message, recipients: &HashMap<Uuid, &'static str>) {
+    for (user_id, kind) in recipients {
+        let event = WebSocketMessage::new(
+            "mention",
+            &serde_json::json!({ "room_id": message.room_id, "kind": kind, "message": message }),
+        );
+        send_to_user(state, *user_id, &event).await;
+    }
+}
+
+pub fn router() -> Router<SharedState> {
+    Router::new().route("/users/me/mentions", get(inbox_handler))
+}
+
+#[derive(Deserialize)]
+struct InboxQuery {
+    limit: Option<i64>,
+    /// Only mentions older than this, for paging.
+    before: Option<DateTime<Utc>>,
+}
+
+/// Messages mentioning the user, newest first, from rooms they still belong to.
+pub async fn inbox(
+    pool: &PgPool,
+    user_id: Uuid,
+    before: Option<DateTime<Utc>>,
+    limit: i64,
+) -> Result<Vec<MentionInboxItem>, AppError> {
+    let items = sqlx::query_as::<_, MentionInboxItem>(
+        r#"
+        SELECT m.*, r.name AS room_name, mm.kind AS mention_kind
+        FROM message_mentions mm
+        JOIN messages m ON m.id = mm.message_id
+        JOIN rooms r ON r.id = mm.room_id
+        JOIN room_members rm ON rm.room_id = mm.room_id AND rm.user_id = mm.user_id
+        WHERE mm.user_id = $1 AND ($2::timestamptz IS NULL OR mm.created_at < $2)
+        ORDER BY mm.created_at DESC
+        LIMIT $3
+        "#
+    )
+    .bind(user_id)
+    .bind(before)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(items)
+}
+
+async fn inbox_handler(
+    Query(query): Query<InboxQuery>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<MentionInboxItem>>, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_INBOX_LIMIT).clamp(1, MAX_INBOX_LIMIT);
+    Ok(Json(inbox(&state.db, claims.user_id()?, query.before, limit).await?))
+}
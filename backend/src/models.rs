@@ -1,4 +1,4 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -7,7 +7,22 @@ pub struct User {
     pub id: Uuid,
     pub username: String,
     pub email: String,
-    pub password_hash: String,
+    /// `None` for accounts that only sign in through an external identity provider
+    pub password_hash: Option<String>,
+    pub role: String,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub token_version: i32,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+    pub totp_last_step: Option<i64>,
+    pub is_bot: bool,
+    pub bot_owner_id: Option<Uuid>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub timezone: Option<String>,
+    pub status_text: Option<String>,
+    pub avatar_file_id: Option<Uuid>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -17,14 +32,63 @@ pub struct UserInfo {
     pub id: Uuid,
     pub username: String,
     pub email: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
 }
 
+impl From<&User> for UserInfo {
+    fn from(user: &User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username.clone(),
+            email: user.email.clone(),
+            display_name: user.display_name.clone(),
+            avatar_url: user.avatar_file_id.map(avatar_url),
+        }
+    }
+}
+
+pub fn avatar_url(file_id: Uuid) -> String {
+    format!("/api/avatars/{}", file_id)
+}
+
+/// What other users can see about an account.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UserProfile {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub timezone: Option<String>,
+    pub status_text: Option<String>,
+    pub avatar_url: Option<String>,
+    pub is_bot: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The signed-in user's own profile, including private account details.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AuthUser {
+pub struct OwnProfile {
+    #[serde(flatten)]
+    pub profile: UserProfile,
+    pub email: String,
+    pub email_verified: bool,
+    pub has_password: bool,
+}
+
+/// A user as seen by administrators (everything but the password hash).
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AdminUserView {
     pub id: Uuid,
     pub username: String,
     pub email: String,
-    pub token: String,
+    pub role: String,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+    pub is_bot: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,25 +97,535 @@ pub struct AuthResponse {
     pub user: UserInfo,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RegisterResponse {
+    SignedIn(LoginResponse),
+    VerificationRequired {
+        verification_required: bool,
+        user: UserInfo,
+    },
+}
+
+/// Login answer: either a session, or a short-lived token to exchange for one
+/// once the second factor has been verified (or enrolled, if 2FA is required).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    MfaRequired {
+        mfa_required: bool,
+        mfa_token: String,
+        enrollment_required: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactorEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+    /// PNG data URL of the provisioning URI as a QR code
+    pub qr_code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub required: bool,
+    pub recovery_codes_remaining: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceSettings {
+    pub require_two_factor: bool,
+    pub account_deletion_policy: String,
+    /// Suspends purging expired messages and room retention.
+    pub legal_hold: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UserIdentity {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_login_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub token_prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// The owner's token version at creation; see `users.token_version`.
+    #[serde(skip)]
+    pub token_version: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct IncomingWebhook {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    /// The bot account messages are posted as.
+    pub user_id: Uuid,
+    pub name: String,
+    pub token_prefix: String,
+    pub created_by: Option<Uuid>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Returned once when a webhook is created; the URL contains the secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedIncomingWebhook {
+    pub url: String,
+    #[serde(flatten)]
+    pub webhook: IncomingWebhook,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct OutgoingWebhook {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub name: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub keyword_prefix: Option<String>,
+    pub message_types: Vec<String>,
+    pub mention_user_id: Option<Uuid>,
+    pub created_by: Option<Uuid>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Returned once when a webhook is created; the signing secret isn't shown again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedOutgoingWebhook {
+    pub secret: String,
+    #[serde(flatten)]
+    pub webhook: OutgoingWebhook,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub event: String,
+    pub payload: serde_json::Value,
+    /// `pending`, `succeeded` or `dead`.
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct WebhookDeliveryAttempt {
+    pub id: Uuid,
+    pub delivery_id: Uuid,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SlashCommand {
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub usage_hint: Option<String>,
+    pub url: String,
+    /// `ephemeral` or `in_channel`, used when the handler's reply doesn't say.
+    pub response_type: String,
+    /// The bot account public replies are posted as.
+    pub user_id: Uuid,
+    pub created_by: Option<Uuid>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Returned when a command is created; requests to its URL are signed with the secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedSlashCommand {
+    pub secret: String,
+    #[serde(flatten)]
+    pub command: SlashCommand,
+}
+
+/// A built-in or custom command, as listed for autocompletion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub usage_hint: Option<String>,
+    pub builtin: bool,
+}
+
+/// What running a command produced: a message posted to the room, a reply
+/// only the caller sees, or both.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub command: String,
+    pub message: Option<Message>,
+    pub ephemeral: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Reminder {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub room_id: Uuid,
+    pub text: String,
+    pub remind_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub message_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ScheduledMessage {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub room_id: Uuid,
+    pub content: String,
+    pub send_at: DateTime<Utc>,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub message_id: Option<Uuid>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollOption {
+    pub text: String,
+    pub votes: i64,
+    /// Who chose the option; `None` for anonymous polls.
+    pub voters: Option<Vec<Uuid>>,
+}
+
+/// A poll with its current tally. `my_votes` holds the requesting user's
+/// choices and is empty in broadcasts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Poll {
+    pub message_id: Uuid,
+    pub room_id: Uuid,
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub multiple_choice: bool,
+    pub anonymous: bool,
+    pub closes_at: Option<DateTime<Utc>>,
+    pub closed_at: Option<DateTime<Utc>>,
+    pub is_closed: bool,
+    /// Users who voted, each counted once however many options they chose.
+    pub total_voters: i64,
+    pub my_votes: Vec<i32>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Returned once when a token is created; the secret can't be retrieved later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedApiToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiToken,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PushSubscription {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub endpoint: String,
+    #[serde(skip_serializing)]
+    pub p256dh: String,
+    #[serde(skip_serializing)]
+    pub auth: String,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Do-not-disturb times are wall-clock times in the user's profile timezone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, sqlx::FromRow)]
+pub struct NotificationPreferences {
+    pub keywords: Vec<String>,
+    pub dnd_start: Option<NaiveTime>,
+    pub dnd_end: Option<NaiveTime>,
+    pub dnd_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct EmailDigestSettings {
+    /// `off`, `hourly` or `daily`.
+    pub frequency: String,
+    pub last_digest_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Bot {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bot_owner_id: Option<Uuid>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct EmailToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub purpose: String,
+    pub token_hash: String,
+    pub new_email: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Room {
     pub id: Uuid,
     pub name: String,
     pub description: Option<String>,
+    pub topic: Option<String>,
+    pub created_by: Option<Uuid>,
+    pub slow_mode_seconds: i32,
+    pub is_private: bool,
+    /// Messages older than this many days are purged.
+    pub retention_days: Option<i32>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Set when a single room is fetched.
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pinned_messages: Option<Vec<PinnedMessage>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AdminRoomView {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub room: Room,
+    pub member_count: i64,
+    pub message_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct DailyCount {
+    pub day: DateTime<Utc>,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceStats {
+    pub users: i64,
+    pub disabled_users: i64,
+    pub admins: i64,
+    pub rooms: i64,
+    pub private_rooms: i64,
+    pub messages: i64,
+    pub messages_per_day: Vec<DailyCount>,
+    pub storage_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RoomMember {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: String,
+    pub joined_at: DateTime<Utc>,
+}
+
+/// Everything up to and including `last_read_at` counts as read.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RoomReadMarker {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub last_read_message_id: Option<Uuid>,
+    pub last_read_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RoomBan {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub banned_by: Option<Uuid>,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RoomMute {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub muted_by: Option<Uuid>,
+    pub reason: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ModerationAction {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub moderator_id: Option<Uuid>,
+    pub target_user_id: Option<Uuid>,
+    pub action: String,
+    pub reason: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Message {
     pub id: Uuid,
     pub room_id: Uuid,
-    pub user_id: Uuid,
+    /// `None` once the author's account has been deleted.
+    pub user_id: Option<Uuid>,
     pub content: String,
+    /// `content` rendered from Markdown to sanitized HTML, for message types
+    /// that support formatting. `content` keeps the source for editing.
+    pub content_html: Option<String>,
     pub message_type: String,
+    pub is_bot: bool,
+    #[sqlx(json)]
+    pub mentions: Vec<MentionSpan>,
+    /// Shown instead of the author's name, for integrations posting as someone else.
+    pub author_name: Option<String>,
+    #[sqlx(json)]
+    pub attachments: Vec<MessageAttachment>,
+    /// Filled in shortly after the message is sent, followed by a
+    /// `message_updated` event.
+    #[sqlx(json)]
+    pub link_previews: Vec<LinkPreview>,
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Self-destructing messages are purged after this.
+    pub expires_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
+    /// Set on `poll` messages.
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<Poll>,
+}
+
+/// A resolved mention in a message's content. Offsets count characters
+/// (Unicode code points), `end` exclusive, and include the `@`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MentionSpan {
+    /// `user`, `room` or `here`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A Slack-style attachment: a colored card below the message text.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessageAttachment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pretext: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<AttachmentField>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub footer: Option<String>,
+}
+
+/// OpenGraph / oEmbed metadata for a link in a message.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkPreview {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AttachmentField {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub value: String,
+    /// Short fields may be laid out side by side.
+    #[serde(default)]
+    pub short: bool,
+}
+
+/// An entry in a user's mentions inbox.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct MentionInboxItem {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub message: Message,
+    pub room_name: String,
+    /// How the user was mentioned: `user`, `room` or `here`.
+    pub mention_kind: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PinnedMessage {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub message: Message,
+    pub pinned_by: Option<Uuid>,
+    pub pinned_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Bookmark {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub message: Message,
+    pub room_name: String,
+    pub note: Option<String>,
+    pub bookmarked_at: DateTime<Utc>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageWithUser {
     pub id: Uuid,
@@ -63,12 +637,85 @@ pub struct MessageWithUser {
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct FileRecord {
+    pub id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub filename: String,
+    pub size: i64,
+    /// Only set for images; other uploads are served as opaque downloads.
+    pub content_type: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub blurhash: Option<String>,
+    #[sqlx(json)]
+    pub thumbnails: Vec<Thumbnail>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A downscaled copy of an uploaded image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnail {
+    /// `small`, `medium` or `large`.
+    pub size: String,
+    pub width: i32,
+    pub height: i32,
+    pub content_type: String,
+}
+
+/// Also the content of `file` messages.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UploadResponse {
     pub id: Uuid,
     pub filename: String,
     pub url: String,
     pub size: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    /// A placeholder to show while the image loads (https://blurha.sh).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// Smallest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thumbnails: Vec<ThumbnailLink>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThumbnailLink {
+    pub size: String,
+    pub width: i32,
+    pub height: i32,
+    pub url: String,
+}
+
+impl UploadResponse {
+    pub fn new(file: &FileRecord) -> Self {
+        let url = format!("/api/files/{}", file.id);
+        Self {
+            id: file.id,
+            filename: file.filename.clone(),
+            size: file.size,
+            content_type: file.content_type.clone(),
+            width: file.width,
+            height: file.height,
+            blurhash: file.blurhash.clone(),
+            thumbnails: file
+                .thumbnails
+                .iter()
+                .map(|thumbnail| ThumbnailLink {
+                    size: thumbnail.size.clone(),
+                    width: thumbnail.width,
+                    height: thumbnail.height,
+                    url: format!("{}/thumbnails/{}", url, thumbnail.size),
+                })
+                .collect(),
+            url,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -77,9 +724,33 @@ pub struct WebSocketMessage {
     pub data: serde_json::Value,
 }
 
+impl WebSocketMessage {
+    pub fn new<T: Serialize>(message_type: &str, data: &T) -> Self {
+        Self {
+            message_type: message_type.to_string(),
+            data: serde_json::to_value(data).unwrap_or(serde_json::Value::Null),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub room_id: Uuid,
     pub content: String,
     pub message_type: Option<String>,
-}
\ No newline at end of file
+    /// Seconds until the message self-destructs.
+    pub expires_in: Option<i64>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AuditEventRecord {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    pub action: String,
+    pub actor_id: Option<Uuid>,
+    pub target_type: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub room_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: serde_json::Value,
+}
@@ -0,0 +1,859 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{get_user_by_id, AuthClaims},
+    chat::{
+        ensure_room_access, get_member_role, get_room_by_id, get_room_members,
+        join_room, leave_room, send_message,
+    },
+    error::AppError,
+    models::*,
+    outgoing_webhooks::{self, EVENT_MEMBER_JOINED, EVENT_MEMBER_LEFT},
+    retention,
+    websocket::{broadcast_to_room, disconnect_user},
+    AppState, SharedState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub const ROLE_OWNER: &str = "owner";
+pub const ROLE_MODERATOR: &str = "moderator";
+pub const ROLE_MEMBER: &str = "member";
+
+/// Longest mute a moderator can hand out (30 days).
+const MAX_MUTE_SECONDS: i64 = 30 * 24 * 60 * 60;
+/// Longest slow-mode interval (1 hour).
+const MAX_SLOW_MODE_SECONDS: i32 = 60 * 60;
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/rooms/:room_id/members", get(list_members_handler).post(add_member_handler))
+        .route("/rooms/:room_id/members/:user_id/role", put(set_role_handler))
+        .route("/rooms/:room_id/kick", post(kick_handler))
+        .route("/rooms/:room_id/bans", get(list_bans_handler).post(ban_handler))
+        .route("/rooms/:room_id/bans/:user_id", delete(unban_handler))
+        .route("/rooms/:room_id/mutes", get(list_mutes_handler).post(mute_handler))
+        .route("/rooms/:room_id/mutes/:user_id", delete(unmute_handler))
+        .route("/rooms/:room_id/slow-mode", put(slow_mode_handler))
+        .route("/rooms/:room_id/retention", put(retention_handler))
+        .route("/rooms/:room_id/moderation-log", get(moderation_log_handler))
+}
+
+pub fn is_moderator_role(role: &str) -> bool {
+    role == ROLE_OWNER || role == ROLE_MODERATOR
+}
+
+pub async fn is_banned(pool: &PgPool, room_id: Uuid, user_id: Uuid) -> Result<bool, AppError> {
+    let banned = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM room_bans WHERE room_id = $1 AND user_id = $2)"
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(banned)
+}
+
+pub async fn get_active_mute(
+    pool: &PgPool,
+    room_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<RoomMute>, AppError> {
+    let mute = sqlx::query_as::<_, RoomMute>(
+        "SELECT * FROM room_mutes WHERE room_id = $1 AND user_id = $2 AND expires_at > NOW()"
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(mute)
+}
+
+/// Checks bans, mutes and slow mode before a user posts to a room.
+pub async fn ensure_can_post(pool: &PgPool, room_id: Uuid, user_id: Uuid) -> Result<(), AppError> {
+    let room = ensure_room_access(pool, room_id, user_id).await?;
+
+    if let Some(mute) = get_active_mute(pool, room_id, user_id).await? {
+        return Err(AppError::Authorization(format!(
+            "You are muted in this room until {}",
+            mute.expires_at.to_rfc3339()
+        )));
+    }
+
+    if room.slow_mode_seconds > 0 {
+        let role = get_member_role(pool, room_id, user_id).await?;
+        if !role.as_deref().is_some_and(is_moderator_role) {
+            let last_sent = sqlx::query_scalar::<_, chrono::DateTime<Utc>>(
+                r#"
+                SELECT created_at FROM messages
+                WHERE room_id = $1 AND user_id = $2 AND message_type <> 'system'
+                ORDER BY created_at DESC
+                LIMIT 1
+                "#
+            )
+            .bind(room_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+            if let Some(last_sent) = last_sent {
+                let next_allowed = last_sent + Duration::seconds(room.slow_mode_seconds as i64);
+                let now = Utc::now();
+                if next_allowed > now {
+                    let retry_after = (next_allowed - now).num_seconds().max(1) as u64;
+                    return Err(AppError::RateLimited { retry_after });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the actor's role, failing unless they moderate the room.
+pub async fn require_moderator(pool: &PgPool, room_id: Uuid, actor_id: Uuid) -> Result<String, AppError> {
+    get_room_by_id(pool, room_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Room not found".to_string()))?;
+
+    match get_member_role(pool, room_id, actor_id).await? {
+        Some(role) if is_moderator_role(&role) => Ok(role),
+        _ => Err(AppError::Authorization("Moderator privileges required".to_string())),
+    }
+}
+
+/// Moderators may only act on regular members; owners may act on anyone but themselves.
+async fn ensure_can_target(
+    pool: &PgPool,
+    room_id: Uuid,
+    actor_id: Uuid,
+    actor_role: &str,
+    target_id: Uuid,
+) -> Result<(), AppError> {
+    if actor_id == target_id {
+        return Err(AppError::BadRequest("You cannot moderate yourself".to_string()));
+    }
+
+    let target_role = get_member_role(pool, room_id, target_id).await?;
+    let target_role = target_role.as_deref().unwrap_or(ROLE_MEMBER);
+    if target_role == ROLE_OWNER || (actor_role != ROLE_OWNER && is_moderator_role(target_role)) {
+        return Err(AppError::Authorization(
+            "You cannot moderate a user with an equal or higher role".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+struct ActionRecord<'a> {
+    room_id: Uuid,
+    moderator_id: Uuid,
+    target_user_id: Option<Uuid>,
+    action: &'a str,
+    reason: Option<&'a str>,
+    expires_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// Records the action in the room's moderation log and the audit log.
+async fn record_action(pool: &PgPool, client: &ClientInfo, record: ActionRecord<'_>) -> Result<(), AppError> {
+    let ActionRecord { room_id, moderator_id, target_user_id, action, reason, expires_at } = record;
+
+    sqlx::query(
+        r#"
+        INSERT INTO moderation_actions (id, room_id, moderator_id, target_user_id, action, reason, expires_at, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(room_id)
+    .bind(moderator_id)
+    .bind(target_user_id)
+    .bind(action)
+    .bind(reason)
+    .bind(expires_at)
+    .bind(Utc::now())
+    .execute(pool)
+    .await?;
+
+    let mut event = AuditEvent::new(audit_action(action))
+        .actor(moderator_id)
+        .room(room_id)
+        .metadata(serde_json::json!({ "reason": reason, "expires_at": expires_at }));
+    if let Some(target_user_id) = target_user_id {
+        event = event.target("user", target_user_id);
+    }
+    audit::record(pool, client, event).await;
+
+    Ok(())
+}
+
+fn audit_action(action: &str) -> &'static str {
+    match action {
+        "set_role" => "room.member_role",
+        "invite" => "room.member_add",
+        "kick" => "moderation.kick",
+        "ban" => "moderation.ban",
+        "unban" => "moderation.unban",
+        "mute" => "moderation.mute",
+        "unmute" => "moderation.unmute",
+        "slow_mode" => "moderation.slow_mode",
+        "retention" => "room.retention",
+        _ => "moderation.other",
+    }
+}
+
+/// Posts a system message to the room and broadcasts it.
+pub async fn post_system_message(
+    state: &AppState,
+    room_id: Uuid,
+    actor_id: Uuid,
+    text: &str,
+) -> Result<(), AppError> {
+    let message = send_message(state, room_id, actor_id, text, "system").await?;
+    broadcast_to_room(state, room_id, &WebSocketMessage::new("new_message", &message)).await;
+    Ok(())
+}
+
+async fn username_of(pool: &PgPool, user_id: Uuid) -> Result<String, AppError> {
+    get_user_by_id(pool, user_id)
+        .await?
+        .map(|user| user.username)
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))
+}
+
+fn with_reason(text: String, reason: Option<&str>) -> String {
+    match reason {
+        Some(reason) if !reason.trim().is_empty() => format!("{} ({})", text, reason.trim()),
+        _ => text,
+    }
+}
+
+fn format_duration(seconds: i64) -> String {
+    match seconds {
+        s if s % 86400 == 0 => format!("{} day(s)", s / 86400),
+        s if s % 3600 == 0 => format!("{} hour(s)", s / 3600),
+        s if s % 60 == 0 => format!("{} minute(s)", s / 60),
+        s => format!("{} second(s)", s),
+    }
+}
+
+async fn list_members_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<RoomMember>>, AppError> {
+    ensure_room_access(&state.db, room_id, claims.user_id()?).await?;
+    let members = get_room_members(&state.db, room_id).await?;
+    Ok(Json(members))
+}
+
+#[derive(Deserialize)]
+struct AddMemberRequest {
+    user_id: Uuid,
+}
+
+/// Adds a user to the room on a moderator's behalf. Returns whether they
+/// weren't a member yet.
+pub async fn add_member(
+    state: &AppState,
+    client: &ClientInfo,
+    room_id: Uuid,
+    actor: &AuthClaims,
+    user_id: Uuid,
+) -> Result<bool, AppError> {
+    let actor_id = actor.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    let username = username_of(&state.db, user_id).await?;
+    if is_banned(&state.db, room_id, user_id).await? {
+        return Err(AppError::BadRequest("User is banned from this room".to_string()));
+    }
+
+    if !join_room(&state.db, room_id, user_id).await? {
+        return Ok(false);
+    }
+    outgoing_webhooks::dispatch_member(&state.db, room_id, EVENT_MEMBER_JOINED, user_id, None).await;
+    record_action(
+        &state.db,
+        client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: Some(user_id),
+            action: "invite",
+            reason: None,
+            expires_at: None,
+        },
+    ).await?;
+    post_system_message(
+        state,
+        room_id,
+        actor_id,
+        &format!("{} was added by {}", username, actor.username),
+    ).await?;
+
+    Ok(true)
+}
+
+async fn add_member_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<AddMemberRequest>,
+) -> Result<Json<Vec<RoomMember>>, AppError> {
+    add_member(&state, &client, room_id, &claims, req.user_id).await?;
+    let members = get_room_members(&state.db, room_id).await?;
+    Ok(Json(members))
+}
+
+#[derive(Deserialize)]
+struct SetRoleRequest {
+    role: String,
+}
+
+async fn set_role_handler(
+    Path((room_id, user_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<SetRoleRequest>,
+) -> Result<Json<RoomMember>, AppError> {
+    let actor_id = claims.user_id()?;
+    let actor_role = require_moderator(&state.db, room_id, actor_id).await?;
+    if actor_role != ROLE_OWNER {
+        return Err(AppError::Authorization("Only the room owner can change roles".to_string()));
+    }
+    if req.role != ROLE_MODERATOR && req.role != ROLE_MEMBER {
+        return Err(AppError::Validation("Role must be 'moderator' or 'member'".to_string()));
+    }
+    if actor_id == user_id {
+        return Err(AppError::BadRequest("You cannot change your own role".to_string()));
+    }
+
+    let result = sqlx::query("UPDATE room_members SET role = $3 WHERE room_id = $1 AND user_id = $2")
+        .bind(room_id)
+        .bind(user_id)
+        .bind(&req.role)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User is not a member of this room".to_string()));
+    }
+
+    record_action(
+        &state.db,
+        &client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: Some(user_id),
+            action: "set_role",
+            reason: Some(&req.role),
+            expires_at: None,
+        },
+    ).await?;
+
+    let username = username_of(&state.db, user_id).await?;
+    post_system_message(
+        &state,
+        room_id,
+        actor_id,
+        &format!("{} is now a {} (set by {})", username, req.role, claims.username),
+    ).await?;
+
+    let member = get_room_members(&state.db, room_id)
+        .await?
+        .into_iter()
+        .find(|member| member.user_id == user_id)
+        .ok_or_else(|| AppError::NotFound("User is not a member of this room".to_string()))?;
+    Ok(Json(member))
+}
+
+#[derive(Deserialize)]
+struct ModerationRequest {
+    user_id: Uuid,
+    reason: Option<String>,
+}
+
+/// Removes a user from the room and closes their sockets for it.
+pub async fn kick(
+    state: &AppState,
+    client: &ClientInfo,
+    room_id: Uuid,
+    actor: &AuthClaims,
+    user_id: Uuid,
+    reason: Option<&str>,
+) -> Result<(), AppError> {
+    let actor_id = actor.user_id()?;
+    let actor_role = require_moderator(&state.db, room_id, actor_id).await?;
+    ensure_can_target(&state.db, room_id, actor_id, &actor_role, user_id).await?;
+
+    let username = username_of(&state.db, user_id).await?;
+    if leave_room(&state.db, room_id, user_id).await? {
+        outgoing_webhooks::dispatch_member(&state.db, room_id, EVENT_MEMBER_LEFT, user_id, Some("kick")).await;
+    }
+    disconnect_user(state, user_id, Some(room_id), "kicked").await;
+
+    record_action(
+        &state.db,
+        client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: Some(user_id),
+            action: "kick",
+            reason,
+            expires_at: None,
+        },
+    ).await?;
+    post_system_message(
+        state,
+        room_id,
+        actor_id,
+        &with_reason(format!("{} was kicked by {}", username, actor.username), reason),
+    ).await?;
+
+    Ok(())
+}
+
+async fn kick_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<ModerationRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    kick(&state, &client, room_id, &claims, req.user_id, req.reason.as_deref()).await?;
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+async fn list_bans_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<RoomBan>>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+
+    let bans = sqlx::query_as::<_, RoomBan>(
+        "SELECT * FROM room_bans WHERE room_id = $1 ORDER BY created_at DESC"
+    )
+    .bind(room_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(bans))
+}
+
+async fn ban_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<ModerationRequest>,
+) -> Result<Json<RoomBan>, AppError> {
+    let actor_id = claims.user_id()?;
+    let actor_role = require_moderator(&state.db, room_id, actor_id).await?;
+    ensure_can_target(&state.db, room_id, actor_id, &actor_role, req.user_id).await?;
+
+    let username = username_of(&state.db, req.user_id).await?;
+    let ban = sqlx::query_as::<_, RoomBan>(
+        r#"
+        INSERT INTO room_bans (room_id, user_id, banned_by, reason, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (room_id, user_id)
+        DO UPDATE SET banned_by = EXCLUDED.banned_by, reason = EXCLUDED.reason, created_at = EXCLUDED.created_at
+        RETURNING *
+        "#
+    )
+    .bind(room_id)
+    .bind(req.user_id)
+    .bind(actor_id)
+    .bind(req.reason.as_deref())
+    .bind(Utc::now())
+    .fetch_one(&state.db)
+    .await?;
+
+    if leave_room(&state.db, room_id, req.user_id).await? {
+        outgoing_webhooks::dispatch_member(&state.db, room_id, EVENT_MEMBER_LEFT, req.user_id, Some("ban")).await;
+    }
+    disconnect_user(&state, req.user_id, Some(room_id), "banned").await;
+
+    record_action(
+        &state.db,
+        &client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: Some(req.user_id),
+            action: "ban",
+            reason: req.reason.as_deref(),
+            expires_at: None,
+        },
+    ).await?;
+    post_system_message(
+        &state,
+        room_id,
+        actor_id,
+        &with_reason(format!("{} was banned by {}", username, claims.username), req.reason.as_deref()),
+    ).await?;
+
+    Ok(Json(ban))
+}
+
+async fn unban_handler(
+    Path((room_id, user_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    let result = sqlx::query("DELETE FROM room_bans WHERE room_id = $1 AND user_id = $2")
+        .bind(room_id)
+        .bind(user_id)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User is not banned".to_string()));
+    }
+
+    let username = username_of(&state.db, user_id).await?;
+    record_action(
+        &state.db,
+        &client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: Some(user_id),
+            action: "unban",
+            reason: None,
+            expires_at: None,
+        },
+    ).await?;
+    post_system_message(
+        &state,
+        room_id,
+        actor_id,
+        &format!("{} was unbanned by {}", username, claims.username),
+    ).await?;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+async fn list_mutes_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<RoomMute>>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+
+    let mutes = sqlx::query_as::<_, RoomMute>(
+        "SELECT * FROM room_mutes WHERE room_id = $1 AND expires_at > NOW() ORDER BY expires_at"
+    )
+    .bind(room_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(mutes))
+}
+
+#[derive(Deserialize)]
+struct MuteRequest {
+    user_id: Uuid,
+    duration_seconds: i64,
+    reason: Option<String>,
+}
+
+/// Mutes a user in the room for `duration_seconds`, replacing any earlier mute.
+pub async fn mute(
+    state: &AppState,
+    client: &ClientInfo,
+    room_id: Uuid,
+    actor: &AuthClaims,
+    user_id: Uuid,
+    duration_seconds: i64,
+    reason: Option<&str>,
+) -> Result<RoomMute, AppError> {
+    let actor_id = actor.user_id()?;
+    let actor_role = require_moderator(&state.db, room_id, actor_id).await?;
+    ensure_can_target(&state.db, room_id, actor_id, &actor_role, user_id).await?;
+
+    if duration_seconds <= 0 || duration_seconds > MAX_MUTE_SECONDS {
+        return Err(AppError::Validation(format!(
+            "Mute duration must be between 1 and {} seconds",
+            MAX_MUTE_SECONDS
+        )));
+    }
+
+    let username = username_of(&state.db, user_id).await?;
+    let now = Utc::now();
+    let expires_at = now + Duration::seconds(duration_seconds);
+
+    let mute = sqlx::query_as::<_, RoomMute>(
+        r#"
+        INSERT INTO room_mutes (room_id, user_id, muted_by, reason, expires_at, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (room_id, user_id)
+        DO UPDATE SET muted_by = EXCLUDED.muted_by, reason = EXCLUDED.reason,
+                      expires_at = EXCLUDED.expires_at, created_at = EXCLUDED.created_at
+        RETURNING *
+        "#
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .bind(actor_id)
+    .bind(reason)
+    .bind(expires_at)
+    .bind(now)
+    .fetch_one(&state.db)
+    .await?;
+
+    record_action(
+        &state.db,
+        client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: Some(user_id),
+            action: "mute",
+            reason,
+            expires_at: Some(expires_at),
+        },
+    ).await?;
+    post_system_message(
+        state,
+        room_id,
+        actor_id,
+        &with_reason(
+            format!(
+                "{} was muted for {} by {}",
+                username,
+                format_duration(duration_seconds),
+                actor.username
+            ),
+            reason,
+        ),
+    ).await?;
+
+    Ok(mute)
+}
+
+async fn mute_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<MuteRequest>,
+) -> Result<Json<RoomMute>, AppError> {
+    let mute = mute(
+        &state,
+        &client,
+        room_id,
+        &claims,
+        req.user_id,
+        req.duration_seconds,
+        req.reason.as_deref(),
+    ).await?;
+    Ok(Json(mute))
+}
+
+async fn unmute_handler(
+    Path((room_id, user_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    let result = sqlx::query(
+        "DELETE FROM room_mutes WHERE room_id = $1 AND user_id = $2 AND expires_at > NOW()"
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .execute(&state.db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("User is not muted".to_string()));
+    }
+
+    let username = username_of(&state.db, user_id).await?;
+    record_action(
+        &state.db,
+        &client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: Some(user_id),
+            action: "unmute",
+            reason: None,
+            expires_at: None,
+        },
+    ).await?;
+    post_system_message(
+        &state,
+        room_id,
+        actor_id,
+        &format!("{} was unmuted by {}", username, claims.username),
+    ).await?;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[derive(Deserialize)]
+struct SlowModeRequest {
+    seconds: i32,
+}
+
+async fn slow_mode_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<SlowModeRequest>,
+) -> Result<Json<Room>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    if req.seconds < 0 || req.seconds > MAX_SLOW_MODE_SECONDS {
+        return Err(AppError::Validation(format!(
+            "Slow mode interval must be between 0 and {} seconds",
+            MAX_SLOW_MODE_SECONDS
+        )));
+    }
+
+    let room = sqlx::query_as::<_, Room>(
+        "UPDATE rooms SET slow_mode_seconds = $2, updated_at = NOW() WHERE id = $1 RETURNING *"
+    )
+    .bind(room_id)
+    .bind(req.seconds)
+    .fetch_one(&state.db)
+    .await?;
+
+    record_action(
+        &state.db,
+        &client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: None,
+            action: "slow_mode",
+            reason: Some(&req.seconds.to_string()),
+            expires_at: None,
+        },
+    ).await?;
+    let text = if req.seconds == 0 {
+        format!("Slow mode was disabled by {}", claims.username)
+    } else {
+        format!(
+            "Slow mode was set to {} by {}",
+            format_duration(req.seconds as i64),
+            claims.username
+        )
+    };
+    post_system_message(&state, room_id, actor_id, &text).await?;
+
+    Ok(Json(room))
+}
+
+#[derive(Deserialize)]
+struct RetentionRequest {
+    /// `None` keeps history forever.
+    days: Option<i32>,
+}
+
+/// Only the owner can set how long the room keeps messages; older ones are
+/// purged by the retention job.
+async fn retention_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<RetentionRequest>,
+) -> Result<Json<Room>, AppError> {
+    let actor_id = claims.user_id()?;
+    let actor_role = require_moderator(&state.db, room_id, actor_id).await?;
+    if actor_role != ROLE_OWNER {
+        return Err(AppError::Authorization("Only the room owner can change message retention".to_string()));
+    }
+    if req.days.is_some_and(|days| !(1..=retention::MAX_RETENTION_DAYS).contains(&days)) {
+        return Err(AppError::Validation(format!(
+            "Retention must be between 1 and {} days",
+            retention::MAX_RETENTION_DAYS
+        )));
+    }
+
+    let room = sqlx::query_as::<_, Room>(
+        "UPDATE rooms SET retention_days = $2, updated_at = NOW() WHERE id = $1 RETURNING *"
+    )
+    .bind(room_id)
+    .bind(req.days)
+    .fetch_one(&state.db)
+    .await?;
+
+    record_action(
+        &state.db,
+        &client,
+        ActionRecord {
+            room_id,
+            moderator_id: actor_id,
+            target_user_id: None,
+            action: "retention",
+            reason: req.days.map(|days| days.to_string()).as_deref(),
+            expires_at: None,
+        },
+    ).await?;
+    let text = match req.days {
+        Some(days) => format!(
+            "{} set messages to be deleted after {}",
+            claims.username,
+            format_duration(days as i64 * 86400)
+        ),
+        None => format!("{} turned off message retention; history is kept", claims.username),
+    };
+    post_system_message(&state, room_id, actor_id, &text).await?;
+    broadcast_to_room(&state, room_id, &WebSocketMessage::new("room_updated", &room)).await;
+
+    Ok(Json(room))
+}
+
+#[derive(Deserialize)]
+struct ModerationLogQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+async fn moderation_log_handler(
+    Path(room_id): Path<Uuid>,
+    Query(query): Query<ModerationLogQuery>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<ModerationAction>>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+
+    let actions = sqlx::query_as::<_, ModerationAction>(
+        r#"
+        SELECT * FROM moderation_actions
+        WHERE room_id = $1
+        ORDER BY created_at DESC
+        LIMIT $2 OFFSET $3
+        "#
+    )
+    .bind(room_id)
+    .bind(query.limit.unwrap_or(50).clamp(1, 200))
+    .bind(query.offset.unwrap_or(0).max(0))
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(actions))
+}
@@ -0,0 +1,992 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{check_can_sign_in, complete_login, create_external_user, generate_secret, get_user_by_id, AuthClaims},
+    error::AppError,
+    models::*,
+    verification, AppState, SharedState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{COOKIE, SET_COOKIE},
+        HeaderMap,
+    },
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+use uuid::Uuid;
+
+const LOGIN_STATE_TTL_MINUTES: i64 = 10;
+/// Holds the `state` of the flow this browser started. The callback must
+/// come with it, so a callback URL can't be completed in someone else's
+/// browser (login CSRF, or linking an attacker's identity to a victim).
+const STATE_COOKIE: &str = "konect_oidc_state";
+const STATE_COOKIE_PATH: &str = "/api/auth/oidc/callback";
+const HTTP_TIMEOUT_SECONDS: u64 = 10;
+/// Allowed clock difference with the identity provider when checking `exp`.
+const CLOCK_SKEW_SECONDS: u64 = 60;
+
+/// ID tokens must be signed with an asymmetric key published in the provider's JWKS.
+const ALLOWED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+pub struct OidcConfig {
+    /// Name stored with linked identities, e.g. "oidc" or "okta".
+    pub provider: String,
+    /// Label for the sign-in button.
+    pub display_name: String,
+    pub issuer: String,
+    pub client_id: String,
+    /// Omitted for public clients, which rely on PKCE alone.
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub scopes: String,
+    pub username_claim: String,
+    pub email_claim: String,
+    /// Create accounts on first sign-in instead of requiring an existing link.
+    pub auto_provision: bool,
+}
+
+impl OidcConfig {
+    /// Single sign-on is enabled by setting `OIDC_ISSUER` and `OIDC_CLIENT_ID`.
+    fn from_env(base_url: &str) -> Option<Self> {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        let issuer = env("OIDC_ISSUER")?;
+        let client_id = env("OIDC_CLIENT_ID")?;
+
+        Some(Self {
+            provider: env("OIDC_PROVIDER").unwrap_or_else(|| "oidc".to_string()),
+            display_name: env("OIDC_DISPLAY_NAME").unwrap_or_else(|| "Single sign-on".to_string()),
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: env("OIDC_CLIENT_SECRET"),
+            redirect_url: env("OIDC_REDIRECT_URL")
+                .unwrap_or_else(|| format!("{}/api/auth/oidc/callback", base_url)),
+            scopes: env("OIDC_SCOPES").unwrap_or_else(|| "openid email profile".to_string()),
+            username_claim: env("OIDC_USERNAME_CLAIM").unwrap_or_else(|| "preferred_username".to_string()),
+            email_claim: env("OIDC_EMAIL_CLAIM").unwrap_or_else(|| "email".to_string()),
+            auto_provision: env("OIDC_AUTO_PROVISION").is_none_or(|value| value != "false" && value != "0"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// An OpenID Connect relying party. Discovery and signing keys are fetched on
+/// first use and cached; keys are refetched when an unknown key ID shows up.
+pub struct Oidc {
+    pub config: OidcConfig,
+    http: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+impl Oidc {
+    pub fn from_env(base_url: &str) -> anyhow::Result<Option<Self>> {
+        let Some(config) = OidcConfig::from_env(base_url) else {
+            return Ok(None);
+        };
+        info!("Single sign-on enabled with issuer {}", config.issuer);
+        Self::new(config).map(Some)
+    }
+
+    pub fn new(config: OidcConfig) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(HTTP_TIMEOUT_SECONDS))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        Ok(Self {
+            config,
+            http,
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(None),
+        })
+    }
+
+    async fn fetch_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, AppError> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| AppError::InternalError(format!("Identity provider request failed: {}", e)))?
+            .json::<T>()
+            .await
+            .map_err(|e| AppError::InternalError(format!("Invalid identity provider response: {}", e)))
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata, AppError> {
+        if let Some(metadata) = self.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let url = format!("{}/.well-known/openid-configuration", self.config.issuer);
+        let metadata: ProviderMetadata = self.fetch_json(&url).await?;
+
+        // The discovery document must describe the issuer we were configured with
+        if metadata.issuer.trim_end_matches('/') != self.config.issuer {
+            return Err(AppError::InternalError(format!(
+                "Discovered issuer {} does not match OIDC_ISSUER",
+                metadata.issuer
+            )));
+        }
+
+        *self.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn signing_key(&self, kid: Option<&str>) -> Result<DecodingKey, AppError> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+
+        let cached = self.jwks.read().await.as_ref().and_then(find);
+        let jwk = match cached {
+            Some(jwk) => jwk,
+            None => {
+                // The provider may have rotated its keys
+                let jwks: JwkSet = self.fetch_json(&self.metadata().await?.jwks_uri).await?;
+                let jwk = find(&jwks);
+                *self.jwks.write().await = Some(jwks);
+                jwk.ok_or_else(|| AppError::Auth("ID token signed with an unknown key".to_string()))?
+            }
+        };
+
+        Ok(DecodingKey::from_jwk(&jwk)?)
+    }
+
+    async fn authorization_url(&self, state: &str, nonce: &str, code_verifier: &str) -> Result<String, AppError> {
+        let metadata = self.metadata().await?;
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        let mut url = Url::parse(&metadata.authorization_endpoint)
+            .map_err(|e| AppError::InternalError(format!("Invalid authorization endpoint: {}", e)))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(url.into())
+    }
+
+    /// Redeems the authorization code and returns the validated ID token claims.
+    async fn exchange_code(&self, code: &str, code_verifier: &str, nonce: &str) -> Result<Map<String, Value>, AppError> {
+        let metadata = self.metadata().await?;
+
+        let mut request = self.http.post(&metadata.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ]);
+        if let Some(secret) = &self.config.client_secret {
+            request = request.basic_auth(&self.config.client_id, Some(secret));
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| AppError::InternalError(format!("Token request failed: {}", e)))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            error!("Token endpoint returned {}: {}", status, body);
+            return Err(AppError::Auth("The identity provider rejected the sign-in".to_string()));
+        }
+        let tokens: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| AppError::InternalError(format!("Invalid token response: {}", e)))?;
+
+        self.validate_id_token(&tokens.id_token, &metadata.issuer, nonce).await
+    }
+
+    async fn validate_id_token(&self, id_token: &str, issuer: &str, nonce: &str) -> Result<Map<String, Value>, AppError> {
+        let header = decode_header(id_token)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(AppError::Auth("ID token uses an unsupported signing algorithm".to_string()));
+        }
+        let key = self.signing_key(header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = CLOCK_SKEW_SECONDS;
+
+        let claims = decode::<Map<String, Value>>(id_token, &key, &validation)?.claims;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(AppError::Auth("ID token nonce does not match".to_string()));
+        }
+        // With several audiences, the token must have been issued to us
+        if claims.get("aud").and_then(Value::as_array).is_some_and(|aud| aud.len() > 1)
+            && claims.get("azp").and_then(Value::as_str) != Some(self.config.client_id.as_str())
+        {
+            return Err(AppError::Auth("ID token was issued to another client".to_string()));
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Unauthenticated endpoints, mounted under `/api/auth`.
+pub fn public_router() -> Router<SharedState> {
+    Router::new()
+        .route("/oidc", get(provider_handler))
+        .route("/oidc/login", get(login_handler))
+        .route("/oidc/callback", get(callback_handler))
+}
+
+/// Endpoints for signed-in users, mounted under `/api`.
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/users/me/identities", get(list_identities_handler))
+        .route("/users/me/identities/oidc", post(link_handler))
+        .route("/users/me/identities/:identity_id", delete(unlink_handler))
+}
+
+fn provider(state: &AppState) -> Result<&Oidc, AppError> {
+    state
+        .oidc
+        .as_deref()
+        .ok_or_else(|| AppError::NotFound("Single sign-on is not configured".to_string()))
+}
+
+/// A login started by `begin_login`: where to send the browser, and the
+/// cookie that ties the callback to it.
+struct PendingLogin {
+    authorization_url: String,
+    cookie: String,
+}
+
+/// Records a new authorization request.
+async fn begin_login(state: &AppState, link_user_id: Option<Uuid>) -> Result<PendingLogin, AppError> {
+    let oidc = provider(state)?;
+    let login_state = generate_secret();
+    let nonce = generate_secret();
+    let code_verifier = generate_secret();
+
+    sqlx::query("DELETE FROM oidc_login_states WHERE expires_at < NOW()")
+        .execute(&state.db)
+        .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO oidc_login_states (state, code_verifier, nonce, link_user_id, expires_at, created_at)
+        VALUES ($1, $2, $3, $4, $5, NOW())
+        "#
+    )
+    .bind(&login_state)
+    .bind(&code_verifier)
+    .bind(&nonce)
+    .bind(link_user_id)
+    .bind(Utc::now() + Duration::minutes(LOGIN_STATE_TTL_MINUTES))
+    .execute(&state.db)
+    .await?;
+
+    Ok(PendingLogin {
+        authorization_url: oidc.authorization_url(&login_state, &nonce, &code_verifier).await?,
+        cookie: state_cookie(state, &login_state, LOGIN_STATE_TTL_MINUTES * 60),
+    })
+}
+
+fn state_cookie(state: &AppState, value: &str, max_age_seconds: i64) -> String {
+    let secure = if state.mailer.base_url.starts_with("https://") { "; Secure" } else { "" };
+    format!(
+        "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        STATE_COOKIE, value, STATE_COOKIE_PATH, max_age_seconds, secure
+    )
+}
+
+fn state_from_cookies(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            (name == STATE_COOKIE).then(|| value.to_string())
+        })
+}
+
+/// Usernames are taken from the configured claim, falling back to the local
+/// part of the email address, and reduced to characters safe in mentions.
+fn map_username(config: &OidcConfig, claims: &Map<String, Value>, email: &str) -> String {
+    let claimed = claims
+        .get(&config.username_claim)
+        .and_then(Value::as_str)
+        .filter(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+
+    let username: String = claimed
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        .take(32)
+        .collect();
+
+    if username.is_empty() {
+        "user".to_string()
+    } else {
+        username
+    }
+}
+
+async fn find_identity(pool: &PgPool, provider: &str, subject: &str) -> Result<Option<UserIdentity>, AppError> {
+    let identity = sqlx::query_as::<_, UserIdentity>(
+        "SELECT * FROM user_identities WHERE provider = $1 AND subject = $2"
+    )
+    .bind(provider)
+    .bind(subject)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(identity)
+}
+
+pub async fn link_identity(
+    pool: &PgPool,
+    user_id: Uuid,
+    provider: &str,
+    subject: &str,
+    email: Option<&str>,
+) -> Result<UserIdentity, AppError> {
+    if let Some(existing) = find_identity(pool, provider, subject).await? {
+        if existing.user_id != user_id {
+            return Err(AppError::Validation(
+                "This identity is already linked to another account".to_string(),
+            ));
+        }
+        return Ok(existing);
+    }
+
+    let identity = sqlx::query_as::<_, UserIdentity>(
+        r#"
+        INSERT INTO user_identities (id, user_id, provider, subject, email, created_at, last_login_at)
+        VALUES ($1, $2, $3, $4, $5, NOW(), NOW())
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(provider)
+    .bind(subject)
+    .bind(email)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(identity)
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+struct LoginState {
+    code_verifier: String,
+    nonce: String,
+    link_user_id: Option<Uuid>,
+}
+
+/// What the callback did, so the browser can be sent to the right place.
+enum CallbackOutcome {
+    SignedIn(LoginResponse),
+    Linked,
+}
+
+/// `browser_state` is the state cookie the callback request carried.
+async fn handle_callback(
+    state: &AppState,
+    client: &ClientInfo,
+    query: CallbackQuery,
+    browser_state: Option<&str>,
+) -> Result<CallbackOutcome, AppError> {
+    let oidc = provider(state)?;
+
+    if let Some(error) = query.error {
+        return Err(AppError::Auth(query.error_description.unwrap_or(error)));
+    }
+    let (Some(code), Some(login_state)) = (query.code, query.state) else {
+        return Err(AppError::BadRequest("Missing code or state".to_string()));
+    };
+    if browser_state != Some(login_state.as_str()) {
+        return Err(AppError::Auth(
+            "Sign-in was started in another browser or has expired, please try again".to_string(),
+        ));
+    }
+
+    // Each state is good for exactly one callback
+    let pending = sqlx::query_as::<_, (String, String, Option<Uuid>)>(
+        r#"
+        DELETE FROM oidc_login_states
+        WHERE state = $1 AND expires_at > NOW()
+        RETURNING code_verifier, nonce, link_user_id
+        "#
+    )
+    .bind(&login_state)
+    .fetch_optional(&state.db)
+    .await?
+    .map(|(code_verifier, nonce, link_user_id)| LoginState {
+        code_verifier,
+        nonce,
+        link_user_id,
+    })
+    .ok_or_else(|| AppError::Auth("Sign-in request expired, please try again".to_string()))?;
+
+    let claims = oidc.exchange_code(&code, &pending.code_verifier, &pending.nonce).await?;
+    let subject = claims
+        .get("sub")
+        .and_then(Value::as_str)
+        .ok_or_else(|| AppError::Auth("ID token has no subject".to_string()))?;
+    let email = claims.get(&oidc.config.email_claim).and_then(Value::as_str);
+    let provider_name = oidc.config.provider.as_str();
+
+    if let Some(user_id) = pending.link_user_id {
+        let identity = link_identity(&state.db, user_id, provider_name, subject, email).await?;
+        audit::record(
+            &state.db,
+            client,
+            AuditEvent::new("auth.identity_linked")
+                .actor(user_id)
+                .target("user", user_id)
+                .metadata(serde_json::json!({ "provider": identity.provider, "subject": identity.subject })),
+        ).await;
+        return Ok(CallbackOutcome::Linked);
+    }
+
+    let user = match find_identity(&state.db, provider_name, subject).await? {
+        Some(identity) => {
+            sqlx::query("UPDATE user_identities SET last_login_at = NOW(), email = COALESCE($2, email) WHERE id = $1")
+                .bind(identity.id)
+                .bind(email)
+                .execute(&state.db)
+                .await?;
+            get_user_by_id(&state.db, identity.user_id)
+                .await?
+                .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?
+        }
+        None => provision_user(state, client, oidc, &claims, subject, email).await?,
+    };
+
+    // The provider stands in for the password; the rest of the login is the same
+    check_can_sign_in(&user)?;
+    let metadata = serde_json::json!({ "method": "oidc", "provider": provider_name });
+    let login = complete_login(&state.db, client, &user, Some(metadata)).await?;
+    Ok(CallbackOutcome::SignedIn(login))
+}
+
+/// Just-in-time provisioning for identities that aren't linked to an account yet.
+async fn provision_user(
+    state: &AppState,
+    client: &ClientInfo,
+    oidc: &Oidc,
+    claims: &Map<String, Value>,
+    subject: &str,
+    email: Option<&str>,
+) -> Result<User, AppError> {
+    if !oidc.config.auto_provision {
+        return Err(AppError::Authorization(
+            "No account is linked to this identity. Sign in with your password and link it first".to_string(),
+        ));
+    }
+    let email = email.ok_or_else(|| {
+        AppError::Validation("The identity provider did not share an email address".to_string())
+    })?;
+
+    // Never attach to an existing account by email alone; the owner must link it
+    let email_taken = sqlx::query_scalar::<_, Uuid>("SELECT id FROM users WHERE LOWER(email) = LOWER($1)")
+        .bind(email)
+        .fetch_optional(&state.db)
+        .await?
+        .is_some();
+    if email_taken {
+        return Err(AppError::Validation(
+            "An account with this email already exists. Sign in with your password and link it first".to_string(),
+        ));
+    }
+
+    let email_verified = claims.get("email_verified").and_then(Value::as_bool).unwrap_or(false);
+    let username = map_username(&oidc.config, claims, email);
+    let user = create_external_user(&state.db, &username, email, email_verified).await?;
+    link_identity(&state.db, user.id, &oidc.config.provider, subject, Some(email)).await?;
+
+    audit::record(
+        &state.db,
+        client,
+        AuditEvent::new("auth.register")
+            .actor(user.id)
+            .target("user", user.id)
+            .metadata(serde_json::json!({ "method": "oidc", "provider": oidc.config.provider })),
+    ).await;
+
+    // Addresses the provider doesn't vouch for are confirmed like a registration's
+    if !email_verified {
+        verification::send_verification_email(state, &user).await?;
+    }
+
+    Ok(user)
+}
+
+/// Errors end up in the browser's address bar, so internal details are only logged.
+fn public_message(error: &AppError) -> String {
+    match error {
+        AppError::Auth(message)
+        | AppError::Authorization(message)
+        | AppError::Validation(message)
+        | AppError::BadRequest(message)
+        | AppError::NotFound(message) => message.clone(),
+        other => {
+            error!("Single sign-on failed: {}", other);
+            "Single sign-on failed".to_string()
+        }
+    }
+}
+
+async fn provider_handler(State(state): State<SharedState>) -> Json<serde_json::Value> {
+    Json(match &state.oidc {
+        Some(oidc) => serde_json::json!({ "enabled": true, "name": oidc.config.display_name }),
+        None => serde_json::json!({ "enabled": false }),
+    })
+}
+
+async fn login_handler(State(state): State<SharedState>) -> Result<impl IntoResponse, AppError> {
+    let login = begin_login(&state, None).await?;
+    Ok(([(SET_COOKIE, login.cookie)], Redirect::to(&login.authorization_url)))
+}
+
+async fn callback_handler(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let base_url = &state.mailer.base_url;
+    let browser_state = state_from_cookies(&headers);
+
+    // Tokens go in the fragment so they never reach server logs
+    let redirect = match handle_callback(&state, &client, query, browser_state.as_deref()).await {
+        Ok(CallbackOutcome::SignedIn(LoginResponse::Authenticated(session))) => {
+            Redirect::to(&format!("{}/#sso_token={}", base_url, session.token))
+        }
+        Ok(CallbackOutcome::SignedIn(LoginResponse::MfaRequired { mfa_token, enrollment_required, .. })) => {
+            Redirect::to(&format!(
+                "{}/#sso_mfa_token={}&enrollment_required={}",
+                base_url, mfa_token, enrollment_required
+            ))
+        }
+        Ok(CallbackOutcome::Linked) => Redirect::to(&format!("{}/#sso_linked", base_url)),
+        Err(e) => {
+            let message = public_message(&e);
+            match Url::parse_with_params(&format!("{}/", base_url), &[("sso_error", message)]) {
+                Ok(url) => Redirect::to(url.as_str()),
+                Err(_) => Redirect::to("/"),
+            }
+        }
+    };
+    ([(SET_COOKIE, state_cookie(&state, "", 0))], redirect).into_response()
+}
+
+async fn list_identities_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<UserIdentity>>, AppError> {
+    let identities = sqlx::query_as::<_, UserIdentity>(
+        "SELECT * FROM user_identities WHERE user_id = $1 ORDER BY created_at"
+    )
+    .bind(claims.user_id()?)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(identities))
+}
+
+/// Starts the same redirect flow as login, but the callback attaches the
+/// identity to the signed-in account instead of starting a session.
+async fn link_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<impl IntoResponse, AppError> {
+    let login = begin_login(&state, Some(claims.user_id()?)).await?;
+    Ok((
+        [(SET_COOKIE, login.cookie)],
+        Json(serde_json::json!({ "authorization_url": login.authorization_url })),
+    ))
+}
+
+async fn unlink_handler(
+    Path(identity_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user_id = claims.user_id()?;
+    let user = get_user_by_id(&state.db, user_id)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+
+    let identity = sqlx::query_as::<_, UserIdentity>(
+        "SELECT * FROM user_identities WHERE id = $1 AND user_id = $2"
+    )
+    .bind(identity_id)
+    .bind(user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Identity not found".to_string()))?;
+
+    // Keep at least one way to sign in
+    let identity_count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM user_identities WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_one(&state.db)
+        .await?;
+    if user.password_hash.is_none() && identity_count <= 1 {
+        return Err(AppError::BadRequest(
+            "Set a password before unlinking your only sign-in method".to_string(),
+        ));
+    }
+
+    sqlx::query("DELETE FROM user_identities WHERE id = $1")
+        .bind(identity.id)
+        .execute(&state.db)
+        .await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.identity_unlinked")
+            .actor(user_id)
+            .target("user", user_id)
+            .metadata(serde_json::json!({ "provider": identity.provider, "subject": identity.subject })),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use axum::{extract::Form, http::StatusCode};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use p256::{ecdsa::SigningKey, pkcs8::EncodePrivateKey};
+    use rand::rngs::OsRng;
+    use serde_json::json;
+    use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+    /// A stand-in identity provider. Instead of a login page, `authorize`
+    /// plays the browser: it reads the authorization URL and issues a code
+    /// that the token endpoint redeems for an ID token with the given claims.
+    struct MockIdp {
+        issuer: String,
+        key: EncodingKey,
+        /// Authorization codes and the PKCE challenge and claims they were issued with.
+        codes: Mutex<HashMap<String, (String, Value)>>,
+    }
+
+    impl MockIdp {
+        async fn start() -> (Arc<Self>, Oidc) {
+            let signing_key = SigningKey::random(&mut OsRng);
+            let pem = signing_key.to_pkcs8_pem(Default::default()).expect("encode key");
+            let point = signing_key.verifying_key().to_encoded_point(false);
+            let jwks = json!({ "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": "test",
+                "alg": "ES256",
+                "use": "sig",
+                "x": URL_SAFE_NO_PAD.encode(point.x().expect("x")),
+                "y": URL_SAFE_NO_PAD.encode(point.y().expect("y")),
+            }]});
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+            let issuer = format!("http://{}", listener.local_addr().expect("address"));
+            let idp = Arc::new(Self {
+                issuer: issuer.clone(),
+                key: EncodingKey::from_ec_pem(pem.as_bytes()).expect("signing key"),
+                codes: Mutex::new(HashMap::new()),
+            });
+
+            let discovery = json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+            });
+            let token_idp = Arc::clone(&idp);
+            let router = Router::new()
+                .route("/.well-known/openid-configuration", get(move || async move { Json(discovery) }))
+                .route("/jwks", get(move || async move { Json(jwks) }))
+                .route(
+                    "/token",
+                    post(move |Form(form): Form<HashMap<String, String>>| async move { token_idp.token(form) }),
+                );
+            tokio::spawn(async move {
+                axum::serve(listener, router).await.ok();
+            });
+
+            let oidc = Oidc::new(OidcConfig {
+                provider: "oidc".to_string(),
+                display_name: "Test".to_string(),
+                issuer,
+                client_id: "konect".to_string(),
+                client_secret: None,
+                redirect_url: "http://localhost:3000/api/auth/oidc/callback".to_string(),
+                scopes: "openid email".to_string(),
+                username_claim: "preferred_username".to_string(),
+                email_claim: "email".to_string(),
+                auto_provision: true,
+            })
+            .expect("client");
+            (idp, oidc)
+        }
+
+        fn token(&self, form: HashMap<String, String>) -> Response {
+            let issued = self.codes.lock().unwrap().remove(&form["code"]);
+            let Some((challenge, claims)) = issued else {
+                return (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" }))).into_response();
+            };
+            let verifier = form.get("code_verifier").map(String::as_str).unwrap_or_default();
+            if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != challenge {
+                return (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" }))).into_response();
+            }
+
+            let mut header = Header::new(Algorithm::ES256);
+            header.kid = Some("test".to_string());
+            let id_token = encode(&header, &claims, &self.key).expect("sign ID token");
+            Json(json!({ "id_token": id_token, "token_type": "Bearer", "access_token": "unused" })).into_response()
+        }
+
+        /// Approves the sign-in the authorization URL asks for and returns
+        /// the callback's state and code. `claims` override the defaults.
+        fn authorize(&self, authorization_url: &str, claims: Value) -> (String, String) {
+            let params: HashMap<String, String> =
+                Url::parse(authorization_url).expect("URL").query_pairs().into_owned().collect();
+            let mut id_claims = json!({
+                "iss": self.issuer,
+                "aud": params["client_id"],
+                "exp": (Utc::now() + Duration::minutes(5)).timestamp(),
+                "iat": Utc::now().timestamp(),
+                "nonce": params["nonce"],
+            });
+            for (name, value) in claims.as_object().expect("claims object") {
+                id_claims[name] = value.clone();
+            }
+
+            let code = generate_secret();
+            self.codes
+                .lock()
+                .unwrap()
+                .insert(code.clone(), (params["code_challenge"].clone(), id_claims));
+            (params["state"].clone(), code)
+        }
+    }
+
+    async fn setup(pool: PgPool) -> (Arc<MockIdp>, AppState) {
+        let (idp, oidc) = MockIdp::start().await;
+        let mut state = test_support::state(pool);
+        state.oidc = Some(Arc::new(oidc));
+        (idp, state)
+    }
+
+    async fn callback(
+        state: &AppState,
+        login_state: &str,
+        code: &str,
+        browser_state: Option<&str>,
+    ) -> Result<CallbackOutcome, AppError> {
+        let query = CallbackQuery {
+            code: Some(code.to_string()),
+            state: Some(login_state.to_string()),
+            error: None,
+            error_description: None,
+        };
+        let client = ClientInfo { ip: None, user_agent: None };
+        handle_callback(state, &client, query, browser_state).await
+    }
+
+    fn alice() -> Value {
+        json!({ "sub": "alice-1", "email": "alice@example.com", "email_verified": true, "preferred_username": "alice" })
+    }
+
+    #[sqlx::test]
+    async fn callback_needs_the_cookie_of_the_browser_that_started_it(pool: PgPool) {
+        let (idp, state) = setup(pool).await;
+        let login = begin_login(&state, None).await.unwrap();
+        assert!(login.cookie.contains("HttpOnly") && login.cookie.contains("SameSite=Lax"));
+        let (login_state, code) = idp.authorize(&login.authorization_url, alice());
+
+        for browser_state in [None, Some("someone-elses-state")] {
+            let result = callback(&state, &login_state, &code, browser_state).await;
+            assert!(matches!(result, Err(AppError::Auth(_))));
+        }
+
+        // The rejected attempts didn't use up the state
+        let outcome = callback(&state, &login_state, &code, Some(&login_state)).await.unwrap();
+        assert!(matches!(outcome, CallbackOutcome::SignedIn(LoginResponse::Authenticated(_))));
+    }
+
+    #[sqlx::test]
+    async fn state_is_single_use(pool: PgPool) {
+        let (idp, state) = setup(pool).await;
+        let login = begin_login(&state, None).await.unwrap();
+        let (login_state, code) = idp.authorize(&login.authorization_url, alice());
+        callback(&state, &login_state, &code, Some(&login_state)).await.unwrap();
+
+        let replay = callback(&state, &login_state, &code, Some(&login_state)).await;
+        assert!(matches!(replay, Err(AppError::Auth(message)) if message.contains("expired")));
+    }
+
+    #[sqlx::test]
+    async fn rejects_id_tokens_for_another_nonce(pool: PgPool) {
+        let (idp, state) = setup(pool).await;
+        let login = begin_login(&state, None).await.unwrap();
+        let mut claims = alice();
+        claims["nonce"] = json!("replayed-nonce");
+        let (login_state, code) = idp.authorize(&login.authorization_url, claims);
+
+        let result = callback(&state, &login_state, &code, Some(&login_state)).await;
+        assert!(matches!(result, Err(AppError::Auth(message)) if message.contains("nonce")));
+    }
+
+    #[sqlx::test]
+    async fn code_is_only_redeemed_with_the_matching_verifier(pool: PgPool) {
+        let (idp, state) = setup(pool).await;
+        let first = begin_login(&state, None).await.unwrap();
+        let second = begin_login(&state, None).await.unwrap();
+        // A code issued for the first request, presented with the second one's verifier
+        let (_, stolen_code) = idp.authorize(&first.authorization_url, alice());
+        let second_state = Url::parse(&second.authorization_url)
+            .unwrap()
+            .query_pairs()
+            .find(|(name, _)| name == "state")
+            .unwrap()
+            .1
+            .into_owned();
+
+        let result = callback(&state, &second_state, &stolen_code, Some(&second_state)).await;
+        assert!(matches!(result, Err(AppError::Auth(message)) if message.contains("rejected")));
+    }
+
+    #[sqlx::test]
+    async fn provisions_unverified_emails_without_trusting_them(pool: PgPool) {
+        let (idp, state) = setup(pool.clone()).await;
+        let login = begin_login(&state, None).await.unwrap();
+        let mut claims = alice();
+        claims["email_verified"] = json!(false);
+        let (login_state, code) = idp.authorize(&login.authorization_url, claims);
+        callback(&state, &login_state, &code, Some(&login_state)).await.unwrap();
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = 'alice@example.com'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(user.email_verified_at.is_none());
+        assert_eq!(user.username, "alice");
+        let identity = find_identity(&pool, "oidc", "alice-1").await.unwrap().unwrap();
+        assert_eq!(identity.user_id, user.id);
+    }
+
+    #[sqlx::test]
+    async fn existing_emails_are_not_taken_over(pool: PgPool) {
+        let (idp, state) = setup(pool.clone()).await;
+        test_support::create_user(&pool, "alice").await;
+        let login = begin_login(&state, None).await.unwrap();
+        let (login_state, code) = idp.authorize(&login.authorization_url, alice());
+
+        let result = callback(&state, &login_state, &code, Some(&login_state)).await;
+        assert!(matches!(result, Err(AppError::Validation(_))));
+        assert!(find_identity(&pool, "oidc", "alice-1").await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    async fn sign_in_asks_for_the_second_factor_when_required(pool: PgPool) {
+        let (idp, state) = setup(pool.clone()).await;
+        let admin = test_support::create_user(&pool, "admin").await;
+        crate::settings::set(&pool, crate::settings::REQUIRE_TWO_FACTOR, json!(true), admin.id)
+            .await
+            .unwrap();
+
+        let login = begin_login(&state, None).await.unwrap();
+        let (login_state, code) = idp.authorize(&login.authorization_url, alice());
+        let outcome = callback(&state, &login_state, &code, Some(&login_state)).await.unwrap();
+        assert!(matches!(
+            outcome,
+            CallbackOutcome::SignedIn(LoginResponse::MfaRequired { enrollment_required: true, .. })
+        ));
+    }
+
+    #[sqlx::test]
+    async fn links_the_identity_to_the_account_that_started_the_flow(pool: PgPool) {
+        let (idp, state) = setup(pool.clone()).await;
+        let bob = test_support::create_user(&pool, "bob").await;
+        let link = begin_login(&state, Some(bob.id)).await.unwrap();
+        let mut claims = alice();
+        claims["sub"] = json!("bob-sso");
+        let (login_state, code) = idp.authorize(&link.authorization_url, claims.clone());
+
+        let outcome = callback(&state, &login_state, &code, Some(&login_state)).await.unwrap();
+        assert!(matches!(outcome, CallbackOutcome::Linked));
+        assert_eq!(find_identity(&pool, "oidc", "bob-sso").await.unwrap().unwrap().user_id, bob.id);
+
+        // Signing in with it now reaches Bob's account
+        let login = begin_login(&state, None).await.unwrap();
+        let (login_state, code) = idp.authorize(&login.authorization_url, claims.clone());
+        let outcome = callback(&state, &login_state, &code, Some(&login_state)).await.unwrap();
+        let CallbackOutcome::SignedIn(LoginResponse::Authenticated(session)) = outcome else {
+            panic!("expected a session");
+        };
+        assert_eq!(session.user.id, bob.id);
+
+        // Nobody else can claim it
+        let carol = test_support::create_user(&pool, "carol").await;
+        let link = begin_login(&state, Some(carol.id)).await.unwrap();
+        let (login_state, code) = idp.authorize(&link.authorization_url, claims);
+        let result = callback(&state, &login_state, &code, Some(&login_state)).await;
+        assert!(matches!(result, Err(AppError::Validation(_))));
+    }
+}
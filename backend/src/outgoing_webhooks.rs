@@ -0,0 +1,902 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{generate_secret, AuthClaims},
+    chat::get_member_role,
+    egress::Egress,
+    error::AppError,
+    models::*,
+    moderation::require_moderator,
+    SharedState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::time::Instant;
+use tracing::warn;
+use uuid::Uuid;
+
+pub const EVENT_MESSAGE_CREATED: &str = "message.created";
+pub const EVENT_MESSAGE_UPDATED: &str = "message.updated";
+pub const EVENT_MESSAGE_DELETED: &str = "message.deleted";
+pub const EVENT_MEMBER_JOINED: &str = "member.joined";
+pub const EVENT_MEMBER_LEFT: &str = "member.left";
+const EVENTS: &[&str] = &[
+    EVENT_MESSAGE_CREATED,
+    EVENT_MESSAGE_UPDATED,
+    EVENT_MESSAGE_DELETED,
+    EVENT_MEMBER_JOINED,
+    EVENT_MEMBER_LEFT,
+];
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_SUCCEEDED: &str = "succeeded";
+pub const STATUS_DEAD: &str = "dead";
+
+const MAX_WEBHOOKS_PER_ROOM: i64 = 10;
+const MAX_NAME_LEN: usize = 100;
+const MAX_PREFIX_LEN: usize = 100;
+/// Attempts before a delivery is moved to the dead letters.
+const MAX_ATTEMPTS: i32 = 8;
+/// Retries wait 30s, 1m, 2m, ... up to an hour, plus up to 10% jitter.
+const BASE_BACKOFF_SECONDS: i64 = 30;
+const MAX_BACKOFF_SECONDS: i64 = 3600;
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+/// How long a claimed delivery stays hidden from other workers; longer than
+/// `REQUEST_TIMEOUT` so a slow endpoint isn't called twice.
+const CLAIM_SECONDS: i64 = 60;
+const BATCH_SIZE: i64 = 20;
+const MAX_ERROR_LEN: usize = 500;
+const DEFAULT_DELIVERY_LIMIT: i64 = 50;
+const MAX_DELIVERY_LIMIT: i64 = 200;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs `{timestamp}.{body}` so receivers can check both the payload and
+/// its age.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn backoff(attempts: i32) -> Duration {
+    let seconds = (BASE_BACKOFF_SECONDS << (attempts - 1).clamp(0, 16)).min(MAX_BACKOFF_SECONDS);
+    let jitter = rand::thread_rng().gen_range(0..=seconds / 10);
+    Duration::seconds(seconds + jitter)
+}
+
+/// Whether a message passes the webhook's filters. Unset filters match
+/// everything.
+fn matches(webhook: &OutgoingWebhook, message: &Message) -> bool {
+    let prefix_matches = webhook.keyword_prefix.as_deref().is_none_or(|prefix| {
+        message
+            .content
+            .trim_start()
+            .to_lowercase()
+            .starts_with(&prefix.to_lowercase())
+    });
+    let type_matches = webhook.message_types.is_empty() || webhook.message_types.contains(&message.message_type);
+    let mention_matches = webhook.mention_user_id.is_none_or(|bot_id| {
+        message.mentions.iter().any(|span| span.user_id == Some(bot_id))
+    });
+
+    prefix_matches && type_matches && mention_matches
+}
+
+async fn subscribed(pool: &PgPool, room_id: Uuid, event: &str) -> Result<Vec<OutgoingWebhook>, AppError> {
+    let webhooks = sqlx::query_as::<_, OutgoingWebhook>(
+        "SELECT * FROM outgoing_webhooks WHERE room_id = $1 AND $2 = ANY(events) AND disabled_at IS NULL"
+    )
+    .bind(room_id)
+    .bind(event)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(webhooks)
+}
+
+async fn enqueue(
+    pool: &PgPool,
+    webhooks: &[OutgoingWebhook],
+    room_id: Uuid,
+    event: &str,
+    data: serde_json::Value,
+) -> Result<(), AppError> {
+    let now = Utc::now();
+    for webhook in webhooks {
+        let delivery_id = Uuid::new_v4();
+        let payload = serde_json::json!({
+            "id": delivery_id,
+            "event": event,
+            "webhook_id": webhook.id,
+            "room_id": room_id,
+            "timestamp": now,
+            "data": data,
+        });
+        sqlx::query(
+            "INSERT INTO webhook_deliveries (id, webhook_id, event, payload) VALUES ($1, $2, $3, $4)"
+        )
+        .bind(delivery_id)
+        .bind(webhook.id)
+        .bind(event)
+        .bind(&payload)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Queues a message event for the room's webhooks whose filters match.
+/// Messages from bots are skipped so webhooks can't answer each other in a
+/// loop. Failures are logged rather than returned: a webhook problem
+/// shouldn't fail the request that triggered it.
+pub async fn dispatch_message(pool: &PgPool, event: &str, message: &Message) {
+    if message.is_bot {
+        return;
+    }
+    let result = async {
+        let webhooks: Vec<OutgoingWebhook> = subscribed(pool, message.room_id, event)
+            .await?
+            .into_iter()
+            .filter(|webhook| matches(webhook, message))
+            .collect();
+        if webhooks.is_empty() {
+            return Ok(());
+        }
+        enqueue(pool, &webhooks, message.room_id, event, serde_json::json!({ "message": message })).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        warn!("Failed to queue {} webhooks for message {}: {}", event, message.id, e);
+    }
+}
+
+/// Queues a `member.joined` or `member.left` event. `reason` says why a
+/// member left (`kick`, `ban`).
+pub async fn dispatch_member(pool: &PgPool, room_id: Uuid, event: &str, user_id: Uuid, reason: Option<&str>) {
+    let result = async {
+        let webhooks = subscribed(pool, room_id, event).await?;
+        if webhooks.is_empty() {
+            return Ok(());
+        }
+        let username = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+        let data = serde_json::json!({
+            "user": { "id": user_id, "username": username },
+            "reason": reason,
+        });
+        enqueue(pool, &webhooks, room_id, event, data).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        warn!("Failed to queue {} webhooks for room {}: {}", event, room_id, e);
+    }
+}
+
+/// Where webhooks and slash commands may be sent. Receivers on internal
+/// hosts must be allowed with `WEBHOOK_ALLOWED_HOSTS`.
+pub fn egress() -> anyhow::Result<Egress> {
+    Egress::from_env("WEBHOOK_ALLOWED_HOSTS")
+}
+
+/// Starts the delivery worker. Deliveries are claimed with
+/// `FOR UPDATE SKIP LOCKED`, so every instance can run one.
+pub fn spawn(pool: PgPool) -> anyhow::Result<()> {
+    let egress = egress()?;
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = deliver_due(&pool, &egress).await {
+                warn!("Failed to deliver webhooks: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct ClaimedDelivery {
+    id: Uuid,
+    event: String,
+    payload: serde_json::Value,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+async fn deliver_due(pool: &PgPool, egress: &Egress) -> Result<(), AppError> {
+    loop {
+        let deliveries = sqlx::query_as::<_, ClaimedDelivery>(
+            r#"
+            WITH due AS (
+                SELECT d.id
+                FROM webhook_deliveries d
+                JOIN outgoing_webhooks w ON w.id = d.webhook_id
+                WHERE d.status = 'pending' AND d.next_attempt_at <= NOW() AND w.disabled_at IS NULL
+                ORDER BY d.next_attempt_at
+                LIMIT $1
+                FOR UPDATE OF d SKIP LOCKED
+            )
+            UPDATE webhook_deliveries d
+            SET next_attempt_at = NOW() + make_interval(secs => $2)
+            FROM due, outgoing_webhooks w
+            WHERE d.id = due.id AND w.id = d.webhook_id
+            RETURNING d.id, d.event, d.payload, d.attempts, w.url, w.secret
+            "#
+        )
+        .bind(BATCH_SIZE)
+        .bind(CLAIM_SECONDS as f64)
+        .fetch_all(pool)
+        .await?;
+
+        let claimed = deliveries.len() as i64;
+        futures_util::future::join_all(deliveries.into_iter().map(|delivery| attempt(pool, egress, delivery))).await;
+        if claimed < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+async fn attempt(pool: &PgPool, egress: &Egress, delivery: ClaimedDelivery) {
+    let body = delivery.payload.to_string();
+    let timestamp = Utc::now().timestamp();
+    let started = Instant::now();
+    let response = async {
+        let url = reqwest::Url::parse(&delivery.url)?;
+        let http = egress
+            .client(
+                reqwest::Client::builder().timeout(REQUEST_TIMEOUT).user_agent("rust-konect-webhooks"),
+                &url,
+            )
+            .await?;
+        let response = http
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Konect-Event", &delivery.event)
+            .header("X-Konect-Delivery", delivery.id.to_string())
+            .header("X-Konect-Timestamp", timestamp.to_string())
+            .header("X-Konect-Signature", signature(&delivery.secret, timestamp, &body))
+            .body(body)
+            .send()
+            .await?;
+        Ok::<_, anyhow::Error>(response)
+    }
+    .await;
+    let duration_ms = started.elapsed().as_millis().min(i32::MAX as u128) as i32;
+
+    // Only the status is kept: the response body could be anything the receiver's host serves
+    let (status_code, error) = match response {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16() as i32), None),
+        Ok(response) => (Some(response.status().as_u16() as i32), Some(format!("HTTP {}", response.status()))),
+        Err(e) => (None, Some(e.to_string().chars().take(MAX_ERROR_LEN).collect())),
+    };
+
+    let attempts = delivery.attempts + 1;
+    let (status, next_attempt_at) = match &error {
+        None => (STATUS_SUCCEEDED, Utc::now()),
+        Some(_) if attempts >= MAX_ATTEMPTS => (STATUS_DEAD, Utc::now()),
+        Some(_) => (STATUS_PENDING, Utc::now() + backoff(attempts)),
+    };
+
+    let result = async {
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_delivery_attempts (id, delivery_id, attempt, status_code, error, duration_ms)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(delivery.id)
+        .bind(attempts)
+        .bind(status_code)
+        .bind(&error)
+        .bind(duration_ms)
+        .execute(pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = $2, attempts = $3, next_attempt_at = $4, last_status_code = $5, last_error = $6,
+                delivered_at = CASE WHEN $2 = 'succeeded' THEN NOW() END, updated_at = NOW()
+            WHERE id = $1
+            "#
+        )
+        .bind(delivery.id)
+        .bind(status)
+        .bind(attempts)
+        .bind(next_attempt_at)
+        .bind(status_code)
+        .bind(&error)
+        .execute(pool)
+        .await?;
+
+        Ok::<_, AppError>(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        warn!("Failed to record webhook delivery {}: {}", delivery.id, e);
+    }
+    if status == STATUS_DEAD {
+        warn!("Webhook delivery {} failed {} times, giving up", delivery.id, attempts);
+    }
+}
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/rooms/:room_id/outgoing-webhooks", get(list_handler).post(create_handler))
+        .route("/rooms/:room_id/outgoing-webhooks/dead-letters", get(dead_letters_handler))
+        .route(
+            "/rooms/:room_id/outgoing-webhooks/:webhook_id",
+            put(update_handler).delete(delete_handler),
+        )
+        .route("/rooms/:room_id/outgoing-webhooks/:webhook_id/deliveries", get(deliveries_handler))
+        .route(
+            "/rooms/:room_id/outgoing-webhooks/:webhook_id/deliveries/:delivery_id",
+            get(delivery_handler),
+        )
+        .route(
+            "/rooms/:room_id/outgoing-webhooks/:webhook_id/deliveries/:delivery_id/retry",
+            post(retry_handler),
+        )
+}
+
+/// Webhook receivers and command handlers must be HTTPS unless
+/// `WEBHOOK_ALLOW_INSECURE_URLS` is set, for testing against a local endpoint,
+/// and on public hosts unless allowed by `egress`.
+pub async fn validate_url(url: &str) -> Result<(), AppError> {
+    let parsed = reqwest::Url::parse(url).map_err(|_| AppError::Validation("Invalid URL".to_string()))?;
+    let allow_insecure = std::env::var("WEBHOOK_ALLOW_INSECURE_URLS")
+        .is_ok_and(|value| value == "true" || value == "1");
+    match parsed.scheme() {
+        "https" => {}
+        "http" if allow_insecure => {}
+        _ => return Err(AppError::Validation("URLs must use HTTPS".to_string())),
+    }
+    let egress = egress().map_err(|e| AppError::InternalError(e.to_string()))?;
+    if let Err(e) = egress.address(&parsed).await {
+        return Err(AppError::Validation(format!("URL not allowed: {}", e)));
+    }
+    Ok(())
+}
+
+/// Everything about a webhook except its secret; updates replace all fields.
+#[derive(Deserialize)]
+struct WebhookRequest {
+    name: String,
+    url: String,
+    #[serde(default)]
+    events: Vec<String>,
+    keyword_prefix: Option<String>,
+    #[serde(default)]
+    message_types: Vec<String>,
+    /// Only messages mentioning this bot (which must be a room member).
+    mention_bot_id: Option<Uuid>,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// Validated fields ready to store.
+struct WebhookFields {
+    name: String,
+    events: Vec<String>,
+    keyword_prefix: Option<String>,
+    message_types: Vec<String>,
+}
+
+async fn validate(pool: &PgPool, room_id: Uuid, req: &WebhookRequest) -> Result<WebhookFields, AppError> {
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::Validation(format!(
+            "Webhook name must be between 1 and {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    validate_url(&req.url).await?;
+
+    let mut events: Vec<String> = Vec::new();
+    for event in &req.events {
+        if !EVENTS.contains(&event.as_str()) {
+            return Err(AppError::Validation(format!(
+                "Unknown event '{}', expected one of: {}",
+                event,
+                EVENTS.join(", ")
+            )));
+        }
+        if !events.contains(event) {
+            events.push(event.clone());
+        }
+    }
+    if events.is_empty() {
+        return Err(AppError::Validation("At least one event is required".to_string()));
+    }
+
+    let keyword_prefix = req.keyword_prefix.as_deref().map(str::trim).filter(|prefix| !prefix.is_empty());
+    if keyword_prefix.is_some_and(|prefix| prefix.chars().count() > MAX_PREFIX_LEN) {
+        return Err(AppError::Validation(format!(
+            "Keyword prefix must be at most {} characters",
+            MAX_PREFIX_LEN
+        )));
+    }
+
+    let mut message_types: Vec<String> = Vec::new();
+    for message_type in &req.message_types {
+        let message_type = message_type.trim();
+        if !message_type.is_empty() && !message_types.iter().any(|existing| existing == message_type) {
+            message_types.push(message_type.to_string());
+        }
+    }
+
+    if let Some(bot_id) = req.mention_bot_id {
+        let is_bot = sqlx::query_scalar::<_, bool>("SELECT is_bot FROM users WHERE id = $1")
+            .bind(bot_id)
+            .fetch_optional(pool)
+            .await?;
+        if is_bot != Some(true) {
+            return Err(AppError::Validation("mention_bot_id must be a bot".to_string()));
+        }
+        if get_member_role(pool, room_id, bot_id).await?.is_none() {
+            return Err(AppError::Validation("The bot must be a member of the room".to_string()));
+        }
+    }
+
+    Ok(WebhookFields {
+        name: name.to_string(),
+        events,
+        keyword_prefix: keyword_prefix.map(str::to_string),
+        message_types,
+    })
+}
+
+async fn list_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<OutgoingWebhook>>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+
+    let webhooks = sqlx::query_as::<_, OutgoingWebhook>(
+        "SELECT * FROM outgoing_webhooks WHERE room_id = $1 ORDER BY created_at"
+    )
+    .bind(room_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(webhooks))
+}
+
+async fn create_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<WebhookRequest>,
+) -> Result<Json<CreatedOutgoingWebhook>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+    let fields = validate(&state.db, room_id, &req).await?;
+
+    let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM outgoing_webhooks WHERE room_id = $1")
+        .bind(room_id)
+        .fetch_one(&state.db)
+        .await?;
+    if count >= MAX_WEBHOOKS_PER_ROOM {
+        return Err(AppError::Validation(format!(
+            "A room can have at most {} outgoing webhooks",
+            MAX_WEBHOOKS_PER_ROOM
+        )));
+    }
+
+    let secret = generate_secret();
+    let webhook = sqlx::query_as::<_, OutgoingWebhook>(
+        r#"
+        INSERT INTO outgoing_webhooks (id, room_id, name, url, secret, events, keyword_prefix, message_types,
+                                       mention_user_id, created_by, disabled_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, CASE WHEN $11 THEN NULL ELSE NOW() END)
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(room_id)
+    .bind(&fields.name)
+    .bind(&req.url)
+    .bind(&secret)
+    .bind(&fields.events)
+    .bind(&fields.keyword_prefix)
+    .bind(&fields.message_types)
+    .bind(req.mention_bot_id)
+    .bind(actor_id)
+    .bind(req.enabled)
+    .fetch_one(&state.db)
+    .await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("outgoing_webhook.create")
+            .actor(actor_id)
+            .room(room_id)
+            .target("outgoing_webhook", webhook.id)
+            .metadata(serde_json::json!({ "name": webhook.name, "url": webhook.url, "events": webhook.events })),
+    ).await;
+
+    Ok(Json(CreatedOutgoingWebhook { secret, webhook }))
+}
+
+async fn update_handler(
+    Path((room_id, webhook_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<WebhookRequest>,
+) -> Result<Json<OutgoingWebhook>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+    let fields = validate(&state.db, room_id, &req).await?;
+
+    let webhook = sqlx::query_as::<_, OutgoingWebhook>(
+        r#"
+        UPDATE outgoing_webhooks
+        SET name = $3, url = $4, events = $5, keyword_prefix = $6, message_types = $7, mention_user_id = $8,
+            disabled_at = CASE WHEN $9 THEN NULL ELSE COALESCE(disabled_at, NOW()) END,
+            updated_at = NOW()
+        WHERE id = $1 AND room_id = $2
+        RETURNING *
+        "#
+    )
+    .bind(webhook_id)
+    .bind(room_id)
+    .bind(&fields.name)
+    .bind(&req.url)
+    .bind(&fields.events)
+    .bind(&fields.keyword_prefix)
+    .bind(&fields.message_types)
+    .bind(req.mention_bot_id)
+    .bind(req.enabled)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Webhook not found".to_string()))?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("outgoing_webhook.update")
+            .actor(actor_id)
+            .room(room_id)
+            .target("outgoing_webhook", webhook.id)
+            .metadata(serde_json::json!({
+                "name": webhook.name,
+                "url": webhook.url,
+                "events": webhook.events,
+                "enabled": webhook.disabled_at.is_none(),
+            })),
+    ).await;
+
+    Ok(Json(webhook))
+}
+
+async fn delete_handler(
+    Path((room_id, webhook_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    let webhook = sqlx::query_as::<_, OutgoingWebhook>(
+        "DELETE FROM outgoing_webhooks WHERE id = $1 AND room_id = $2 RETURNING *"
+    )
+    .bind(webhook_id)
+    .bind(room_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Webhook not found".to_string()))?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("outgoing_webhook.delete")
+            .actor(actor_id)
+            .room(room_id)
+            .target("outgoing_webhook", webhook.id)
+            .metadata(serde_json::json!({ "name": webhook.name, "url": webhook.url })),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[derive(Deserialize)]
+struct DeliveriesQuery {
+    status: Option<String>,
+    limit: Option<i64>,
+}
+
+async fn deliveries_handler(
+    Path((room_id, webhook_id)): Path<(Uuid, Uuid)>,
+    Query(query): Query<DeliveriesQuery>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<WebhookDelivery>>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        SELECT d.*
+        FROM webhook_deliveries d
+        JOIN outgoing_webhooks w ON w.id = d.webhook_id
+        WHERE d.webhook_id = $1 AND w.room_id = $2 AND ($3::text IS NULL OR d.status = $3)
+        ORDER BY d.created_at DESC
+        LIMIT $4
+        "#
+    )
+    .bind(webhook_id)
+    .bind(room_id)
+    .bind(&query.status)
+    .bind(query.limit.unwrap_or(DEFAULT_DELIVERY_LIMIT).clamp(1, MAX_DELIVERY_LIMIT))
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(deliveries))
+}
+
+/// Deliveries that ran out of retries, across all of the room's webhooks.
+async fn dead_letters_handler(
+    Path(room_id): Path<Uuid>,
+    Query(query): Query<DeliveriesQuery>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<WebhookDelivery>>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        SELECT d.*
+        FROM webhook_deliveries d
+        JOIN outgoing_webhooks w ON w.id = d.webhook_id
+        WHERE w.room_id = $1 AND d.status = 'dead'
+        ORDER BY d.updated_at DESC
+        LIMIT $2
+        "#
+    )
+    .bind(room_id)
+    .bind(query.limit.unwrap_or(DEFAULT_DELIVERY_LIMIT).clamp(1, MAX_DELIVERY_LIMIT))
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(deliveries))
+}
+
+async fn find_delivery(
+    pool: &PgPool,
+    room_id: Uuid,
+    webhook_id: Uuid,
+    delivery_id: Uuid,
+) -> Result<WebhookDelivery, AppError> {
+    sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        SELECT d.*
+        FROM webhook_deliveries d
+        JOIN outgoing_webhooks w ON w.id = d.webhook_id
+        WHERE d.id = $1 AND d.webhook_id = $2 AND w.room_id = $3
+        "#
+    )
+    .bind(delivery_id)
+    .bind(webhook_id)
+    .bind(room_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Delivery not found".to_string()))
+}
+
+#[derive(Serialize)]
+struct DeliveryDetails {
+    #[serde(flatten)]
+    delivery: WebhookDelivery,
+    attempt_log: Vec<WebhookDeliveryAttempt>,
+}
+
+async fn delivery_handler(
+    Path((room_id, webhook_id, delivery_id)): Path<(Uuid, Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<DeliveryDetails>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+    let delivery = find_delivery(&state.db, room_id, webhook_id, delivery_id).await?;
+
+    let attempt_log = sqlx::query_as::<_, WebhookDeliveryAttempt>(
+        "SELECT * FROM webhook_delivery_attempts WHERE delivery_id = $1 ORDER BY created_at"
+    )
+    .bind(delivery.id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(DeliveryDetails { delivery, attempt_log }))
+}
+
+/// Queues a delivery again with a fresh set of attempts, e.g. once the
+/// receiver is fixed.
+async fn retry_handler(
+    Path((room_id, webhook_id, delivery_id)): Path<(Uuid, Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<WebhookDelivery>, AppError> {
+    require_moderator(&state.db, room_id, claims.user_id()?).await?;
+    let delivery = find_delivery(&state.db, room_id, webhook_id, delivery_id).await?;
+    if delivery.status != STATUS_DEAD {
+        return Err(AppError::BadRequest("Only dead deliveries can be retried".to_string()));
+    }
+
+    let delivery = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        UPDATE webhook_deliveries
+        SET status = 'pending', attempts = 0, next_attempt_at = NOW(), updated_at = NOW()
+        WHERE id = $1
+        RETURNING *
+        "#
+    )
+    .bind(delivery.id)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(delivery))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat::create_room, test_support};
+    use axum::http::StatusCode;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn signature_covers_timestamp_and_body() {
+        let body = r#"{"event":"message.created"}"#;
+        assert_eq!(
+            signature("secret", 1_700_000_000, body),
+            "sha256=39e442eaff327dcb8b1928c5e20f0eb2ae9df15a96a515e319999417b326c228"
+        );
+        assert_ne!(signature("secret", 1_700_000_001, body), signature("secret", 1_700_000_000, body));
+        assert_ne!(signature("other", 1_700_000_000, body), signature("secret", 1_700_000_000, body));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        for (attempts, seconds) in [(1, 30), (2, 60), (3, 120), (5, 480), (8, 3600), (40, 3600)] {
+            let delay = backoff(attempts).num_seconds();
+            assert!(
+                (seconds..=seconds + seconds / 10).contains(&delay),
+                "attempt {} waited {}s",
+                attempts,
+                delay
+            );
+        }
+    }
+
+    /// A webhook to `url` with one pending delivery, claimed as the worker would.
+    async fn claimed_delivery(pool: &PgPool, url: &str) -> ClaimedDelivery {
+        let owner = test_support::create_user(pool, "owner").await;
+        let room = create_room(pool, "general", None, false, owner.id).await.unwrap();
+        let webhook_id = Uuid::new_v4();
+        sqlx::query("INSERT INTO outgoing_webhooks (id, room_id, name, url, secret, events) VALUES ($1, $2, 'test', $3, 'secret', $4)")
+            .bind(webhook_id)
+            .bind(room.id)
+            .bind(url)
+            .bind(vec![EVENT_MESSAGE_CREATED])
+            .execute(pool)
+            .await
+            .unwrap();
+        let delivery = ClaimedDelivery {
+            id: Uuid::new_v4(),
+            event: EVENT_MESSAGE_CREATED.to_string(),
+            payload: serde_json::json!({ "event": EVENT_MESSAGE_CREATED }),
+            attempts: 0,
+            url: url.to_string(),
+            secret: "secret".to_string(),
+        };
+        sqlx::query("INSERT INTO webhook_deliveries (id, webhook_id, event, payload) VALUES ($1, $2, $3, $4)")
+            .bind(delivery.id)
+            .bind(webhook_id)
+            .bind(&delivery.event)
+            .bind(&delivery.payload)
+            .execute(pool)
+            .await
+            .unwrap();
+        delivery
+    }
+
+    async fn last_attempt(pool: &PgPool, delivery_id: Uuid) -> (Option<i32>, Option<String>) {
+        sqlx::query_as("SELECT status_code, error FROM webhook_delivery_attempts WHERE delivery_id = $1 ORDER BY attempt DESC LIMIT 1")
+            .bind(delivery_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    /// A receiver that fails every request with a body that mustn't leak.
+    async fn failing_receiver() -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let router = Router::new().route(
+            "/",
+            post(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal secret")
+            }),
+        );
+        (test_support::serve(router).await, requests)
+    }
+
+    #[sqlx::test]
+    async fn failed_deliveries_record_only_the_status(pool: PgPool) {
+        let (address, requests) = failing_receiver().await;
+        let delivery = claimed_delivery(&pool, &format!("http://{}/", address)).await;
+
+        let delivery_id = delivery.id;
+        attempt(&pool, &Egress::allowing("127.0.0.1").unwrap(), delivery).await;
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        let (status_code, error) = last_attempt(&pool, delivery_id).await;
+        assert_eq!(status_code, Some(500));
+        assert_eq!(error.as_deref(), Some("HTTP 500 Internal Server Error"));
+        let (status, last_error): (String, Option<String>) =
+            sqlx::query_as("SELECT status, last_error FROM webhook_deliveries WHERE id = $1")
+                .bind(delivery_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(status, STATUS_PENDING);
+        assert!(!last_error.unwrap_or_default().contains("secret"));
+    }
+
+    #[sqlx::test]
+    async fn internal_receivers_are_not_contacted(pool: PgPool) {
+        let (address, requests) = failing_receiver().await;
+        let delivery = claimed_delivery(&pool, &format!("http://{}/", address)).await;
+
+        let delivery_id = delivery.id;
+        attempt(&pool, &Egress::default(), delivery).await;
+
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+        let (status_code, error) = last_attempt(&pool, delivery_id).await;
+        assert_eq!(status_code, None);
+        assert!(error.unwrap_or_default().contains("blocked address"));
+    }
+
+    #[tokio::test]
+    async fn internal_urls_are_rejected() {
+        for url in [
+            "https://localhost/hook",
+            "https://127.0.0.1/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://[fd00::1]/hook",
+            "https://[::ffff:10.0.0.1]/hook",
+        ] {
+            assert!(matches!(validate_url(url).await, Err(AppError::Validation(_))), "{} was accepted", url);
+        }
+        assert!(validate_url("https://93.184.216.34/hook").await.is_ok());
+    }
+}
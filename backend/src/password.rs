@@ -0,0 +1,265 @@
+use crate::error::AppError;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use sha1::{Digest, Sha1};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+pub const SCHEME_ARGON2ID: &str = "argon2id";
+pub const SCHEME_BCRYPT: &str = "bcrypt";
+
+/// OWASP's baseline recommendation for Argon2id.
+const DEFAULT_ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_ARGON2_ITERATIONS: u32 = 2;
+const DEFAULT_ARGON2_PARALLELISM: u32 = 1;
+
+const DEFAULT_MIN_LENGTH: usize = 8;
+/// bcrypt only looks at the first 72 bytes; Argon2 has no limit, but hashing
+/// megabyte-sized passwords is a cheap way to burn CPU.
+const DEFAULT_MAX_LENGTH: usize = 128;
+/// Hex characters of the SHA-1 hash used to pick a range in the breached list.
+const RANGE_PREFIX_LEN: usize = 5;
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// The scheme new hashes are created with (`PASSWORD_HASH_SCHEME`).
+fn scheme() -> &'static str {
+    match std::env::var("PASSWORD_HASH_SCHEME").as_deref() {
+        Ok(SCHEME_BCRYPT) => SCHEME_BCRYPT,
+        _ => SCHEME_ARGON2ID,
+    }
+}
+
+fn bcrypt_cost() -> u32 {
+    env_or("BCRYPT_COST", bcrypt::DEFAULT_COST)
+}
+
+fn argon2() -> Result<Argon2<'static>, AppError> {
+    let params = Params::new(
+        env_or("ARGON2_MEMORY_KIB", DEFAULT_ARGON2_MEMORY_KIB),
+        env_or("ARGON2_ITERATIONS", DEFAULT_ARGON2_ITERATIONS),
+        env_or("ARGON2_PARALLELISM", DEFAULT_ARGON2_PARALLELISM),
+        None,
+    )
+    .map_err(|e| AppError::InternalError(format!("Invalid Argon2 parameters: {}", e)))?;
+
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+fn is_bcrypt(password_hash: &str) -> bool {
+    password_hash.starts_with("$2")
+}
+
+/// Hashes a password with the configured scheme and parameters.
+pub fn hash(password: &str) -> Result<String, AppError> {
+    if scheme() == SCHEME_BCRYPT {
+        return Ok(bcrypt::hash(password, bcrypt_cost())?);
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = argon2()?
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| AppError::InternalError(format!("Password hashing failed: {}", e)))?;
+
+    Ok(password_hash.to_string())
+}
+
+/// Checks a password against a stored Argon2 or bcrypt hash. The parameters
+/// are read from the hash itself, so older settings keep verifying.
+pub fn verify(password: &str, password_hash: &str) -> Result<bool, AppError> {
+    if is_bcrypt(password_hash) {
+        return Ok(bcrypt::verify(password, password_hash)?);
+    }
+
+    let parsed = PasswordHash::new(password_hash)
+        .map_err(|e| AppError::InternalError(format!("Unreadable password hash: {}", e)))?;
+    Ok(Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+/// Whether a hash was made with a different scheme or parameters than new
+/// hashes would be, and should be replaced after the next successful login.
+pub fn needs_rehash(password_hash: &str) -> bool {
+    if is_bcrypt(password_hash) {
+        let cost = password_hash.get(4..6).and_then(|cost| cost.parse::<u32>().ok());
+        return scheme() != SCHEME_BCRYPT || cost != Some(bcrypt_cost());
+    }
+
+    if scheme() != SCHEME_ARGON2ID {
+        return true;
+    }
+    let Ok(parsed) = PasswordHash::new(password_hash) else {
+        return true;
+    };
+    let (Ok(current), Ok(stored)) = (argon2(), Params::try_from(&parsed)) else {
+        return true;
+    };
+
+    parsed.algorithm.as_str() != SCHEME_ARGON2ID
+        || parsed.version != Some(Version::V0x13.into())
+        || stored.m_cost() != current.params().m_cost()
+        || stored.t_cost() != current.params().t_cost()
+        || stored.p_cost() != current.params().p_cost()
+}
+
+/// Enforces the password policy for new passwords: length limits and, when
+/// `PASSWORD_BREACHED_LIST` is set, absence from a list of breached passwords.
+pub async fn check_policy(password: &str) -> Result<(), AppError> {
+    let min_length = env_or("PASSWORD_MIN_LENGTH", DEFAULT_MIN_LENGTH);
+    let max_length = env_or("PASSWORD_MAX_LENGTH", DEFAULT_MAX_LENGTH);
+    let length = password.chars().count();
+    if length < min_length {
+        return Err(AppError::Validation(format!(
+            "Password must be at least {} characters",
+            min_length
+        )));
+    }
+    if length > max_length {
+        return Err(AppError::Validation(format!(
+            "Password must be at most {} characters",
+            max_length
+        )));
+    }
+
+    if let Ok(list) = std::env::var("PASSWORD_BREACHED_LIST") {
+        if !list.is_empty() && is_breached(Path::new(&list), password).await? {
+            return Err(AppError::Validation(
+                "This password has appeared in a data breach; please choose another".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks the password's SHA-1 up in a Pwned Passwords style list. A directory
+/// holds one range file per 5-character hash prefix (`ABCDE` or `ABCDE.txt`)
+/// listing `SUFFIX:COUNT` lines, so only that range is read. A single file
+/// lists full `HASH[:COUNT]` lines and is scanned, which suits short lists.
+async fn is_breached(list: &Path, password: &str) -> Result<bool, AppError> {
+    let digest = hex::encode_upper(Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = digest.split_at(RANGE_PREFIX_LEN);
+
+    let unreadable = |e: std::io::Error| {
+        AppError::InternalError(format!("Can't read breached password list {}: {}", list.display(), e))
+    };
+
+    let (path, needle) = if list.is_dir() {
+        let range = [prefix.to_string(), format!("{}.txt", prefix)]
+            .into_iter()
+            .map(|name| list.join(name))
+            .find(|path| path.is_file());
+        match range {
+            Some(path) => (path, suffix),
+            None => return Ok(false),
+        }
+    } else {
+        (list.to_path_buf(), digest.as_str())
+    };
+
+    let file = tokio::fs::File::open(&path).await.map_err(unreadable)?;
+    let mut lines = BufReader::new(file).lines();
+    while let Some(line) = lines.next_line().await.map_err(unreadable)? {
+        let hash = line.split(':').next().unwrap_or_default().trim();
+        if hash.eq_ignore_ascii_case(needle) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    /// SHA-1 of `PASSWORD`, split the way range files are.
+    fn range() -> (String, String) {
+        let digest = hex::encode_upper(Sha1::digest(PASSWORD.as_bytes()));
+        let (prefix, suffix) = digest.split_at(RANGE_PREFIX_LEN);
+        (prefix.to_string(), suffix.to_string())
+    }
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("breached-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn bcrypt_hashes_verify_and_are_upgraded() {
+        let password_hash = bcrypt::hash(PASSWORD, 4).unwrap();
+        assert!(verify(PASSWORD, &password_hash).unwrap());
+        assert!(!verify("wrong password", &password_hash).unwrap());
+        assert!(needs_rehash(&password_hash));
+    }
+
+    #[test]
+    fn argon2_hashes_round_trip() {
+        let password_hash = hash(PASSWORD).unwrap();
+        assert!(password_hash.starts_with("$argon2id$v=19$"));
+        assert!(verify(PASSWORD, &password_hash).unwrap());
+        assert!(!verify("wrong password", &password_hash).unwrap());
+        assert!(!needs_rehash(&password_hash));
+    }
+
+    #[test]
+    fn argon2_hashes_with_other_parameters_are_upgraded() {
+        let weaker = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(8 * 1024, 1, 1, None).unwrap());
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = weaker.hash_password(PASSWORD.as_bytes(), &salt).unwrap().to_string();
+        assert!(verify(PASSWORD, &password_hash).unwrap());
+        assert!(needs_rehash(&password_hash));
+        assert!(needs_rehash("not a hash"));
+    }
+
+    #[tokio::test]
+    async fn policy_enforces_length_limits() {
+        assert!(check_policy(PASSWORD).await.is_ok());
+        for password in ["short", &"x".repeat(DEFAULT_MAX_LENGTH + 1)] {
+            let result = check_policy(password).await;
+            assert!(matches!(result, Err(AppError::Validation(_))), "{:?} accepted", password);
+        }
+        // Length counts characters, not bytes
+        assert!(check_policy(&"ü".repeat(DEFAULT_MIN_LENGTH)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn range_files_are_looked_up_by_prefix() {
+        let (prefix, suffix) = range();
+        let dir = scratch_dir();
+        // Suffixes are matched case-insensitively, counts are ignored
+        let lines = format!("0018A45C4D1DEF81644B54AB7F969B88D65:1\n{}:3\n", suffix.to_lowercase());
+        std::fs::write(dir.join(format!("{}.txt", prefix)), lines).unwrap();
+
+        assert!(is_breached(&dir, PASSWORD).await.unwrap());
+        assert!(!is_breached(&dir, "another password").await.unwrap());
+
+        std::fs::rename(dir.join(format!("{}.txt", prefix)), dir.join(&prefix)).unwrap();
+        assert!(is_breached(&dir, PASSWORD).await.unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn single_files_list_full_hashes() {
+        let (prefix, suffix) = range();
+        let dir = scratch_dir();
+        let list = dir.join("breached.txt");
+        std::fs::write(&list, format!("{}{}\n", prefix, suffix)).unwrap();
+        assert!(is_breached(&list, PASSWORD).await.unwrap());
+
+        // A bare suffix doesn't match without its range
+        std::fs::write(&list, format!("{}:3\n", suffix)).unwrap();
+        assert!(!is_breached(&list, PASSWORD).await.unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
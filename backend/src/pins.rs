@@ -0,0 +1,164 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::AuthClaims,
+    chat::{ensure_room_access, get_message},
+    error::AppError,
+    models::*,
+    moderation::require_moderator,
+    websocket::broadcast_to_room,
+    SharedState,
+};
+use axum::{
+    extract::{Path, State},
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Most messages a room can have pinned at once.
+const MAX_PINS_PER_ROOM: i64 = 50;
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/rooms/:room_id/pins", get(list_handler).post(pin_handler))
+        .route("/rooms/:room_id/pins/:message_id", delete(unpin_handler))
+}
+
+/// The room's pinned messages, most recently pinned first.
+pub async fn list(pool: &PgPool, room_id: Uuid) -> Result<Vec<PinnedMessage>, AppError> {
+    let pins = sqlx::query_as::<_, PinnedMessage>(
+        r#"
+        SELECT m.*, p.pinned_by, p.pinned_at
+        FROM pinned_messages p
+        JOIN messages m ON m.id = p.message_id
+        WHERE p.room_id = $1 AND (m.expires_at IS NULL OR m.expires_at > NOW())
+        ORDER BY p.pinned_at DESC
+        "#
+    )
+    .bind(room_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(pins)
+}
+
+async fn list_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<PinnedMessage>>, AppError> {
+    ensure_room_access(&state.db, room_id, claims.user_id()?).await?;
+    Ok(Json(list(&state.db, room_id).await?))
+}
+
+#[derive(Deserialize)]
+struct PinRequest {
+    message_id: Uuid,
+}
+
+/// Moderators pin messages for everyone in the room. Pinning a message
+/// that's already pinned leaves it as it was.
+async fn pin_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<PinRequest>,
+) -> Result<Json<PinnedMessage>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+    let message = get_message(&state.db, room_id, req.message_id).await?;
+    if message.message_type == "system" {
+        return Err(AppError::BadRequest("System messages can't be pinned".to_string()));
+    }
+
+    let pinned = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pinned_messages WHERE room_id = $1")
+        .bind(room_id)
+        .fetch_one(&state.db)
+        .await?;
+    if pinned >= MAX_PINS_PER_ROOM {
+        return Err(AppError::BadRequest(format!(
+            "A room can have at most {} pinned messages",
+            MAX_PINS_PER_ROOM
+        )));
+    }
+
+    let inserted = sqlx::query_as::<_, (Option<Uuid>, DateTime<Utc>)>(
+        r#"
+        INSERT INTO pinned_messages (message_id, room_id, pinned_by)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (message_id) DO NOTHING
+        RETURNING pinned_by, pinned_at
+        "#
+    )
+    .bind(message.id)
+    .bind(room_id)
+    .bind(actor_id)
+    .fetch_optional(&state.db)
+    .await?;
+    let created = inserted.is_some();
+    let (pinned_by, pinned_at) = match inserted {
+        Some(row) => row,
+        None => {
+            sqlx::query_as::<_, (Option<Uuid>, DateTime<Utc>)>(
+                "SELECT pinned_by, pinned_at FROM pinned_messages WHERE message_id = $1"
+            )
+            .bind(message.id)
+            .fetch_one(&state.db)
+            .await?
+        }
+    };
+
+    let pin = PinnedMessage { message, pinned_by, pinned_at };
+    if created {
+        audit::record(
+            &state.db,
+            &client,
+            AuditEvent::new("message.pin")
+                .actor(actor_id)
+                .room(room_id)
+                .target("message", pin.message.id),
+        ).await;
+        broadcast_to_room(&state, room_id, &WebSocketMessage::new("message_pinned", &pin)).await;
+    }
+
+    Ok(Json(pin))
+}
+
+async fn unpin_handler(
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let actor_id = claims.user_id()?;
+    require_moderator(&state.db, room_id, actor_id).await?;
+
+    let result = sqlx::query("DELETE FROM pinned_messages WHERE message_id = $1 AND room_id = $2")
+        .bind(message_id)
+        .bind(room_id)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Message is not pinned".to_string()));
+    }
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("message.unpin")
+            .actor(actor_id)
+            .room(room_id)
+            .target("message", message_id),
+    ).await;
+    let event = WebSocketMessage::new(
+        "message_unpinned",
+        &serde_json::json!({ "room_id": room_id, "message_id": message_id }),
+    );
+    broadcast_to_room(&state, room_id, &event).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
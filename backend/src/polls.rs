@@ -0,0 +1,410 @@
+use crate::{
+    audit::ClientInfo,
+    auth::AuthClaims,
+    chat::{ensure_room_access, get_member_role, send_message},
+    error::AppError,
+    models::*,
+    moderation,
+    outgoing_webhooks::{self, EVENT_MESSAGE_CREATED},
+    push,
+    rate_limit::keys_for,
+    websocket::broadcast_to_room,
+    AppState, SharedState,
+};
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub const MIN_OPTIONS: usize = 2;
+pub const MAX_OPTIONS: usize = 10;
+const MAX_QUESTION_CHARS: usize = 300;
+const MAX_OPTION_CHARS: usize = 100;
+/// Furthest ahead a poll's close time can be set.
+pub const MAX_OPEN_DAYS: i64 = 90;
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/rooms/:room_id/polls", post(create_handler))
+        .route("/rooms/:room_id/polls/:message_id", get(get_handler))
+        .route("/rooms/:room_id/polls/:message_id/votes", post(vote_handler).delete(retract_handler))
+        .route("/rooms/:room_id/polls/:message_id/close", post(close_handler))
+}
+
+#[derive(Deserialize)]
+pub struct NewPoll {
+    pub question: String,
+    pub options: Vec<String>,
+    #[serde(default)]
+    pub multiple_choice: bool,
+    #[serde(default)]
+    pub anonymous: bool,
+    pub closes_at: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PollRow {
+    message_id: Uuid,
+    room_id: Uuid,
+    question: String,
+    options: Vec<String>,
+    multiple_choice: bool,
+    anonymous: bool,
+    closes_at: Option<DateTime<Utc>>,
+    closed_at: Option<DateTime<Utc>>,
+    created_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+}
+
+impl PollRow {
+    fn is_closed(&self) -> bool {
+        self.closed_at.is_some() || self.closes_at.is_some_and(|closes_at| closes_at <= Utc::now())
+    }
+}
+
+/// Trims the question and options, returning the options.
+fn validate(poll: &NewPoll) -> Result<Vec<String>, AppError> {
+    let question = poll.question.trim();
+    if question.is_empty() || question.chars().count() > MAX_QUESTION_CHARS {
+        return Err(AppError::Validation(format!(
+            "Poll question must be between 1 and {} characters",
+            MAX_QUESTION_CHARS
+        )));
+    }
+
+    let options: Vec<String> = poll.options.iter().map(|option| option.trim().to_string()).collect();
+    if options.len() < MIN_OPTIONS || options.len() > MAX_OPTIONS {
+        return Err(AppError::Validation(format!(
+            "Polls need between {} and {} options",
+            MIN_OPTIONS, MAX_OPTIONS
+        )));
+    }
+    if options
+        .iter()
+        .any(|option| option.is_empty() || option.chars().count() > MAX_OPTION_CHARS)
+    {
+        return Err(AppError::Validation(format!(
+            "Poll options must be between 1 and {} characters",
+            MAX_OPTION_CHARS
+        )));
+    }
+    for (i, option) in options.iter().enumerate() {
+        if options[..i].iter().any(|earlier| earlier.to_lowercase() == option.to_lowercase()) {
+            return Err(AppError::Validation(format!("Duplicate poll option '{}'", option)));
+        }
+    }
+
+    if let Some(closes_at) = poll.closes_at {
+        let now = Utc::now();
+        if closes_at <= now || closes_at > now + Duration::days(MAX_OPEN_DAYS) {
+            return Err(AppError::Validation(format!(
+                "Polls must close within the next {} days",
+                MAX_OPEN_DAYS
+            )));
+        }
+    }
+
+    Ok(options)
+}
+
+/// Posts a `poll` message with the question as its content and stores the
+/// poll under the message's id. Returns the message with its poll attached;
+/// broadcasting is up to the caller.
+pub async fn create(state: &AppState, room_id: Uuid, user_id: Uuid, poll: &NewPoll) -> Result<Message, AppError> {
+    let options = validate(poll)?;
+    let question = poll.question.trim();
+
+    let mut message = send_message(state, room_id, user_id, question, "poll").await?;
+    let stored = sqlx::query(
+        r#"
+        INSERT INTO polls (message_id, room_id, question, options, multiple_choice, anonymous, closes_at, created_by)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#
+    )
+    .bind(message.id)
+    .bind(room_id)
+    .bind(question)
+    .bind(&options)
+    .bind(poll.multiple_choice)
+    .bind(poll.anonymous)
+    .bind(poll.closes_at)
+    .bind(user_id)
+    .execute(&state.db)
+    .await;
+    if let Err(e) = stored {
+        // Don't leave a poll message behind without its poll
+        crate::chat::delete_message(&state.db, message.id).await?;
+        return Err(e.into());
+    }
+
+    message.poll = Some(get_poll(&state.db, room_id, message.id, Some(user_id)).await?);
+    Ok(message)
+}
+
+/// Loads polls with their tallies. `viewer` gets their own votes filled in.
+async fn load(pool: &PgPool, rows: Vec<PollRow>, viewer: Option<Uuid>) -> Result<Vec<Poll>, AppError> {
+    let ids: Vec<Uuid> = rows.iter().map(|row| row.message_id).collect();
+    let votes = sqlx::query_as::<_, (Uuid, Uuid, i32)>(
+        "SELECT message_id, user_id, option_index FROM poll_votes WHERE message_id = ANY($1) ORDER BY created_at"
+    )
+    .bind(&ids)
+    .fetch_all(pool)
+    .await?;
+
+    let mut by_poll: HashMap<Uuid, Vec<(Uuid, i32)>> = HashMap::new();
+    for (message_id, user_id, option_index) in votes {
+        by_poll.entry(message_id).or_default().push((user_id, option_index));
+    }
+
+    let polls = rows
+        .into_iter()
+        .map(|row| {
+            let votes = by_poll.remove(&row.message_id).unwrap_or_default();
+            let is_closed = row.is_closed();
+            let options = row
+                .options
+                .into_iter()
+                .enumerate()
+                .map(|(i, text)| {
+                    let voters: Vec<Uuid> = votes
+                        .iter()
+                        .filter(|(_, option_index)| *option_index as usize == i)
+                        .map(|(user_id, _)| *user_id)
+                        .collect();
+                    PollOption {
+                        text,
+                        votes: voters.len() as i64,
+                        voters: (!row.anonymous).then_some(voters),
+                    }
+                })
+                .collect();
+            let mut voters: Vec<Uuid> = votes.iter().map(|(user_id, _)| *user_id).collect();
+            voters.sort();
+            voters.dedup();
+            let mut my_votes: Vec<i32> = votes
+                .iter()
+                .filter(|(user_id, _)| Some(*user_id) == viewer)
+                .map(|(_, option_index)| *option_index)
+                .collect();
+            my_votes.sort();
+
+            Poll {
+                message_id: row.message_id,
+                room_id: row.room_id,
+                question: row.question,
+                options,
+                multiple_choice: row.multiple_choice,
+                anonymous: row.anonymous,
+                closes_at: row.closes_at,
+                closed_at: row.closed_at,
+                is_closed,
+                total_voters: voters.len() as i64,
+                my_votes,
+                created_by: row.created_by,
+                created_at: row.created_at,
+            }
+        })
+        .collect();
+
+    Ok(polls)
+}
+
+async fn get_row(pool: &PgPool, room_id: Uuid, message_id: Uuid) -> Result<PollRow, AppError> {
+    let row = sqlx::query_as::<_, PollRow>("SELECT * FROM polls WHERE message_id = $1 AND room_id = $2")
+        .bind(message_id)
+        .bind(room_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Poll not found".to_string()))?;
+
+    Ok(row)
+}
+
+pub async fn get_poll(pool: &PgPool, room_id: Uuid, message_id: Uuid, viewer: Option<Uuid>) -> Result<Poll, AppError> {
+    let row = get_row(pool, room_id, message_id).await?;
+    let poll = load(pool, vec![row], viewer).await?.remove(0);
+    Ok(poll)
+}
+
+/// Fills in `poll` on the poll messages among `messages`.
+pub async fn attach(pool: &PgPool, messages: &mut [Message], viewer: Uuid) -> Result<(), AppError> {
+    let ids: Vec<Uuid> = messages
+        .iter()
+        .filter(|message| message.message_type == "poll")
+        .map(|message| message.id)
+        .collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let rows = sqlx::query_as::<_, PollRow>("SELECT * FROM polls WHERE message_id = ANY($1)")
+        .bind(&ids)
+        .fetch_all(pool)
+        .await?;
+    let mut polls: HashMap<Uuid, Poll> = load(pool, rows, Some(viewer))
+        .await?
+        .into_iter()
+        .map(|poll| (poll.message_id, poll))
+        .collect();
+    for message in messages.iter_mut() {
+        if let Some(poll) = polls.remove(&message.id) {
+            message.poll = Some(poll);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the room the poll's new tally.
+async fn broadcast_results(state: &AppState, room_id: Uuid, message_id: Uuid) -> Result<(), AppError> {
+    let poll = get_poll(&state.db, room_id, message_id, None).await?;
+    broadcast_to_room(state, room_id, &WebSocketMessage::new("poll_updated", &poll)).await;
+    Ok(())
+}
+
+async fn create_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<NewPoll>,
+) -> Result<Json<Message>, AppError> {
+    state
+        .rate_limits
+        .check(&state.rate_limits.messages, &keys_for(&client, Some(&claims)))?;
+    let user_id = claims.user_id()?;
+    moderation::ensure_can_post(&state.db, room_id, user_id).await?;
+
+    let message = create(&state, room_id, user_id, &req).await?;
+    broadcast_to_room(&state, room_id, &WebSocketMessage::new("new_message", &message)).await;
+    push::notify_new_message(&state, &message);
+    outgoing_webhooks::dispatch_message(&state.db, EVENT_MESSAGE_CREATED, &message).await;
+
+    Ok(Json(message))
+}
+
+async fn get_handler(
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Poll>, AppError> {
+    let user_id = claims.user_id()?;
+    ensure_room_access(&state.db, room_id, user_id).await?;
+    Ok(Json(get_poll(&state.db, room_id, message_id, Some(user_id)).await?))
+}
+
+#[derive(Deserialize)]
+struct VoteRequest {
+    options: Vec<i32>,
+}
+
+/// Replaces the caller's votes with `options`: exactly one option for
+/// single-choice polls, one or more for multiple choice.
+async fn vote_handler(
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<VoteRequest>,
+) -> Result<Json<Poll>, AppError> {
+    let user_id = claims.user_id()?;
+    ensure_room_access(&state.db, room_id, user_id).await?;
+    let poll = get_row(&state.db, room_id, message_id).await?;
+    if poll.is_closed() {
+        return Err(AppError::BadRequest("This poll is closed".to_string()));
+    }
+
+    let mut options = req.options;
+    options.sort();
+    options.dedup();
+    if options.is_empty() || (!poll.multiple_choice && options.len() > 1) {
+        return Err(AppError::Validation(if poll.multiple_choice {
+            "Choose at least one option".to_string()
+        } else {
+            "Choose exactly one option".to_string()
+        }));
+    }
+    if options
+        .iter()
+        .any(|option| *option < 0 || *option as usize >= poll.options.len())
+    {
+        return Err(AppError::Validation("Unknown poll option".to_string()));
+    }
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query("DELETE FROM poll_votes WHERE message_id = $1 AND user_id = $2")
+        .bind(message_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO poll_votes (message_id, user_id, option_index) SELECT $1, $2, UNNEST($3::INTEGER[])"
+    )
+    .bind(message_id)
+    .bind(user_id)
+    .bind(&options)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    broadcast_results(&state, room_id, message_id).await?;
+    Ok(Json(get_poll(&state.db, room_id, message_id, Some(user_id)).await?))
+}
+
+async fn retract_handler(
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Poll>, AppError> {
+    let user_id = claims.user_id()?;
+    ensure_room_access(&state.db, room_id, user_id).await?;
+    if get_row(&state.db, room_id, message_id).await?.is_closed() {
+        return Err(AppError::BadRequest("This poll is closed".to_string()));
+    }
+
+    let result = sqlx::query("DELETE FROM poll_votes WHERE message_id = $1 AND user_id = $2")
+        .bind(message_id)
+        .bind(user_id)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() > 0 {
+        broadcast_results(&state, room_id, message_id).await?;
+    }
+
+    Ok(Json(get_poll(&state.db, room_id, message_id, Some(user_id)).await?))
+}
+
+/// Ends voting early. Open to the poll's creator and the room's moderators.
+async fn close_handler(
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Poll>, AppError> {
+    let user_id = claims.user_id()?;
+    ensure_room_access(&state.db, room_id, user_id).await?;
+    let poll = get_row(&state.db, room_id, message_id).await?;
+    if poll.created_by != Some(user_id) {
+        let role = get_member_role(&state.db, room_id, user_id).await?;
+        if !role.as_deref().is_some_and(moderation::is_moderator_role) {
+            return Err(AppError::Authorization(
+                "Only the poll's creator or a moderator can close it".to_string(),
+            ));
+        }
+    }
+    if poll.is_closed() {
+        return Err(AppError::BadRequest("This poll is already closed".to_string()));
+    }
+
+    sqlx::query("UPDATE polls SET closed_at = NOW() WHERE message_id = $1")
+        .bind(message_id)
+        .execute(&state.db)
+        .await?;
+
+    broadcast_results(&state, room_id, message_id).await?;
+    Ok(Json(get_poll(&state.db, room_id, message_id, Some(user_id)).await?))
+}
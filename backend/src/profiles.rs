@@ -0,0 +1,480 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{get_user_by_id, issue_session, validate_username, verify_password, AuthClaims},
+    error::AppError,
+    models::*,
+    password, uploads,
+    websocket::{broadcast_to_room, disconnect_user},
+    AppState, SharedState,
+};
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use axum_extra::extract::Multipart;
+use chrono::Utc;
+use image::{imageops::FilterType, ImageFormat, ImageReader, Limits};
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::io::Cursor;
+use uuid::Uuid;
+
+const MAX_DISPLAY_NAME_LEN: usize = 100;
+const MAX_BIO_LEN: usize = 500;
+const MAX_STATUS_LEN: usize = 140;
+const MAX_TIMEZONE_LEN: usize = 64;
+
+/// Avatars are cropped to a square of this many pixels.
+const AVATAR_SIZE: u32 = 256;
+/// Larger source images are rejected before they are decoded.
+const MAX_AVATAR_SOURCE_DIMENSION: u32 = 8192;
+
+/// Accounts without a password confirm sensitive changes by having signed in
+/// within this many seconds.
+const REAUTH_WINDOW_SECONDS: i64 = 5 * 60;
+
+const PROFILE_COLUMNS: &str = r#"
+    id, username, display_name, bio, timezone, status_text,
+    CASE WHEN avatar_file_id IS NULL THEN NULL ELSE '/api/avatars/' || avatar_file_id END AS avatar_url,
+    is_bot, created_at
+"#;
+
+/// Endpoints for signed-in users, mounted under `/api`. Avatar uploads are
+/// routed in `create_router` so they share the upload rate limit.
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/users/me", get(get_me_handler).patch(update_me_handler))
+        .route("/users/me/username", put(change_username_handler))
+        .route("/users/me/password", put(change_password_handler))
+        .route("/users/:user_id", get(get_user_handler))
+}
+
+/// Serves avatar images without authentication, so they work in `<img>` tags.
+/// Mounted under `/api/avatars`.
+pub fn avatar_router() -> Router<SharedState> {
+    Router::new().route("/:file_id", get(avatar_handler))
+}
+
+pub async fn get_profile(pool: &PgPool, user_id: Uuid) -> Result<UserProfile, AppError> {
+    sqlx::query_as::<_, UserProfile>(&format!("SELECT {} FROM users WHERE id = $1", PROFILE_COLUMNS))
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))
+}
+
+pub async fn get_own_profile(pool: &PgPool, user_id: Uuid) -> Result<OwnProfile, AppError> {
+    let user = get_user_by_id(pool, user_id)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+
+    Ok(OwnProfile {
+        profile: get_profile(pool, user_id).await?,
+        email: user.email,
+        email_verified: user.email_verified_at.is_some(),
+        has_password: user.password_hash.is_some(),
+    })
+}
+
+/// Lets everyone in the user's rooms pick up the new name, avatar or status.
+async fn broadcast_profile(state: &AppState, profile: &UserProfile) -> Result<(), AppError> {
+    let room_ids = sqlx::query_scalar::<_, Uuid>("SELECT room_id FROM room_members WHERE user_id = $1")
+        .bind(profile.id)
+        .fetch_all(&state.db)
+        .await?;
+
+    let event = WebSocketMessage::new("user_updated", profile);
+    for room_id in room_ids {
+        broadcast_to_room(state, room_id, &event).await;
+    }
+
+    Ok(())
+}
+
+/// Confirms that the person making a sensitive change is the account holder:
+/// by password, or for password-less (SSO) accounts by a recent sign-in.
+/// Wrong passwords count towards the login lockout.
+pub async fn reauthenticate(
+    state: &AppState,
+    user: &User,
+    claims: &AuthClaims,
+    password: Option<&str>,
+) -> Result<(), AppError> {
+    let lockout_keys = vec![format!("reauth:{}", user.id)];
+    let lockout = &state.rate_limits.login_lockout;
+    if state.rate_limits.enabled {
+        lockout.check(&lockout_keys)?;
+    }
+
+    match check_reauthentication(user, claims, password) {
+        Ok(()) => {
+            lockout.record_success(&lockout_keys);
+            Ok(())
+        }
+        Err(e) => {
+            if matches!(e, AppError::Auth(_)) {
+                lockout.record_failure(&lockout_keys);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn check_reauthentication(user: &User, claims: &AuthClaims, password: Option<&str>) -> Result<(), AppError> {
+    if user.password_hash.is_some() {
+        let password = password.ok_or_else(|| AppError::Auth("Current password is required".to_string()))?;
+        if !verify_password(user, password)? {
+            return Err(AppError::Auth("Invalid password".to_string()));
+        }
+        return Ok(());
+    }
+
+    if Utc::now().timestamp() - claims.iat as i64 > REAUTH_WINDOW_SECONDS {
+        return Err(AppError::Auth("Please sign in again to make this change".to_string()));
+    }
+    Ok(())
+}
+
+/// Trims the value and checks its length; empty strings clear the field.
+fn normalize_field(value: &str, field: &str, max_len: usize) -> Result<Option<String>, AppError> {
+    let value = value.trim();
+    if value.chars().count() > max_len {
+        return Err(AppError::Validation(format!("{} must be at most {} characters", field, max_len)));
+    }
+    Ok((!value.is_empty()).then(|| value.to_string()))
+}
+
+/// IANA zone names such as `Europe/Berlin`, `America/Argentina/Buenos_Aires` or `UTC`.
+fn validate_timezone(timezone: &str) -> Result<(), AppError> {
+    let valid = timezone
+        .split('/')
+        .all(|part| {
+            !part.is_empty()
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+        });
+    if !valid {
+        return Err(AppError::Validation("Invalid timezone".to_string()));
+    }
+    Ok(())
+}
+
+async fn get_me_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<OwnProfile>, AppError> {
+    Ok(Json(get_own_profile(&state.db, claims.user_id()?).await?))
+}
+
+async fn get_user_handler(
+    Path(user_id): Path<Uuid>,
+    State(state): State<SharedState>,
+) -> Result<Json<UserProfile>, AppError> {
+    Ok(Json(get_profile(&state.db, user_id).await?))
+}
+
+/// Omitted fields are left unchanged; empty strings clear them.
+#[derive(Deserialize)]
+struct UpdateProfileRequest {
+    display_name: Option<String>,
+    bio: Option<String>,
+    timezone: Option<String>,
+    status_text: Option<String>,
+}
+
+async fn update_me_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<UpdateProfileRequest>,
+) -> Result<Json<OwnProfile>, AppError> {
+    let user_id = claims.user_id()?;
+
+    let display_name = req
+        .display_name
+        .as_deref()
+        .map(|value| normalize_field(value, "Display name", MAX_DISPLAY_NAME_LEN))
+        .transpose()?;
+    let bio = req
+        .bio
+        .as_deref()
+        .map(|value| normalize_field(value, "Bio", MAX_BIO_LEN))
+        .transpose()?;
+    let status_text = req
+        .status_text
+        .as_deref()
+        .map(|value| normalize_field(value, "Status", MAX_STATUS_LEN))
+        .transpose()?;
+    let timezone = req
+        .timezone
+        .as_deref()
+        .map(|value| normalize_field(value, "Timezone", MAX_TIMEZONE_LEN))
+        .transpose()?;
+    if let Some(Some(timezone)) = &timezone {
+        validate_timezone(timezone)?;
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE users SET
+            display_name = CASE WHEN $2 THEN $3 ELSE display_name END,
+            bio = CASE WHEN $4 THEN $5 ELSE bio END,
+            timezone = CASE WHEN $6 THEN $7 ELSE timezone END,
+            status_text = CASE WHEN $8 THEN $9 ELSE status_text END,
+            updated_at = NOW()
+        WHERE id = $1
+        "#
+    )
+    .bind(user_id)
+    .bind(display_name.is_some())
+    .bind(display_name.clone().flatten())
+    .bind(bio.is_some())
+    .bind(bio.clone().flatten())
+    .bind(timezone.is_some())
+    .bind(timezone.clone().flatten())
+    .bind(status_text.is_some())
+    .bind(status_text.clone().flatten())
+    .execute(&state.db)
+    .await?;
+
+    let changed: Vec<&str> = [
+        ("display_name", display_name.is_some()),
+        ("bio", bio.is_some()),
+        ("timezone", timezone.is_some()),
+        ("status_text", status_text.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(field, changed)| changed.then_some(field))
+    .collect();
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("profile.update")
+            .actor(user_id)
+            .target("user", user_id)
+            .metadata(serde_json::json!({ "fields": changed })),
+    ).await;
+
+    let profile = get_own_profile(&state.db, user_id).await?;
+    broadcast_profile(&state, &profile.profile).await?;
+    Ok(Json(profile))
+}
+
+/// Decodes an uploaded image and crops it to a square PNG. Re-encoding also
+/// drops any metadata the original carried.
+fn resize_avatar(data: &[u8]) -> Result<Vec<u8>, AppError> {
+    let invalid = || AppError::Validation("Avatar must be a PNG, JPEG, GIF or WebP image".to_string());
+
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|_| invalid())?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_SOURCE_DIMENSION);
+    reader.limits(limits);
+
+    let image = reader.decode().map_err(|_| invalid())?;
+    let avatar = image.resize_to_fill(AVATAR_SIZE, AVATAR_SIZE, FilterType::Lanczos3);
+
+    let mut png = Cursor::new(Vec::new());
+    avatar
+        .write_to(&mut png, ImageFormat::Png)
+        .map_err(|e| AppError::InternalError(format!("Failed to encode avatar: {}", e)))?;
+    Ok(png.into_inner())
+}
+
+/// Replaces the user's avatar and deletes the previous image.
+async fn set_avatar(state: &AppState, user_id: Uuid, file_id: Option<Uuid>) -> Result<UserProfile, AppError> {
+    let previous = sqlx::query_scalar::<_, Option<Uuid>>(
+        r#"
+        UPDATE users u SET avatar_file_id = $2, updated_at = NOW()
+        FROM (SELECT avatar_file_id FROM users WHERE id = $1 FOR UPDATE) old
+        WHERE u.id = $1
+        RETURNING old.avatar_file_id
+        "#
+    )
+    .bind(user_id)
+    .bind(file_id)
+    .fetch_optional(&state.db)
+    .await?
+    .flatten();
+
+    if let Some(previous) = previous {
+        uploads::remove(previous).await?;
+    }
+
+    let profile = get_profile(&state.db, user_id).await?;
+    broadcast_profile(state, &profile).await?;
+    Ok(profile)
+}
+
+pub async fn upload_avatar_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    mut multipart: Multipart,
+) -> Result<Json<UserProfile>, AppError> {
+    let user_id = claims.user_id()?;
+
+    while let Some(field) = multipart.next_field().await.map_err(|_| AppError::BadRequest("Invalid multipart data".to_string()))? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let data = field.bytes().await.map_err(|_| AppError::BadRequest("Failed to read file".to_string()))?;
+
+        let size = data.len();
+        let avatar = tokio::task::spawn_blocking(move || resize_avatar(&data))
+            .await
+            .map_err(|e| AppError::InternalError(format!("Avatar processing failed: {}", e)))??;
+        let file_id = uploads::store(&avatar).await?;
+        let profile = set_avatar(&state, user_id, Some(file_id)).await?;
+
+        audit::record(
+            &state.db,
+            &client,
+            AuditEvent::new("profile.avatar_update")
+                .actor(user_id)
+                .target("file", file_id)
+                .metadata(serde_json::json!({ "size": size })),
+        ).await;
+
+        return Ok(Json(profile));
+    }
+
+    Err(AppError::BadRequest("No file provided".to_string()))
+}
+
+pub async fn delete_avatar_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<UserProfile>, AppError> {
+    let user_id = claims.user_id()?;
+    let profile = set_avatar(&state, user_id, None).await?;
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("profile.avatar_remove").actor(user_id).target("user", user_id),
+    ).await;
+
+    Ok(Json(profile))
+}
+
+async fn avatar_handler(
+    Path(file_id): Path<Uuid>,
+    State(state): State<SharedState>,
+) -> Result<Response, AppError> {
+    // Only current avatars are public, not arbitrary uploads
+    let in_use = sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM users WHERE avatar_file_id = $1)")
+        .bind(file_id)
+        .fetch_one(&state.db)
+        .await?;
+    let data = match in_use {
+        true => uploads::read(file_id).await?,
+        false => None,
+    }
+    .ok_or_else(|| AppError::NotFound("Avatar not found".to_string()))?;
+
+    // A new upload gets a new id, so the image never changes
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct ChangeUsernameRequest {
+    username: String,
+    password: Option<String>,
+}
+
+async fn change_username_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<ChangeUsernameRequest>,
+) -> Result<Json<OwnProfile>, AppError> {
+    let user = get_user_by_id(&state.db, claims.user_id()?)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+    reauthenticate(&state, &user, &claims, req.password.as_deref()).await?;
+
+    let username = req.username.trim();
+    validate_username(username)?;
+    let taken = sqlx::query("SELECT id FROM users WHERE LOWER(username) = LOWER($1) AND id <> $2")
+        .bind(username)
+        .bind(user.id)
+        .fetch_optional(&state.db)
+        .await?;
+    if taken.is_some() {
+        return Err(AppError::Validation("Username is already taken".to_string()));
+    }
+
+    sqlx::query("UPDATE users SET username = $2, updated_at = NOW() WHERE id = $1")
+        .bind(user.id)
+        .bind(username)
+        .execute(&state.db)
+        .await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("profile.username_change")
+            .actor(user.id)
+            .target("user", user.id)
+            .metadata(serde_json::json!({ "from": user.username, "to": username })),
+    ).await;
+
+    let profile = get_own_profile(&state.db, user.id).await?;
+    broadcast_profile(&state, &profile.profile).await?;
+    Ok(Json(profile))
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordRequest {
+    current_password: Option<String>,
+    new_password: String,
+}
+
+/// Sets a new password (or a first one for SSO accounts) and signs out every
+/// other session. The response carries a fresh session for the caller.
+async fn change_password_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<ChangePasswordRequest>,
+) -> Result<Json<AuthResponse>, AppError> {
+    let user = get_user_by_id(&state.db, claims.user_id()?)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+    reauthenticate(&state, &user, &claims, req.current_password.as_deref()).await?;
+
+    password::check_policy(&req.new_password).await?;
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET password_hash = $2, token_version = token_version + 1, updated_at = NOW()
+        WHERE id = $1
+        RETURNING *
+        "#
+    )
+    .bind(user.id)
+    .bind(password::hash(&req.new_password)?)
+    .fetch_one(&state.db)
+    .await?;
+
+    disconnect_user(&state, user.id, None, "password changed").await;
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.password_change").actor(user.id).target("user", user.id),
+    ).await;
+
+    Ok(Json(issue_session(&user)?))
+}
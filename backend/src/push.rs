@@ -0,0 +1,1014 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::AuthClaims,
+    chat::{ensure_room_access, get_room_by_id},
+    egress::Egress,
+    error::AppError,
+    models::*,
+    AppState, SharedState,
+};
+use aes_gcm::{aead::Aead, Aes128Gcm, KeyInit, Nonce};
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use chrono_tz::Tz;
+use hkdf::Hkdf;
+use p256::{
+    ecdh::EphemeralSecret,
+    ecdsa::{signature::Signer, Signature, SigningKey},
+    elliptic_curve::sec1::ToEncodedPoint,
+    PublicKey, SecretKey,
+};
+use rand::{rngs::OsRng, RngCore};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::{collections::HashSet, sync::Arc};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const HTTP_TIMEOUT_SECONDS: u64 = 10;
+/// How long push services hold a notification for an offline device.
+const TTL_SECONDS: u64 = 24 * 60 * 60;
+const VAPID_TOKEN_LIFETIME_HOURS: i64 = 12;
+/// Record size advertised in the aes128gcm header; payloads fit in one record.
+const RECORD_SIZE: u32 = 4096;
+/// Keeps the encrypted payload well under the 4 KB push services must accept.
+const MAX_PREVIEW_CHARS: usize = 200;
+const MAX_SUBSCRIPTIONS_PER_USER: i64 = 20;
+const MAX_KEYWORDS: usize = 20;
+const MAX_KEYWORD_LEN: usize = 50;
+
+pub const LEVEL_ALL: &str = "all";
+pub const LEVEL_MENTIONS: &str = "mentions";
+pub const LEVEL_NONE: &str = "none";
+
+/// Sends Web Push notifications (RFC 8030) with VAPID authentication
+/// (RFC 8292) and aes128gcm payload encryption (RFC 8291).
+pub struct Push {
+    signing_key: SigningKey,
+    /// Uncompressed application server public key, base64url-encoded.
+    public_key: String,
+    /// Contact for push services, a `mailto:` or `https:` URL.
+    subject: String,
+    /// Accept plain-HTTP endpoints, for testing against a local stub.
+    allow_insecure_endpoints: bool,
+    /// Endpoints are user-supplied, so internal hosts are off limits.
+    egress: Egress,
+}
+
+impl Push {
+    /// Enabled by setting `VAPID_PRIVATE_KEY`, either as a PEM-encoded P-256
+    /// key or as the base64url-encoded raw private key. Internal push
+    /// services can be allowed with `PUSH_ALLOWED_HOSTS`.
+    pub fn from_env(base_url: &str) -> anyhow::Result<Option<Self>> {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        let Some(private_key) = env("VAPID_PRIVATE_KEY") else {
+            return Ok(None);
+        };
+        let private_key = private_key.replace("\\n", "\n");
+        let secret_key = if private_key.trim_start().starts_with("-----BEGIN") {
+            SecretKey::from_sec1_pem(&private_key)
+                .or_else(|_| p256::pkcs8::DecodePrivateKey::from_pkcs8_pem(&private_key))
+                .map_err(|e| anyhow::anyhow!("Invalid VAPID_PRIVATE_KEY: {}", e))?
+        } else {
+            let bytes = URL_SAFE_NO_PAD.decode(private_key.trim().trim_end_matches('='))?;
+            SecretKey::from_slice(&bytes).map_err(|e| anyhow::anyhow!("Invalid VAPID_PRIVATE_KEY: {}", e))?
+        };
+
+        let subject = env("VAPID_SUBJECT").unwrap_or_else(|| base_url.to_string());
+        info!("Web Push enabled with VAPID subject {}", subject);
+
+        Ok(Some(Self::new(
+            secret_key,
+            subject,
+            env("PUSH_ALLOW_INSECURE_ENDPOINTS").is_some_and(|value| value == "true" || value == "1"),
+            Egress::from_env("PUSH_ALLOWED_HOSTS")?,
+        )))
+    }
+
+    fn new(secret_key: SecretKey, subject: String, allow_insecure_endpoints: bool, egress: Egress) -> Self {
+        Self {
+            public_key: URL_SAFE_NO_PAD.encode(secret_key.public_key().to_encoded_point(false).as_bytes()),
+            signing_key: SigningKey::from(secret_key),
+            subject,
+            allow_insecure_endpoints,
+            egress,
+        }
+    }
+
+    /// The `Authorization` header for a request to the endpoint's push service.
+    fn vapid_authorization(&self, endpoint: &Url) -> String {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(
+            serde_json::json!({
+                "aud": endpoint.origin().ascii_serialization(),
+                "exp": (Utc::now() + Duration::hours(VAPID_TOKEN_LIFETIME_HOURS)).timestamp(),
+                "sub": self.subject,
+            })
+            .to_string(),
+        );
+        let signing_input = format!("{}.{}", header, claims);
+        let signature: Signature = self.signing_key.sign(signing_input.as_bytes());
+
+        format!(
+            "vapid t={}.{}, k={}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            self.public_key
+        )
+    }
+
+    /// Delivers one payload. Subscriptions the push service reports as gone
+    /// are deleted.
+    async fn send(&self, pool: &PgPool, subscription: &PushSubscription, payload: &[u8]) -> Result<(), AppError> {
+        let endpoint = Url::parse(&subscription.endpoint)
+            .map_err(|e| AppError::InternalError(format!("Invalid push endpoint: {}", e)))?;
+        let body = encrypt(&decode_key(&subscription.p256dh)?, &decode_key(&subscription.auth)?, payload)?;
+
+        let http = self
+            .egress
+            .client(
+                reqwest::Client::builder().timeout(std::time::Duration::from_secs(HTTP_TIMEOUT_SECONDS)),
+                &endpoint,
+            )
+            .await
+            .map_err(|e| AppError::InternalError(format!("Push endpoint not allowed: {}", e)))?;
+        let response = http
+            .post(endpoint.clone())
+            .header(reqwest::header::AUTHORIZATION, self.vapid_authorization(&endpoint))
+            .header(reqwest::header::CONTENT_ENCODING, "aes128gcm")
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header("TTL", TTL_SECONDS)
+            .header("Urgency", "high")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| AppError::InternalError(format!("Push request failed: {}", e)))?;
+
+        match response.status() {
+            status if status.is_success() => {
+                sqlx::query("UPDATE push_subscriptions SET last_used_at = NOW() WHERE id = $1")
+                    .bind(subscription.id)
+                    .execute(pool)
+                    .await?;
+                Ok(())
+            }
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                sqlx::query("DELETE FROM push_subscriptions WHERE id = $1")
+                    .bind(subscription.id)
+                    .execute(pool)
+                    .await?;
+                Ok(())
+            }
+            status => Err(AppError::InternalError(format!("Push service responded with {}", status))),
+        }
+    }
+}
+
+fn decode_key(value: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim().trim_end_matches('='))
+        .map_err(|_| AppError::Validation("Subscription keys must be base64url-encoded".to_string()))
+}
+
+/// Encrypts a payload for a subscription as a single aes128gcm record
+/// (RFC 8291 section 3.4), keyed with an ephemeral ECDH key per message.
+fn encrypt(ua_public: &[u8], auth_secret: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, AppError> {
+    let failed = |what: &str| AppError::InternalError(format!("Push encryption failed: {}", what));
+
+    let ua_key = PublicKey::from_sec1_bytes(ua_public).map_err(|_| failed("invalid subscription key"))?;
+    let as_secret = EphemeralSecret::random(&mut OsRng);
+    let as_public = as_secret.public_key().to_encoded_point(false);
+    let shared = as_secret.diffie_hellman(&ua_key);
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(ua_public);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth_secret), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|_| failed("key derivation"))?;
+
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let prk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .and_then(|_| prk.expand(b"Content-Encoding: nonce\0", &mut nonce))
+        .map_err(|_| failed("key derivation"))?;
+
+    // A single, final record: the payload followed by the 0x02 delimiter
+    let mut record = plaintext.to_vec();
+    record.push(2);
+    let ciphertext = Aes128Gcm::new(&cek.into())
+        .encrypt(Nonce::from_slice(&nonce), record.as_slice())
+        .map_err(|_| failed("cipher"))?;
+
+    let mut body = Vec::with_capacity(86 + ciphertext.len());
+    body.extend_from_slice(&salt);
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+
+    Ok(body)
+}
+
+/// Whether `needle` occurs in `text` as a whole word, ignoring case.
+fn contains_word(text: &str, needle: &str) -> bool {
+    let text = text.to_lowercase();
+    let needle = needle.to_lowercase();
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+
+    text.match_indices(&needle).any(|(start, matched)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + matched.len()..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+#[derive(sqlx::FromRow)]
+struct Recipient {
+    user_id: Uuid,
+    timezone: Option<String>,
+    /// How the message mentions the recipient, if it does.
+    mention: Option<String>,
+    level: Option<String>,
+    keywords: Option<Vec<String>>,
+    dnd_start: Option<NaiveTime>,
+    dnd_end: Option<NaiveTime>,
+    dnd_until: Option<DateTime<Utc>>,
+}
+
+impl Recipient {
+    /// Why this message should be pushed to the recipient, if at all.
+    fn reason(&self, content: &str, is_direct: bool) -> Option<&'static str> {
+        let level = self
+            .level
+            .as_deref()
+            .unwrap_or(if is_direct { LEVEL_ALL } else { LEVEL_MENTIONS });
+
+        if level == LEVEL_NONE {
+            None
+        } else if self.mention.is_some() {
+            Some("mention")
+        } else if is_direct && level == LEVEL_ALL {
+            Some("direct_message")
+        } else if self.keywords.iter().flatten().any(|keyword| contains_word(content, keyword)) {
+            Some("keyword")
+        } else if level == LEVEL_ALL {
+            Some("message")
+        } else {
+            None
+        }
+    }
+
+    fn in_do_not_disturb(&self, now: DateTime<Utc>) -> bool {
+        if self.dnd_until.is_some_and(|until| until > now) {
+            return true;
+        }
+        let (Some(start), Some(end)) = (self.dnd_start, self.dnd_end) else {
+            return false;
+        };
+
+        let timezone: Tz = self
+            .timezone
+            .as_deref()
+            .and_then(|timezone| timezone.parse().ok())
+            .unwrap_or(Tz::UTC);
+        let local = now.with_timezone(&timezone).time();
+        if start <= end {
+            local >= start && local < end
+        } else {
+            // The window spans midnight, e.g. 22:00-07:00
+            local >= start || local < end
+        }
+    }
+}
+
+/// Direct messages are private rooms with exactly two members.
+async fn is_direct_room(pool: &PgPool, room: &Room) -> Result<bool, AppError> {
+    if !room.is_private {
+        return Ok(false);
+    }
+    let members = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM room_members WHERE room_id = $1")
+        .bind(room.id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(members == 2)
+}
+
+pub fn preview(message: &Message) -> String {
+    let text = match message.message_type.as_str() {
+        "file" => serde_json::from_str::<serde_json::Value>(&message.content)
+            .ok()
+            .and_then(|file| file["filename"].as_str().map(|name| format!("📎 {}", name)))
+            .unwrap_or_else(|| "Sent a file".to_string()),
+        _ => message.content.clone(),
+    };
+    if text.chars().count() > MAX_PREVIEW_CHARS {
+        format!("{}…", text.chars().take(MAX_PREVIEW_CHARS).collect::<String>())
+    } else {
+        text
+    }
+}
+
+/// Pushes a new message to room members who aren't looking at the room,
+/// according to their notification settings. Runs in the background.
+pub fn notify_new_message(state: &AppState, message: &Message) {
+    let Some(push) = state.push.clone() else {
+        return;
+    };
+    if message.message_type == "system" {
+        return;
+    }
+    let state = state.clone();
+    let message = message.clone();
+    tokio::spawn(async move {
+        if let Err(e) = deliver(&state, &push, &message).await {
+            warn!("Failed to send push notifications for message {}: {}", message.id, e);
+        }
+    });
+}
+
+async fn deliver(state: &AppState, push: &Arc<Push>, message: &Message) -> Result<(), AppError> {
+    let Some(sender_id) = message.user_id else {
+        return Ok(());
+    };
+    let Some(room) = get_room_by_id(&state.db, message.room_id).await? else {
+        return Ok(());
+    };
+
+    let recipients = sqlx::query_as::<_, Recipient>(
+        r#"
+        SELECT u.id AS user_id, u.timezone, mm.kind AS mention, rns.level,
+               np.keywords, np.dnd_start, np.dnd_end, np.dnd_until
+        FROM room_members rm
+        JOIN users u ON u.id = rm.user_id
+        LEFT JOIN message_mentions mm ON mm.message_id = $3 AND mm.user_id = rm.user_id
+        LEFT JOIN room_notification_settings rns ON rns.room_id = rm.room_id AND rns.user_id = rm.user_id
+        LEFT JOIN notification_preferences np ON np.user_id = rm.user_id
+        WHERE rm.room_id = $1
+          AND rm.user_id <> $2
+          AND NOT u.is_bot
+          AND u.disabled_at IS NULL
+          AND EXISTS(SELECT 1 FROM push_subscriptions ps WHERE ps.user_id = rm.user_id)
+        "#
+    )
+    .bind(room.id)
+    .bind(sender_id)
+    .bind(message.id)
+    .fetch_all(&state.db)
+    .await?;
+    if recipients.is_empty() {
+        return Ok(());
+    }
+
+    let is_direct = is_direct_room(&state.db, &room).await?;
+    let now = Utc::now();
+    // Members with the room open already see the message
+    let watching: HashSet<Uuid> = {
+        let connections = state.connections.read().await;
+        recipients
+            .iter()
+            .map(|recipient| recipient.user_id)
+            .filter(|user_id| {
+                connections
+                    .get(user_id)
+                    .is_some_and(|handles| handles.iter().any(|handle| handle.room_id == room.id))
+            })
+            .collect()
+    };
+    let targets: Vec<(Uuid, &'static str)> = recipients
+        .iter()
+        .filter(|recipient| !watching.contains(&recipient.user_id) && !recipient.in_do_not_disturb(now))
+        .filter_map(|recipient| {
+            recipient
+                .reason(&message.content, is_direct)
+                .map(|reason| (recipient.user_id, reason))
+        })
+        .collect();
+    if targets.is_empty() {
+        return Ok(());
+    }
+
+    let sender = sqlx::query_as::<_, (String, Option<String>)>(
+        "SELECT username, display_name FROM users WHERE id = $1"
+    )
+    .bind(sender_id)
+    .fetch_optional(&state.db)
+    .await?
+    .map(|(username, display_name)| display_name.unwrap_or(username))
+    .unwrap_or_default();
+    let body = preview(message);
+
+    for (user_id, reason) in targets {
+        let payload = serde_json::json!({
+            "type": "message",
+            "reason": reason,
+            "room_id": room.id,
+            "room_name": room.name,
+            "message_id": message.id,
+            "sender": sender,
+            "body": body,
+            "url": format!("/?room={}", room.id),
+            "created_at": message.created_at,
+        })
+        .to_string();
+
+        for subscription in list_subscriptions(&state.db, user_id).await? {
+            if let Err(e) = push.send(&state.db, &subscription, payload.as_bytes()).await {
+                warn!("Push to subscription {} failed: {}", subscription.id, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/push/public-key", get(public_key_handler))
+        .route("/users/me/push-subscriptions", get(list_handler).post(subscribe_handler))
+        .route("/users/me/push-subscriptions/:subscription_id", delete(unsubscribe_handler))
+        .route("/users/me/notifications", get(get_preferences_handler).put(update_preferences_handler))
+        .route(
+            "/rooms/:room_id/notifications",
+            get(get_room_level_handler).put(update_room_level_handler),
+        )
+}
+
+#[derive(Serialize)]
+struct PublicKeyResponse {
+    enabled: bool,
+    public_key: Option<String>,
+}
+
+/// The application server key browsers need for `pushManager.subscribe()`.
+async fn public_key_handler(State(state): State<SharedState>) -> Json<PublicKeyResponse> {
+    Json(PublicKeyResponse {
+        enabled: state.push.is_some(),
+        public_key: state.push.as_ref().map(|push| push.public_key.clone()),
+    })
+}
+
+pub async fn list_subscriptions(pool: &PgPool, user_id: Uuid) -> Result<Vec<PushSubscription>, AppError> {
+    let subscriptions = sqlx::query_as::<_, PushSubscription>(
+        "SELECT * FROM push_subscriptions WHERE user_id = $1 ORDER BY created_at"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(subscriptions)
+}
+
+async fn list_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<PushSubscription>>, AppError> {
+    Ok(Json(list_subscriptions(&state.db, claims.user_id()?).await?))
+}
+
+#[derive(Deserialize)]
+struct SubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+/// The browser's `PushSubscription.toJSON()`.
+#[derive(Deserialize)]
+struct SubscribeRequest {
+    endpoint: String,
+    keys: SubscriptionKeys,
+}
+
+async fn subscribe_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(req): Json<SubscribeRequest>,
+) -> Result<Json<PushSubscription>, AppError> {
+    let user_id = claims.user_id()?;
+    let push = state
+        .push
+        .as_ref()
+        .ok_or_else(|| AppError::BadRequest("Push notifications are not enabled".to_string()))?;
+
+    let endpoint = Url::parse(&req.endpoint)
+        .map_err(|_| AppError::Validation("Invalid push endpoint".to_string()))?;
+    let secure = endpoint.scheme() == "https" || (push.allow_insecure_endpoints && endpoint.scheme() == "http");
+    if !secure || endpoint.host_str().is_none() {
+        return Err(AppError::Validation("Push endpoints must use HTTPS".to_string()));
+    }
+    if let Err(e) = push.egress.address(&endpoint).await {
+        return Err(AppError::Validation(format!("Push endpoint not allowed: {}", e)));
+    }
+    let p256dh = decode_key(&req.keys.p256dh)?;
+    if PublicKey::from_sec1_bytes(&p256dh).is_err() {
+        return Err(AppError::Validation("Invalid p256dh key".to_string()));
+    }
+    if decode_key(&req.keys.auth)?.len() != 16 {
+        return Err(AppError::Validation("Invalid auth secret".to_string()));
+    }
+
+    let count = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM push_subscriptions WHERE user_id = $1 AND endpoint <> $2"
+    )
+    .bind(user_id)
+    .bind(endpoint.as_str())
+    .fetch_one(&state.db)
+    .await?;
+    if count >= MAX_SUBSCRIPTIONS_PER_USER {
+        return Err(AppError::Validation(format!(
+            "At most {} devices can receive notifications",
+            MAX_SUBSCRIPTIONS_PER_USER
+        )));
+    }
+
+    // Re-subscribing (or signing in as someone else) on a device takes its endpoint over
+    let subscription = sqlx::query_as::<_, PushSubscription>(
+        r#"
+        INSERT INTO push_subscriptions (id, user_id, endpoint, p256dh, auth, user_agent)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (endpoint) DO UPDATE
+            SET user_id = $2, p256dh = $4, auth = $5, user_agent = $6, created_at = NOW(), last_used_at = NULL
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(endpoint.as_str())
+    .bind(URL_SAFE_NO_PAD.encode(&p256dh))
+    .bind(URL_SAFE_NO_PAD.encode(decode_key(&req.keys.auth)?))
+    .bind(headers.get(header::USER_AGENT).and_then(|value| value.to_str().ok()))
+    .fetch_one(&state.db)
+    .await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("push.subscribe")
+            .actor(user_id)
+            .target("push_subscription", subscription.id)
+            .metadata(serde_json::json!({ "push_service": endpoint.host_str() })),
+    ).await;
+
+    Ok(Json(subscription))
+}
+
+async fn unsubscribe_handler(
+    Path(subscription_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user_id = claims.user_id()?;
+    let result = sqlx::query("DELETE FROM push_subscriptions WHERE id = $1 AND user_id = $2")
+        .bind(subscription_id)
+        .bind(user_id)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Subscription not found".to_string()));
+    }
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("push.unsubscribe")
+            .actor(user_id)
+            .target("push_subscription", subscription_id),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+pub async fn get_preferences(pool: &PgPool, user_id: Uuid) -> Result<NotificationPreferences, AppError> {
+    let preferences = sqlx::query_as::<_, NotificationPreferences>(
+        "SELECT keywords, dnd_start, dnd_end, dnd_until FROM notification_preferences WHERE user_id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(preferences.unwrap_or_default())
+}
+
+async fn get_preferences_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<NotificationPreferences>, AppError> {
+    Ok(Json(get_preferences(&state.db, claims.user_id()?).await?))
+}
+
+/// Replaces all preferences; omitted fields are cleared.
+#[derive(Deserialize)]
+struct UpdatePreferencesRequest {
+    #[serde(default)]
+    keywords: Vec<String>,
+    /// `HH:MM` in the user's timezone; set together with `dnd_end`.
+    dnd_start: Option<String>,
+    dnd_end: Option<String>,
+    /// Silences notifications until this time, regardless of the schedule.
+    dnd_until: Option<DateTime<Utc>>,
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, AppError> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M:%S"))
+        .map_err(|_| AppError::Validation(format!("Invalid time '{}', expected HH:MM", value)))
+}
+
+async fn update_preferences_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<UpdatePreferencesRequest>,
+) -> Result<Json<NotificationPreferences>, AppError> {
+    let user_id = claims.user_id()?;
+
+    let mut keywords: Vec<String> = Vec::new();
+    for keyword in &req.keywords {
+        let keyword = keyword.trim();
+        if keyword.is_empty() || keywords.iter().any(|existing| existing.eq_ignore_ascii_case(keyword)) {
+            continue;
+        }
+        if keyword.chars().count() > MAX_KEYWORD_LEN {
+            return Err(AppError::Validation(format!(
+                "Keywords must be at most {} characters",
+                MAX_KEYWORD_LEN
+            )));
+        }
+        keywords.push(keyword.to_string());
+    }
+    if keywords.len() > MAX_KEYWORDS {
+        return Err(AppError::Validation(format!("At most {} keywords are allowed", MAX_KEYWORDS)));
+    }
+
+    let dnd_start = req.dnd_start.as_deref().map(parse_time).transpose()?;
+    let dnd_end = req.dnd_end.as_deref().map(parse_time).transpose()?;
+    if dnd_start.is_some() != dnd_end.is_some() {
+        return Err(AppError::Validation(
+            "Do-not-disturb needs both a start and an end time".to_string(),
+        ));
+    }
+
+    let preferences = sqlx::query_as::<_, NotificationPreferences>(
+        r#"
+        INSERT INTO notification_preferences (user_id, keywords, dnd_start, dnd_end, dnd_until, updated_at)
+        VALUES ($1, $2, $3, $4, $5, NOW())
+        ON CONFLICT (user_id) DO UPDATE
+            SET keywords = $2, dnd_start = $3, dnd_end = $4, dnd_until = $5, updated_at = NOW()
+        RETURNING keywords, dnd_start, dnd_end, dnd_until
+        "#
+    )
+    .bind(user_id)
+    .bind(&keywords)
+    .bind(dnd_start)
+    .bind(dnd_end)
+    .bind(req.dnd_until)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(preferences))
+}
+
+#[derive(Serialize)]
+struct RoomNotificationLevel {
+    room_id: Uuid,
+    /// The effective level.
+    level: String,
+    /// Whether the level is the room's default rather than the user's choice.
+    is_default: bool,
+}
+
+async fn room_level(pool: &PgPool, room: &Room, user_id: Uuid) -> Result<RoomNotificationLevel, AppError> {
+    let level = sqlx::query_scalar::<_, String>(
+        "SELECT level FROM room_notification_settings WHERE room_id = $1 AND user_id = $2"
+    )
+    .bind(room.id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let is_default = level.is_none();
+    let level = match level {
+        Some(level) => level,
+        None if is_direct_room(pool, room).await? => LEVEL_ALL.to_string(),
+        None => LEVEL_MENTIONS.to_string(),
+    };
+
+    Ok(RoomNotificationLevel { room_id: room.id, level, is_default })
+}
+
+async fn get_room_level_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<RoomNotificationLevel>, AppError> {
+    let user_id = claims.user_id()?;
+    let room = ensure_room_access(&state.db, room_id, user_id).await?;
+    Ok(Json(room_level(&state.db, &room, user_id).await?))
+}
+
+/// `null` returns the room to its default level.
+#[derive(Deserialize)]
+struct UpdateRoomLevelRequest {
+    level: Option<String>,
+}
+
+async fn update_room_level_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<UpdateRoomLevelRequest>,
+) -> Result<Json<RoomNotificationLevel>, AppError> {
+    let user_id = claims.user_id()?;
+    let room = ensure_room_access(&state.db, room_id, user_id).await?;
+
+    match req.level.as_deref() {
+        Some(level @ (LEVEL_ALL | LEVEL_MENTIONS | LEVEL_NONE)) => {
+            sqlx::query(
+                r#"
+                INSERT INTO room_notification_settings (room_id, user_id, level, updated_at)
+                VALUES ($1, $2, $3, NOW())
+                ON CONFLICT (room_id, user_id) DO UPDATE SET level = $3, updated_at = NOW()
+                "#
+            )
+            .bind(room.id)
+            .bind(user_id)
+            .bind(level)
+            .execute(&state.db)
+            .await?;
+        }
+        Some(level) => {
+            return Err(AppError::Validation(format!(
+                "Unknown notification level '{}', expected all, mentions or none",
+                level
+            )));
+        }
+        None => {
+            sqlx::query("DELETE FROM room_notification_settings WHERE room_id = $1 AND user_id = $2")
+                .bind(room.id)
+                .bind(user_id)
+                .execute(&state.db)
+                .await?;
+        }
+    }
+
+    Ok(Json(room_level(&state.db, &room, user_id).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use axum::{body::Bytes, http::StatusCode, routing::post};
+    use p256::ecdsa::{signature::Verifier, VerifyingKey};
+    use std::{net::SocketAddr, sync::Mutex};
+
+    /// Requests a push service received: the path, `Authorization` header and body.
+    type Received = Arc<Mutex<Vec<(String, String, Bytes)>>>;
+
+    /// A stand-in push service. `/gone` and `/missing` answer 410 and 404 as
+    /// for expired subscriptions, `/broken` 500 and anything else 201.
+    async fn push_service() -> (SocketAddr, Received) {
+        let received = Received::default();
+        let log = Arc::clone(&received);
+        let router = Router::new().route(
+            "/:device",
+            post(move |Path(device): Path<String>, headers: HeaderMap, body: Bytes| async move {
+                let authorization = headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                log.lock().unwrap().push((device.clone(), authorization, body));
+                match device.as_str() {
+                    "gone" => StatusCode::GONE,
+                    "missing" => StatusCode::NOT_FOUND,
+                    "broken" => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::CREATED,
+                }
+            }),
+        );
+        (test_support::serve(router).await, received)
+    }
+
+    fn push(allowed_hosts: &str) -> Push {
+        Push::new(
+            SecretKey::random(&mut OsRng),
+            "mailto:admin@example.com".to_string(),
+            true,
+            Egress::allowing(allowed_hosts).expect("allow list"),
+        )
+    }
+
+    /// A browser's side of a subscription: its key pair and auth secret.
+    struct Device {
+        secret: SecretKey,
+        auth: [u8; 16],
+    }
+
+    impl Device {
+        fn new() -> Self {
+            let mut auth = [0u8; 16];
+            OsRng.fill_bytes(&mut auth);
+            Self { secret: SecretKey::random(&mut OsRng), auth }
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            self.secret.public_key().to_encoded_point(false).as_bytes().to_vec()
+        }
+
+        fn request(&self, endpoint: &str) -> SubscribeRequest {
+            SubscribeRequest {
+                endpoint: endpoint.to_string(),
+                keys: SubscriptionKeys {
+                    p256dh: URL_SAFE_NO_PAD.encode(self.public_key()),
+                    auth: URL_SAFE_NO_PAD.encode(self.auth),
+                },
+            }
+        }
+
+        async fn subscribe(&self, pool: &PgPool, user_id: Uuid, endpoint: &str) -> PushSubscription {
+            sqlx::query_as::<_, PushSubscription>(
+                "INSERT INTO push_subscriptions (id, user_id, endpoint, p256dh, auth) VALUES ($1, $2, $3, $4, $5) RETURNING *"
+            )
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(endpoint)
+            .bind(URL_SAFE_NO_PAD.encode(self.public_key()))
+            .bind(URL_SAFE_NO_PAD.encode(self.auth))
+            .fetch_one(pool)
+            .await
+            .expect("subscription is stored")
+        }
+
+        /// Decrypts an aes128gcm body the way the browser does (RFC 8291).
+        fn decrypt(&self, body: &[u8]) -> Vec<u8> {
+            let (salt, rest) = body.split_at(16);
+            let key_len = rest[4] as usize;
+            let (as_public, ciphertext) = rest[5..].split_at(key_len);
+            let shared = p256::ecdh::diffie_hellman(
+                self.secret.to_nonzero_scalar(),
+                PublicKey::from_sec1_bytes(as_public).expect("server key").as_affine(),
+            );
+
+            let mut key_info = b"WebPush: info\0".to_vec();
+            key_info.extend_from_slice(&self.public_key());
+            key_info.extend_from_slice(as_public);
+            let mut ikm = [0u8; 32];
+            Hkdf::<Sha256>::new(Some(&self.auth), shared.raw_secret_bytes())
+                .expand(&key_info, &mut ikm)
+                .unwrap();
+            let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+            let mut cek = [0u8; 16];
+            let mut nonce = [0u8; 12];
+            prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek).unwrap();
+            prk.expand(b"Content-Encoding: nonce\0", &mut nonce).unwrap();
+
+            let mut record = Aes128Gcm::new(&cek.into())
+                .decrypt(Nonce::from_slice(&nonce), ciphertext)
+                .expect("payload decrypts");
+            assert_eq!(record.pop(), Some(2), "single final record");
+            record
+        }
+    }
+
+    async fn subscription_exists(pool: &PgPool, id: Uuid) -> bool {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM push_subscriptions WHERE id = $1)")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn subscribing_registers_the_device(pool: PgPool) {
+        let (address, _) = push_service().await;
+        let user = test_support::create_user(&pool, "alice").await;
+        let mut state = test_support::state(pool.clone());
+        state.push = Some(Arc::new(push("127.0.0.1")));
+        let device = Device::new();
+        let endpoint = format!("http://{}/device", address);
+
+        let Json(subscription) = subscribe_handler(
+            State(Arc::new(state)),
+            Extension(test_support::claims(&user)),
+            ClientInfo { ip: None, user_agent: None },
+            HeaderMap::new(),
+            Json(device.request(&endpoint)),
+        )
+        .await
+        .expect("subscribed");
+
+        assert_eq!(subscription.user_id, user.id);
+        assert_eq!(subscription.endpoint, endpoint);
+        assert_eq!(list_subscriptions(&pool, user.id).await.unwrap().len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn internal_endpoints_are_refused(pool: PgPool) {
+        let (address, _) = push_service().await;
+        let user = test_support::create_user(&pool, "alice").await;
+        let mut state = test_support::state(pool.clone());
+        state.push = Some(Arc::new(push("")));
+        let state = Arc::new(state);
+        let device = Device::new();
+
+        for endpoint in [
+            format!("http://{}/device", address),
+            "http://localhost/device".to_string(),
+            "https://169.254.169.254/latest/meta-data".to_string(),
+            "https://10.0.0.1/device".to_string(),
+            "https://[::1]/device".to_string(),
+            "https://[::ffff:192.168.1.1]/device".to_string(),
+        ] {
+            let result = subscribe_handler(
+                State(Arc::clone(&state)),
+                Extension(test_support::claims(&user)),
+                ClientInfo { ip: None, user_agent: None },
+                HeaderMap::new(),
+                Json(device.request(&endpoint)),
+            )
+            .await;
+            assert!(matches!(result, Err(AppError::Validation(_))), "{} was accepted", endpoint);
+        }
+        assert!(list_subscriptions(&pool, user.id).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn notifications_are_encrypted_for_the_device(pool: PgPool) {
+        let (address, received) = push_service().await;
+        let user = test_support::create_user(&pool, "alice").await;
+        let push = push("127.0.0.1");
+        let device = Device::new();
+        let subscription = device.subscribe(&pool, user.id, &format!("http://{}/device", address)).await;
+
+        push.send(&pool, &subscription, b"{\"body\":\"hello\"}").await.expect("delivered");
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        let (_, authorization, body) = &received[0];
+        assert_eq!(device.decrypt(body), b"{\"body\":\"hello\"}");
+
+        // vapid t=<JWT>, k=<application server key>
+        let (token, key) = authorization
+            .strip_prefix("vapid t=")
+            .and_then(|rest| rest.split_once(", k="))
+            .expect("VAPID authorization");
+        assert_eq!(key, push.public_key);
+        let (signing_input, signature) = token.rsplit_once('.').expect("signed token");
+        let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(signature).unwrap()).unwrap();
+        let verifying_key = VerifyingKey::from_sec1_bytes(&URL_SAFE_NO_PAD.decode(key).unwrap()).unwrap();
+        verifying_key.verify(signing_input.as_bytes(), &signature).expect("valid VAPID signature");
+
+        let last_used_at: Option<DateTime<Utc>> =
+            sqlx::query_scalar("SELECT last_used_at FROM push_subscriptions WHERE id = $1")
+                .bind(subscription.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(last_used_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn expired_subscriptions_are_pruned(pool: PgPool) {
+        let (address, _) = push_service().await;
+        let user = test_support::create_user(&pool, "alice").await;
+        let push = push("127.0.0.1");
+
+        for device in ["gone", "missing"] {
+            let subscription = Device::new().subscribe(&pool, user.id, &format!("http://{}/{}", address, device)).await;
+            push.send(&pool, &subscription, b"hello").await.expect("handled");
+            assert!(!subscription_exists(&pool, subscription.id).await, "{} subscription kept", device);
+        }
+
+        let broken = Device::new().subscribe(&pool, user.id, &format!("http://{}/broken", address)).await;
+        assert!(push.send(&pool, &broken, b"hello").await.is_err());
+        assert!(subscription_exists(&pool, broken.id).await);
+    }
+
+    #[sqlx::test]
+    async fn stored_internal_endpoints_are_not_contacted(pool: PgPool) {
+        let (address, received) = push_service().await;
+        let user = test_support::create_user(&pool, "alice").await;
+        let subscription = Device::new().subscribe(&pool, user.id, &format!("http://{}/device", address)).await;
+
+        assert!(push("").send(&pool, &subscription, b"hello").await.is_err());
+        assert!(received.lock().unwrap().is_empty());
+        assert!(subscription_exists(&pool, subscription.id).await);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::{audit::ClientInfo, auth::AuthClaims, error::AppError, SharedState};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// How often idle buckets and expired lockouts are swept from memory.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A token bucket holding `capacity` tokens that refills completely over `period`.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Policy {
+    /// Reads a `CAPACITY/SECONDS` policy (e.g. `10/60`) from the environment.
+    fn from_env(name: &str, default: Policy) -> Policy {
+        let Ok(value) = std::env::var(name) else {
+            return default;
+        };
+
+        let parsed = value.split_once('/').and_then(|(capacity, seconds)| {
+            let capacity = capacity.trim().parse::<u32>().ok()?;
+            let seconds = seconds.trim().parse::<u64>().ok()?;
+            (capacity > 0 && seconds > 0).then(|| Policy {
+                capacity,
+                period: Duration::from_secs(seconds),
+            })
+        });
+
+        parsed.unwrap_or_else(|| {
+            warn!("Ignoring invalid {}={:?}, expected CAPACITY/SECONDS", name, value);
+            default
+        })
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+pub struct RateLimiter {
+    policy: Policy,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one token from every key's bucket, or none if any bucket is empty.
+    pub fn check(&self, keys: &[String]) -> Result<(), AppError> {
+        let now = Instant::now();
+        let capacity = self.policy.capacity as f64;
+        let refill = self.policy.refill_per_sec();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut retry_after = 0.0_f64;
+        for key in keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: capacity,
+                updated_at: now,
+            });
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * refill).min(capacity);
+            bucket.updated_at = now;
+            if bucket.tokens < 1.0 {
+                retry_after = retry_after.max((1.0 - bucket.tokens) / refill);
+            }
+        }
+
+        if retry_after > 0.0 {
+            return Err(AppError::RateLimited {
+                retry_after: retry_after.ceil() as u64,
+            });
+        }
+
+        for key in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops buckets that have refilled completely; they'd be recreated full anyway.
+    fn prune(&self) {
+        let now = Instant::now();
+        let capacity = self.policy.capacity as f64;
+        let refill = self.policy.refill_per_sec();
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+            bucket.tokens + elapsed * refill < capacity
+        });
+    }
+}
+
+struct FailureRecord {
+    failures: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// Progressive lockout after repeated failed logins: once `threshold` failures
+/// accumulate, each further failure doubles the lockout up to `max_lockout`.
+pub struct LoginLockout {
+    threshold: u32,
+    base_lockout: Duration,
+    max_lockout: Duration,
+    records: Mutex<HashMap<String, FailureRecord>>,
+}
+
+impl LoginLockout {
+    /// Locks out after `threshold_var` failures (default `default_threshold`).
+    fn from_env(threshold_var: &str, default_threshold: u64) -> Self {
+        let env_u64 = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+
+        Self {
+            threshold: env_u64(threshold_var, default_threshold) as u32,
+            base_lockout: Duration::from_secs(env_u64("LOGIN_LOCKOUT_BASE_SECONDS", 30)),
+            max_lockout: Duration::from_secs(env_u64("LOGIN_LOCKOUT_MAX_SECONDS", 3600)),
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fails while any of the keys (e.g. email and IP) is locked out.
+    pub fn check(&self, keys: &[String]) -> Result<(), AppError> {
+        let now = Instant::now();
+        let records = self.records.lock().unwrap();
+
+        let retry_after = keys
+            .iter()
+            .filter_map(|key| records.get(key)?.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until.duration_since(now).as_secs().max(1))
+            .max();
+
+        match retry_after {
+            Some(retry_after) => Err(AppError::RateLimited { retry_after }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&self, keys: &[String]) {
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+
+        for key in keys {
+            let record = records.entry(key.clone()).or_insert(FailureRecord {
+                failures: 0,
+                locked_until: None,
+                last_failure: now,
+            });
+
+            // Failures are forgotten after a quiet period as long as the longest lockout
+            if now.duration_since(record.last_failure) > self.max_lockout {
+                record.failures = 0;
+            }
+            record.failures += 1;
+            record.last_failure = now;
+
+            if record.failures >= self.threshold {
+                let doublings = (record.failures - self.threshold).min(16);
+                let lockout = self
+                    .base_lockout
+                    .saturating_mul(1 << doublings)
+                    .min(self.max_lockout);
+                record.locked_until = Some(now + lockout);
+            }
+        }
+    }
+
+    pub fn record_success(&self, keys: &[String]) {
+        let mut records = self.records.lock().unwrap();
+        for key in keys {
+            records.remove(key);
+        }
+    }
+
+    fn prune(&self) {
+        let now = Instant::now();
+        let max_lockout = self.max_lockout;
+        self.records
+            .lock()
+            .unwrap()
+            .retain(|_, record| now.duration_since(record.last_failure) <= max_lockout);
+    }
+}
+
+/// Per-endpoint-group limits. Each policy is configured as `CAPACITY/SECONDS`.
+pub struct RateLimits {
+    pub enabled: bool,
+    pub auth: RateLimiter,
+    pub messages: RateLimiter,
+    pub room_creation: RateLimiter,
+    pub uploads: RateLimiter,
+    pub webhooks: RateLimiter,
+    pub login_lockout: LoginLockout,
+    /// Failed logins for an email from any address; trips later than `login_lockout`.
+    pub account_lockout: LoginLockout,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("RATE_LIMIT_ENABLED")
+            .map(|value| value != "false" && value != "0")
+            .unwrap_or(true);
+
+        let policy = |name, capacity, seconds| {
+            RateLimiter::new(Policy::from_env(
+                name,
+                Policy {
+                    capacity,
+                    period: Duration::from_secs(seconds),
+                },
+            ))
+        };
+
+        Self {
+            enabled,
+            auth: policy("RATE_LIMIT_AUTH", 10, 60),
+            messages: policy("RATE_LIMIT_MESSAGES", 30, 30),
+            room_creation: policy("RATE_LIMIT_ROOM_CREATION", 5, 300),
+            uploads: policy("RATE_LIMIT_UPLOADS", 20, 300),
+            webhooks: policy("RATE_LIMIT_WEBHOOKS", 30, 60),
+            login_lockout: LoginLockout::from_env("LOGIN_LOCKOUT_THRESHOLD", 5),
+            account_lockout: LoginLockout::from_env("LOGIN_ACCOUNT_LOCKOUT_THRESHOLD", 20),
+        }
+    }
+
+    /// Checks `limiter` for the given keys unless rate limiting is disabled.
+    pub fn check(&self, limiter: &RateLimiter, keys: &[String]) -> Result<(), AppError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        limiter.check(keys)
+    }
+
+    pub fn prune(&self) {
+        self.auth.prune();
+        self.messages.prune();
+        self.room_creation.prune();
+        self.uploads.prune();
+        self.webhooks.prune();
+        self.login_lockout.prune();
+        self.account_lockout.prune();
+    }
+}
+
+/// Bucket keys for a request: the client IP and, when authenticated, the user.
+pub fn keys_for(client: &ClientInfo, claims: Option<&AuthClaims>) -> Vec<String> {
+    let mut keys = Vec::with_capacity(2);
+    if let Some(ip) = &client.ip {
+        keys.push(format!("ip:{}", ip));
+    }
+    if let Some(claims) = claims {
+        keys.push(format!("user:{}", claims.sub));
+    }
+    keys
+}
+
+async fn enforce(
+    state: &SharedState,
+    limiter: impl Fn(&RateLimits) -> &RateLimiter,
+    client: ClientInfo,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let keys = keys_for(&client, req.extensions().get::<AuthClaims>());
+    state.rate_limits.check(limiter(&state.rate_limits), &keys)?;
+    Ok(next.run(req).await)
+}
+
+pub async fn limit_auth(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    enforce(&state, |limits| &limits.auth, client, req, next).await
+}
+
+pub async fn limit_messages(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    enforce(&state, |limits| &limits.messages, client, req, next).await
+}
+
+pub async fn limit_room_creation(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    enforce(&state, |limits| &limits.room_creation, client, req, next).await
+}
+
+pub async fn limit_uploads(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    enforce(&state, |limits| &limits.uploads, client, req, next).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout(threshold: u32) -> LoginLockout {
+        LoginLockout {
+            threshold,
+            base_lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(3600),
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn retry_after(lockout: &LoginLockout, keys: &[String]) -> u64 {
+        match lockout.check(keys) {
+            Err(AppError::RateLimited { retry_after }) => retry_after,
+            _ => panic!("not locked out"),
+        }
+    }
+
+    #[test]
+    fn failures_spread_over_addresses_lock_the_account_later() {
+        let per_address = lockout(5);
+        let per_account = lockout(20);
+        let account = vec!["login:alice@example.com".to_string()];
+
+        for attempt in 0..20 {
+            let address = vec![format!("login:alice@example.com:10.0.0.{}", attempt / 4)];
+            assert!(per_address.check(&address).is_ok());
+            assert!(per_account.check(&account).is_ok(), "locked after {} failures", attempt);
+            per_address.record_failure(&address);
+            per_account.record_failure(&account);
+        }
+
+        assert!((29..=30).contains(&retry_after(&per_account, &account)));
+        per_account.record_success(&account);
+        assert!(per_account.check(&account).is_ok());
+    }
+
+    #[test]
+    fn lockout_doubles_with_each_further_failure() {
+        let lockout = lockout(2);
+        let keys = vec!["login:alice@example.com:10.0.0.1".to_string()];
+        lockout.record_failure(&keys);
+        assert!(lockout.check(&keys).is_ok());
+        lockout.record_failure(&keys);
+        assert!((29..=30).contains(&retry_after(&lockout, &keys)));
+        lockout.record_failure(&keys);
+        assert!((59..=60).contains(&retry_after(&lockout, &keys)));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{
+    auth::AuthClaims,
+    chat::{ensure_room_access, get_message, send_message},
+    error::AppError,
+    models::*,
+    outgoing_webhooks::{self, EVENT_MESSAGE_CREATED},
+    push,
+    websocket::broadcast_to_room,
+    AppState, SharedState,
+};
+use axum::{
+    extract::{Path, State},
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::warn;
+use uuid::Uuid;
+
+pub const MAX_TEXT_CHARS: usize = 1000;
+/// Furthest ahead a reminder can be set.
+pub const MAX_AHEAD_DAYS: i64 = 365;
+/// Pending reminders a user can have at once.
+const MAX_PENDING_PER_USER: i64 = 100;
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+const BATCH_SIZE: i64 = 50;
+/// How much of a message a reminder about it quotes.
+const QUOTE_CHARS: usize = 200;
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/reminders", get(list_handler))
+        .route("/reminders/:reminder_id", put(update_handler).delete(cancel_handler))
+        .route("/rooms/:room_id/reminders", post(create_handler))
+        .route("/rooms/:room_id/messages/:message_id/reminders", post(create_for_message_handler))
+}
+
+/// Starts the loop that posts due reminders. Reminders are claimed with
+/// `FOR UPDATE SKIP LOCKED`, so every instance can run it.
+pub fn spawn(state: SharedState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = deliver_due(&state).await {
+                warn!("Failed to deliver reminders: {}", e);
+            }
+        }
+    });
+}
+
+fn validate(text: &str, remind_at: DateTime<Utc>) -> Result<(), AppError> {
+    if text.is_empty() || text.chars().count() > MAX_TEXT_CHARS {
+        return Err(AppError::Validation(format!(
+            "Reminder text must be between 1 and {} characters",
+            MAX_TEXT_CHARS
+        )));
+    }
+    let now = Utc::now();
+    if remind_at <= now || remind_at > now + Duration::days(MAX_AHEAD_DAYS) {
+        return Err(AppError::Validation(format!(
+            "Reminders must be set for the next {} days",
+            MAX_AHEAD_DAYS
+        )));
+    }
+    Ok(())
+}
+
+/// `message_id` makes it a reminder about that message, which it quotes
+/// when it's posted.
+pub async fn create(
+    pool: &PgPool,
+    user_id: Uuid,
+    room_id: Uuid,
+    text: &str,
+    remind_at: DateTime<Utc>,
+    message_id: Option<Uuid>,
+) -> Result<Reminder, AppError> {
+    let text = text.trim();
+    validate(text, remind_at)?;
+
+    let pending = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM reminders WHERE user_id = $1 AND delivered_at IS NULL"
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    if pending >= MAX_PENDING_PER_USER {
+        return Err(AppError::BadRequest(format!(
+            "You can have at most {} pending reminders",
+            MAX_PENDING_PER_USER
+        )));
+    }
+
+    let reminder = sqlx::query_as::<_, Reminder>(
+        r#"
+        INSERT INTO reminders (id, user_id, room_id, text, remind_at, message_id)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(room_id)
+    .bind(text)
+    .bind(remind_at)
+    .bind(message_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(reminder)
+}
+
+async fn get_pending(pool: &PgPool, reminder_id: Uuid, user_id: Uuid) -> Result<Reminder, AppError> {
+    let reminder = sqlx::query_as::<_, Reminder>(
+        "SELECT * FROM reminders WHERE id = $1 AND user_id = $2 AND delivered_at IS NULL"
+    )
+    .bind(reminder_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Reminder not found".to_string()))?;
+
+    Ok(reminder)
+}
+
+async fn deliver_due(state: &AppState) -> Result<(), AppError> {
+    loop {
+        // Marked delivered as they're claimed: a crash mid-batch drops a
+        // reminder rather than posting it twice
+        let reminders = sqlx::query_as::<_, Reminder>(
+            r#"
+            WITH due AS (
+                SELECT id FROM reminders
+                WHERE delivered_at IS NULL AND remind_at <= NOW()
+                ORDER BY remind_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            UPDATE reminders r
+            SET delivered_at = NOW()
+            FROM due
+            WHERE r.id = due.id
+            RETURNING r.*
+            "#
+        )
+        .bind(BATCH_SIZE)
+        .fetch_all(&state.db)
+        .await?;
+
+        for reminder in &reminders {
+            if let Err(e) = deliver(state, reminder).await {
+                warn!("Failed to deliver reminder {}: {}", reminder.id, e);
+            }
+        }
+        if (reminders.len() as i64) < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+/// Posts the reminder to the room it was set in, unless the user has lost
+/// access to the room since.
+async fn deliver(state: &AppState, reminder: &Reminder) -> Result<(), AppError> {
+    if ensure_room_access(&state.db, reminder.room_id, reminder.user_id).await.is_err() {
+        return Ok(());
+    }
+
+    let mut content = format!("Reminder: {}", reminder.text);
+    if let Some(message_id) = reminder.message_id {
+        if let Ok(message) = get_message(&state.db, reminder.room_id, message_id).await {
+            content.push_str(&format!("\n> {}", quote(&message)));
+        }
+    }
+    let message = send_message(state, reminder.room_id, reminder.user_id, &content, "reminder").await?;
+    broadcast_to_room(state, reminder.room_id, &WebSocketMessage::new("new_message", &message)).await;
+    push::notify_new_message(state, &message);
+    outgoing_webhooks::dispatch_message(&state.db, EVENT_MESSAGE_CREATED, &message).await;
+
+    Ok(())
+}
+
+/// A one-line excerpt of the message a reminder is about.
+fn quote(message: &Message) -> String {
+    if message.message_type == "file" {
+        return "(a file)".to_string();
+    }
+    let line = message.content.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() > QUOTE_CHARS {
+        format!("{}…", line.chars().take(QUOTE_CHARS).collect::<String>())
+    } else {
+        line
+    }
+}
+
+/// The caller's reminders that haven't been posted yet, soonest first.
+async fn list_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<Vec<Reminder>>, AppError> {
+    let user_id = claims.user_id()?;
+    let reminders = sqlx::query_as::<_, Reminder>(
+        "SELECT * FROM reminders WHERE user_id = $1 AND delivered_at IS NULL ORDER BY remind_at"
+    )
+    .bind(user_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(reminders))
+}
+
+#[derive(Deserialize)]
+struct CreateReminderRequest {
+    text: String,
+    remind_at: DateTime<Utc>,
+}
+
+async fn create_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<CreateReminderRequest>,
+) -> Result<Json<Reminder>, AppError> {
+    let user_id = claims.user_id()?;
+    ensure_room_access(&state.db, room_id, user_id).await?;
+    let reminder = create(&state.db, user_id, room_id, &req.text, req.remind_at, None).await?;
+    Ok(Json(reminder))
+}
+
+#[derive(Deserialize)]
+struct MessageReminderRequest {
+    text: Option<String>,
+    remind_at: DateTime<Utc>,
+}
+
+/// "Remind me about this message": the text defaults to a generic note,
+/// since the reminder quotes the message anyway.
+async fn create_for_message_handler(
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<MessageReminderRequest>,
+) -> Result<Json<Reminder>, AppError> {
+    let user_id = claims.user_id()?;
+    ensure_room_access(&state.db, room_id, user_id).await?;
+    get_message(&state.db, room_id, message_id).await?;
+
+    let text = req.text.as_deref().map(str::trim).filter(|text| !text.is_empty()).unwrap_or("this message");
+    let reminder = create(&state.db, user_id, room_id, text, req.remind_at, Some(message_id)).await?;
+    Ok(Json(reminder))
+}
+
+#[derive(Deserialize)]
+struct UpdateReminderRequest {
+    text: Option<String>,
+    remind_at: Option<DateTime<Utc>>,
+}
+
+/// Changes a pending reminder's text or time. Reminders already claimed for
+/// delivery can't be changed.
+async fn update_handler(
+    Path(reminder_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<UpdateReminderRequest>,
+) -> Result<Json<Reminder>, AppError> {
+    let user_id = claims.user_id()?;
+    let reminder = get_pending(&state.db, reminder_id, user_id).await?;
+    let text = req.text.as_deref().map(str::trim).unwrap_or(&reminder.text);
+    let remind_at = req.remind_at.unwrap_or(reminder.remind_at);
+    validate(text, remind_at)?;
+
+    // Rechecks `delivered_at`: the scheduler may have claimed it meanwhile
+    let reminder = sqlx::query_as::<_, Reminder>(
+        r#"
+        UPDATE reminders SET text = $3, remind_at = $4, updated_at = NOW()
+        WHERE id = $1 AND user_id = $2 AND delivered_at IS NULL
+        RETURNING *
+        "#
+    )
+    .bind(reminder_id)
+    .bind(user_id)
+    .bind(text)
+    .bind(remind_at)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Reminder not found".to_string()))?;
+
+    Ok(Json(reminder))
+}
+
+async fn cancel_handler(
+    Path(reminder_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user_id = claims.user_id()?;
+    let result = sqlx::query("DELETE FROM reminders WHERE id = $1 AND user_id = $2 AND delivered_at IS NULL")
+        .bind(reminder_id)
+        .bind(user_id)
+        .execute(&state.db)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Reminder not found".to_string()));
+    }
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
@@ -0,0 +1,194 @@
+use crate::{
+    error::AppError,
+    models::*,
+    settings, uploads,
+    websocket::broadcast_to_room,
+    AppState, SharedState,
+};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+pub const MIN_EXPIRY_SECONDS: i64 = 5;
+/// Longest self-destruct timer (30 days).
+pub const MAX_EXPIRY_SECONDS: i64 = 30 * 24 * 60 * 60;
+/// Longest room retention (10 years).
+pub const MAX_RETENTION_DAYS: i32 = 3650;
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const BATCH_SIZE: i64 = 500;
+
+/// Starts the loop that purges self-destructed messages and messages past
+/// their room's retention. Rows are claimed with `FOR UPDATE SKIP LOCKED`,
+/// so every instance can run it.
+pub fn spawn(state: SharedState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge(&state).await {
+                warn!("Failed to purge expired messages: {}", e);
+            }
+        }
+    });
+}
+
+/// When a message sent with a self-destruct timer of `expires_in` seconds
+/// expires.
+pub fn expires_at(expires_in: Option<i64>) -> Result<Option<DateTime<Utc>>, AppError> {
+    let Some(seconds) = expires_in else {
+        return Ok(None);
+    };
+    if !(MIN_EXPIRY_SECONDS..=MAX_EXPIRY_SECONDS).contains(&seconds) {
+        return Err(AppError::Validation(format!(
+            "Self-destruct timers must be between {} and {} seconds",
+            MIN_EXPIRY_SECONDS, MAX_EXPIRY_SECONDS
+        )));
+    }
+    Ok(Some(Utc::now() + Duration::seconds(seconds)))
+}
+
+/// Deletes expired messages in batches until none are left, unless the
+/// instance is under legal hold.
+async fn purge(state: &AppState) -> Result<(), AppError> {
+    loop {
+        // Checked per batch, so a hold placed mid-run stops it
+        if settings::get_bool(&state.db, settings::LEGAL_HOLD).await? {
+            return Ok(());
+        }
+
+        let deleted = sqlx::query_as::<_, (Uuid, Uuid, Option<Uuid>)>(
+            r#"
+            WITH expired AS (
+                SELECT m.id FROM messages m
+                JOIN rooms r ON r.id = m.room_id
+                WHERE m.expires_at <= NOW()
+                   OR (r.retention_days IS NOT NULL
+                       AND m.created_at < NOW() - make_interval(days => r.retention_days))
+                LIMIT $1
+                FOR UPDATE OF m SKIP LOCKED
+            )
+            DELETE FROM messages m
+            USING expired
+            WHERE m.id = expired.id
+            RETURNING m.id, m.room_id, m.file_id
+            "#
+        )
+        .bind(BATCH_SIZE)
+        .fetch_all(&state.db)
+        .await?;
+
+        let mut by_room: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+        let mut file_ids = Vec::new();
+        for (message_id, room_id, file_id) in &deleted {
+            by_room.entry(*room_id).or_default().push(*message_id);
+            file_ids.extend(*file_id);
+        }
+
+        // Uploads shared in another message or used as an avatar are kept
+        let removed_files = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            DELETE FROM files f
+            WHERE f.id = ANY($1)
+              AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.file_id = f.id)
+              AND NOT EXISTS (SELECT 1 FROM users u WHERE u.avatar_file_id = f.id)
+            RETURNING f.id
+            "#
+        )
+        .bind(&file_ids)
+        .fetch_all(&state.db)
+        .await?;
+        for file_id in &removed_files {
+            uploads::remove(*file_id).await?;
+        }
+
+        for (room_id, message_ids) in by_room {
+            let event = WebSocketMessage::new(
+                "messages_deleted",
+                &serde_json::json!({ "room_id": room_id, "message_ids": message_ids }),
+            );
+            broadcast_to_room(state, room_id, &event).await;
+        }
+
+        if !deleted.is_empty() {
+            info!("Purged {} expired messages and {} files", deleted.len(), removed_files.len());
+        }
+        if (deleted.len() as i64) < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat::{create_room, send_message, send_message_with, MessageOptions},
+        test_support,
+    };
+    use sqlx::PgPool;
+
+    #[test]
+    fn expires_at_is_optional() {
+        assert!(expires_at(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn expires_at_adds_the_timer_to_now() {
+        let before = Utc::now();
+        let expires = expires_at(Some(60)).unwrap().unwrap();
+        assert!(expires >= before + Duration::seconds(60));
+        assert!(expires <= Utc::now() + Duration::seconds(60));
+        assert!(expires_at(Some(MIN_EXPIRY_SECONDS)).unwrap().is_some());
+        assert!(expires_at(Some(MAX_EXPIRY_SECONDS)).unwrap().is_some());
+    }
+
+    #[test]
+    fn expires_at_rejects_timers_out_of_range() {
+        for seconds in [-1, 0, MIN_EXPIRY_SECONDS - 1, MAX_EXPIRY_SECONDS + 1, i64::MAX] {
+            assert!(matches!(expires_at(Some(seconds)), Err(AppError::Validation(_))), "{}s accepted", seconds);
+        }
+    }
+
+    async fn upload(pool: &PgPool, user_id: Uuid) -> Uuid {
+        let file_id = Uuid::new_v4();
+        sqlx::query("INSERT INTO files (id, user_id, filename, size) VALUES ($1, $2, 'notes.txt', 5)")
+            .bind(file_id)
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        file_id
+    }
+
+    async fn exists(pool: &PgPool, table: &str, id: Uuid) -> bool {
+        sqlx::query_scalar(&format!("SELECT EXISTS(SELECT 1 FROM {} WHERE id = $1)", table))
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn purging_removes_uploads_no_other_message_shares(pool: PgPool) {
+        let state = test_support::state(pool.clone());
+        let user = test_support::create_user(&pool, "alice").await;
+        let room = create_room(&pool, "general", None, false, user.id).await.unwrap();
+        let expired = || MessageOptions { expires_at: Some(Utc::now() - Duration::seconds(1)), ..Default::default() };
+        let shared = upload(&pool, user.id).await;
+        let unshared = upload(&pool, user.id).await;
+
+        let content = |file_id: Uuid| serde_json::json!({ "id": file_id }).to_string();
+        let first = send_message_with(&state, room.id, user.id, &content(shared), "file", expired()).await.unwrap();
+        let second = send_message(&state, room.id, user.id, &content(shared), "file").await.unwrap();
+        let third = send_message_with(&state, room.id, user.id, &content(unshared), "file", expired()).await.unwrap();
+
+        purge(&state).await.unwrap();
+
+        assert!(!exists(&pool, "messages", first.id).await);
+        assert!(exists(&pool, "messages", second.id).await);
+        assert!(!exists(&pool, "messages", third.id).await);
+        assert!(exists(&pool, "files", shared).await, "an upload still shared was removed");
+        assert!(!exists(&pool, "files", unshared).await);
+    }
+}
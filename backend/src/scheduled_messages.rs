@@ -0,0 +1,304 @@
+use crate::{
+    auth::AuthClaims,
+    chat::{ensure_room_access, join_room, send_message},
+    commands,
+    error::AppError,
+    models::*,
+    moderation,
+    outgoing_webhooks::{self, EVENT_MESSAGE_CREATED},
+    push,
+    websocket::broadcast_to_room,
+    AppState, SharedState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::warn;
+use uuid::Uuid;
+
+const MAX_CONTENT_CHARS: usize = 40_000;
+/// Furthest ahead a message can be scheduled.
+const MAX_AHEAD_DAYS: i64 = 365;
+/// Pending messages a user can have at once.
+const MAX_PENDING_PER_USER: i64 = 100;
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+const BATCH_SIZE: i64 = 50;
+
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/scheduled-messages", get(list_handler))
+        .route("/scheduled-messages/:scheduled_id", put(update_handler).delete(cancel_handler))
+        .route("/rooms/:room_id/scheduled-messages", post(create_handler))
+}
+
+/// Starts the loop that posts due messages. Like reminders, they're claimed
+/// with `FOR UPDATE SKIP LOCKED`, so every instance can run it.
+pub fn spawn(state: SharedState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = send_due(&state).await {
+                warn!("Failed to send scheduled messages: {}", e);
+            }
+        }
+    });
+}
+
+fn validate(content: &str, send_at: DateTime<Utc>) -> Result<(), AppError> {
+    if content.trim().is_empty() || content.chars().count() > MAX_CONTENT_CHARS {
+        return Err(AppError::Validation(format!(
+            "Message content must be between 1 and {} characters",
+            MAX_CONTENT_CHARS
+        )));
+    }
+    // Commands run as they're sent, not later
+    if commands::parse(content).is_some() {
+        return Err(AppError::Validation("Slash commands can't be scheduled".to_string()));
+    }
+    let now = Utc::now();
+    if send_at <= now || send_at > now + Duration::days(MAX_AHEAD_DAYS) {
+        return Err(AppError::Validation(format!(
+            "Messages can be scheduled for the next {} days",
+            MAX_AHEAD_DAYS
+        )));
+    }
+    Ok(())
+}
+
+/// A message of the user's that's still to be posted, or failed to be.
+async fn get_pending(pool: &PgPool, scheduled_id: Uuid, user_id: Uuid) -> Result<ScheduledMessage, AppError> {
+    let scheduled = sqlx::query_as::<_, ScheduledMessage>(
+        r#"
+        SELECT * FROM scheduled_messages
+        WHERE id = $1 AND user_id = $2 AND (sent_at IS NULL OR error IS NOT NULL)
+        "#
+    )
+    .bind(scheduled_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Scheduled message not found".to_string()))?;
+
+    Ok(scheduled)
+}
+
+async fn send_due(state: &AppState) -> Result<(), AppError> {
+    loop {
+        // Marked sent as they're claimed: a crash mid-batch drops a message
+        // rather than posting it twice
+        let due = sqlx::query_as::<_, ScheduledMessage>(
+            r#"
+            WITH due AS (
+                SELECT id FROM scheduled_messages
+                WHERE sent_at IS NULL AND send_at <= NOW()
+                ORDER BY send_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            UPDATE scheduled_messages s
+            SET sent_at = NOW()
+            FROM due
+            WHERE s.id = due.id
+            RETURNING s.*
+            "#
+        )
+        .bind(BATCH_SIZE)
+        .fetch_all(&state.db)
+        .await?;
+
+        for scheduled in &due {
+            let (message_id, error) = match deliver(state, scheduled).await {
+                Ok(message) => (Some(message.id), None),
+                Err(AppError::Authorization(reason)) | Err(AppError::NotFound(reason)) => (None, Some(reason)),
+                Err(e) => {
+                    warn!("Failed to send scheduled message {}: {}", scheduled.id, e);
+                    (None, Some("The message couldn't be posted".to_string()))
+                }
+            };
+            sqlx::query("UPDATE scheduled_messages SET message_id = $2, error = $3, updated_at = NOW() WHERE id = $1")
+                .bind(scheduled.id)
+                .bind(message_id)
+                .bind(error)
+                .execute(&state.db)
+                .await?;
+        }
+        if (due.len() as i64) < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+/// Posts the message as if the author sent it now. Slow mode doesn't apply,
+/// but losing access to the room or being muted keeps it from being posted.
+async fn deliver(state: &AppState, scheduled: &ScheduledMessage) -> Result<Message, AppError> {
+    ensure_room_access(&state.db, scheduled.room_id, scheduled.user_id).await?;
+    if let Some(mute) = moderation::get_active_mute(&state.db, scheduled.room_id, scheduled.user_id).await? {
+        return Err(AppError::Authorization(format!(
+            "You were muted in this room until {}",
+            mute.expires_at.to_rfc3339()
+        )));
+    }
+    if join_room(&state.db, scheduled.room_id, scheduled.user_id).await? {
+        outgoing_webhooks::dispatch_member(
+            &state.db,
+            scheduled.room_id,
+            outgoing_webhooks::EVENT_MEMBER_JOINED,
+            scheduled.user_id,
+            None,
+        )
+        .await;
+    }
+
+    let message = send_message(state, scheduled.room_id, scheduled.user_id, &scheduled.content, "text").await?;
+    broadcast_to_room(state, scheduled.room_id, &WebSocketMessage::new("new_message", &message)).await;
+    push::notify_new_message(state, &message);
+    outgoing_webhooks::dispatch_message(&state.db, EVENT_MESSAGE_CREATED, &message).await;
+
+    Ok(message)
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    room_id: Option<Uuid>,
+}
+
+/// The caller's messages still to be posted, soonest first, along with any
+/// that failed (with `error` set) until they're rescheduled or cancelled.
+async fn list_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Vec<ScheduledMessage>>, AppError> {
+    let user_id = claims.user_id()?;
+    let scheduled = sqlx::query_as::<_, ScheduledMessage>(
+        r#"
+        SELECT * FROM scheduled_messages
+        WHERE user_id = $1
+          AND (sent_at IS NULL OR error IS NOT NULL)
+          AND ($2::UUID IS NULL OR room_id = $2)
+        ORDER BY send_at
+        "#
+    )
+    .bind(user_id)
+    .bind(query.room_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(scheduled))
+}
+
+#[derive(Deserialize)]
+struct CreateScheduledRequest {
+    content: String,
+    send_at: DateTime<Utc>,
+}
+
+async fn create_handler(
+    Path(room_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<CreateScheduledRequest>,
+) -> Result<Json<ScheduledMessage>, AppError> {
+    let user_id = claims.user_id()?;
+    ensure_room_access(&state.db, room_id, user_id).await?;
+    validate(&req.content, req.send_at)?;
+
+    let pending = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM scheduled_messages WHERE user_id = $1 AND sent_at IS NULL"
+    )
+    .bind(user_id)
+    .fetch_one(&state.db)
+    .await?;
+    if pending >= MAX_PENDING_PER_USER {
+        return Err(AppError::BadRequest(format!(
+            "You can have at most {} scheduled messages",
+            MAX_PENDING_PER_USER
+        )));
+    }
+
+    let scheduled = sqlx::query_as::<_, ScheduledMessage>(
+        r#"
+        INSERT INTO scheduled_messages (id, user_id, room_id, content, send_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(room_id)
+    .bind(&req.content)
+    .bind(req.send_at)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(scheduled))
+}
+
+#[derive(Deserialize)]
+struct UpdateScheduledRequest {
+    content: Option<String>,
+    send_at: Option<DateTime<Utc>>,
+}
+
+/// Changes a pending message's content or time. Saving a failed message
+/// schedules it again, so it needs a time in the future.
+async fn update_handler(
+    Path(scheduled_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    Json(req): Json<UpdateScheduledRequest>,
+) -> Result<Json<ScheduledMessage>, AppError> {
+    let user_id = claims.user_id()?;
+    let scheduled = get_pending(&state.db, scheduled_id, user_id).await?;
+    let content = req.content.unwrap_or(scheduled.content);
+    let send_at = req.send_at.unwrap_or(scheduled.send_at);
+    validate(&content, send_at)?;
+
+    // Rechecks the status: the scheduler may have claimed it meanwhile
+    let scheduled = sqlx::query_as::<_, ScheduledMessage>(
+        r#"
+        UPDATE scheduled_messages
+        SET content = $3, send_at = $4, sent_at = NULL, error = NULL, updated_at = NOW()
+        WHERE id = $1 AND user_id = $2 AND (sent_at IS NULL OR error IS NOT NULL)
+        RETURNING *
+        "#
+    )
+    .bind(scheduled_id)
+    .bind(user_id)
+    .bind(&content)
+    .bind(send_at)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Scheduled message not found".to_string()))?;
+
+    Ok(Json(scheduled))
+}
+
+async fn cancel_handler(
+    Path(scheduled_id): Path<Uuid>,
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user_id = claims.user_id()?;
+    let result = sqlx::query(
+        r#"
+        DELETE FROM scheduled_messages
+        WHERE id = $1 AND user_id = $2 AND (sent_at IS NULL OR error IS NOT NULL)
+        "#
+    )
+    .bind(scheduled_id)
+    .bind(user_id)
+    .execute(&state.db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("Scheduled message not found".to_string()));
+    }
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
@@ -0,0 +1,58 @@
+use crate::{error::AppError, models::InstanceSettings};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub const REQUIRE_TWO_FACTOR: &str = "require_two_factor";
+pub const ACCOUNT_DELETION_POLICY: &str = "account_deletion_policy";
+pub const LEGAL_HOLD: &str = "legal_hold";
+
+/// Deleted accounts' messages stay in their rooms without an author.
+pub const DELETION_ANONYMIZE: &str = "anonymize";
+/// Deleted accounts' messages and uploads are removed too.
+pub const DELETION_DELETE: &str = "delete";
+
+async fn get(pool: &PgPool, key: &str) -> Result<Option<serde_json::Value>, AppError> {
+    let value = sqlx::query_scalar::<_, serde_json::Value>("SELECT value FROM instance_settings WHERE key = $1")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(value)
+}
+
+/// Unset keys read as `false`.
+pub async fn get_bool(pool: &PgPool, key: &str) -> Result<bool, AppError> {
+    Ok(get(pool, key).await?.and_then(|value| value.as_bool()).unwrap_or(false))
+}
+
+pub async fn get_string(pool: &PgPool, key: &str, default: &str) -> Result<String, AppError> {
+    Ok(get(pool, key)
+        .await?
+        .and_then(|value| value.as_str().map(|value| value.to_string()))
+        .unwrap_or_else(|| default.to_string()))
+}
+
+pub async fn set(pool: &PgPool, key: &str, value: serde_json::Value, updated_by: Uuid) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO instance_settings (key, value, updated_by, updated_at)
+        VALUES ($1, $2, $3, NOW())
+        ON CONFLICT (key) DO UPDATE SET value = $2, updated_by = $3, updated_at = NOW()
+        "#
+    )
+    .bind(key)
+    .bind(value)
+    .bind(updated_by)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn load(pool: &PgPool) -> Result<InstanceSettings, AppError> {
+    Ok(InstanceSettings {
+        require_two_factor: get_bool(pool, REQUIRE_TWO_FACTOR).await?,
+        account_deletion_policy: get_string(pool, ACCOUNT_DELETION_POLICY, DELETION_ANONYMIZE).await?,
+        legal_hold: get_bool(pool, LEGAL_HOLD).await?,
+    })
+}
@@ -0,0 +1,62 @@
+//! Fixtures shared by tests. Tests that need a database use `#[sqlx::test]`,
+//! which runs each one against a fresh, migrated database on the server in
+//! `DATABASE_URL`.
+
+use crate::{
+    auth::{create_external_user, AuthClaims},
+    commands::Commands,
+    mailer::Mailer,
+    models::User,
+    rate_limit::RateLimits,
+    AppState,
+};
+use axum::Router;
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::sync::RwLock;
+
+/// An `AppState` with every optional integration turned off.
+pub fn state(db: PgPool) -> AppState {
+    AppState {
+        db,
+        rooms: Arc::new(RwLock::new(HashMap::new())),
+        connections: Arc::new(RwLock::new(HashMap::new())),
+        rate_limits: Arc::new(RateLimits::from_env()),
+        mailer: Arc::new(Mailer::disabled("http://localhost:3000")),
+        oidc: None,
+        ldap: None,
+        push: None,
+        commands: Arc::new(Commands::new().expect("command client")),
+        link_previews: None,
+    }
+}
+
+/// A verified, password-less account.
+pub async fn create_user(pool: &PgPool, username: &str) -> User {
+    create_external_user(pool, username, &format!("{}@example.com", username), true)
+        .await
+        .expect("user is created")
+}
+
+/// The claims of a session for `user`.
+pub fn claims(user: &User) -> AuthClaims {
+    let now = Utc::now();
+    AuthClaims {
+        sub: user.id.to_string(),
+        username: user.username.clone(),
+        exp: (now + Duration::hours(1)).timestamp() as usize,
+        iat: now.timestamp() as usize,
+        ver: user.token_version,
+    }
+}
+
+/// Serves `router` on a free loopback port until the test ends.
+pub async fn serve(router: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let address = listener.local_addr().expect("address");
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.ok();
+    });
+    address
+}
@@ -0,0 +1,565 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{authenticate_mfa_token, get_user_by_id, hash_secret, issue_session, verify_password, AuthClaims},
+    error::AppError,
+    models::*,
+    settings, AppState, SharedState,
+};
+use axum::{
+    extract::State,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::Utc;
+use rand::RngCore;
+use serde::Deserialize;
+use sqlx::PgPool;
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+const ISSUER: &str = "Rust Konect";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP_SECONDS: u64 = 30;
+/// Codes from the previous and next time step are accepted to allow for clock drift.
+const TOTP_SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const METHOD_TOTP: &str = "totp";
+const METHOD_RECOVERY_CODE: &str = "recovery_code";
+
+/// Endpoints for the second login step, mounted under `/api/auth`. They
+/// authenticate with the "mfa pending" token returned by `/login`.
+pub fn public_router() -> Router<SharedState> {
+    Router::new()
+        .route("/2fa/verify", post(verify_login_handler))
+        .route("/2fa/enroll", post(enroll_pending_handler))
+        .route("/2fa/confirm", post(confirm_pending_handler))
+}
+
+/// Endpoints for signed-in users, mounted under `/api`.
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/users/me/2fa", get(status_handler))
+        .route("/users/me/2fa/enroll", post(enroll_handler))
+        .route("/users/me/2fa/confirm", post(confirm_handler))
+        .route("/users/me/2fa/recovery-codes", post(regenerate_recovery_codes_handler))
+        .route("/users/me/2fa/disable", post(disable_handler))
+}
+
+pub async fn is_required(pool: &PgPool) -> Result<bool, AppError> {
+    settings::get_bool(pool, settings::REQUIRE_TWO_FACTOR).await
+}
+
+/// Whether logging in needs a second step: `Some(false)` to verify a code,
+/// `Some(true)` when 2FA is required but the account hasn't enrolled yet.
+pub async fn pending_step(pool: &PgPool, user: &User) -> Result<Option<bool>, AppError> {
+    if user.totp_enabled_at.is_some() {
+        return Ok(Some(false));
+    }
+    if is_required(pool).await? {
+        return Ok(Some(true));
+    }
+    Ok(None)
+}
+
+fn build_totp(secret: &str, account: &str) -> Result<TOTP, AppError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| AppError::InternalError(format!("Invalid TOTP secret: {:?}", e)))?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        0,
+        TOTP_STEP_SECONDS,
+        secret,
+        Some(ISSUER.to_string()),
+        account.to_string(),
+    )
+    .map_err(|e| AppError::InternalError(format!("Failed to set up TOTP: {}", e)))
+}
+
+/// Stores a fresh secret for the user and returns what the authenticator app
+/// needs. 2FA stays off until a code from it is confirmed.
+pub async fn begin_enrollment(pool: &PgPool, user: &User) -> Result<TwoFactorEnrollment, AppError> {
+    if user.totp_enabled_at.is_some() {
+        return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string()));
+    }
+
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let Secret::Encoded(secret) = Secret::Raw(bytes.to_vec()).to_encoded() else {
+        unreachable!("to_encoded always returns an encoded secret");
+    };
+
+    let totp = build_totp(&secret, &user.email)?;
+    let qr_code = totp
+        .get_qr_base64()
+        .map_err(|e| AppError::InternalError(format!("Failed to render QR code: {}", e)))?;
+
+    sqlx::query("UPDATE users SET totp_secret = $2, totp_last_step = NULL, updated_at = NOW() WHERE id = $1")
+        .bind(user.id)
+        .bind(&secret)
+        .execute(pool)
+        .await?;
+
+    Ok(TwoFactorEnrollment {
+        otpauth_uri: totp.get_url(),
+        qr_code: format!("data:image/png;base64,{}", qr_code),
+        secret,
+    })
+}
+
+/// Enables 2FA once the user proves their app generates valid codes, and
+/// returns the initial set of recovery codes.
+pub async fn confirm_enrollment(pool: &PgPool, user: &User, code: &str) -> Result<Vec<String>, AppError> {
+    if user.totp_enabled_at.is_some() {
+        return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string()));
+    }
+    if user.totp_secret.is_none() {
+        return Err(AppError::BadRequest("Start enrollment before confirming it".to_string()));
+    }
+    if !check_totp(pool, user, code).await? {
+        return Err(AppError::Validation("Invalid authentication code".to_string()));
+    }
+
+    sqlx::query("UPDATE users SET totp_enabled_at = NOW(), updated_at = NOW() WHERE id = $1")
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    replace_recovery_codes(pool, user.id).await
+}
+
+/// Checks a TOTP code and records its time step so it can't be used twice.
+async fn check_totp(pool: &PgPool, user: &User, code: &str) -> Result<bool, AppError> {
+    let Some(secret) = &user.totp_secret else {
+        return Ok(false);
+    };
+    let totp = build_totp(secret, &user.email)?;
+    let code = code.trim();
+
+    let now = Utc::now().timestamp();
+    let step_seconds = TOTP_STEP_SECONDS as i64;
+    let matched_step = (-TOTP_SKEW_STEPS..=TOTP_SKEW_STEPS)
+        .map(|offset| now / step_seconds + offset)
+        .find(|step| totp.generate((step * step_seconds) as u64) == code);
+
+    let Some(step) = matched_step else {
+        return Ok(false);
+    };
+
+    // Only moves forward, so a replayed code (or an older one) is rejected
+    let result = sqlx::query(
+        "UPDATE users SET totp_last_step = $2 WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)"
+    )
+    .bind(user.id)
+    .bind(step)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Invalidates any existing recovery codes and generates a new set.
+pub async fn replace_recovery_codes(pool: &PgPool, user_id: Uuid) -> Result<Vec<String>, AppError> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = hex::encode(bytes);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for code in &codes {
+        sqlx::query("INSERT INTO recovery_codes (id, user_id, code_hash, created_at) VALUES ($1, $2, $3, NOW())")
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(hash_secret(&normalize_recovery_code(code)))
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(codes)
+}
+
+async fn use_recovery_code(pool: &PgPool, user_id: Uuid, code: &str) -> Result<bool, AppError> {
+    let result = sqlx::query(
+        "UPDATE recovery_codes SET used_at = NOW() WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL"
+    )
+    .bind(user_id)
+    .bind(hash_secret(&normalize_recovery_code(code)))
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn remaining_recovery_codes(pool: &PgPool, user_id: Uuid) -> Result<i64, AppError> {
+    let count = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL"
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count)
+}
+
+/// Accepts either an authenticator code or an unused recovery code and
+/// returns which one was used.
+async fn verify_second_factor(
+    pool: &PgPool,
+    user: &User,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Result<&'static str, AppError> {
+    if user.totp_enabled_at.is_none() {
+        return Err(AppError::BadRequest("Two-factor authentication is not enabled".to_string()));
+    }
+
+    match (code, recovery_code) {
+        (Some(code), _) if check_totp(pool, user, code).await? => Ok(METHOD_TOTP),
+        (None, Some(recovery_code)) if use_recovery_code(pool, user.id, recovery_code).await? => {
+            Ok(METHOD_RECOVERY_CODE)
+        }
+        _ => Err(AppError::Auth("Invalid authentication code".to_string())),
+    }
+}
+
+/// `verify_second_factor`, with guessing codes throttled the same way as
+/// guessing passwords.
+async fn verify_second_factor_throttled(
+    state: &AppState,
+    user: &User,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Result<&'static str, AppError> {
+    let lockout_keys = vec![format!("mfa:{}", user.id)];
+    let lockout = &state.rate_limits.login_lockout;
+    if state.rate_limits.enabled {
+        lockout.check(&lockout_keys)?;
+    }
+
+    match verify_second_factor(&state.db, user, code, recovery_code).await {
+        Ok(method) => {
+            lockout.record_success(&lockout_keys);
+            Ok(method)
+        }
+        Err(e) => {
+            if matches!(e, AppError::Auth(_)) {
+                lockout.record_failure(&lockout_keys);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Turns 2FA off and forgets the secret and recovery codes.
+pub async fn disable(pool: &PgPool, user_id: Uuid) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL, updated_at = NOW()
+        WHERE id = $1
+        "#
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+async fn current_user(pool: &PgPool, claims: &AuthClaims) -> Result<User, AppError> {
+    get_user_by_id(pool, claims.user_id()?)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))
+}
+
+#[derive(Deserialize)]
+struct VerifyLoginRequest {
+    mfa_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+async fn verify_login_handler(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<VerifyLoginRequest>,
+) -> Result<Json<AuthResponse>, AppError> {
+    let user = authenticate_mfa_token(&state.db, &req.mfa_token).await?;
+
+    let method = match verify_second_factor_throttled(
+        &state,
+        &user,
+        req.code.as_deref(),
+        req.recovery_code.as_deref(),
+    ).await {
+        Ok(method) => method,
+        Err(e) => {
+            audit::record(
+                &state.db,
+                &client,
+                AuditEvent::new("auth.2fa_failed").target("user", user.id),
+            ).await;
+            return Err(e);
+        }
+    };
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.login")
+            .actor(user.id)
+            .metadata(serde_json::json!({ "second_factor": method })),
+    ).await;
+
+    Ok(Json(issue_session(&user)?))
+}
+
+#[derive(Deserialize)]
+struct PendingRequest {
+    mfa_token: String,
+}
+
+/// Enrollment for accounts that must set up 2FA before their first session.
+async fn enroll_pending_handler(
+    State(state): State<SharedState>,
+    Json(req): Json<PendingRequest>,
+) -> Result<Json<TwoFactorEnrollment>, AppError> {
+    let user = authenticate_mfa_token(&state.db, &req.mfa_token).await?;
+    Ok(Json(begin_enrollment(&state.db, &user).await?))
+}
+
+#[derive(Deserialize)]
+struct ConfirmPendingRequest {
+    mfa_token: String,
+    code: String,
+}
+
+async fn confirm_pending_handler(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<ConfirmPendingRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user = authenticate_mfa_token(&state.db, &req.mfa_token).await?;
+    let recovery_codes = confirm_enrollment(&state.db, &user, &req.code).await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.2fa_enabled").actor(user.id).target("user", user.id),
+    ).await;
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.login")
+            .actor(user.id)
+            .metadata(serde_json::json!({ "second_factor": METHOD_TOTP })),
+    ).await;
+
+    let session = issue_session(&user)?;
+    Ok(Json(serde_json::json!({
+        "token": session.token,
+        "user": session.user,
+        "recovery_codes": recovery_codes,
+    })))
+}
+
+async fn status_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<TwoFactorStatus>, AppError> {
+    let user = current_user(&state.db, &claims).await?;
+
+    Ok(Json(TwoFactorStatus {
+        enabled: user.totp_enabled_at.is_some(),
+        required: is_required(&state.db).await?,
+        recovery_codes_remaining: remaining_recovery_codes(&state.db, user.id).await?,
+    }))
+}
+
+async fn enroll_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+) -> Result<Json<TwoFactorEnrollment>, AppError> {
+    let user = current_user(&state.db, &claims).await?;
+    Ok(Json(begin_enrollment(&state.db, &user).await?))
+}
+
+#[derive(Deserialize)]
+struct CodeRequest {
+    code: String,
+}
+
+async fn confirm_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<CodeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user = current_user(&state.db, &claims).await?;
+    let recovery_codes = confirm_enrollment(&state.db, &user, &req.code).await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.2fa_enabled").actor(user.id).target("user", user.id),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "recovery_codes": recovery_codes })))
+}
+
+async fn regenerate_recovery_codes_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<CodeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user = current_user(&state.db, &claims).await?;
+    verify_second_factor(&state.db, &user, Some(&req.code), None).await?;
+
+    let recovery_codes = replace_recovery_codes(&state.db, user.id).await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.2fa_recovery_codes_regenerated").actor(user.id).target("user", user.id),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "recovery_codes": recovery_codes })))
+}
+
+#[derive(Deserialize)]
+struct DisableRequest {
+    password: Option<String>,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+async fn disable_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<DisableRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user = current_user(&state.db, &claims).await?;
+
+    if is_required(&state.db).await? {
+        return Err(AppError::Authorization(
+            "Two-factor authentication is required on this server".to_string(),
+        ));
+    }
+    // Password-less (SSO) accounts are confirmed by the second factor alone
+    if user.password_hash.is_some() {
+        let password = req
+            .password
+            .as_deref()
+            .ok_or_else(|| AppError::Auth("Current password is required".to_string()))?;
+        if !verify_password(&user, password)? {
+            return Err(AppError::Auth("Invalid password".to_string()));
+        }
+    }
+    verify_second_factor_throttled(&state, &user, req.code.as_deref(), req.recovery_code.as_deref()).await?;
+
+    disable(&state.db, user.id).await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.2fa_disabled").actor(user.id).target("user", user.id),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::sync::Arc;
+
+    /// Turns 2FA on for the user and returns their authenticator.
+    async fn enable(pool: &PgPool, user: &User) -> TOTP {
+        let secret = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+        sqlx::query("UPDATE users SET totp_secret = $2, totp_enabled_at = NOW() WHERE id = $1")
+            .bind(user.id)
+            .bind(secret)
+            .execute(pool)
+            .await
+            .unwrap();
+        build_totp(secret, &user.email).unwrap()
+    }
+
+    fn request(password: Option<&str>, code: &str) -> Json<DisableRequest> {
+        Json(DisableRequest {
+            password: password.map(str::to_string),
+            code: Some(code.to_string()),
+            recovery_code: None,
+        })
+    }
+
+    #[sqlx::test]
+    async fn password_less_accounts_disable_with_a_code(pool: PgPool) {
+        let state = Arc::new(test_support::state(pool.clone()));
+        let user = test_support::create_user(&pool, "alice").await;
+        let totp = enable(&pool, &user).await;
+        let claims = test_support::claims(&user);
+
+        let client = ClientInfo::default();
+        let wrong = disable_handler(State(state.clone()), Extension(claims.clone()), client.clone(), request(None, "abcdef")).await;
+        assert!(matches!(wrong, Err(AppError::Auth(_))));
+
+        let code = totp.generate_current().unwrap();
+        let disabled = disable_handler(State(state), Extension(claims), client, request(None, &code)).await;
+        assert!(disabled.is_ok());
+        let user = get_user_by_id(&pool, user.id).await.unwrap().unwrap();
+        assert!(user.totp_enabled_at.is_none());
+    }
+
+    #[sqlx::test]
+    async fn accounts_with_a_password_still_need_it(pool: PgPool) {
+        let state = Arc::new(test_support::state(pool.clone()));
+        let user = crate::auth::create_user(&pool, "alice", "alice@example.com", "correct horse battery staple")
+            .await
+            .unwrap();
+        let totp = enable(&pool, &user).await;
+        let code = totp.generate_current().unwrap();
+
+        let result = disable_handler(
+            State(state),
+            Extension(test_support::claims(&user)),
+            ClientInfo::default(),
+            request(None, &code),
+        ).await;
+        assert!(matches!(result, Err(AppError::Auth(message)) if message.contains("password")));
+    }
+}
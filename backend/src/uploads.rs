@@ -0,0 +1,204 @@
+use crate::{
+    error::AppError,
+    media,
+    models::{FileRecord, UploadResponse},
+    SharedState, UPLOAD_DIR,
+};
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Serves uploads without authentication, so they work in `<img>` tags and
+/// plain links. File ids are random and only shared through the rooms the
+/// file was posted in.
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/:file_id", get(file_handler))
+        .route("/:file_id/thumbnails/:size", get(thumbnail_handler))
+}
+
+fn path(file_id: Uuid) -> String {
+    format!("{}/{}", UPLOAD_DIR, file_id)
+}
+
+fn thumbnail_path(file_id: Uuid, size: &str) -> String {
+    format!("{}/{}_{}", UPLOAD_DIR, file_id, size)
+}
+
+/// GPS positions are removed from uploaded images unless
+/// `UPLOAD_STRIP_GPS=false`.
+fn strip_gps() -> bool {
+    !std::env::var("UPLOAD_STRIP_GPS").is_ok_and(|value| value == "false" || value == "0")
+}
+
+/// Writes the bytes to the storage directory under a new file id.
+pub async fn store(data: &[u8]) -> Result<Uuid, AppError> {
+    let file_id = Uuid::new_v4();
+
+    tokio::fs::create_dir_all(UPLOAD_DIR).await.map_err(|_| AppError::InternalError("Failed to create uploads directory".to_string()))?;
+    tokio::fs::write(path(file_id), data).await.map_err(|_| AppError::InternalError("Failed to save file".to_string()))?;
+
+    Ok(file_id)
+}
+
+/// Stores a user's upload and records who uploaded it. Images get their
+/// dimensions, a blurhash and thumbnails recorded too.
+pub async fn store_file(
+    pool: &PgPool,
+    user_id: Uuid,
+    filename: &str,
+    data: &[u8],
+) -> Result<FileRecord, AppError> {
+    let owned = data.to_vec();
+    let image = tokio::task::spawn_blocking(move || media::process(&owned, strip_gps()))
+        .await
+        .map_err(|e| AppError::InternalError(format!("Image processing failed: {}", e)))??;
+    let data = image.as_ref().and_then(|image| image.data.as_deref()).unwrap_or(data);
+    let file_id = store(data).await?;
+
+    let mut thumbnails = Vec::new();
+    for (thumbnail, bytes) in image.iter().flat_map(|image| &image.thumbnails) {
+        tokio::fs::write(thumbnail_path(file_id, &thumbnail.size), bytes)
+            .await
+            .map_err(|_| AppError::InternalError("Failed to save thumbnail".to_string()))?;
+        thumbnails.push(thumbnail);
+    }
+    // Images that couldn't be decoded have no dimensions
+    let dimensions = image.as_ref().filter(|image| image.width > 0);
+
+    let record = sqlx::query_as::<_, FileRecord>(
+        r#"
+        INSERT INTO files (id, user_id, filename, size, content_type, width, height, blurhash, thumbnails)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        RETURNING *
+        "#
+    )
+    .bind(file_id)
+    .bind(user_id)
+    .bind(filename)
+    .bind(data.len() as i64)
+    .bind(image.as_ref().map(|image| image.content_type))
+    .bind(dimensions.map(|image| image.width as i32))
+    .bind(dimensions.map(|image| image.height as i32))
+    .bind(image.as_ref().and_then(|image| image.blurhash.as_deref()))
+    .bind(sqlx::types::Json(&thumbnails))
+    .fetch_one(pool)
+    .await?;
+
+    Ok(record)
+}
+
+pub async fn get_file(pool: &PgPool, file_id: Uuid) -> Result<FileRecord, AppError> {
+    sqlx::query_as::<_, FileRecord>("SELECT * FROM files WHERE id = $1")
+        .bind(file_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("File not found".to_string()))
+}
+
+/// The upload that `content` refers to by `id`, and the content of a `file`
+/// message sharing it. Clients send what the upload returned; the server
+/// fills in the metadata itself so it can be trusted.
+pub async fn file_message_content(pool: &PgPool, content: &str) -> Result<(Uuid, String), AppError> {
+    let invalid = || AppError::Validation("File messages must contain the id of an upload".to_string());
+    let file_id = serde_json::from_str::<serde_json::Value>(content)
+        .ok()
+        .and_then(|file| file.get("id")?.as_str()?.parse::<Uuid>().ok())
+        .ok_or_else(invalid)?;
+    let file = get_file(pool, file_id).await.map_err(|_| invalid())?;
+
+    let content = serde_json::to_string(&UploadResponse::new(&file))
+        .map_err(|e| AppError::InternalError(format!("Failed to encode file message: {}", e)))?;
+    Ok((file.id, content))
+}
+
+/// Reads a stored file, or `None` if it doesn't exist.
+pub async fn read(file_id: Uuid) -> Result<Option<Vec<u8>>, AppError> {
+    match tokio::fs::read(path(file_id)).await {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(_) => Err(AppError::InternalError("Failed to read file".to_string())),
+    }
+}
+
+/// Deletes a stored file and its thumbnails. Missing files are not an error.
+pub async fn remove(file_id: Uuid) -> Result<(), AppError> {
+    let thumbnails = media::THUMBNAIL_SIZES.iter().map(|(size, _)| thumbnail_path(file_id, size));
+    for path in std::iter::once(path(file_id)).chain(thumbnails) {
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(_) => return Err(AppError::InternalError("Failed to delete file".to_string())),
+        }
+    }
+    Ok(())
+}
+
+/// `Content-Disposition` with the name encoded per RFC 6266 / RFC 8187.
+fn content_disposition(kind: &str, filename: &str) -> String {
+    let encoded: String = filename
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect();
+    format!("{}; filename*=UTF-8''{}", kind, encoded)
+}
+
+/// Images are shown inline; anything else is downloaded, so uploaded HTML
+/// never runs on this origin.
+async fn file_handler(
+    Path(file_id): Path<Uuid>,
+    State(state): State<SharedState>,
+) -> Result<Response, AppError> {
+    let file = get_file(&state.db, file_id).await?;
+    let data = read(file_id).await?.ok_or_else(|| AppError::NotFound("File not found".to_string()))?;
+
+    let (content_type, disposition) = match &file.content_type {
+        Some(content_type) => (content_type.clone(), content_disposition("inline", &file.filename)),
+        None => ("application/octet-stream".to_string(), content_disposition("attachment", &file.filename)),
+    };
+    // An id always refers to the same bytes
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (header::CACHE_CONTROL, "private, max-age=31536000, immutable".to_string()),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+async fn thumbnail_handler(
+    Path((file_id, size)): Path<(Uuid, String)>,
+    State(state): State<SharedState>,
+) -> Result<Response, AppError> {
+    let file = get_file(&state.db, file_id).await?;
+    let thumbnail = file
+        .thumbnails
+        .iter()
+        .find(|thumbnail| thumbnail.size == size)
+        .ok_or_else(|| AppError::NotFound("Thumbnail not found".to_string()))?;
+    let data = tokio::fs::read(thumbnail_path(file_id, &thumbnail.size))
+        .await
+        .map_err(|_| AppError::NotFound("Thumbnail not found".to_string()))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, thumbnail.content_type.clone()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (header::CACHE_CONTROL, "private, max-age=31536000, immutable".to_string()),
+        ],
+        data,
+    )
+        .into_response())
+}
@@ -0,0 +1,440 @@
+use crate::{
+    audit::{self, AuditEvent, ClientInfo},
+    auth::{generate_secret, get_user_by_id, hash_secret, normalize_email, promote_bootstrap_admin, AuthClaims},
+    error::AppError,
+    mailer::{self, Template},
+    models::*,
+    password, profiles,
+    websocket::disconnect_user,
+    AppState, SharedState,
+};
+use axum::{
+    extract::State,
+    routing::post,
+    Extension, Json, Router,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::error;
+use uuid::Uuid;
+
+pub const PURPOSE_VERIFY_EMAIL: &str = "verify_email";
+pub const PURPOSE_PASSWORD_RESET: &str = "password_reset";
+pub const PURPOSE_EMAIL_CHANGE: &str = "email_change";
+
+const VERIFY_EMAIL_TTL_HOURS: i64 = 48;
+const PASSWORD_RESET_TTL_HOURS: i64 = 1;
+const EMAIL_CHANGE_TTL_HOURS: i64 = 24;
+
+/// Unauthenticated endpoints, mounted under `/api/auth`.
+pub fn public_router() -> Router<SharedState> {
+    Router::new()
+        .route("/verify-email", post(verify_email_handler))
+        .route("/resend-verification", post(resend_verification_handler))
+        .route("/password-reset/request", post(request_password_reset_handler))
+        .route("/password-reset/confirm", post(confirm_password_reset_handler))
+        .route("/email-change/confirm", post(confirm_email_change_handler))
+}
+
+/// Endpoints for signed-in users, mounted under `/api`.
+pub fn router() -> Router<SharedState> {
+    Router::new().route("/users/me/email", post(request_email_change_handler))
+}
+
+/// Creates a new single-use token, replacing any unused one for the same purpose.
+async fn issue_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    purpose: &str,
+    new_email: Option<&str>,
+    ttl: Duration,
+) -> Result<String, AppError> {
+    let token = generate_secret();
+    let now = Utc::now();
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM email_tokens WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL")
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO email_tokens (id, user_id, purpose, token_hash, new_email, expires_at, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(purpose)
+    .bind(hash_secret(&token))
+    .bind(new_email)
+    .bind(now + ttl)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(token)
+}
+
+/// Marks the token used and returns it, failing if it's unknown, expired or already used.
+async fn consume_token(pool: &PgPool, token: &str, purpose: &str) -> Result<EmailToken, AppError> {
+    sqlx::query_as::<_, EmailToken>(
+        r#"
+        UPDATE email_tokens SET used_at = NOW()
+        WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING *
+        "#
+    )
+    .bind(hash_secret(token))
+    .bind(purpose)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::BadRequest("Invalid or expired token".to_string()))
+}
+
+/// Sends the email in the background so response times don't reveal whether
+/// an address is registered.
+fn send_in_background(state: &AppState, to: String, template: &'static Template, vars: Vec<(&'static str, String)>) {
+    let mailer = state.mailer.clone();
+    tokio::spawn(async move {
+        let vars: Vec<(&str, &str)> = vars.iter().map(|(name, value)| (*name, value.as_str())).collect();
+        if let Err(e) = mailer.send(&to, template, &vars).await {
+            error!("Failed to send email: {}", e);
+        }
+    });
+}
+
+pub async fn send_verification_email(state: &AppState, user: &User) -> Result<(), AppError> {
+    let token = issue_token(
+        &state.db,
+        user.id,
+        PURPOSE_VERIFY_EMAIL,
+        None,
+        Duration::hours(VERIFY_EMAIL_TTL_HOURS),
+    ).await?;
+
+    send_in_background(
+        state,
+        user.email.clone(),
+        &mailer::VERIFY_EMAIL,
+        vec![
+            ("username", user.username.clone()),
+            ("link", format!("{}/?verify_email={}", state.mailer.base_url, token)),
+            ("expires_in", format!("{} hours", VERIFY_EMAIL_TTL_HOURS)),
+        ],
+    );
+
+    Ok(())
+}
+
+async fn find_user_by_email(pool: &PgPool, email: &str) -> Result<Option<User>, AppError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE LOWER(email) = LOWER($1)")
+        .bind(email)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(user)
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    token: String,
+}
+
+async fn verify_email_handler(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<TokenRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let token = consume_token(&state.db, &req.token, PURPOSE_VERIFY_EMAIL).await?;
+
+    sqlx::query(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW() WHERE id = $1"
+    )
+    .bind(token.user_id)
+    .execute(&state.db)
+    .await?;
+    promote_bootstrap_admin(&state.db, token.user_id).await?;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.email_verified").actor(token.user_id).target("user", token.user_id),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[derive(Deserialize)]
+struct EmailRequest {
+    email: String,
+}
+
+async fn resend_verification_handler(
+    State(state): State<SharedState>,
+    Json(req): Json<EmailRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    // Always succeed so the endpoint can't be used to probe for accounts
+    if let Some(user) = find_user_by_email(&state.db, &req.email).await? {
+        if user.email_verified_at.is_none() {
+            send_verification_email(&state, &user).await?;
+        }
+    }
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+async fn request_password_reset_handler(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<EmailRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    if let Some(user) = find_user_by_email(&state.db, &req.email).await? {
+        if user.disabled_at.is_none() {
+            let token = issue_token(
+                &state.db,
+                user.id,
+                PURPOSE_PASSWORD_RESET,
+                None,
+                Duration::hours(PASSWORD_RESET_TTL_HOURS),
+            ).await?;
+
+            send_in_background(
+                &state,
+                user.email.clone(),
+                &mailer::PASSWORD_RESET,
+                vec![
+                    ("username", user.username.clone()),
+                    ("link", format!("{}/?reset_password={}", state.mailer.base_url, token)),
+                    ("expires_in", format!("{} hour(s)", PASSWORD_RESET_TTL_HOURS)),
+                ],
+            );
+
+            audit::record(
+                &state.db,
+                &client,
+                AuditEvent::new("auth.password_reset_requested").target("user", user.id),
+            ).await;
+        }
+    }
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[derive(Deserialize)]
+struct ConfirmPasswordResetRequest {
+    token: String,
+    new_password: String,
+}
+
+async fn confirm_password_reset_handler(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<ConfirmPasswordResetRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    // Check the policy first so a rejected password doesn't use up the link
+    password::check_policy(&req.new_password).await?;
+    let token = consume_token(&state.db, &req.token, PURPOSE_PASSWORD_RESET).await?;
+
+    // Following the emailed link also proves ownership of the address
+    let password_hash = password::hash(&req.new_password)?;
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET password_hash = $2,
+            token_version = token_version + 1,
+            email_verified_at = COALESCE(email_verified_at, NOW()),
+            updated_at = NOW()
+        WHERE id = $1
+        "#
+    )
+    .bind(token.user_id)
+    .bind(password_hash)
+    .execute(&state.db)
+    .await?;
+    promote_bootstrap_admin(&state.db, token.user_id).await?;
+
+    disconnect_user(&state, token.user_id, None, "password reset").await;
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.password_reset").actor(token.user_id).target("user", token.user_id),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[derive(Deserialize)]
+struct EmailChangeRequest {
+    new_email: String,
+    /// Not needed by password-less (SSO) accounts that signed in recently.
+    password: Option<String>,
+}
+
+async fn request_email_change_handler(
+    State(state): State<SharedState>,
+    Extension(claims): Extension<AuthClaims>,
+    client: ClientInfo,
+    Json(req): Json<EmailChangeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let user = get_user_by_id(&state.db, claims.user_id()?)
+        .await?
+        .ok_or_else(|| AppError::Auth("Unknown user".to_string()))?;
+
+    profiles::reauthenticate(&state, &user, &claims, req.password.as_deref()).await?;
+    let new_email = normalize_email(&req.new_email);
+    if !mailer::is_valid_address(&new_email) {
+        return Err(AppError::Validation("Invalid email address".to_string()));
+    }
+    if find_user_by_email(&state.db, &new_email).await?.is_some() {
+        return Err(AppError::Validation("Email address is already in use".to_string()));
+    }
+
+    let token = issue_token(
+        &state.db,
+        user.id,
+        PURPOSE_EMAIL_CHANGE,
+        Some(&new_email),
+        Duration::hours(EMAIL_CHANGE_TTL_HOURS),
+    ).await?;
+
+    send_in_background(
+        &state,
+        new_email.clone(),
+        &mailer::EMAIL_CHANGE,
+        vec![
+            ("username", user.username.clone()),
+            ("new_email", new_email.clone()),
+            ("link", format!("{}/?confirm_email={}", state.mailer.base_url, token)),
+            ("expires_in", format!("{} hours", EMAIL_CHANGE_TTL_HOURS)),
+        ],
+    );
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.email_change_requested")
+            .actor(user.id)
+            .target("user", user.id)
+            .metadata(serde_json::json!({ "new_email": new_email })),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+async fn confirm_email_change_handler(
+    State(state): State<SharedState>,
+    client: ClientInfo,
+    Json(req): Json<TokenRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let token = consume_token(&state.db, &req.token, PURPOSE_EMAIL_CHANGE).await?;
+    let new_email = token
+        .new_email
+        .ok_or_else(|| AppError::InternalError("Email change token without an address".to_string()))?;
+
+    let user = get_user_by_id(&state.db, token.user_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    // The address may have been claimed since the change was requested
+    if find_user_by_email(&state.db, &new_email).await?.is_some() {
+        return Err(AppError::Validation("Email address is already in use".to_string()));
+    }
+
+    sqlx::query(
+        "UPDATE users SET email = $2, email_verified_at = NOW(), updated_at = NOW() WHERE id = $1"
+    )
+    .bind(user.id)
+    .bind(&new_email)
+    .execute(&state.db)
+    .await?;
+    promote_bootstrap_admin(&state.db, user.id).await?;
+
+    // Let the previous address know, in case the change wasn't wanted
+    send_in_background(
+        &state,
+        user.email.clone(),
+        &mailer::EMAIL_CHANGED,
+        vec![("username", user.username.clone()), ("new_email", new_email.clone())],
+    );
+
+    audit::record(
+        &state.db,
+        &client,
+        AuditEvent::new("auth.email_changed")
+            .actor(user.id)
+            .target("user", user.id)
+            .metadata(serde_json::json!({ "old_email": user.email, "new_email": new_email })),
+    ).await;
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::sync::Arc;
+
+    fn request(password: Option<&str>) -> Json<EmailChangeRequest> {
+        Json(EmailChangeRequest {
+            new_email: "Alice@Example.org".to_string(),
+            password: password.map(str::to_string),
+        })
+    }
+
+    #[sqlx::test]
+    async fn password_less_accounts_change_email_after_signing_in_recently(pool: PgPool) {
+        let state = Arc::new(test_support::state(pool.clone()));
+        let user = test_support::create_user(&pool, "alice").await;
+
+        let mut stale = test_support::claims(&user);
+        stale.iat -= 10 * 60;
+        let client = ClientInfo::default();
+        let result = request_email_change_handler(State(state.clone()), Extension(stale), client.clone(), request(None)).await;
+        assert!(matches!(result, Err(AppError::Auth(message)) if message.contains("sign in again")));
+
+        let claims = test_support::claims(&user);
+        let result = request_email_change_handler(State(state), Extension(claims), client, request(None)).await;
+        assert!(result.is_ok());
+        let new_email = sqlx::query_scalar::<_, Option<String>>("SELECT new_email FROM email_tokens WHERE user_id = $1")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(new_email.as_deref(), Some("alice@example.org"));
+    }
+
+    #[sqlx::test]
+    async fn accounts_with_a_password_confirm_it(pool: PgPool) {
+        let state = Arc::new(test_support::state(pool.clone()));
+        let user = crate::auth::create_user(&pool, "alice", "alice@example.com", "correct horse battery staple")
+            .await
+            .unwrap();
+        let claims = test_support::claims(&user);
+
+        for password in [None, Some("wrong password")] {
+            let result = request_email_change_handler(
+                State(state.clone()),
+                Extension(claims.clone()),
+                ClientInfo::default(),
+                request(password),
+            ).await;
+            assert!(matches!(result, Err(AppError::Auth(_))));
+        }
+        let result = request_email_change_handler(
+            State(state),
+            Extension(claims),
+            ClientInfo::default(),
+            request(Some("correct horse battery staple")),
+        ).await;
+        assert!(result.is_ok());
+    }
+}
@@ -1,12 +1,47 @@
-use crate::{models::*, SharedState};
-use axum::extract::ws::{Message, WebSocket};
+use crate::{
+    api_tokens::{TokenScopes, SCOPE_MESSAGES_WRITE},
+    audit::ClientInfo,
+    auth::AuthClaims,
+    commands,
+    error::AppError,
+    models::*,
+    AppState, SharedState,
+};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use futures_util::{sink::SinkExt, stream::StreamExt};
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
-pub async fn handle_socket(socket: WebSocket, room_id: Uuid, state: SharedState) {
+/// Close code sent to sockets that a moderator removed from the room.
+const CLOSE_REMOVED: u16 = 4000;
+
+pub enum SocketCommand {
+    Send(String),
+    Close(String),
+}
+
+/// A live WebSocket connection that can be addressed by user.
+pub struct ConnectionHandle {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub tx: mpsc::UnboundedSender<SocketCommand>,
+}
+
+pub async fn handle_socket(
+    socket: WebSocket,
+    room_id: Uuid,
+    claims: AuthClaims,
+    scopes: Option<TokenScopes>,
+    client: ClientInfo,
+    state: SharedState,
+) {
+    let Ok(user_id) = claims.user_id() else {
+        return;
+    };
+    // API tokens need messages:write to post
+    let can_post = scopes.as_ref().is_none_or(|scopes| scopes.allows(SCOPE_MESSAGES_WRITE));
     let (mut sender, mut receiver) = socket.split();
 
     // Get or create broadcast channel for this room
@@ -21,18 +56,57 @@ pub async fn handle_socket(socket: WebSocket, room_id: Uuid, state: SharedState)
         }
     };
 
-    // Spawn task to handle incoming WebSocket messages
-    let _state_clone = Arc::clone(&state);
+    // Register this connection so it can be targeted (e.g. kicked) by user
+    let connection_id = Uuid::new_v4();
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    state
+        .connections
+        .write()
+        .await
+        .entry(user_id)
+        .or_default()
+        .push(ConnectionHandle {
+            id: connection_id,
+            room_id,
+            tx: control_tx.clone(),
+        });
+    touch_last_seen(&state, user_id).await;
+
+    // Spawn task to forward room broadcasts and direct commands to the client
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg)).await.is_err() {
-                break;
+        loop {
+            tokio::select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => {
+                        if sender.send(Message::Text(msg)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                },
+                cmd = control_rx.recv() => match cmd {
+                    Some(SocketCommand::Send(msg)) => {
+                        if sender.send(Message::Text(msg)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(SocketCommand::Close(reason)) => {
+                        let _ = sender
+                            .send(Message::Close(Some(CloseFrame {
+                                code: CLOSE_REMOVED,
+                                reason: reason.into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    None => break,
+                },
             }
         }
     });
 
-    // Spawn task to handle outgoing WebSocket messages
-    let state_clone2 = Arc::clone(&state);
+    // Spawn task to handle incoming WebSocket messages
+    let state_clone = Arc::clone(&state);
     let mut recv_task = tokio::spawn(async move {
         while let Some(msg) = receiver.next().await {
             match msg {
@@ -41,27 +115,36 @@ pub async fn handle_socket(socket: WebSocket, room_id: Uuid, state: SharedState)
                         match ws_msg.message_type.as_str() {
                             "chat_message" => {
                                 if let Ok(chat_msg) = serde_json::from_value::<ChatMessage>(ws_msg.data) {
-                                    // TODO: Extract user ID from WebSocket connection authentication
-                                    let user_id = Uuid::new_v4(); // Placeholder
-                                    
-                                    match crate::chat::send_message(
-                                        &state_clone2.db,
-                                        room_id,
-                                        user_id,
-                                        &chat_msg.content,
-                                        chat_msg.message_type.as_deref().unwrap_or("text"),
-                                    ).await {
-                                        Ok(message) => {
-                                            // Broadcast to all clients in the room
-                                            if let Some(tx) = state_clone2.rooms.read().await.get(&room_id) {
-                                                let message_json = serde_json::to_string(&message).unwrap();
-                                                let _ = tx.send(message_json);
-                                            }
+                                    let result = if can_post {
+                                        let invocation = commands::Invocation {
+                                            room_id,
+                                            claims: &claims,
+                                            scopes: scopes.as_ref(),
+                                            client: &client,
+                                        };
+                                        post_chat_message(&state_clone, &invocation, &chat_msg).await
+                                    } else {
+                                        Err(AppError::Authorization(
+                                            "Token lacks the 'messages:write' scope".to_string(),
+                                        ))
+                                    };
+
+                                    let event = match result {
+                                        Ok(Some(response)) if response.ephemeral.is_some() => {
+                                            WebSocketMessage::new("command_response", &response)
                                         }
+                                        Ok(_) => continue,
                                         Err(e) => {
-                                            error!("Failed to send message: {}", e);
+                                            warn!("Rejected message from {}: {}", user_id, e);
+                                            WebSocketMessage::new(
+                                                "error",
+                                                &serde_json::json!({ "error": e.to_string() }),
+                                            )
                                         }
-                                    }
+                                    };
+                                    let _ = control_tx.send(SocketCommand::Send(
+                                        serde_json::to_string(&event).unwrap(),
+                                    ));
                                 }
                             }
                             "join_room" => {
@@ -104,5 +187,99 @@ pub async fn handle_socket(socket: WebSocket, room_id: Uuid, state: SharedState)
         }
     }
 
+    // Deregister the connection
+    let mut connections = state.connections.write().await;
+    if let Some(handles) = connections.get_mut(&user_id) {
+        handles.retain(|handle| handle.id != connection_id);
+        if handles.is_empty() {
+            connections.remove(&user_id);
+        }
+    }
+    drop(connections);
+    touch_last_seen(&state, user_id).await;
+
     info!("WebSocket connection closed for room: {}", room_id);
-}
\ No newline at end of file
+}
+
+/// Records when the user was last connected; email digests only cover what
+/// happened after that.
+async fn touch_last_seen(state: &AppState, user_id: Uuid) {
+    if let Err(e) = sqlx::query("UPDATE users SET last_seen_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .execute(&state.db)
+        .await
+    {
+        warn!("Failed to update last seen time for {}: {}", user_id, e);
+    }
+}
+
+/// Posts a message sent over the socket, or runs it if it's a command. A
+/// command's response is returned so its ephemeral part can be sent back.
+async fn post_chat_message(
+    state: &AppState,
+    invocation: &commands::Invocation<'_>,
+    chat_msg: &ChatMessage,
+) -> Result<Option<CommandResponse>, AppError> {
+    let room_id = invocation.room_id;
+    let user_id = invocation.claims.user_id()?;
+    let mut keys = crate::rate_limit::keys_for(invocation.client, None);
+    keys.push(format!("user:{}", user_id));
+    state.rate_limits.check(&state.rate_limits.messages, &keys)?;
+
+    let message_type = chat_msg.message_type.as_deref().unwrap_or("text");
+    if message_type == "text" && commands::parse(&chat_msg.content).is_some() {
+        return commands::execute(state, invocation, &chat_msg.content).await.map(Some);
+    }
+    crate::chat::ensure_client_message_type(message_type)?;
+    let expires_at = crate::retention::expires_at(chat_msg.expires_in)?;
+    crate::moderation::ensure_can_post(&state.db, room_id, user_id).await?;
+
+    let message = crate::chat::send_message_with(
+        state,
+        room_id,
+        user_id,
+        &chat_msg.content,
+        message_type,
+        crate::chat::MessageOptions { expires_at, ..Default::default() },
+    ).await?;
+
+    broadcast_to_room(state, room_id, &WebSocketMessage::new("new_message", &message)).await;
+    crate::push::notify_new_message(state, &message);
+    crate::outgoing_webhooks::dispatch_message(
+        &state.db,
+        crate::outgoing_webhooks::EVENT_MESSAGE_CREATED,
+        &message,
+    ).await;
+    Ok(None)
+}
+
+/// Sends an event to every client connected to the room.
+pub async fn broadcast_to_room(state: &AppState, room_id: Uuid, event: &WebSocketMessage) {
+    if let Some(tx) = state.rooms.read().await.get(&room_id) {
+        let event_json = serde_json::to_string(event).unwrap();
+        let _ = tx.send(event_json);
+    }
+}
+
+/// Sends an event to each of the user's connections, whichever room it's for.
+pub async fn send_to_user(state: &AppState, user_id: Uuid, event: &WebSocketMessage) {
+    let connections = state.connections.read().await;
+    if let Some(handles) = connections.get(&user_id) {
+        let event_json = serde_json::to_string(event).unwrap();
+        for handle in handles {
+            let _ = handle.tx.send(SocketCommand::Send(event_json.clone()));
+        }
+    }
+}
+
+/// Closes the user's sockets, either for a single room or for all rooms.
+pub async fn disconnect_user(state: &AppState, user_id: Uuid, room_id: Option<Uuid>, reason: &str) {
+    let connections = state.connections.read().await;
+    if let Some(handles) = connections.get(&user_id) {
+        for handle in handles {
+            if room_id.is_none_or(|room_id| handle.room_id == room_id) {
+                let _ = handle.tx.send(SocketCommand::Close(reason.to_string()));
+            }
+        }
+    }
+}
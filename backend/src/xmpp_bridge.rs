@@ -1,5 +1,3 @@
-#[allow(dead_code)]
-
 use crate::error::AppError;
 use std::collections::HashMap;
 use tokio::sync::RwLock;
@@ -7,17 +5,20 @@ use tracing::info;
 use uuid::Uuid;
 
 // Basic XMPP bridge structure for future implementation
+#[allow(dead_code)]
 pub struct XmppBridge {
     // This would contain XMPP client connections and room mappings
     connections: RwLock<HashMap<String, XmppConnection>>,
 }
 
+#[allow(dead_code)]
 pub struct XmppConnection {
     pub jid: String,
     pub room_id: Uuid,
     // In a full implementation, this would contain the actual XMPP client
 }
 
+#[allow(dead_code)]
 impl XmppBridge {
     pub fn new() -> Self {
         Self {
@@ -50,7 +51,7 @@ impl XmppBridge {
     pub async fn send_message_to_xmpp(
         &self,
         room_jid: &str,
-        message: &str,
+        _message: &str,
     ) -> Result<(), AppError> {
         info!("Sending message to XMPP room: {}", room_jid);
         
@@ -0,0 +1,89 @@
+"""Local Web Push endpoint for development.
+
+Prints a subscription to register with POST /api/users/me/push-subscriptions,
+then decrypts and prints every notification it receives after checking the
+VAPID signature. Endpoints ending in /gone answer 410 like an expired
+subscription would.
+"""
+import base64
+import json
+import os
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.hazmat.primitives.asymmetric.utils import encode_dss_signature
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+from cryptography.hazmat.primitives.kdf.hkdf import HKDF
+
+PORT = int(os.environ.get("PORT", "9091"))
+ENDPOINT = os.environ.get("STUB_ENDPOINT", f"http://localhost:{PORT}/push/stub")
+
+
+def b64decode(value):
+    return base64.urlsafe_b64decode(value + "=" * (-len(value) % 4))
+
+
+def b64encode(value):
+    return base64.urlsafe_b64encode(value).rstrip(b"=").decode()
+
+
+def hkdf(salt, ikm, info, length):
+    return HKDF(hashes.SHA256(), length, salt, info).derive(ikm)
+
+
+private_key = ec.generate_private_key(ec.SECP256R1())
+public_key = private_key.public_key().public_bytes(
+    serialization.Encoding.X962, serialization.PublicFormat.UncompressedPoint
+)
+auth_secret = os.urandom(16)
+
+
+def verify_vapid(authorization):
+    params = dict(part.strip().split("=", 1) for part in authorization.removeprefix("vapid ").split(","))
+    header, claims, signature = params["t"].split(".")
+    signature = b64decode(signature)
+    key = ec.EllipticCurvePublicKey.from_encoded_point(ec.SECP256R1(), b64decode(params["k"]))
+    key.verify(
+        encode_dss_signature(int.from_bytes(signature[:32], "big"), int.from_bytes(signature[32:], "big")),
+        f"{header}.{claims}".encode(),
+        ec.ECDSA(hashes.SHA256()),
+    )
+    return json.loads(b64decode(claims))
+
+
+def decrypt(body):
+    salt, key_length = body[:16], body[20]
+    server_key, ciphertext = body[21:21 + key_length], body[21 + key_length:]
+    shared = private_key.exchange(
+        ec.ECDH(), ec.EllipticCurvePublicKey.from_encoded_point(ec.SECP256R1(), server_key)
+    )
+    ikm = hkdf(auth_secret, shared, b"WebPush: info\0" + public_key + server_key, 32)
+    cek = hkdf(salt, ikm, b"Content-Encoding: aes128gcm\0", 16)
+    nonce = hkdf(salt, ikm, b"Content-Encoding: nonce\0", 12)
+    record = AESGCM(cek).decrypt(nonce, ciphertext, None)
+    return record.rstrip(b"\0")[:-1].decode()
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers["Content-Length"]))
+        try:
+            claims = verify_vapid(self.headers["Authorization"])
+            payload = decrypt(body)
+            print(f"{self.path} TTL={self.headers['TTL']} aud={claims['aud']} sub={claims['sub']}", flush=True)
+            print(f"  {payload}", flush=True)
+            self.send_response(410 if self.path.endswith("/gone") else 201)
+        except Exception as e:
+            print(f"{self.path} rejected: {e!r}", flush=True)
+            self.send_response(400)
+        self.end_headers()
+
+    def log_message(self, *args):
+        pass
+
+
+if __name__ == "__main__":
+    subscription = {"endpoint": ENDPOINT, "keys": {"p256dh": b64encode(public_key), "auth": b64encode(auth_secret)}}
+    print("Subscribe with:", json.dumps(subscription), flush=True)
+    HTTPServer(("0.0.0.0", PORT), Handler).serve_forever()
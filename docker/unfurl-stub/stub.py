@@ -0,0 +1,106 @@
+"""Local pages to try out link previews against.
+
+Run the backend with LINK_PREVIEW_ALLOWED_HOSTS=localhost (the stub is on a
+private address) and post links to these paths:
+
+  /article            OpenGraph title, description, site name and image
+  /plain              Only a <title> and a meta description
+  /oembed             No OpenGraph tags; the title and thumbnail come from /oembed.json
+  /redirect?to=<path> Redirects to another path on the stub
+  /metadata           Redirects to the cloud metadata address, which is never fetched
+  /huge               A <title>, then 50 MB of padding the fetcher stops reading early
+  /slow               Answers after 30 seconds, past the fetch timeout
+  /image              Not HTML, so there's nothing to preview
+"""
+import json
+import os
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from urllib.parse import parse_qs, urlparse
+
+PORT = int(os.environ.get("PORT", "9093"))
+
+PAGES = {
+    "/article": """<!doctype html><html><head>
+<title>Ignored when og:title is set</title>
+<meta property="og:title" content="Rust Konect ships link previews">
+<meta property="og:description" content="Titles, descriptions and images for the links in your messages.">
+<meta property="og:site_name" content="Konect Stub News">
+<meta property="og:image" content="/image">
+</head><body><p>Article body</p></body></html>""",
+    "/plain": """<!doctype html><html><head>
+<title>  A page with
+  only the basics </title>
+<meta name="description" content="Falls back to the title tag and meta description.">
+</head><body></body></html>""",
+    "/oembed": """<!doctype html><html><head>
+<link rel="alternate" type="application/json+oembed" href="/oembed.json">
+<meta name="description" content="The title and thumbnail come from oEmbed.">
+</head><body></body></html>""",
+}
+
+OEMBED = {
+    "version": "1.0",
+    "type": "video",
+    "title": "A video described by oEmbed",
+    "provider_name": "Stub Video",
+    "thumbnail_url": "/image",
+}
+
+# 1x1 transparent PNG
+PNG = bytes.fromhex(
+    "89504e470d0a1a0a0000000d49484452000000010000000108060000001f15c489"
+    "0000000d49444154789c6300010000000500010d0a2db40000000049454e44ae426082"
+)
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_GET(self):
+        url = urlparse(self.path)
+        print(f"GET {self.path} ({self.headers.get('User-Agent')})", flush=True)
+
+        if url.path in PAGES:
+            self.respond(200, "text/html; charset=utf-8", PAGES[url.path].encode())
+        elif url.path == "/oembed.json":
+            self.respond(200, "application/json", json.dumps(OEMBED).encode())
+        elif url.path == "/redirect":
+            self.redirect(parse_qs(url.query).get("to", ["/article"])[0])
+        elif url.path == "/metadata":
+            self.redirect("http://169.254.169.254/latest/meta-data/")
+        elif url.path == "/huge":
+            self.send_response(200)
+            self.send_header("Content-Type", "text/html")
+            self.end_headers()
+            self.wfile.write(b"<!doctype html><html><head><title>A very large page</title></head><body><!--")
+            try:
+                for _ in range(50 * 1024):
+                    self.wfile.write(b"x" * 1024)
+            except (BrokenPipeError, ConnectionResetError):
+                print("  /huge: the client stopped reading", flush=True)
+        elif url.path == "/slow":
+            time.sleep(30)
+            self.respond(200, "text/html", PAGES["/article"].encode())
+        elif url.path == "/image":
+            self.respond(200, "image/png", PNG)
+        else:
+            self.respond(404, "text/plain", b"not found")
+
+    def respond(self, status, content_type, body):
+        self.send_response(status)
+        self.send_header("Content-Type", content_type)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def redirect(self, location):
+        self.send_response(302)
+        self.send_header("Location", location)
+        self.end_headers()
+
+    def log_message(self, *args):
+        pass
+
+
+if __name__ == "__main__":
+    print(f"Serving link preview fixtures on http://localhost:{PORT}/", flush=True)
+    ThreadingHTTPServer(("", PORT), Handler).serve_forever()
@@ -0,0 +1,68 @@
+"""Local receiver for outgoing webhooks and custom slash commands.
+
+Prints every event it receives and checks the X-Konect-Signature header when
+WEBHOOK_SECRET is set to the secret returned at creation. Paths ending in
+/fail answer 500, to try out retries and the dead letters.
+
+Commands are answered with an echo of their text, shown only to the caller;
+register the command with a URL ending in /public to have it posted to the
+room instead.
+"""
+import hashlib
+import hmac
+import json
+import os
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+PORT = int(os.environ.get("PORT", "9092"))
+SECRET = os.environ.get("WEBHOOK_SECRET", "")
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get("Content-Length", 0)))
+        timestamp = self.headers.get("X-Konect-Timestamp", "")
+        received = self.headers.get("X-Konect-Signature", "")
+
+        if SECRET:
+            expected = "sha256=" + hmac.new(
+                SECRET.encode(), f"{timestamp}.".encode() + body, hashlib.sha256
+            ).hexdigest()
+            verified = "valid" if hmac.compare_digest(expected, received) else "INVALID"
+        else:
+            verified = "not checked"
+
+        command = self.headers.get("X-Konect-Command")
+        if command:
+            invocation = json.loads(body)
+            print(f"{command} from {invocation['username']} signature={verified}", flush=True)
+            reply = {
+                "text": f"{command} {invocation['text']}".strip() + " (echoed by the stub)",
+                "response_type": "in_channel" if self.path.endswith("/public") else "ephemeral",
+            }
+            self.send_response(200)
+            self.send_header("Content-Type", "application/json")
+            self.end_headers()
+            self.wfile.write(json.dumps(reply).encode())
+            return
+
+        event = json.loads(body)
+        print(
+            f"{self.headers.get('X-Konect-Event')} delivery={self.headers.get('X-Konect-Delivery')} "
+            f"signature={verified}",
+            flush=True,
+        )
+        print(json.dumps(event["data"], indent=2), flush=True)
+
+        status = 500 if self.path.endswith("/fail") else 200
+        self.send_response(status)
+        self.end_headers()
+        self.wfile.write(b"failing on purpose" if status == 500 else b"ok")
+
+    def log_message(self, *args):
+        pass
+
+
+if __name__ == "__main__":
+    print(f"Listening for webhooks on http://localhost:{PORT}/", flush=True)
+    HTTPServer(("", PORT), Handler).serve_forever()
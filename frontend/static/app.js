@@ -1,5 +1,6 @@
 class ChatApp {
     constructor() {
+        this.handleSsoRedirect();
         this.token = localStorage.getItem('auth_token');
         this.currentUser = null;
         this.currentRoom = null;
@@ -8,12 +9,15 @@ class ChatApp {
         
         this.initializeElements();
         this.attachEventListeners();
+        this.handleEmailLinks();
+        this.loadSsoProvider();
         
         if (this.token) {
             this.showChatInterface();
             this.loadRooms();
         } else {
             this.showAuthModal();
+            if (this.pendingSecondFactor) this.completeSsoLogin();
         }
     }
     
@@ -31,12 +35,17 @@ class ChatApp {
         this.chatContainer = document.getElementById('chat-container');
         this.currentUserSpan = document.getElementById('current-user');
         this.logoutBtn = document.getElementById('logout-btn');
+        this.notificationsBtn = document.getElementById('notifications-btn');
         this.roomsList = document.getElementById('rooms-list');
         this.createRoomBtn = document.getElementById('create-room-btn');
         this.currentRoomName = document.getElementById('current-room-name');
+        this.currentRoomTopic = document.getElementById('current-room-topic');
+        this.pinnedList = document.getElementById('pinned-messages');
+        this.pins = [];
         this.messagesList = document.getElementById('messages-list');
         this.messageInput = document.getElementById('message-input');
         this.sendBtn = document.getElementById('send-btn');
+        this.expirySelect = document.getElementById('expiry-select');
         this.fileBtn = document.getElementById('file-btn');
         this.fileInput = document.getElementById('file-input');
         
@@ -50,9 +59,11 @@ class ChatApp {
         // Auth form
         this.authForm.addEventListener('submit', (e) => this.handleAuth(e));
         this.toggleAuth.addEventListener('click', (e) => this.toggleAuthMode(e));
+        document.getElementById('forgot-password').addEventListener('click', (e) => this.requestPasswordReset(e));
         
         // Chat interface
         this.logoutBtn.addEventListener('click', () => this.logout());
+        this.notificationsBtn.addEventListener('click', () => this.enablePushNotifications());
         this.createRoomBtn.addEventListener('click', () => this.showCreateRoomModal());
         this.messageInput.addEventListener('keypress', (e) => {
             if (e.key === 'Enter') this.sendMessage();
@@ -88,7 +99,15 @@ class ChatApp {
             });
             
             if (response.ok) {
-                const result = await response.json();
+                let result = await response.json();
+                if (result.verification_required) {
+                    this.showError('Check your inbox for a link to verify your email address');
+                    return;
+                }
+                if (result.mfa_required) {
+                    result = await this.completeSecondFactor(result);
+                    if (!result) return;
+                }
                 this.token = result.token;
                 this.currentUser = result.user;
                 localStorage.setItem('auth_token', this.token);
@@ -104,6 +123,151 @@ class ChatApp {
         }
     }
     
+    // Single sign-on redirects back here with the outcome in the URL
+    handleSsoRedirect() {
+        const hash = new URLSearchParams(window.location.hash.slice(1));
+        const params = new URLSearchParams(window.location.search);
+        
+        if (hash.has('sso_token')) {
+            localStorage.setItem('auth_token', hash.get('sso_token'));
+        } else if (hash.has('sso_mfa_token')) {
+            this.pendingSecondFactor = {
+                mfa_token: hash.get('sso_mfa_token'),
+                enrollment_required: hash.get('enrollment_required') === 'true'
+            };
+        } else if (hash.has('sso_linked')) {
+            this.pendingNotice = 'Your single sign-on identity is now linked';
+        } else if (params.has('sso_error')) {
+            this.pendingNotice = params.get('sso_error');
+        } else {
+            return;
+        }
+        window.history.replaceState({}, '', window.location.pathname);
+    }
+    
+    async loadSsoProvider() {
+        if (this.pendingNotice) this.showError(this.pendingNotice);
+        try {
+            const response = await fetch('/api/auth/oidc');
+            const provider = await response.json();
+            if (provider.enabled) {
+                const button = document.getElementById('sso-login');
+                button.textContent = `Sign in with ${provider.name}`;
+                button.style.display = 'block';
+            }
+        } catch (error) {
+            // Password login still works without it
+        }
+    }
+    
+    // Single sign-on still asks for the second factor before handing out a session
+    async completeSsoLogin() {
+        const result = await this.completeSecondFactor(this.pendingSecondFactor);
+        this.pendingSecondFactor = null;
+        if (!result) return;
+        this.token = result.token;
+        this.currentUser = result.user;
+        localStorage.setItem('auth_token', this.token);
+        this.showChatInterface();
+        this.loadRooms();
+    }
+    
+    // Second login step: verify a code, or enroll first if the server requires 2FA
+    async completeSecondFactor({ mfa_token, enrollment_required }) {
+        const post = (path, body) => fetch(`/api/auth/2fa/${path}`, {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ mfa_token, ...body })
+        });
+        
+        let response;
+        if (enrollment_required) {
+            const enrollResponse = await post('enroll', {});
+            if (!enrollResponse.ok) {
+                this.showError('Could not start two-factor enrollment');
+                return null;
+            }
+            const enrollment = await enrollResponse.json();
+            const code = prompt(`This server requires two-factor authentication.\nAdd this key to your authenticator app:\n\n${enrollment.secret}\n\nThen enter the 6-digit code it shows`);
+            if (!code) return null;
+            response = await post('confirm', { code });
+        } else {
+            const code = prompt('Enter the 6-digit code from your authenticator app, or a recovery code');
+            if (!code) return null;
+            const body = /^\d{6}$/.test(code.trim()) ? { code } : { recovery_code: code };
+            response = await post('verify', body);
+        }
+        
+        const result = await response.json();
+        if (!response.ok) {
+            this.showError(result.error || 'Verification failed');
+            return null;
+        }
+        if (result.recovery_codes) {
+            alert('Save these recovery codes somewhere safe. Each can be used once if you lose your device:\n\n' + result.recovery_codes.join('\n'));
+        }
+        return result;
+    }
+    
+    // Links in verification, password reset and email change emails point back here
+    async handleEmailLinks() {
+        const params = new URLSearchParams(window.location.search);
+        let endpoint = null;
+        const body = {};
+        
+        if (params.has('verify_email')) {
+            endpoint = '/api/auth/verify-email';
+            body.token = params.get('verify_email');
+        } else if (params.has('confirm_email')) {
+            endpoint = '/api/auth/email-change/confirm';
+            body.token = params.get('confirm_email');
+        } else if (params.has('reset_password')) {
+            const password = prompt('Choose a new password');
+            if (!password) return;
+            endpoint = '/api/auth/password-reset/confirm';
+            body.token = params.get('reset_password');
+            body.new_password = password;
+        } else {
+            return;
+        }
+        
+        window.history.replaceState({}, '', window.location.pathname);
+        
+        try {
+            const response = await fetch(endpoint, {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify(body)
+            });
+            
+            if (response.ok) {
+                this.showError('Done! You can now log in.');
+            } else {
+                const error = await response.json();
+                this.showError(error.error || 'This link is invalid or has expired');
+            }
+        } catch (error) {
+            this.showError('Network error: ' + error.message);
+        }
+    }
+    
+    async requestPasswordReset(e) {
+        e.preventDefault();
+        const email = prompt('Enter your account email');
+        if (!email) return;
+        
+        try {
+            await fetch('/api/auth/password-reset/request', {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ email })
+            });
+            this.showError('If that address has an account, a reset link is on its way');
+        } catch (error) {
+            this.showError('Network error: ' + error.message);
+        }
+    }
+    
     toggleAuthMode(e) {
         e.preventDefault();
         const isLogin = this.authTitle.textContent === 'Login';
@@ -137,11 +301,92 @@ class ChatApp {
         this.chatContainer.style.display = 'block';
         
         if (this.currentUser) {
-            this.currentUserSpan.textContent = this.currentUser.username;
+            this.currentUserSpan.textContent = this.currentUser.display_name || this.currentUser.username;
+        }
+        this.setupPushNotifications();
+    }
+    
+    // Offer Web Push when the server has it enabled and this device isn't subscribed yet
+    async setupPushNotifications() {
+        this.notificationsBtn.style.display = 'none';
+        if (!('serviceWorker' in navigator) || !('PushManager' in window)) return;
+        
+        try {
+            const response = await fetch('/api/push/public-key', {
+                headers: { 'Authorization': `Bearer ${this.token}` }
+            });
+            if (!response.ok) return;
+            const config = await response.json();
+            if (!config.enabled) return;
+            this.pushPublicKey = config.public_key;
+            
+            this.serviceWorker = await navigator.serviceWorker.register('/static/sw.js');
+            const subscription = await this.serviceWorker.pushManager.getSubscription();
+            if (subscription && Notification.permission === 'granted') {
+                await this.savePushSubscription(subscription);
+            } else if (Notification.permission !== 'denied') {
+                this.notificationsBtn.style.display = 'inline-block';
+            }
+        } catch (error) {
+            console.log('Push notifications unavailable:', error);
+        }
+    }
+    
+    async enablePushNotifications() {
+        try {
+            if (await Notification.requestPermission() !== 'granted') {
+                this.notificationsBtn.style.display = 'none';
+                return;
+            }
+            const key = atob(this.pushPublicKey.replace(/-/g, '+').replace(/_/g, '/'));
+            const subscription = await this.serviceWorker.pushManager.subscribe({
+                userVisibleOnly: true,
+                applicationServerKey: Uint8Array.from(key, c => c.charCodeAt(0))
+            });
+            await this.savePushSubscription(subscription);
+            this.notificationsBtn.style.display = 'none';
+        } catch (error) {
+            this.showError('Failed to enable notifications: ' + error.message);
+        }
+    }
+    
+    async savePushSubscription(subscription) {
+        const response = await fetch('/api/users/me/push-subscriptions', {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+                'Authorization': `Bearer ${this.token}`
+            },
+            body: JSON.stringify(subscription.toJSON())
+        });
+        if (!response.ok) {
+            const error = await response.json();
+            throw new Error(error.error || 'Subscription failed');
         }
     }
     
+    // Stop pushing the signed-out account's messages to this device
+    async removePushSubscription(token) {
+        if (!this.serviceWorker) return;
+        const subscription = await this.serviceWorker.pushManager.getSubscription();
+        if (!subscription) return;
+        
+        const response = await fetch('/api/users/me/push-subscriptions', {
+            headers: { 'Authorization': `Bearer ${token}` }
+        });
+        if (!response.ok) return;
+        const saved = (await response.json()).find(s => s.endpoint === subscription.endpoint);
+        if (saved) {
+            await fetch(`/api/users/me/push-subscriptions/${saved.id}`, {
+                method: 'DELETE',
+                headers: { 'Authorization': `Bearer ${token}` }
+            });
+        }
+        await subscription.unsubscribe();
+    }
+    
     logout() {
+        this.removePushSubscription(this.token).catch(() => {});
         this.token = null;
         this.currentUser = null;
         this.currentRoom = null;
@@ -164,12 +409,23 @@ class ChatApp {
             if (response.ok) {
                 this.rooms = await response.json();
                 this.renderRooms();
+                this.openLinkedRoom();
             }
         } catch (error) {
             this.showError('Failed to load rooms: ' + error.message);
         }
     }
     
+    // Notifications link to /?room=<id>
+    openLinkedRoom() {
+        const params = new URLSearchParams(window.location.search);
+        const room = this.rooms.find(r => r.id === params.get('room'));
+        if (!room) return;
+        
+        window.history.replaceState({}, '', window.location.pathname);
+        this.selectRoom(room);
+    }
+    
     renderRooms() {
         this.roomsList.innerHTML = '';
         
@@ -193,21 +449,26 @@ class ChatApp {
         document.querySelectorAll('.room-item').forEach(item => {
             item.classList.remove('active');
         });
-        document.querySelector(`[data-room-id="${room.id}"]`).classList.add('active');
+        const roomItem = document.querySelector(`[data-room-id="${room.id}"]`);
+        roomItem.classList.add('active');
+        roomItem.classList.remove('has-mention');
         
         this.currentRoom = room;
         this.currentRoomName.textContent = room.name;
+        this.currentRoomTopic.textContent = room.topic || '';
         
         // Enable message input
         this.messageInput.disabled = false;
         this.sendBtn.disabled = false;
         this.fileBtn.disabled = false;
+        this.expirySelect.disabled = false;
         
         // Connect WebSocket
         this.connectWebSocket(room.id);
         
         // Load message history
         this.loadMessages(room.id);
+        this.loadPins(room.id);
     }
     
     connectWebSocket(roomId) {
@@ -230,9 +491,9 @@ class ChatApp {
         this.websocket.onmessage = (event) => {
             console.log('📨 Received WebSocket message:', event.data);
             try {
-                const message = JSON.parse(event.data);
-                console.log('📨 Parsed message:', message);
-                this.displayMessage(message);
+                const payload = JSON.parse(event.data);
+                console.log('📨 Parsed event:', payload);
+                this.handleServerEvent(payload);
             } catch (error) {
                 console.error('❌ Failed to parse WebSocket message:', error);
             }
@@ -240,6 +501,14 @@ class ChatApp {
         
         this.websocket.onclose = (event) => {
             console.log('❌ WebSocket disconnected:', event.code, event.reason);
+            // 4000: removed from the room by a moderator
+            if (event.code === 4000) {
+                this.showError(`You were ${event.reason || 'removed'} from this room`);
+                this.messageInput.disabled = true;
+                this.sendBtn.disabled = true;
+                this.fileBtn.disabled = true;
+                this.expirySelect.disabled = true;
+            }
         };
         
         this.websocket.onerror = (error) => {
@@ -248,6 +517,67 @@ class ChatApp {
     }
 
     
+    handleServerEvent(event) {
+        switch (event.message_type) {
+            case 'new_message':
+                this.displayMessage(event.data);
+                this.markRead(event.data.room_id, event.data.id);
+                break;
+            case 'message_updated': {
+                const existing = this.messagesList.querySelector(`[data-message-id="${event.data.id}"]`);
+                if (existing) existing.replaceWith(this.createMessageElement(event.data));
+                break;
+            }
+            case 'messages_deleted':
+                event.data.message_ids.forEach(id => {
+                    const existing = this.messagesList.querySelector(`[data-message-id="${id}"]`);
+                    if (existing) existing.remove();
+                });
+                this.pins = this.pins.filter(pin => !event.data.message_ids.includes(pin.id));
+                this.renderPins();
+                break;
+            case 'message_pinned':
+                this.pins.unshift(event.data);
+                this.renderPins();
+                break;
+            case 'message_unpinned':
+                this.pins = this.pins.filter(pin => pin.id !== event.data.message_id);
+                this.renderPins();
+                break;
+            case 'error':
+                this.showError(event.data.error);
+                break;
+            case 'command_response':
+                this.displayEphemeral(event.data.ephemeral);
+                break;
+            case 'poll_updated':
+                this.updatePoll(event.data);
+                break;
+            case 'room_updated':
+                if (this.currentRoom && event.data.id === this.currentRoom.id) {
+                    this.currentRoom = event.data;
+                    this.currentRoomTopic.textContent = event.data.topic || '';
+                }
+                break;
+            case 'user_updated':
+                if (this.currentUser && event.data.id === this.currentUser.id) {
+                    this.currentUser.username = event.data.username;
+                    this.currentUser.display_name = event.data.display_name;
+                    this.currentUser.avatar_url = event.data.avatar_url;
+                    this.currentUserSpan.textContent = event.data.display_name || event.data.username;
+                }
+                break;
+            case 'mention':
+                if (!this.currentRoom || event.data.room_id !== this.currentRoom.id) {
+                    const item = document.querySelector(`[data-room-id="${event.data.room_id}"]`);
+                    if (item) item.classList.add('has-mention');
+                }
+                break;
+            default:
+                console.log('Unhandled WebSocket event:', event.message_type);
+        }
+    }
+    
     sendWebSocketMessage(type, data) {
         if (this.websocket && this.websocket.readyState === WebSocket.OPEN) {
             this.websocket.send(JSON.stringify({
@@ -257,9 +587,11 @@ class ChatApp {
         }
     }
     
-    async loadMessages(roomId) {
+    // `aroundId` loads the page around an older message instead of the latest one
+    async loadMessages(roomId, aroundId) {
+        const around = aroundId ? `&around=${aroundId}` : '';
         try {
-            const response = await fetch(`/api/rooms/${roomId}/messages?limit=50`, {
+            const response = await fetch(`/api/rooms/${roomId}/messages?limit=50${around}`, {
                 headers: { 'Authorization': `Bearer ${this.token}` }
             });
             
@@ -267,45 +599,242 @@ class ChatApp {
                 const messages = await response.json();
                 this.messagesList.innerHTML = '';
                 messages.reverse().forEach(message => this.displayMessage(message));
-                this.scrollToBottom();
+                if (aroundId) {
+                    this.highlightMessage(aroundId);
+                } else {
+                    this.scrollToBottom();
+                    if (messages.length > 0) {
+                        this.markRead(roomId, messages[messages.length - 1].id);
+                    }
+                }
             }
         } catch (error) {
             this.showError('Failed to load messages: ' + error.message);
         }
     }
+
+    async loadPins(roomId) {
+        try {
+            const response = await fetch(`/api/rooms/${roomId}`, {
+                headers: { 'Authorization': `Bearer ${this.token}` }
+            });
+            if (response.ok) {
+                const room = await response.json();
+                this.pins = room.pinned_messages || [];
+                this.renderPins();
+            }
+        } catch (error) {
+            console.error('Failed to load pinned messages:', error);
+        }
+    }
+
+    renderPins() {
+        this.pinnedList.innerHTML = '';
+        this.pins.forEach(pin => {
+            const item = document.createElement('button');
+            item.className = 'pinned-message';
+            const preview = pin.message_type === 'file' ? '📎 ' + JSON.parse(pin.content).filename : pin.content;
+            item.textContent = '📌 ' + (preview.length > 60 ? preview.slice(0, 60) + '…' : preview);
+            item.addEventListener('click', () => this.jumpToMessage(pin.id));
+            this.pinnedList.appendChild(item);
+        });
+    }
+
+    // Pinned messages may be older than the loaded page
+    jumpToMessage(messageId) {
+        if (this.messagesList.querySelector(`[data-message-id="${messageId}"]`)) {
+            this.highlightMessage(messageId);
+        } else if (this.currentRoom) {
+            this.loadMessages(this.currentRoom.id, messageId);
+        }
+    }
+
+    highlightMessage(messageId) {
+        const messageEl = this.messagesList.querySelector(`[data-message-id="${messageId}"]`);
+        if (!messageEl) return;
+        messageEl.scrollIntoView({ block: 'center' });
+        messageEl.classList.add('highlighted');
+        setTimeout(() => messageEl.classList.remove('highlighted'), 2000);
+    }
+
+    // Read markers keep already-seen messages out of email digests
+    async markRead(roomId, messageId) {
+        try {
+            await fetch(`/api/rooms/${roomId}/read`, {
+                method: 'PUT',
+                headers: {
+                    'Content-Type': 'application/json',
+                    'Authorization': `Bearer ${this.token}`
+                },
+                body: JSON.stringify({ message_id: messageId })
+            });
+        } catch (error) {
+            console.error('Failed to update read marker:', error);
+        }
+    }
     
     displayMessage(message) {
+        this.messagesList.appendChild(this.createMessageElement(message));
+        this.scrollToBottom();
+    }
+
+    // Replies to slash commands that only the sender sees; they aren't stored
+    displayEphemeral(text) {
+        const note = document.createElement('div');
+        note.className = 'message ephemeral';
+        note.innerHTML = `
+            <div class="message-content">${this.escapeHtml(text)}</div>
+            <div class="message-time">Only visible to you</div>
+        `;
+        this.messagesList.appendChild(note);
+        this.scrollToBottom();
+    }
+
+    renderPoll(messageEl, poll) {
+        messageEl.poll = poll;
+        const container = messageEl.querySelector('.poll-options');
+        container.innerHTML = '';
+        poll.options.forEach((option, index) => {
+            const button = document.createElement('button');
+            button.className = 'poll-option' + (poll.my_votes.includes(index) ? ' voted' : '');
+            button.disabled = poll.is_closed;
+            const percent = poll.total_voters ? Math.round(option.votes * 100 / poll.total_voters) : 0;
+            button.innerHTML = `
+                <span class="poll-bar" style="width: ${percent}%"></span>
+                <span class="poll-text">${poll.multiple_choice ? (poll.my_votes.includes(index) ? '☑ ' : '☐ ') : ''}${this.escapeHtml(option.text)}</span>
+                <span class="poll-count">${option.votes}</span>
+            `;
+            button.addEventListener('click', () => this.votePoll(messageEl, index));
+            container.appendChild(button);
+        });
+
+        const labels = [];
+        if (poll.multiple_choice) labels.push('Multiple choice');
+        if (poll.anonymous) labels.push('Anonymous');
+        if (poll.is_closed) {
+            labels.push('Closed');
+        } else if (poll.closes_at) {
+            labels.push('Closes ' + new Date(poll.closes_at).toLocaleString());
+        }
+        labels.push(`${poll.total_voters} voter${poll.total_voters === 1 ? '' : 's'}`);
+        const footer = messageEl.querySelector('.poll-footer');
+        footer.textContent = labels.join(' · ');
+        if (!poll.is_closed && this.currentUser && poll.created_by === this.currentUser.id) {
+            const close = document.createElement('button');
+            close.className = 'poll-close';
+            close.textContent = 'Close poll';
+            close.addEventListener('click', () => this.closePoll(messageEl));
+            footer.appendChild(close);
+        }
+    }
+
+    // Broadcast results don't know who's looking, so keep our own votes
+    updatePoll(poll) {
+        const messageEl = this.messagesList.querySelector(`[data-message-id="${poll.message_id}"]`);
+        if (!messageEl || !messageEl.poll) return;
+        this.renderPoll(messageEl, { ...poll, my_votes: messageEl.poll.my_votes });
+    }
+
+    async votePoll(messageEl, index) {
+        const poll = messageEl.poll;
+        let options;
+        if (poll.multiple_choice) {
+            options = poll.my_votes.includes(index)
+                ? poll.my_votes.filter(vote => vote !== index)
+                : [...poll.my_votes, index];
+        } else {
+            options = poll.my_votes.includes(index) ? [] : [index];
+        }
+        await this.pollRequest(messageEl, 'votes', options.length ? 'POST' : 'DELETE',
+            options.length ? { options } : undefined);
+    }
+
+    async closePoll(messageEl) {
+        await this.pollRequest(messageEl, 'close', 'POST');
+    }
+
+    async pollRequest(messageEl, path, method, body) {
+        const poll = messageEl.poll;
+        try {
+            const response = await fetch(`/api/rooms/${poll.room_id}/polls/${poll.message_id}/${path}`, {
+                method,
+                headers: {
+                    'Content-Type': 'application/json',
+                    'Authorization': `Bearer ${this.token}`
+                },
+                body: body ? JSON.stringify(body) : undefined
+            });
+            if (response.ok) {
+                this.renderPoll(messageEl, await response.json());
+            } else {
+                const error = await response.json();
+                this.showError(error.error || 'Failed to update poll');
+            }
+        } catch (error) {
+            this.showError('Network error: ' + error.message);
+        }
+    }
+
+    createMessageElement(message) {
         const messageEl = document.createElement('div');
         messageEl.className = 'message';
+        messageEl.dataset.messageId = message.id;
         
         const isOwnMessage = this.currentUser && message.user_id === this.currentUser.id;
         messageEl.classList.add(isOwnMessage ? 'own' : 'other');
+        const botBadge = message.is_bot ? ' <span class="bot-badge">BOT</span>' : '';
         
-        const timestamp = new Date(message.created_at).toLocaleTimeString();
+        const timestamp = new Date(message.created_at).toLocaleTimeString() + (message.edited_at ? ' (edited)' : '')
+            + (message.expires_at ? ` · disappears ${new Date(message.expires_at).toLocaleString()}` : '');
         
-        if (message.message_type === 'file') {
+        if (message.message_type === 'system') {
+            messageEl.className = 'message system';
+            messageEl.innerHTML = `
+                <div class="message-content">${this.escapeHtml(message.content)}</div>
+                <div class="message-time">${timestamp}</div>
+            `;
+        } else if (message.message_type === 'action') {
+            const author = isOwnMessage ? 'You' : 'User';
+            messageEl.classList.add('action');
+            messageEl.innerHTML = `
+                <div class="message-content">* ${author} ${this.renderContent(message)}</div>
+                <div class="message-time">${timestamp}</div>
+            `;
+        } else if (message.message_type === 'poll') {
+            messageEl.innerHTML = `
+                <div class="message-header">${isOwnMessage ? 'You' : 'User'} started a poll</div>
+                <div class="message-content poll-question">${this.escapeHtml(message.content)}</div>
+                <div class="poll-options"></div>
+                <div class="poll-footer"></div>
+                <div class="message-time">${timestamp}</div>
+            `;
+            if (message.poll) this.renderPoll(messageEl, message.poll);
+        } else if (message.message_type === 'file') {
             const fileData = JSON.parse(message.content);
             messageEl.innerHTML = `
-                <div class="message-header">${isOwnMessage ? 'You' : 'User'}</div>
+                <div class="message-header">${isOwnMessage ? 'You' : 'User'}${botBadge}</div>
                 <div class="message-content">
+                    ${this.renderImagePreview(fileData)}
                     <div class="file-item">
-                        <a href="${fileData.url}" class="file-link" target="_blank">
-                            📎 ${fileData.filename} (${this.formatFileSize(fileData.size)})
+                        <a href="${this.escapeAttribute(fileData.url)}" class="file-link" target="_blank">
+                            📎 ${this.escapeHtml(fileData.filename)} (${this.formatFileSize(fileData.size)})
                         </a>
                     </div>
                 </div>
                 <div class="message-time">${timestamp}</div>
             `;
         } else {
+            const author = message.author_name ? this.escapeHtml(message.author_name) : (isOwnMessage ? 'You' : 'User');
             messageEl.innerHTML = `
-                <div class="message-header">${isOwnMessage ? 'You' : 'User'}</div>
-                <div class="message-content">${this.escapeHtml(message.content)}</div>
+                <div class="message-header">${author}${botBadge}</div>
+                <div class="message-content">${this.renderContent(message)}</div>
+                ${this.renderAttachments(message)}
+                ${this.renderLinkPreviews(message)}
                 <div class="message-time">${timestamp}</div>
             `;
         }
-        
-        this.messagesList.appendChild(messageEl);
-        this.scrollToBottom();
+
+        return messageEl;
     }
     
     async sendMessage() {
@@ -321,13 +850,16 @@ class ChatApp {
                 },
                 body: JSON.stringify({
                     content: content,
-                    message_type: 'text'
+                    message_type: 'text',
+                    expires_in: Number(this.expirySelect.value) || undefined
                 })
             });
             
             if (response.ok) {
                 this.messageInput.value = '';
-                // Message will be displayed via WebSocket
+                // Message will be displayed via WebSocket; command replies only go to us
+                const result = await response.json();
+                if (result.ephemeral) this.displayEphemeral(result.ephemeral);
             } else {
                 const error = await response.json();
                 this.showError(error.error || 'Failed to send message');
@@ -447,11 +979,148 @@ class ChatApp {
         }, 5000);
     }
     
+    // Highlights the mention spans resolved by the server (character offsets)
+    // Slack-style attachment cards posted by incoming webhooks
+    renderAttachments(message) {
+        return (message.attachments || []).map(attachment => {
+            const title = attachment.title
+                ? (attachment.title_link
+                    ? `<a href="${this.escapeAttribute(attachment.title_link)}" target="_blank" rel="noopener">${this.escapeHtml(attachment.title)}</a>`
+                    : this.escapeHtml(attachment.title))
+                : '';
+            const fields = (attachment.fields || []).map(field => `
+                <div class="attachment-field${field.short ? ' short' : ''}">
+                    <strong>${this.escapeHtml(field.title)}</strong>
+                    <div>${this.escapeHtml(field.value)}</div>
+                </div>
+            `).join('');
+            return `
+                <div class="message-attachment" style="border-left-color: ${attachment.color || '#dee2e6'}">
+                    ${attachment.pretext ? `<div>${this.escapeHtml(attachment.pretext)}</div>` : ''}
+                    ${attachment.author_name ? `<div class="attachment-author">${this.escapeHtml(attachment.author_name)}</div>` : ''}
+                    ${title ? `<div class="attachment-title">${title}</div>` : ''}
+                    ${attachment.text ? `<div>${this.escapeHtml(attachment.text)}</div>` : ''}
+                    ${fields ? `<div class="attachment-fields">${fields}</div>` : ''}
+                    ${attachment.image_url ? `<img src="${this.escapeAttribute(attachment.image_url)}" alt="" class="attachment-image">` : ''}
+                    ${attachment.footer ? `<div class="attachment-footer">${this.escapeHtml(attachment.footer)}</div>` : ''}
+                </div>
+            `;
+        }).join('');
+    }
+
+    // Shows the medium thumbnail (or a small original) over the blurhash until it loads
+    renderImagePreview(fileData) {
+        if (!fileData.content_type?.startsWith('image/') || !fileData.width) return '';
+        const thumbnails = fileData.thumbnails || [];
+        const thumbnail = thumbnails.find(t => t.size === 'medium') || thumbnails[0];
+        const src = thumbnail ? thumbnail.url : fileData.url;
+        const placeholder = fileData.blurhash ? `background-image: url(${this.blurhashToDataUrl(fileData.blurhash)});` : '';
+        return `
+            <a href="${this.escapeAttribute(fileData.url)}" target="_blank" class="image-preview">
+                <img src="${this.escapeAttribute(src)}" alt="${this.escapeAttribute(fileData.filename)}" loading="lazy"
+                    style="aspect-ratio: ${fileData.width} / ${fileData.height}; ${placeholder}">
+            </a>
+        `;
+    }
+
+    // Decodes a blurhash (https://blurha.sh) into a tiny image
+    blurhashToDataUrl(hash, width = 32, height = 32) {
+        const digits = '0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~';
+        const decode83 = text => [...text].reduce((value, c) => value * 83 + digits.indexOf(c), 0);
+        const toLinear = value => {
+            const v = value / 255;
+            return v <= 0.04045 ? v / 12.92 : Math.pow((v + 0.055) / 1.055, 2.4);
+        };
+        const toSrgb = value => {
+            const v = Math.max(0, Math.min(1, value));
+            return Math.round((v <= 0.0031308 ? v * 12.92 : 1.055 * Math.pow(v, 1 / 2.4) - 0.055) * 255);
+        };
+
+        const sizeFlag = decode83(hash[0]);
+        const numX = (sizeFlag % 9) + 1;
+        const numY = Math.floor(sizeFlag / 9) + 1;
+        const maxValue = (decode83(hash[1]) + 1) / 166;
+        const dc = decode83(hash.slice(2, 6));
+        const colors = [[toLinear(dc >> 16), toLinear((dc >> 8) & 255), toLinear(dc & 255)]];
+        for (let i = 1; i < numX * numY; i++) {
+            const value = decode83(hash.slice(4 + i * 2, 6 + i * 2));
+            colors.push([Math.floor(value / 361), Math.floor(value / 19) % 19, value % 19].map(quantized => {
+                const q = (quantized - 9) / 9;
+                return Math.sign(q) * q * q * maxValue;
+            }));
+        }
+
+        const canvas = document.createElement('canvas');
+        canvas.width = width;
+        canvas.height = height;
+        const context = canvas.getContext('2d');
+        const pixels = context.createImageData(width, height);
+        for (let y = 0; y < height; y++) {
+            for (let x = 0; x < width; x++) {
+                const rgb = [0, 0, 0];
+                for (let j = 0; j < numY; j++) {
+                    for (let i = 0; i < numX; i++) {
+                        const basis = Math.cos(Math.PI * x * i / width) * Math.cos(Math.PI * y * j / height);
+                        colors[i + j * numX].forEach((channel, c) => rgb[c] += channel * basis);
+                    }
+                }
+                pixels.data.set([...rgb.map(toSrgb), 255], 4 * (x + y * width));
+            }
+        }
+        context.putImageData(pixels, 0, 0);
+        return canvas.toDataURL();
+    }
+
+    renderLinkPreviews(message) {
+        return (message.link_previews || []).map(preview => `
+            <div class="link-preview">
+                ${preview.image_url ? `<img src="${this.escapeAttribute(preview.image_url)}" alt="" class="link-preview-image" loading="lazy">` : ''}
+                <div class="link-preview-text">
+                    ${preview.site_name ? `<div class="link-preview-site">${this.escapeHtml(preview.site_name)}</div>` : ''}
+                    <a href="${this.escapeAttribute(preview.url)}" target="_blank" rel="noopener noreferrer nofollow" class="link-preview-title">
+                        ${this.escapeHtml(preview.title || preview.url)}
+                    </a>
+                    ${preview.description ? `<div class="link-preview-description">${this.escapeHtml(preview.description)}</div>` : ''}
+                </div>
+            </div>
+        `).join('');
+    }
+
+    renderContent(message) {
+        // Formatted messages arrive as sanitized HTML rendered by the server
+        if (message.content_html != null) {
+            const template = document.createElement('template');
+            template.innerHTML = message.content_html;
+            template.content.querySelectorAll('span.mention').forEach(span => {
+                const isMe = span.dataset.mention !== 'user'
+                    || (this.currentUser && span.dataset.userId === this.currentUser.id);
+                if (isMe) span.classList.add('mention-me');
+            });
+            template.content.querySelectorAll('a').forEach(link => link.target = '_blank');
+            return template.innerHTML;
+        }
+
+        const chars = Array.from(message.content);
+        let html = '';
+        let position = 0;
+        for (const span of message.mentions || []) {
+            const isMe = span.type !== 'user' || (this.currentUser && span.user_id === this.currentUser.id);
+            html += this.escapeHtml(chars.slice(position, span.start).join(''));
+            html += `<span class="mention${isMe ? ' mention-me' : ''}">${this.escapeHtml(chars.slice(span.start, span.end).join(''))}</span>`;
+            position = span.end;
+        }
+        return html + this.escapeHtml(chars.slice(position).join(''));
+    }
+    
     escapeHtml(text) {
         const div = document.createElement('div');
         div.textContent = text;
         return div.innerHTML;
     }
+
+    escapeAttribute(text) {
+        return this.escapeHtml(text).replace(/"/g, '&quot;');
+    }
     
     formatFileSize(bytes) {
         if (bytes === 0) return '0 Bytes';
@@ -0,0 +1,18 @@
+// Shows Web Push notifications sent by the server and opens the room on click
+self.addEventListener('push', (event) => {
+    const data = event.data ? event.data.json() : {};
+    const title = data.reason === 'direct_message'
+        ? data.sender
+        : `${data.sender} in ${data.room_name}`;
+    
+    event.waitUntil(self.registration.showNotification(title, {
+        body: data.body,
+        tag: data.message_id,
+        data: { url: data.url || '/' }
+    }));
+});
+
+self.addEventListener('notificationclick', (event) => {
+    event.notification.close();
+    event.waitUntil(self.clients.openWindow(event.notification.data.url));
+});